@@ -21,7 +21,8 @@ export async function POST({ request }) {
             cache: 'no-store',
             redirect: 'error',
             mode: "cors",
-            body: request_body + "\r\n"//request_body
+            headers: {'Content-Type': 'application/json'},
+            body: request_body
         })
     } catch(err) {
         console.log("/api/ar64 errored" + err)
@@ -30,7 +31,8 @@ export async function POST({ request }) {
         return new Response(response_body, {status: status})
     }
     response_body = await response.text();
-    console.log('API: ' + request_body + ', finished, ' + response_body)
-    
-	return new Response(response_body, {status: status})
+    console.log('API: ' + request_body + ', finished, ' + response.status)
+
+    // errors of the simulator are JSON {"error": ...} with a 4xx/5xx status, pass both on
+	return new Response(response_body, {status: response.status, headers: {'Content-Type': 'application/json'}})
   }
\ No newline at end of file
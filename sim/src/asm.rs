@@ -0,0 +1,1121 @@
+use std::collections::HashMap;
+
+use crate::debug::*;
+use crate::elf::*;
+use crate::sim::*;
+
+/*
+ * Assembler for everything the engine executes: RV64I, M, A, C, Zicsr, Zifencei and the privileged instructions,
+ * so that small tests and live patches do not need a cross toolchain.
+ *
+ *      instructions    add a0, a1, a2     lw t0, 8(sp)     amoadd.w.aqrl a0, a1, (a2)     c.addi sp, -16
+ *      pseudo          nop li mv not neg negw sext.w zext.b seqz snez sltz sgtz beqz bnez blez bgez bltz bgtz
+ *                      bgt ble bgtu bleu j jal jr jalr ret call tail la lla csrr csrw csrs csrc csrwi csrsi csrci unimp
+ *      labels          name:  and numeric labels 1: referenced as 1b (backwards) and 1f (forwards)
+ *      immediates      decimal, 0x hex, 0b binary, 'c', labels, sums of them, %hi(x) and %lo(x)
+ *      directives      .byte .half .2byte .word .4byte .dword .8byte .quad .zero .space .align .p2align .balign
+ *                      .ascii .string .asciz .equ .set, the section and symbol directives are accepted and ignored
+ *
+ * Compressed instructions are only produced for c. mnemonics, the others always assemble to 32 bits.
+ * li expands to the same lui/addiw/slli/addi sequence as GNU as and LLVM, its value has to be known where li is,
+ * a number or a label defined above it.
+ * Comments start with # or //. CSRs are named like the disassembler names them or given as a number.
+ * The caller gives the most bytes a source may assemble to, .zero and .balign are checked before they allocate.
+ *
+ * The RISC-V Instruction Set Manual, Volume I, chapter RV32/64G Instruction Set Listings
+ * https://github.com/riscv-non-isa/riscv-asm-manual/blob/main/src/asm-manual.adoc
+ */
+
+// the bytes of a source, labels are the symbols it defined
+#[derive(Debug)]
+pub struct Assembly {
+    pub bytes:   Vec<u8>,
+    pub labels:  Vec<Symbol>,
+}
+
+#[derive(Debug)]
+enum Item {
+    Instruction(String, Vec<String>), // mnemonic, operands
+    Data(u64, Vec<String>),           // size of every value, values
+    Bytes(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct Line {
+    number:  usize, // in the source, for errors
+    address: u64,
+    item:    Item,
+}
+
+struct Context {
+    symbols:   HashMap<String, u64>,
+    constants: HashMap<String, u64>, // .equ and .set
+    numeric:   Vec<(String, usize, u64)>, // numeric labels: name, index of the item they precede, address
+}
+
+fn register(text: &str) -> Result<u32, String> {
+    let text = text.trim();
+    if text == "fp" {
+        return Ok(8);
+    }
+    if let Some(index) = text.strip_prefix('x').and_then(|n| n.parse::<u32>().ok()).filter(|n| *n < 32) {
+        return Ok(index);
+    }
+    return ABI_NAMES.iter().position(|n| *n == text).map(|i| i as u32).ok_or(format!("not a register: {}", text));
+}
+
+// x8-x15, the registers of the 3 bit fields of compressed instructions
+fn compressed_register(text: &str) -> Result<u32, String> {
+    let number = register(text)?;
+    if !(8..16).contains(&number) {
+        return Err(format!("{} is not one of x8-x15", text.trim()));
+    }
+    return Ok(number - 8);
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        return u64::from_str_radix(hex, 16).ok().map(|v| v as i64);
+    }
+    if let Some(binary) = text.strip_prefix("0b") {
+        return u64::from_str_radix(binary, 2).ok().map(|v| v as i64);
+    }
+    if text.len() == 3 && text.starts_with('\'') && text.ends_with('\'') {
+        return text.chars().nth(1).map(|c| c as i64);
+    }
+    return text.parse::<u64>().ok().map(|v| v as i64);
+}
+
+// "0x800 <loop>" as the disassembler writes targets is just 0x800
+fn strip_symbol(text: &str) -> &str {
+    return match text.find('<') {
+        Some(i) if text.trim_end().ends_with('>') => text[..i].trim(),
+        _ => text.trim(),
+    };
+}
+
+fn low_12(value: i64) -> i64 {
+    return ((value & 0xfff) ^ 0x800) - 0x800;
+}
+
+fn high_20(value: i64) -> i64 {
+    return ((value + 0x800) >> 12) & 0xfffff;
+}
+
+impl Context {
+    // a label, numeric label or .equ constant, index is the item that refers to it
+    fn symbol(&self, name: &str, index: usize) -> Option<u64> {
+        if let Some(number) = name.strip_suffix('b').filter(|n| n.chars().all(|c| c.is_ascii_digit()) && !n.is_empty()) {
+            return self.numeric.iter().rev().find(|(n, i, _)| n == number && *i <= index).map(|(_, _, a)| *a);
+        }
+        if let Some(number) = name.strip_suffix('f').filter(|n| n.chars().all(|c| c.is_ascii_digit()) && !n.is_empty()) {
+            return self.numeric.iter().find(|(n, i, _)| n == number && *i > index).map(|(_, _, a)| *a);
+        }
+        return self.symbols.get(name).or(self.constants.get(name)).copied();
+    }
+
+    // number | symbol | %hi(expression) | %lo(expression), joined by + and -
+    fn value(&self, text: &str, index: usize) -> Result<i64, String> {
+        let text = strip_symbol(text);
+        if let Some(inner) = text.strip_prefix("%hi(").and_then(|t| t.strip_suffix(')')) {
+            return Ok(high_20(self.value(inner, index)?));
+        }
+        if let Some(inner) = text.strip_prefix("%lo(").and_then(|t| t.strip_suffix(')')) {
+            return Ok(low_12(self.value(inner, index)?));
+        }
+        let mut total: i64 = 0;
+        let mut sign = 1;
+        let mut term = String::new();
+        let mut terms = vec![];
+        for (i, c) in text.chars().enumerate() {
+            if (c == '+' || c == '-') && !term.trim().is_empty() && !term.starts_with('\'') {
+                terms.push((sign, std::mem::take(&mut term)));
+                sign = if c == '-' { -1 } else { 1 };
+            } else if (c == '+' || c == '-') && term.trim().is_empty() && i + 1 < text.len() {
+                sign *= if c == '-' { -1 } else { 1 };
+            } else {
+                term.push(c);
+            }
+        }
+        terms.push((sign, term));
+        for (sign, term) in terms {
+            let term = term.trim();
+            let value = match parse_number(term) {
+                Some(value) => value,
+                None => self.symbol(term, index).ok_or(format!("unknown value: {}", term))? as i64,
+            };
+            total = total.wrapping_add(value.wrapping_mul(sign));
+        }
+        return Ok(total);
+    }
+
+    fn csr(&self, text: &str, index: usize) -> Result<u32, String> {
+        let text = text.trim();
+        if let Some(info) = csr_named(text) {
+            return Ok(info.address);
+        }
+        let address = self.value(text, index).map_err(|_| format!("unknown CSR: {}", text))?;
+        return if (0..0x1000).contains(&address) { Ok(address as u32) } else { Err(format!("CSR out of range: {}", text)) };
+    }
+}
+
+// value fits in a signed field of bits bits
+fn signed(value: i64, bits: u32, what: &str) -> Result<i64, String> {
+    let limit = 1i64 << (bits - 1);
+    if value < -limit || value >= limit {
+        return Err(format!("{} {} does not fit in {} bits", what, value, bits));
+    }
+    return Ok(value);
+}
+
+fn unsigned(value: i64, bits: u32, what: &str) -> Result<u32, String> {
+    if value < 0 || value >= 1i64 << bits {
+        return Err(format!("{} {} does not fit in {} unsigned bits", what, value, bits));
+    }
+    return Ok(value as u32);
+}
+
+fn r_type(opcode: u32, func3: u32, func7: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    return func7 << 25 | rs2 << 20 | rs1 << 15 | func3 << 12 | rd << 7 | opcode;
+}
+
+fn i_type(opcode: u32, func3: u32, rd: u32, rs1: u32, imm: i64) -> u32 {
+    return ((imm as u32) & 0xfff) << 20 | rs1 << 15 | func3 << 12 | rd << 7 | opcode;
+}
+
+fn s_type(func3: u32, rs1: u32, rs2: u32, imm: i64) -> u32 {
+    let imm = imm as u32;
+    return ((imm >> 5) & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | func3 << 12 | (imm & 0x1f) << 7 | 0b0100011;
+}
+
+fn b_type(func3: u32, rs1: u32, rs2: u32, offset: i64) -> u32 {
+    let imm = offset as u32;
+    return ((imm >> 12) & 1) << 31 | ((imm >> 5) & 0x3f) << 25 | rs2 << 20 | rs1 << 15 | func3 << 12
+        | ((imm >> 1) & 0xf) << 8 | ((imm >> 11) & 1) << 7 | 0b1100011;
+}
+
+fn u_type(opcode: u32, rd: u32, imm: i64) -> u32 {
+    return ((imm as u32) & 0xfffff) << 12 | rd << 7 | opcode;
+}
+
+fn j_type(rd: u32, offset: i64) -> u32 {
+    let imm = offset as u32;
+    return ((imm >> 20) & 1) << 31 | ((imm >> 1) & 0x3ff) << 21 | ((imm >> 11) & 1) << 20 | ((imm >> 12) & 0xff) << 12 | rd << 7 | 0b1101111;
+}
+
+// the instructions li rd, value expands to, the recursive lui/addiw + slli/addi split of LLVM's RISCVMatInt
+fn li_sequence(rd: u32, value: i64) -> Vec<u32> {
+    if (-2048..2048).contains(&value) {
+        return vec![i_type(0b0010011, 0b000, rd, 0, value)];
+    }
+    if value == value as i32 as i64 {
+        let high = high_20(value);
+        let low = low_12(value);
+        let mut sequence = vec![u_type(0b0110111, rd, high)];
+        if low != 0 {
+            sequence.push(i_type(0b0011011, 0b000, rd, rd, low));
+        }
+        return sequence;
+    }
+    let low = low_12(value);
+    let mut high = value.wrapping_sub(low) >> 12;
+    let shift = 12 + high.trailing_zeros();
+    high >>= shift - 12;
+    let mut sequence = li_sequence(rd, high);
+    sequence.push(i_type(0b0010011, 0b001, rd, rd, shift as i64));
+    if low != 0 {
+        sequence.push(i_type(0b0010011, 0b000, rd, rd, low));
+    }
+    return sequence;
+}
+
+// "imm(reg)" or "(reg)"
+fn memory_operand(context: &Context, text: &str, index: usize) -> Result<(i64, u32), String> {
+    let text = text.trim();
+    let open = text.rfind('(').ok_or(format!("expected offset(register): {}", text))?;
+    let base = text[open + 1..].strip_suffix(')').ok_or(format!("expected offset(register): {}", text))?;
+    let offset = if text[..open].trim().is_empty() { 0 } else { context.value(&text[..open], index)? };
+    return Ok((offset, register(base)?));
+}
+
+// the predecessor or successor set of FENCE, "iorw"
+fn fence_set(text: &str) -> Result<u32, String> {
+    if text.trim() == "0" {
+        return Ok(0);
+    }
+    let mut bits = 0;
+    for c in text.trim().chars() {
+        bits |= match c {
+            'i' => 8,
+            'o' => 4,
+            'r' => 2,
+            'w' => 1,
+            _ => return Err(format!("not a fence set: {}", text)),
+        };
+    }
+    return Ok(bits);
+}
+
+fn expect(operands: &[String], count: usize, mnemonic: &str) -> Result<(), String> {
+    if operands.len() != count {
+        return Err(format!("{} takes {} operands, not {}", mnemonic, count, operands.len()));
+    }
+    return Ok(());
+}
+
+// bytes an instruction assembles to, the value of li has to be known already
+fn instruction_size(context: &Context, mnemonic: &str, operands: &[String], index: usize) -> Result<u64, String> {
+    return match mnemonic {
+        "li" => {
+            expect(operands, 2, mnemonic)?;
+            Ok(4 * li_sequence(0, context.value(&operands[1], index)?).len() as u64)
+        },
+        "la" | "lla" | "call" | "tail" => Ok(8),
+        _ if mnemonic.starts_with("c.") => Ok(2),
+        _ => Ok(4),
+    };
+}
+
+fn branch_offset(context: &Context, text: &str, index: usize, pc: u64, bits: u32) -> Result<i64, String> {
+    let target = context.value(text, index)?;
+    let offset = signed(target.wrapping_sub(pc as i64), bits, "offset")?;
+    if offset & 1 != 0 {
+        return Err(format!("odd offset {}", offset));
+    }
+    return Ok(offset);
+}
+
+// auipc + the low 12 bits of the distance from pc to a label, for la, call and tail
+fn pc_relative(context: &Context, text: &str, index: usize, pc: u64) -> Result<(i64, i64), String> {
+    let offset = signed(context.value(text, index)?.wrapping_sub(pc as i64), 32, "distance")?;
+    return Ok((high_20(offset), low_12(offset)));
+}
+
+fn encode(context: &Context, mnemonic: &str, operands: &[String], index: usize, pc: u64) -> Result<Vec<u32>, String> {
+    let ops = operands;
+    let count = |n: usize| expect(ops, n, mnemonic);
+    let value = |i: usize| context.value(&ops[i], index);
+    let reg = |i: usize| register(&ops[i]);
+    let one = |word: u32| Ok(vec![word]);
+
+    let r_types: &[(&str, u32, u32, u32)] = &[
+        ("add", 0b0110011, 0, 0), ("sub", 0b0110011, 0, 0x20), ("sll", 0b0110011, 1, 0), ("slt", 0b0110011, 2, 0),
+        ("sltu", 0b0110011, 3, 0), ("xor", 0b0110011, 4, 0), ("srl", 0b0110011, 5, 0), ("sra", 0b0110011, 5, 0x20),
+        ("or", 0b0110011, 6, 0), ("and", 0b0110011, 7, 0),
+        ("mul", 0b0110011, 0, 1), ("mulh", 0b0110011, 1, 1), ("mulhsu", 0b0110011, 2, 1), ("mulhu", 0b0110011, 3, 1),
+        ("div", 0b0110011, 4, 1), ("divu", 0b0110011, 5, 1), ("rem", 0b0110011, 6, 1), ("remu", 0b0110011, 7, 1),
+        ("addw", 0b0111011, 0, 0), ("subw", 0b0111011, 0, 0x20), ("sllw", 0b0111011, 1, 0), ("srlw", 0b0111011, 5, 0),
+        ("sraw", 0b0111011, 5, 0x20), ("mulw", 0b0111011, 0, 1), ("divw", 0b0111011, 4, 1), ("divuw", 0b0111011, 5, 1),
+        ("remw", 0b0111011, 6, 1), ("remuw", 0b0111011, 7, 1),
+    ];
+    let i_types: &[(&str, u32, u32)] = &[
+        ("addi", 0b0010011, 0), ("slti", 0b0010011, 2), ("sltiu", 0b0010011, 3), ("xori", 0b0010011, 4),
+        ("ori", 0b0010011, 6), ("andi", 0b0010011, 7), ("addiw", 0b0011011, 0),
+    ];
+    // opcode, func3, the upper bits of the immediate, width of shamt
+    let shifts: &[(&str, u32, u32, i64, u32)] = &[
+        ("slli", 0b0010011, 1, 0, 6), ("srli", 0b0010011, 5, 0, 6), ("srai", 0b0010011, 5, 0x400, 6),
+        ("slliw", 0b0011011, 1, 0, 5), ("srliw", 0b0011011, 5, 0, 5), ("sraiw", 0b0011011, 5, 0x400, 5),
+    ];
+    let loads = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu"];
+    let stores = ["sb", "sh", "sw", "sd"];
+    let branches = [("beq", 0), ("bne", 1), ("blt", 4), ("bge", 5), ("bltu", 6), ("bgeu", 7)];
+
+    if let Some((_, opcode, func3, func7)) = r_types.iter().find(|t| t.0 == mnemonic) {
+        count(3)?;
+        return one(r_type(*opcode, *func3, *func7, reg(0)?, reg(1)?, reg(2)?));
+    }
+    if let Some((_, opcode, func3)) = i_types.iter().find(|t| t.0 == mnemonic) {
+        count(3)?;
+        return one(i_type(*opcode, *func3, reg(0)?, reg(1)?, signed(value(2)?, 12, "immediate")?));
+    }
+    if let Some((_, opcode, func3, upper, width)) = shifts.iter().find(|t| t.0 == mnemonic) {
+        count(3)?;
+        let shamt = unsigned(value(2)?, *width, "shift")? as i64;
+        return one(i_type(*opcode, *func3, reg(0)?, reg(1)?, upper | shamt));
+    }
+    if let Some(func3) = loads.iter().position(|l| *l == mnemonic) {
+        count(2)?;
+        let (offset, base) = memory_operand(context, &ops[1], index)?;
+        return one(i_type(0b0000011, func3 as u32, reg(0)?, base, signed(offset, 12, "offset")?));
+    }
+    if let Some(func3) = stores.iter().position(|s| *s == mnemonic) {
+        count(2)?;
+        let (offset, base) = memory_operand(context, &ops[1], index)?;
+        return one(s_type(func3 as u32, base, reg(0)?, signed(offset, 12, "offset")?));
+    }
+    if let Some((_, func3)) = branches.iter().find(|b| b.0 == mnemonic) {
+        count(3)?;
+        return one(b_type(*func3, reg(0)?, reg(1)?, branch_offset(context, &ops[2], index, pc, 13)?));
+    }
+    if mnemonic.starts_with("lr.") || mnemonic.starts_with("sc.") || mnemonic.starts_with("amo") {
+        return one(encode_atomic(mnemonic, ops)?);
+    }
+    if let Some(compressed) = mnemonic.strip_prefix("c.") {
+        return Ok(vec![encode_compressed(context, compressed, ops, index, pc)? as u32]);
+    }
+
+    return match mnemonic {
+        "lui" | "auipc" => {
+            count(2)?;
+            let imm = value(1)?;
+            if !(-0x80000..0x100000).contains(&imm) {
+                return Err(format!("immediate {} does not fit in 20 bits", imm));
+            }
+            one(u_type(if mnemonic == "lui" { 0b0110111 } else { 0b0010111 }, reg(0)?, imm))
+        },
+        "jal" if ops.len() == 1 => one(j_type(1, branch_offset(context, &ops[0], index, pc, 21)?)),
+        "jal" => {
+            count(2)?;
+            one(j_type(reg(0)?, branch_offset(context, &ops[1], index, pc, 21)?))
+        },
+        "jalr" => match ops.len() {
+            1 => one(i_type(0b1100111, 0, 1, reg(0)?, 0)),
+            2 => {
+                let (offset, base) = memory_operand(context, &ops[1], index)?;
+                one(i_type(0b1100111, 0, reg(0)?, base, signed(offset, 12, "offset")?))
+            },
+            _ => {
+                count(3)?;
+                one(i_type(0b1100111, 0, reg(0)?, reg(1)?, signed(value(2)?, 12, "offset")?))
+            },
+        },
+        "fence" if ops.is_empty() => one(0x0ff0000f),
+        "fence" => {
+            count(2)?;
+            one((fence_set(&ops[0])? << 24 | fence_set(&ops[1])? << 20) | 0b0001111)
+        },
+        "fence.i" => one(0x0000100f),
+        "fence.tso" => one(0x8330000f),
+        "ecall" => one(0x00000073),
+        "ebreak" => one(0x00100073),
+        "sret" => one(0x10200073),
+        "mret" => one(0x30200073),
+        "wfi" => one(0x10500073),
+        "sfence.vma" => match ops.len() {
+            0 => one(r_type(0b1110011, 0, 0b0001001, 0, 0, 0)),
+            1 => one(r_type(0b1110011, 0, 0b0001001, 0, reg(0)?, 0)),
+            _ => {
+                count(2)?;
+                one(r_type(0b1110011, 0, 0b0001001, 0, reg(0)?, reg(1)?))
+            },
+        },
+        "csrrw" | "csrrs" | "csrrc" | "csrrwi" | "csrrsi" | "csrrci" => {
+            count(3)?;
+            let func3 = ["", "csrrw", "csrrs", "csrrc", "", "csrrwi", "csrrsi", "csrrci"].iter().position(|n| *n == mnemonic).unwrap() as u32;
+            let csr = context.csr(&ops[1], index)?;
+            let source = if func3 >= 5 { unsigned(value(2)?, 5, "immediate")? } else { reg(2)? };
+            one(csr << 20 | source << 15 | func3 << 12 | reg(0)? << 7 | 0b1110011)
+        },
+        "csrr" => {
+            count(2)?;
+            one(context.csr(&ops[1], index)? << 20 | 0b010 << 12 | reg(0)? << 7 | 0b1110011)
+        },
+        "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" => {
+            count(2)?;
+            let func3 = ["", "csrw", "csrs", "csrc", "", "csrwi", "csrsi", "csrci"].iter().position(|n| *n == mnemonic).unwrap() as u32;
+            let source = if func3 >= 5 { unsigned(value(1)?, 5, "immediate")? } else { reg(1)? };
+            one(context.csr(&ops[0], index)? << 20 | source << 15 | func3 << 12 | 0b1110011)
+        },
+        "unimp" => one(0xc0001073),
+        "nop" => one(i_type(0b0010011, 0, 0, 0, 0)),
+        "li" => {
+            count(2)?;
+            Ok(li_sequence(reg(0)?, value(1)?))
+        },
+        "mv" => {
+            count(2)?;
+            one(i_type(0b0010011, 0, reg(0)?, reg(1)?, 0))
+        },
+        "not" => {
+            count(2)?;
+            one(i_type(0b0010011, 4, reg(0)?, reg(1)?, -1))
+        },
+        "neg" | "negw" => {
+            count(2)?;
+            one(r_type(if mnemonic == "neg" { 0b0110011 } else { 0b0111011 }, 0, 0x20, reg(0)?, 0, reg(1)?))
+        },
+        "sext.w" => {
+            count(2)?;
+            one(i_type(0b0011011, 0, reg(0)?, reg(1)?, 0))
+        },
+        "zext.b" => {
+            count(2)?;
+            one(i_type(0b0010011, 7, reg(0)?, reg(1)?, 255))
+        },
+        "seqz" => {
+            count(2)?;
+            one(i_type(0b0010011, 3, reg(0)?, reg(1)?, 1))
+        },
+        "snez" => {
+            count(2)?;
+            one(r_type(0b0110011, 3, 0, reg(0)?, 0, reg(1)?))
+        },
+        "sltz" => {
+            count(2)?;
+            one(r_type(0b0110011, 2, 0, reg(0)?, reg(1)?, 0))
+        },
+        "sgtz" => {
+            count(2)?;
+            one(r_type(0b0110011, 2, 0, reg(0)?, 0, reg(1)?))
+        },
+        "beqz" | "bnez" | "bltz" | "bgez" | "blez" | "bgtz" => {
+            count(2)?;
+            let offset = branch_offset(context, &ops[1], index, pc, 13)?;
+            let rs = reg(0)?;
+            one(match mnemonic {
+                "beqz" => b_type(0, rs, 0, offset),
+                "bnez" => b_type(1, rs, 0, offset),
+                "bltz" => b_type(4, rs, 0, offset),
+                "bgez" => b_type(5, rs, 0, offset),
+                "blez" => b_type(5, 0, rs, offset),
+                _ => b_type(4, 0, rs, offset),
+            })
+        },
+        "bgt" | "ble" | "bgtu" | "bleu" => {
+            count(3)?;
+            let func3 = match mnemonic { "bgt" => 4, "ble" => 5, "bgtu" => 6, _ => 7 };
+            one(b_type(func3, reg(1)?, reg(0)?, branch_offset(context, &ops[2], index, pc, 13)?))
+        },
+        "j" => {
+            count(1)?;
+            one(j_type(0, branch_offset(context, &ops[0], index, pc, 21)?))
+        },
+        "jr" => {
+            count(1)?;
+            one(i_type(0b1100111, 0, 0, reg(0)?, 0))
+        },
+        "ret" => one(i_type(0b1100111, 0, 0, 1, 0)),
+        "call" | "tail" => {
+            count(1)?;
+            let (high, low) = pc_relative(context, &ops[0], index, pc)?;
+            let (link, scratch) = if mnemonic == "call" { (1, 1) } else { (0, 6) };
+            Ok(vec![u_type(0b0010111, scratch, high), i_type(0b1100111, 0, link, scratch, low)])
+        },
+        "la" | "lla" => {
+            count(2)?;
+            let rd = reg(0)?;
+            let (high, low) = pc_relative(context, &ops[1], index, pc)?;
+            Ok(vec![u_type(0b0010111, rd, high), i_type(0b0010011, 0, rd, rd, low)])
+        },
+        _ => Err(format!("unknown instruction: {}", mnemonic)),
+    };
+}
+
+// lr.w, sc.d.aq, amoadd.w.aqrl...
+fn encode_atomic(mnemonic: &str, ops: &[String]) -> Result<u32, String> {
+    let mut parts = mnemonic.split('.');
+    let name = parts.next().unwrap_or("");
+    let func3 = match parts.next() {
+        Some("w") => 0b010,
+        Some("d") => 0b011,
+        _ => return Err(format!("unknown instruction: {}", mnemonic)),
+    };
+    let ordering = match parts.next() {
+        None => 0,
+        Some("rl") => 0b01,
+        Some("aq") => 0b10,
+        Some("aqrl") => 0b11,
+        _ => return Err(format!("unknown instruction: {}", mnemonic)),
+    };
+    let func5 = match name {
+        "lr" => 0b00010,
+        "sc" => 0b00011,
+        "amoswap" => 0b00001,
+        "amoadd" => 0b00000,
+        "amoxor" => 0b00100,
+        "amoand" => 0b01100,
+        "amoor" => 0b01000,
+        "amomin" => 0b10000,
+        "amomax" => 0b10100,
+        "amominu" => 0b11000,
+        "amomaxu" => 0b11100,
+        _ => return Err(format!("unknown instruction: {}", mnemonic)),
+    };
+    let address = |text: &str| -> Result<u32, String> {
+        let inner = text.trim().strip_prefix('(').and_then(|t| t.strip_suffix(')')).ok_or(format!("expected (register): {}", text))?;
+        return register(inner);
+    };
+    let (rd, rs2, rs1) = if name == "lr" {
+        expect(ops, 2, mnemonic)?;
+        (register(&ops[0])?, 0, address(&ops[1])?)
+    } else {
+        expect(ops, 3, mnemonic)?;
+        (register(&ops[0])?, register(&ops[1])?, address(&ops[2])?)
+    };
+    return Ok(r_type(0b0101111, func3, func5 << 2 | ordering, rd, rs1, rs2));
+}
+
+// bit `from` of value placed at bit `to`
+fn bit(value: u32, from: u32, to: u32) -> u16 {
+    return (((value >> from) & 1) << to) as u16;
+}
+
+// the C extension, the mnemonic without its "c." prefix
+fn encode_compressed(context: &Context, name: &str, ops: &[String], index: usize, pc: u64) -> Result<u16, String> {
+    let mnemonic = format!("c.{}", name);
+    let count = |n: usize| expect(ops, n, &mnemonic);
+    let value = |i: usize| context.value(&ops[i], index);
+    // a multiple of scale that fits in bits unsigned bits once divided by scale
+    let scaled = |v: i64, bits: u32, scale: i64, what: &str| -> Result<u32, String> {
+        if v % scale != 0 {
+            return Err(format!("{} {} is not a multiple of {}", what, v, scale));
+        }
+        unsigned(v, bits, what)
+    };
+    let memory = |i: usize| memory_operand(context, &ops[i], index);
+
+    return match name {
+        "nop" => Ok(0x0001),
+        "ebreak" => Ok(0x9002),
+        "unimp" => Ok(0x0000),
+        "addi4spn" => {
+            count(3)?;
+            if register(&ops[1])? != 2 {
+                return Err(String::from("c.addi4spn adds to sp"));
+            }
+            let imm = scaled(value(2)?, 10, 4, "immediate")?;
+            if imm == 0 {
+                return Err(String::from("c.addi4spn needs a non-zero immediate"));
+            }
+            Ok(bit(imm, 5, 12) | bit(imm, 4, 11) | bit(imm, 9, 10) | bit(imm, 8, 9) | bit(imm, 7, 8) | bit(imm, 6, 7)
+                | bit(imm, 2, 6) | bit(imm, 3, 5) | (compressed_register(&ops[0])? << 2) as u16)
+        },
+        "lw" | "sw" | "ld" | "sd" => {
+            count(2)?;
+            let (offset, base) = memory(1)?;
+            if !(8..16).contains(&base) {
+                return Err(format!("{} addresses through one of x8-x15", mnemonic));
+            }
+            let rd = compressed_register(&ops[0])?;
+            let (func3, imm) = match name {
+                "lw" => (0b010, scaled(offset, 7, 4, "offset")?),
+                "sw" => (0b110, scaled(offset, 7, 4, "offset")?),
+                "ld" => (0b011, scaled(offset, 8, 8, "offset")?),
+                _ => (0b111, scaled(offset, 8, 8, "offset")?),
+            };
+            let fields = if name.ends_with('w') {
+                bit(imm, 2, 6) | bit(imm, 6, 5)
+            } else {
+                bit(imm, 6, 5) | bit(imm, 7, 6)
+            };
+            Ok((func3 << 13 | ((imm >> 3) & 0x7) << 10 | (base - 8) << 7 | rd << 2) as u16 | fields)
+        },
+        "addi" | "addiw" | "li" | "andi" => {
+            count(2)?;
+            let rd = register(&ops[0])?;
+            let imm = signed(value(1)?, 6, "immediate")? as u32;
+            let (func3, quadrant_bits) = match name {
+                "addi" => (0b000, rd << 7),
+                "addiw" => (0b001, rd << 7),
+                "li" => (0b010, rd << 7),
+                _ => (0b100, 0b10 << 10 | compressed_register(&ops[0])? << 7),
+            };
+            if name == "addiw" && rd == 0 {
+                return Err(String::from("c.addiw can not write x0"));
+            }
+            Ok((func3 << 13) as u16 | bit(imm, 5, 12) | quadrant_bits as u16 | ((imm & 0x1f) << 2) as u16 | 0b01)
+        },
+        "addi16sp" => {
+            let imm = value(ops.len().max(1) - 1)?;
+            if !(-512..512).contains(&imm) || imm % 16 != 0 || imm == 0 {
+                return Err(format!("c.addi16sp immediate {} is not a non-zero multiple of 16 in [-512, 496]", imm));
+            }
+            let imm = imm as u32;
+            Ok(0b011 << 13 | bit(imm, 9, 12) | 2 << 7 | bit(imm, 4, 6) | bit(imm, 6, 5) | bit(imm, 8, 4) | bit(imm, 7, 3) | bit(imm, 5, 2) | 0b01)
+        },
+        "lui" => {
+            count(2)?;
+            let rd = register(&ops[0])?;
+            let imm = value(1)?;
+            // the 6 bit field as the upper 20 bits lui would take, 1-0x1f or 0xfffe0-0xfffff
+            let field = match imm {
+                1..=0x1f => imm as u32,
+                0xfffe0..=0xfffff => (imm & 0x3f) as u32,
+                -32..=-1 => (imm & 0x3f) as u32,
+                _ => return Err(format!("c.lui immediate 0x{:x} out of range", imm)),
+            };
+            if rd == 0 || rd == 2 {
+                return Err(String::from("c.lui can not write x0 or sp"));
+            }
+            Ok(0b011 << 13 | bit(field, 5, 12) | (rd << 7) as u16 | ((field & 0x1f) << 2) as u16 | 0b01)
+        },
+        "srli" | "srai" | "slli" => {
+            count(2)?;
+            let shamt = unsigned(value(1)?, 6, "shift")?;
+            if name == "slli" {
+                return Ok(bit(shamt, 5, 12) | (register(&ops[0])? << 7) as u16 | ((shamt & 0x1f) << 2) as u16 | 0b10);
+            }
+            let kind = if name == "srli" { 0b00 } else { 0b01 };
+            Ok(0b100 << 13 | bit(shamt, 5, 12) | kind << 10 | (compressed_register(&ops[0])? << 7) as u16 | ((shamt & 0x1f) << 2) as u16 | 0b01)
+        },
+        "sub" | "xor" | "or" | "and" | "subw" | "addw" => {
+            count(2)?;
+            let (word, func2) = match name {
+                "sub" => (0, 0b00),
+                "xor" => (0, 0b01),
+                "or" => (0, 0b10),
+                "and" => (0, 0b11),
+                "subw" => (1, 0b00),
+                _ => (1, 0b01),
+            };
+            Ok(0b100 << 13 | word << 12 | 0b11 << 10 | (compressed_register(&ops[0])? << 7) as u16 | func2 << 5
+                | (compressed_register(&ops[1])? << 2) as u16 | 0b01)
+        },
+        "j" => {
+            count(1)?;
+            let imm = branch_offset(context, &ops[0], index, pc, 12)? as u32;
+            Ok(0b101 << 13 | bit(imm, 11, 12) | bit(imm, 4, 11) | bit(imm, 9, 10) | bit(imm, 8, 9) | bit(imm, 10, 8)
+                | bit(imm, 6, 7) | bit(imm, 7, 6) | bit(imm, 3, 5) | bit(imm, 2, 4) | bit(imm, 1, 3) | bit(imm, 5, 2) | 0b01)
+        },
+        "beqz" | "bnez" => {
+            count(2)?;
+            let imm = branch_offset(context, &ops[1], index, pc, 9)? as u32;
+            let func3 = if name == "beqz" { 0b110 } else { 0b111 };
+            Ok(func3 << 13 | bit(imm, 8, 12) | bit(imm, 4, 11) | bit(imm, 3, 10) | (compressed_register(&ops[0])? << 7) as u16
+                | bit(imm, 7, 6) | bit(imm, 6, 5) | bit(imm, 2, 4) | bit(imm, 1, 3) | bit(imm, 5, 2) | 0b01)
+        },
+        "lwsp" | "ldsp" | "swsp" | "sdsp" => {
+            count(2)?;
+            let (offset, base) = memory(1)?;
+            if base != 2 {
+                return Err(format!("{} addresses through sp", mnemonic));
+            }
+            let rs = register(&ops[0])?;
+            match name {
+                "lwsp" => {
+                    let imm = scaled(offset, 8, 4, "offset")?;
+                    Ok(0b010 << 13 | bit(imm, 5, 12) | (rs << 7) as u16 | (((imm >> 2) & 0x7) << 4) as u16 | (((imm >> 6) & 0x3) << 2) as u16 | 0b10)
+                },
+                "ldsp" => {
+                    let imm = scaled(offset, 9, 8, "offset")?;
+                    Ok(0b011 << 13 | bit(imm, 5, 12) | (rs << 7) as u16 | (((imm >> 3) & 0x3) << 5) as u16 | (((imm >> 6) & 0x7) << 2) as u16 | 0b10)
+                },
+                "swsp" => {
+                    let imm = scaled(offset, 8, 4, "offset")?;
+                    Ok(0b110 << 13 | (((imm >> 2) & 0xf) << 9) as u16 | (((imm >> 6) & 0x3) << 7) as u16 | (rs << 2) as u16 | 0b10)
+                },
+                _ => {
+                    let imm = scaled(offset, 9, 8, "offset")?;
+                    Ok(0b111 << 13 | (((imm >> 3) & 0x7) << 10) as u16 | (((imm >> 6) & 0x7) << 7) as u16 | (rs << 2) as u16 | 0b10)
+                },
+            }
+        },
+        "jr" | "jalr" => {
+            count(1)?;
+            let rs1 = register(&ops[0])?;
+            if rs1 == 0 {
+                return Err(format!("{} needs a register other than x0", mnemonic));
+            }
+            let link = if name == "jalr" { 1 << 12 } else { 0 };
+            Ok(0b100 << 13 | link | (rs1 << 7) as u16 | 0b10)
+        },
+        "mv" | "add" => {
+            count(2)?;
+            let rs2 = register(&ops[1])?;
+            if rs2 == 0 {
+                return Err(format!("{} needs a source other than x0", mnemonic));
+            }
+            let add = if name == "add" { 1 << 12 } else { 0 };
+            Ok(0b100 << 13 | add | (register(&ops[0])? << 7) as u16 | (rs2 << 2) as u16 | 0b10)
+        },
+        _ => Err(format!("unknown instruction: {}", mnemonic)),
+    };
+}
+
+// "a, b, c" split at the commas that are not inside a string or character
+fn split_operands(text: &str) -> Vec<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return vec![];
+    }
+    let mut operands = vec![];
+    let mut current = String::new();
+    let mut quoted = None;
+    for c in text.chars() {
+        match (c, quoted) {
+            ('"' | '\'', None) => quoted = Some(c),
+            (_, Some(q)) if c == q => quoted = None,
+            (',', None) => {
+                operands.push(current.trim().to_string());
+                current.clear();
+                continue;
+            },
+            _ => {},
+        }
+        current.push(c);
+    }
+    operands.push(current.trim().to_string());
+    return operands;
+}
+
+// the text of a "..." string with its \n \t \0 \\ \" escapes
+fn parse_string(text: &str) -> Result<Vec<u8>, String> {
+    let inner = text.trim().strip_prefix('"').and_then(|t| t.strip_suffix('"')).ok_or(format!("expected a string: {}", text))?;
+    let mut bytes = vec![];
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some(c) => c,
+                None => '\\',
+            },
+            c => c,
+        };
+        let mut buffer = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+    }
+    return Ok(bytes);
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            '/' if !quoted && line[i..].starts_with("//") => return &line[..i],
+            _ => {},
+        }
+    }
+    return line;
+}
+
+fn is_label(text: &str) -> bool {
+    return !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$');
+}
+
+// the size bytes of a line still fit after the used ones
+fn fits(used: u64, size: u64, max_size: u64) -> Result<(), String> {
+    if size > max_size.saturating_sub(used) {
+        return Err(format!("assembles to more than {} bytes", max_size));
+    }
+    return Ok(());
+}
+
+// first pass: every line at its address, labels and constants defined
+fn layout(context: &mut Context, source: &str, address: u64, max_size: u64) -> Result<Vec<Line>, String> {
+    let mut lines = vec![];
+    let mut pc = address;
+    for (number, text) in source.lines().enumerate() {
+        let number = number + 1;
+        let error = |e: String| format!("line {}: {}", number, e);
+        let mut text = strip_comment(text).trim();
+        // labels, any number of them before the instruction
+        while let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if !is_label(label) || label.contains(' ') {
+                break;
+            }
+            if label.chars().all(|c| c.is_ascii_digit()) {
+                context.numeric.push((label.to_string(), lines.len(), pc));
+            } else if context.symbols.insert(label.to_string(), pc).is_some() {
+                return Err(error(format!("{} is defined twice", label)));
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let (mnemonic, rest) = match text.split_once(char::is_whitespace) {
+            Some((mnemonic, rest)) => (mnemonic.to_lowercase(), rest),
+            None => (text.to_lowercase(), ""),
+        };
+        let operands = split_operands(rest);
+        let (item, size) = match mnemonic.as_str() {
+            ".byte" => (Item::Data(1, operands.clone()), operands.len() as u64),
+            ".half" | ".2byte" | ".short" => (Item::Data(2, operands.clone()), 2 * operands.len() as u64),
+            ".word" | ".4byte" | ".long" => (Item::Data(4, operands.clone()), 4 * operands.len() as u64),
+            ".dword" | ".8byte" | ".quad" => (Item::Data(8, operands.clone()), 8 * operands.len() as u64),
+            ".zero" | ".space" | ".skip" => {
+                let size = context.value(operands.first().map_or("", |o| o.as_str()), lines.len()).map_err(error)?.max(0) as u64;
+                fits(pc.wrapping_sub(address), size, max_size).map_err(error)?;
+                (Item::Bytes(vec![0; size as usize]), size)
+            },
+            ".align" | ".p2align" | ".balign" => {
+                let value = context.value(operands.first().map_or("", |o| o.as_str()), lines.len()).map_err(error)?;
+                let alignment = if mnemonic == ".balign" { value as u64 } else { 1u64 << value.clamp(0, 12) };
+                if !alignment.is_power_of_two() {
+                    return Err(error(format!("alignment {} is not a power of two", alignment)));
+                }
+                let padding = pc.wrapping_neg() & (alignment - 1);
+                fits(pc.wrapping_sub(address), padding, max_size).map_err(error)?;
+                (Item::Bytes(vec![0; padding as usize]), padding)
+            },
+            ".ascii" | ".string" | ".asciz" => {
+                let mut bytes = vec![];
+                for operand in operands.iter() {
+                    bytes.extend(parse_string(operand).map_err(error)?);
+                    if mnemonic != ".ascii" {
+                        bytes.push(0);
+                    }
+                }
+                let size = bytes.len() as u64;
+                (Item::Bytes(bytes), size)
+            },
+            ".equ" | ".set" => {
+                if operands.len() != 2 {
+                    return Err(error(format!("{} takes a name and a value", mnemonic)));
+                }
+                let value = context.value(&operands[1], lines.len()).map_err(error)?;
+                context.constants.insert(operands[0].clone(), value as u64);
+                continue;
+            },
+            ".globl" | ".global" | ".local" | ".text" | ".data" | ".bss" | ".rodata" | ".section" | ".type" | ".size"
+                | ".option" | ".file" | ".ident" | ".attribute" | ".weak" => continue,
+            _ if mnemonic.starts_with('.') => return Err(error(format!("unknown directive: {}", mnemonic))),
+            _ => {
+                let size = instruction_size(context, &mnemonic, &operands, lines.len()).map_err(error)?;
+                (Item::Instruction(mnemonic, operands), size)
+            },
+        };
+        fits(pc.wrapping_sub(address), size, max_size).map_err(error)?;
+        lines.push(Line { number: number, address: pc, item: item });
+        pc = pc.wrapping_add(size);
+    }
+    return Ok(lines);
+}
+
+/*
+ * Assembles source for address, the bytes are in memory order, at most max_size of them.
+ * Errors name the source line.
+ */
+pub fn assemble(source: &str, address: u64, max_size: u64) -> Result<Assembly, String> {
+    let mut context = Context {
+        symbols:   HashMap::new(),
+        constants: HashMap::new(),
+        numeric:   vec![],
+    };
+    let lines = layout(&mut context, source, address, max_size)?;
+
+    let mut bytes = vec![];
+    for (index, line) in lines.iter().enumerate() {
+        let error = |e: String| format!("line {}: {}", line.number, e);
+        match &line.item {
+            Item::Instruction(mnemonic, operands) => {
+                let words = encode(&context, mnemonic, operands, index, line.address).map_err(error)?;
+                if mnemonic.starts_with("c.") {
+                    bytes.extend_from_slice(&(words[0] as u16).to_le_bytes());
+                } else {
+                    for word in words {
+                        bytes.extend_from_slice(&word.to_le_bytes());
+                    }
+                }
+            },
+            Item::Data(size, values) => {
+                for value in values.iter() {
+                    let value = context.value(value, index).map_err(error)?;
+                    bytes.extend_from_slice(&value.to_le_bytes()[..*size as usize]);
+                }
+            },
+            Item::Bytes(data) => bytes.extend_from_slice(data),
+        }
+    }
+
+    let mut labels: Vec<Symbol> = context.symbols.iter()
+        .map(|(name, value)| Symbol { name: name.clone(), value: *value, size: 0 })
+        .collect();
+    labels.sort_by_key(|s| s.value);
+    return Ok(Assembly { bytes: bytes, labels: labels });
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::decode::*;
+    use crate::test_finisher::*;
+
+    // batch steps a test program gets to report through the test finisher
+    const MAX_TEST_STEPS: usize = 1_000_000;
+
+    // reports pass to the test finisher, the end of every test program
+    pub const PASS: &str = "
+        li t0, 0x100000
+        li t1, 0x5555
+        sw t1, 0(t0)
+    1:  j 1b
+    ";
+
+    // source assembled at 0 in a machine with harts HARTs, like -T loads a .s
+    pub fn assembled_sim(source: &str, harts: usize) -> Simulator {
+        let mut sim = new_sim(harts, 0, 0x10000);
+        let assembly = assemble(source, 0, sim.mem.len() as u64).unwrap();
+        sim.mem[..assembly.bytes.len()].copy_from_slice(&assembly.bytes);
+        sim.image_end = assembly.bytes.len() as u64;
+        sim.symbols = assembly.labels;
+        return sim;
+    }
+
+    // batch steps until the guest reports a result
+    pub fn run_to_exit(sim: &mut Simulator) -> Option<FinisherStatus> {
+        for _ in 0..MAX_TEST_STEPS {
+            if !batch_step(sim) {
+                break;
+            }
+        }
+        return guest_exit_status(sim);
+    }
+
+    // what a run leaves behind: registers, pc and CSRs of every HART, RAM and the timer
+    pub fn assert_same_machine(sim: &Simulator, reference: &Simulator) {
+        for (hart, (state, expected)) in sim.states.iter().zip(&reference.states).enumerate() {
+            assert_eq!(state.regs, expected.regs, "registers of HART {}", hart);
+            assert_eq!(state.pc, expected.pc, "pc of HART {}", hart);
+            assert_eq!(serde_json::to_value(&state.csr).unwrap(), serde_json::to_value(&expected.csr).unwrap(), "CSRs of HART {}", hart);
+        }
+        assert!(sim.mem == reference.mem, "RAM differs");
+        assert_eq!(sim.devices.clint.mtime, reference.devices.clint.mtime);
+    }
+
+    fn run(source: &str) -> Simulator {
+        let mut sim = assembled_sim(&format!("{}\n{}", source, PASS), 1);
+        assert_eq!(run_to_exit(&mut sim), Some(FinisherStatus::Pass));
+        return sim;
+    }
+
+    // the first instruction of source
+    fn first(source: &str) -> Decoded {
+        let bytes = assemble(source, 0x1000, 4096).unwrap_or_else(|e| panic!("{}: {}", source, e)).bytes;
+        let raw = if bytes[0] & 0b11 == 0b11 { u32::from_le_bytes(bytes[..4].try_into().unwrap()) } else { u16::from_le_bytes([bytes[0], bytes[1]]) as u32 };
+        return decode_raw(raw);
+    }
+
+    #[test]
+    fn decodes_what_it_assembles() {
+        let cases = [
+            ("addi a0, a1, -5",            Instruction::OpImm { op: AluOp::Add, rd: 10, rs1: 11, imm: -5 }),
+            ("sub t0, t1, t2",             Instruction::Op { op: AluOp::Sub, rd: 5, rs1: 6, rs2: 7 }),
+            ("srai a0, a0, 63",            Instruction::OpImm { op: AluOp::Sra, rd: 10, rs1: 10, imm: 63 }),
+            ("mulhu a0, a1, a2",           Instruction::Op { op: AluOp::Mulhu, rd: 10, rs1: 11, rs2: 12 }),
+            ("addiw a0, a0, -1",           Instruction::OpImm32 { op: AluOp::Add, rd: 10, rs1: 10, imm: -1 }),
+            ("lui a0, 0xfffff",            Instruction::Lui { rd: 10, imm: -4096 }),
+            ("lw t0, 8(sp)",               Instruction::Load { rd: 5, rs1: 2, offset: 8, size: 4, signed: true }),
+            ("lbu a0, -1(a1)",             Instruction::Load { rd: 10, rs1: 11, offset: -1, size: 1, signed: false }),
+            ("sd a0, -8(s0)",              Instruction::Store { rs1: 8, rs2: 10, offset: -8, size: 8 }),
+            ("beq a0, a1, 1f\nnop\n1:",    Instruction::Branch { op: BranchOp::Eq, rs1: 10, rs2: 11, offset: 8 }),
+            ("1: bltu a0, a1, 1b",         Instruction::Branch { op: BranchOp::Ltu, rs1: 10, rs2: 11, offset: 0 }),
+            ("jal ra, 2f\n.zero 2044\n2:", Instruction::Jal { rd: 1, offset: 2048 }),
+            ("jalr ra, -4(t1)",            Instruction::Jalr { rd: 1, rs1: 6, offset: -4 }),
+            ("ret",                        Instruction::Jalr { rd: 0, rs1: 1, offset: 0 }),
+            ("csrr a0, mstatus",           Instruction::Csr { op: CsrOp::Set, rd: 10, csr: 0x300, rs1: 0 }),
+            ("csrwi mscratch, 5",          Instruction::CsrImm { op: CsrOp::Write, rd: 0, csr: 0x340, uimm: 5 }),
+            ("amoadd.w.aqrl a0, a1, (a2)", Instruction::Amo { op: AmoOp::Add, rd: 10, rs1: 12, rs2: 11, size: 4, aq: true, rl: true }),
+            ("lr.d t0, (a0)",              Instruction::Lr { rd: 5, rs1: 10, size: 8, aq: false, rl: false }),
+            ("sc.d t1, t2, (a0)",          Instruction::Sc { rd: 6, rs1: 10, rs2: 7, size: 8, aq: false, rl: false }),
+            ("ecall",                      Instruction::Ecall),
+            ("mret",                       Instruction::Mret),
+            ("sfence.vma",                 Instruction::SfenceVma { rs1: 0, rs2: 0 }),
+        ];
+        for (source, expected) in cases {
+            let decoded = first(source);
+            assert_eq!(decoded.length, 4, "{}", source);
+            assert_eq!(decoded.instruction, expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn compressed_mnemonics_assemble_to_16_bits() {
+        let decoded = first("c.addi sp, -16");
+        assert_eq!(decoded.length, 2);
+        assert_eq!(decoded.instruction, Instruction::OpImm { op: AluOp::Add, rd: 2, rs1: 2, imm: -16 });
+        let decoded = first("c.ld a0, 8(a1)");
+        assert_eq!(decoded.length, 2);
+        assert_eq!(decoded.instruction, Instruction::Load { rd: 10, rs1: 11, offset: 8, size: 8, signed: true });
+    }
+
+    #[test]
+    fn li_loads_any_constant() {
+        let values: [u64; 9] = [0, 2047, (-2048i64) as u64, 0x7fffffff, 0x80000000, 0xffffffff, u64::MAX, 0x123456789abcdef0, 0x8000000000000000];
+        let source: String = values.iter().enumerate().map(|(i, v)| format!("li x{}, 0x{:x}\n", 10 + i, v)).collect();
+        let sim = run(&source);
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(sim.states[0].regs[10 + i], *value, "li 0x{:x}", value);
+        }
+    }
+
+    #[test]
+    fn executes_loops_memory_and_calls() {
+        let sim = run("
+            li a0, 0
+            li t0, 10
+        1:  add a0, a0, t0        # 10 + 9 + ... + 1
+            addi t0, t0, -1
+            bnez t0, 1b
+
+            la s0, data
+            ld a1, 0(s0)
+            lb a2, 8(s0)          # sign extended
+            lbu a3, 8(s0)
+            sw a0, 12(s0)
+            lw a4, 12(s0)
+
+            la t1, twice
+            addi t1, t1, 4
+            li a6, 21
+            jalr ra, -4(t1)       # a negative offset has to be sign extended
+            mv a7, a6
+            j done
+        twice:
+            add a6, a6, a6
+            ret
+        .balign 8
+        data:
+            .dword 0x1122334455667788
+            .byte 0x80, 0, 0, 0
+            .word 0
+        done:
+            la s1, data
+            addi s1, s1, 8
+            lhu a5, -2(s1)        # the high half of the dword
+        ");
+        let regs = &sim.states[0].regs;
+        assert_eq!(regs[10], 55);
+        assert_eq!(regs[11], 0x1122334455667788);
+        assert_eq!(regs[12], (-128i64) as u64);
+        assert_eq!(regs[13], 0x80);
+        assert_eq!(regs[14], 55);
+        assert_eq!(regs[15], 0x1122);
+        assert_eq!(regs[17], 42);
+    }
+
+    #[test]
+    fn data_directives_lay_out_bytes_and_labels() {
+        let assembly = assemble("
+            .equ SIZE, 3
+            .byte 1, SIZE
+            .half 0x0203
+            .balign 8
+        aligned:
+            .word 0xdeadbeef
+            .asciz \"ok\"
+            .zero SIZE
+        end:
+        ", 0x100, 64).unwrap();
+        assert_eq!(assembly.bytes, [1, 3, 3, 2, 0, 0, 0, 0, 0xef, 0xbe, 0xad, 0xde, b'o', b'k', 0, 0, 0, 0]);
+        let label = |name: &str| assembly.labels.iter().find(|s| s.name == name).unwrap().value;
+        assert_eq!(label("aligned"), 0x108);
+        assert_eq!(label("end"), 0x112);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = assemble("nop\nfrobnicate a0\n", 0, 64).unwrap_err();
+        assert!(error.starts_with("line 2:"), "{}", error);
+        let error = assemble("nop\nnop\naddi a0, a0, 4096\n", 0, 64).unwrap_err();
+        assert!(error.starts_with("line 3:"), "{}", error);
+        assert!(assemble("beq a0, a1, nowhere", 0, 64).is_err());
+    }
+
+    #[test]
+    fn sizes_are_checked_before_allocating() {
+        assert!(assemble(".zero 64", 0, 64).is_ok());
+        assert!(assemble(".zero 65", 0, 64).is_err());
+        assert!(assemble("nop\n.zero 0x7fffffffffffffff", 0, 64).is_err());
+        assert!(assemble(".balign 0x4000000000000000\nnop", 4, 64).is_err());
+        assert!(assemble(".space 0x10000000000", 0, 1 << 20).is_err());
+    }
+}
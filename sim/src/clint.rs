@@ -0,0 +1,150 @@
+use serde::{Serialize, Deserialize};
+
+/*
+ * Core Local INTerruptor, SiFive layout as in the QEMU virt machine.
+ *
+ *      0x0000 + 4*hart     msip      bit 0 raises the machine software interrupt of the hart
+ *      0x4000 + 8*hart     mtimecmp  machine timer interrupt while mtime >= mtimecmp
+ *      0xBFF8              mtime     advances by one every simulator step
+ *
+ * https://github.com/riscv/riscv-aclint/blob/main/riscv-aclint.adoc
+ */
+
+pub const CLINT_BASE: u64 = 0x2000000;
+pub const CLINT_SIZE: u64 = 0x10000;
+
+const MSIP_OFFSET:     u64 = 0x0000;
+const MTIMECMP_OFFSET: u64 = 0x4000;
+const MTIME_OFFSET:    u64 = 0xBFF8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Clint {
+    pub base:     u64,
+    pub msip:     Vec<u32>,
+    pub mtimecmp: Vec<u64>,
+    pub mtime:    u64,
+}
+
+pub fn new_clint(harts: usize) -> Clint {
+    return Clint {
+        base:     CLINT_BASE,
+        msip:     vec![0; harts],
+        mtimecmp: vec![u64::MAX; harts],
+        mtime:    0,
+    };
+}
+
+impl Clint {
+    pub fn contains(&self, address: u64) -> bool {
+        return address >= self.base && address < self.base + CLINT_SIZE;
+    }
+
+    // register containing offset: (value, offset of the register, width in bytes)
+    fn register(&self, offset: u64) -> Option<(u64, u64, u64)> {
+        let harts = self.msip.len() as u64;
+        if offset < MSIP_OFFSET + 4 * harts {
+            let hart = (offset - MSIP_OFFSET) / 4;
+            return Some((self.msip[hart as usize] as u64, MSIP_OFFSET + 4 * hart, 4));
+        }
+        if offset >= MTIMECMP_OFFSET && offset < MTIMECMP_OFFSET + 8 * harts {
+            let hart = (offset - MTIMECMP_OFFSET) / 8;
+            return Some((self.mtimecmp[hart as usize], MTIMECMP_OFFSET + 8 * hart, 8));
+        }
+        if (MTIME_OFFSET..MTIME_OFFSET + 8).contains(&offset) {
+            return Some((self.mtime, MTIME_OFFSET, 8));
+        }
+        return None;
+    }
+
+    pub fn read(&self, address: u64, size: u64) -> u64 {
+        let offset = address - self.base;
+        return match self.register(offset) {
+            Some((value, start, _)) => {
+                let shifted = value >> (8 * (offset - start));
+                if size >= 8 { shifted } else { shifted & ((1 << (8 * size)) - 1) }
+            },
+            None => 0,
+        };
+    }
+
+    pub fn write(&mut self, address: u64, size: u64, value: u64) {
+        let offset = address - self.base;
+        let (old, start, _) = match self.register(offset) {
+            Some(r) => r,
+            None => return,
+        };
+        // merge the written bytes into the register, 32 bit halves of the 64 bit registers are common
+        let shift = 8 * (offset - start);
+        let mask = if size >= 8 { u64::MAX } else { ((1u64 << (8 * size)) - 1) << shift };
+        let new = (old & !mask) | ((value << shift) & mask);
+        match start {
+            s if s < MTIMECMP_OFFSET => self.msip[(s / 4) as usize] = (new & 1) as u32,
+            MTIME_OFFSET => self.mtime = new,
+            s => self.mtimecmp[((s - MTIMECMP_OFFSET) / 8) as usize] = new,
+        }
+    }
+
+    pub fn timer_pending(&self, hart: usize) -> bool {
+        return self.mtime >= self.mtimecmp[hart];
+    }
+
+    pub fn software_pending(&self, hart: usize) -> bool {
+        return self.msip[hart] & 1 != 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::tests::*;
+    use crate::test_finisher::*;
+
+    #[test]
+    fn registers_merge_partial_writes() {
+        let mut clint = new_clint(2);
+        // the two 32 bit halves of mtimecmp of HART 1
+        clint.write(CLINT_BASE + 0x4008, 4, 0x89abcdef);
+        clint.write(CLINT_BASE + 0x400c, 4, 0x01234567);
+        assert_eq!(clint.mtimecmp, vec![u64::MAX, 0x0123456789abcdef]);
+        assert_eq!(clint.read(CLINT_BASE + 0x400c, 4), 0x01234567);
+        assert_eq!(clint.read(CLINT_BASE + 0x4009, 1), 0xcd);
+        // msip keeps bit 0 only
+        clint.write(CLINT_BASE + 4, 4, 0xff);
+        assert_eq!(clint.msip, vec![0, 1]);
+        assert!(clint.software_pending(1) && !clint.software_pending(0));
+        clint.write(CLINT_BASE + MTIME_OFFSET, 8, 0x0123456789abcdef);
+        assert!(clint.timer_pending(1) && !clint.timer_pending(0));
+        // past the HARTs and between the registers nothing is there
+        assert_eq!(clint.read(CLINT_BASE + 8, 4), 0);
+        assert_eq!(clint.read(CLINT_BASE + 0x4010, 8), 0);
+        clint.write(CLINT_BASE + 0x4010, 8, 5);
+        assert_eq!(clint.mtimecmp, vec![u64::MAX, 0x0123456789abcdef]);
+    }
+
+    #[test]
+    fn the_timer_interrupts_once_mtime_reaches_mtimecmp() {
+        let mut sim = assembled_sim(&format!("
+            la t0, trap
+            csrw mtvec, t0
+            li t0, 0x200bff8
+            ld t1, 0(t0)
+            addi t1, t1, 50
+            li t0, 0x2004000
+            sd t1, 0(t0)
+            li t0, 0x80
+            csrs mie, t0
+            csrsi mstatus, 8
+        1:  addi a1, a1, 1
+            j 1b
+        trap:
+            csrr a2, mcause
+            li t0, 0x200bff8
+            ld a3, 0(t0)
+            {}", PASS), 1);
+        assert_eq!(run_to_exit(&mut sim), Some(FinisherStatus::Pass));
+        let regs = &sim.states[0].regs;
+        assert_eq!(regs[12], (1 << 63) | 7);
+        assert!(regs[11] > 0);
+        assert!(regs[13] >= sim.devices.clint.mtimecmp[0]);
+    }
+}
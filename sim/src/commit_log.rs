@@ -0,0 +1,173 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::sim::*;
+use crate::cosim::*;
+
+/*
+ * Instruction trace in the format of Spike's --log-commits, so that a run can be diffed against Spike on the same ELF.
+ * One line for every instruction that retires, instructions that trap and interrupts are not logged:
+ *
+ *      core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000
+ *      core   0: 3 0x0000000080000010 (0x30529073) c773_mtvec 0x0000000080000020
+ *      core   0: 1 0x0000000080000024 (0x0062b023) mem 0x0000000080001000 0x0000000000000001
+ *      core   0: 1 0x0000000080000028 (0x4388) x10 0x0000000000000001 mem 0x0000000080001000
+ *
+ * HART, privilege mode, pc, instruction bits (16 bits for compressed ones), then the registers and CSRs written
+ * (x0 is not logged), the virtual address of every load, and address and value of every store with the width of the store.
+ * CSR instructions log the CSR they name with its value after the write, MRET and SRET log mstatus and sstatus.
+ *
+ * --log-start and --log-stop limit the trace to a window: it starts with the instruction at the start pc
+ * and ends after the instruction at the stop pc, and starts again the next time the start pc is reached.
+ * The same records drive --cosim, which compares them with a reference trace instead of writing them.
+ *
+ * https://github.com/riscv-software-src/riscv-isa-sim/blob/master/riscv/execute.cc
+ */
+
+// what one instruction did, a line of the trace
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Commit {
+    pub hart:      u64,
+    pub privilege: u8,
+    pub pc:        u64,
+    pub raw:       u32,
+    pub ilen:      u64,
+    pub regs:      Vec<(char, u32, u64)>, // 'x' or 'c', number, value
+    pub loads:     Vec<u64>,
+    pub stores:    Vec<(u64, u64, u64)>, // address, size, value
+}
+
+#[derive(Debug)]
+pub struct CommitLog {
+    writer:    Option<BufWriter<File>>,
+    start:     Option<u64>,
+    stop:      Option<u64>,
+    active:    bool, // inside the --log-start/--log-stop window
+    pub cosim: Option<Cosim>,
+    commit:    Commit, // the instruction that is running
+}
+
+// path is the --log-commits file, reference the --cosim trace
+pub fn new_commit_log(path: Option<&str>, start: Option<u64>, stop: Option<u64>, reference: Option<&str>) -> Result<CommitLog, String> {
+    let writer = match path {
+        Some(path) => Some(BufWriter::new(File::create(path).map_err(|e| format!("failed to create {}: {:?}", path, e))?)),
+        None => None,
+    };
+    let cosim = match reference {
+        Some(path) => Some(new_cosim(path)?),
+        None => None,
+    };
+    return Ok(CommitLog {
+        writer: writer,
+        start:  start,
+        stop:   stop,
+        active: start.is_none(),
+        cosim:  cosim,
+        commit: Commit::default(),
+    });
+}
+
+impl CommitLog {
+    fn recording(&self) -> bool {
+        return (self.active && self.writer.is_some()) || self.cosim.is_some();
+    }
+}
+
+// called before an instruction runs, true when it is traced
+pub fn commit_log_begin(sim: &mut Simulator, pc: u64) -> bool {
+    let log = match sim.commit_log.as_mut() {
+        Some(log) => log,
+        None => return false,
+    };
+    if log.start == Some(pc) {
+        log.active = true;
+    }
+    log.commit.regs.clear();
+    log.commit.loads.clear();
+    log.commit.stores.clear();
+    return log.recording();
+}
+
+pub fn commit_log_reg(sim: &mut Simulator, number: u8, value: u64) {
+    if let Some(log) = sim.commit_log.as_mut() {
+        log.commit.regs.push(('x', number as u32, value));
+    }
+}
+
+pub fn commit_log_csr(sim: &mut Simulator, address: u32, value: u64) {
+    if let Some(log) = sim.commit_log.as_mut() {
+        log.commit.regs.push(('c', address, value));
+    }
+}
+
+pub fn commit_log_load(sim: &mut Simulator, address: u64) {
+    if let Some(log) = sim.commit_log.as_mut() {
+        log.commit.loads.push(address);
+    }
+}
+
+pub fn commit_log_store(sim: &mut Simulator, address: u64, size: u64, value: u64) {
+    if let Some(log) = sim.commit_log.as_mut() {
+        let mask = if size >= 8 { u64::MAX } else { (1 << (8 * size)) - 1 };
+        log.commit.stores.push((address, size, value & mask));
+    }
+}
+
+// "0x" and as many hex digits as bits / 4, Spike's commit_log_print_value
+fn hex_value(bits: u64, value: u64) -> String {
+    let digits = (bits / 4) as usize;
+    let masked = if bits >= 64 { value } else { value & ((1 << bits) - 1) };
+    return format!("0x{:0width$x}", masked, width = digits);
+}
+
+// the trace line of a commit, without the newline
+pub fn format_commit(commit: &Commit) -> String {
+    let mut line = format!("core{:4}: {} {} ({})", commit.hart, commit.privilege, hex_value(64, commit.pc), hex_value(commit.ilen * 8, commit.raw as u64));
+    for (prefix, number, value) in commit.regs.iter() {
+        match prefix {
+            'c' => {
+                let name = csr_info(*number).map(|info| info.name.to_lowercase()).unwrap_or(format!("0x{:x}", number));
+                line += &format!(" c{}_{} {}", number, name, hex_value(64, *value));
+            },
+            _ => line += &format!(" {}{:<2} {}", prefix, number, hex_value(64, *value)),
+        }
+    }
+    for address in commit.loads.iter() {
+        line += &format!(" mem {}", hex_value(64, *address));
+    }
+    for (address, size, value) in commit.stores.iter() {
+        line += &format!(" mem {} {}", hex_value(64, *address), hex_value(size * 8, *value));
+    }
+    return line;
+}
+
+// called once the instruction retired
+pub fn commit_log_commit(sim: &mut Simulator, state: &CpuState, privilege: u8, pc: u64, raw: u32, ilen: u64) {
+    let log = match sim.commit_log.as_mut() {
+        Some(log) => log,
+        None => return,
+    };
+    log.commit.hart      = state.csr[csr_address::MHARTID];
+    log.commit.privilege = privilege;
+    log.commit.pc        = pc;
+    log.commit.raw       = raw;
+    log.commit.ilen      = ilen;
+    if let Some(cosim) = log.cosim.as_mut() {
+        cosim_check(cosim, &log.commit, state);
+    }
+    if !log.active {
+        return;
+    }
+    if let Some(writer) = log.writer.as_mut() {
+        let line = format_commit(&log.commit) + "\n";
+        if let Err(e) = writer.write_all(line.as_bytes()) {
+            println!("ERROR: commit log: {:?}", e);
+            log.writer = None;
+            return;
+        }
+    }
+    if log.stop == Some(pc) {
+        log.active = false;
+        _ = log.writer.as_mut().map(|w| w.flush());
+    }
+}
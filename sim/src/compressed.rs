@@ -0,0 +1,226 @@
+/*
+ * RV64C: every 16 bit instruction is expanded into the 32 bit instruction it stands for,
+ * which is then executed as usual. The link address of C.JAL(R) is pc + 2, see `step`.
+ *
+ * Instructions that need F or D (C.FLD, C.FSD, C.FLDSP, C.FSDSP) and reserved encodings are illegal.
+ *
+ * The RISC-V Instruction Set Manual, Volume I, chapter "C" Extension for Compressed Instructions
+ */
+
+fn r_type(opcode: u32, rd: u32, func3: u32, rs1: u32, rs2: u32, func7: u32) -> u32 {
+    return func7 << 25 | rs2 << 20 | rs1 << 15 | func3 << 12 | rd << 7 | opcode;
+}
+
+fn i_type(opcode: u32, rd: u32, func3: u32, rs1: u32, imm: i32) -> u32 {
+    return ((imm as u32) & 0xfff) << 20 | rs1 << 15 | func3 << 12 | rd << 7 | opcode;
+}
+
+fn s_type(opcode: u32, func3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    return ((imm >> 5) & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | func3 << 12 | (imm & 0x1f) << 7 | opcode;
+}
+
+fn b_type(func3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    return ((imm >> 12) & 1) << 31 | ((imm >> 5) & 0x3f) << 25 | rs2 << 20 | rs1 << 15 | func3 << 12
+         | ((imm >> 1) & 0xf) << 8 | ((imm >> 11) & 1) << 7 | 0b1100011;
+}
+
+fn j_type(rd: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    return ((imm >> 20) & 1) << 31 | ((imm >> 1) & 0x3ff) << 21 | ((imm >> 11) & 1) << 20
+         | ((imm >> 12) & 0xff) << 12 | rd << 7 | 0b1101111;
+}
+
+const OP_LOAD:     u32 = 0b0000011;
+const OP_STORE:    u32 = 0b0100011;
+const OP_IMM:      u32 = 0b0010011;
+const OP_IMM_32:   u32 = 0b0011011;
+const OP:          u32 = 0b0110011;
+const OP_32:       u32 = 0b0111011;
+const OP_LUI:      u32 = 0b0110111;
+const OP_JALR:     u32 = 0b1100111;
+const OP_SYSTEM:   u32 = 0b1110011;
+
+// bit `from` of ir moved to bit `to`
+fn bit(ir: u32, from: u32, to: u32) -> u32 {
+    return ((ir >> from) & 1) << to;
+}
+
+// sign extends the low `bits` bits
+fn sext(value: u32, bits: u32) -> i32 {
+    return ((value << (32 - bits)) as i32) >> (32 - bits);
+}
+
+// None for illegal or reserved encodings
+pub fn expand_compressed(ir: u16) -> Option<u32> {
+    let ir = ir as u32;
+    let func3 = ir >> 13;
+    let rd    = (ir >> 7) & 0x1f;
+    let rs2   = (ir >> 2) & 0x1f;
+    let rd_c  = 8 + ((ir >> 2) & 0x7); // rd' / rs2'
+    let rs1_c = 8 + ((ir >> 7) & 0x7); // rs1' / rd'
+
+    // imm[5] = ir[12], imm[4:0] = ir[6:2]
+    let imm6 = bit(ir, 12, 5) | (ir >> 2) & 0x1f;
+
+    // uimm of C.LW / C.SW and C.LD / C.SD
+    let uimm_w = ((ir >> 10) & 0x7) << 3 | bit(ir, 6, 2) | bit(ir, 5, 6);
+    let uimm_d = ((ir >> 10) & 0x7) << 3 | ((ir >> 5) & 0x3) << 6;
+
+    match (ir & 0b11, func3) {
+        (_, _) if ir == 0 => None,
+
+        // Quadrant 0
+        (0b00, 0b000) => { // C.ADDI4SPN
+            let nzuimm = ((ir >> 11) & 0x3) << 4 | ((ir >> 7) & 0xf) << 6 | bit(ir, 6, 2) | bit(ir, 5, 3);
+            if nzuimm == 0 { return None; }
+            Some(i_type(OP_IMM, rd_c, 0b000, 2, nzuimm as i32))
+        },
+        (0b00, 0b010) => Some(i_type(OP_LOAD, rd_c, 0b010, rs1_c, uimm_w as i32)),         // C.LW
+        (0b00, 0b011) => Some(i_type(OP_LOAD, rd_c, 0b011, rs1_c, uimm_d as i32)),         // C.LD
+        (0b00, 0b110) => Some(s_type(OP_STORE, 0b010, rs1_c, rd_c, uimm_w as i32)),        // C.SW
+        (0b00, 0b111) => Some(s_type(OP_STORE, 0b011, rs1_c, rd_c, uimm_d as i32)),        // C.SD
+
+        // Quadrant 1
+        (0b01, 0b000) => Some(i_type(OP_IMM, rd, 0b000, rd, sext(imm6, 6))),              // C.ADDI, C.NOP
+        (0b01, 0b001) => {                                                                  // C.ADDIW
+            if rd == 0 { return None; }
+            Some(i_type(OP_IMM_32, rd, 0b000, rd, sext(imm6, 6)))
+        },
+        (0b01, 0b010) => Some(i_type(OP_IMM, rd, 0b000, 0, sext(imm6, 6))),                // C.LI
+        (0b01, 0b011) if rd == 2 => {                                                       // C.ADDI16SP
+            let nzimm = bit(ir, 12, 9) | bit(ir, 6, 4) | bit(ir, 5, 6) | ((ir >> 3) & 0x3) << 7 | bit(ir, 2, 5);
+            if nzimm == 0 { return None; }
+            Some(i_type(OP_IMM, 2, 0b000, 2, sext(nzimm, 10)))
+        },
+        (0b01, 0b011) => {                                                                  // C.LUI
+            if imm6 == 0 { return None; }
+            Some((sext(imm6, 6) as u32) << 12 | rd << 7 | OP_LUI)
+        },
+        (0b01, 0b100) => {
+            let shamt = imm6 as i32;
+            match (ir >> 10) & 0x3 {
+                0b00 => Some(i_type(OP_IMM, rs1_c, 0b101, rs1_c, shamt)),                  // C.SRLI
+                0b01 => Some(i_type(OP_IMM, rs1_c, 0b101, rs1_c, shamt | 0x400)),          // C.SRAI
+                0b10 => Some(i_type(OP_IMM, rs1_c, 0b111, rs1_c, sext(imm6, 6))),          // C.ANDI
+                _ => match bit(ir, 12, 2) | (ir >> 5) & 0x3 {
+                    0b000 => Some(r_type(OP, rs1_c, 0b000, rs1_c, rd_c, 0b0100000)),       // C.SUB
+                    0b001 => Some(r_type(OP, rs1_c, 0b100, rs1_c, rd_c, 0)),               // C.XOR
+                    0b010 => Some(r_type(OP, rs1_c, 0b110, rs1_c, rd_c, 0)),               // C.OR
+                    0b011 => Some(r_type(OP, rs1_c, 0b111, rs1_c, rd_c, 0)),               // C.AND
+                    0b100 => Some(r_type(OP_32, rs1_c, 0b000, rs1_c, rd_c, 0b0100000)),    // C.SUBW
+                    0b101 => Some(r_type(OP_32, rs1_c, 0b000, rs1_c, rd_c, 0)),            // C.ADDW
+                    _ => None,
+                },
+            }
+        },
+        (0b01, 0b101) => {                                                                  // C.J
+            let offset = bit(ir, 12, 11) | bit(ir, 11, 4) | ((ir >> 9) & 0x3) << 8 | bit(ir, 8, 10)
+                       | bit(ir, 7, 6) | bit(ir, 6, 7) | ((ir >> 3) & 0x7) << 1 | bit(ir, 2, 5);
+            Some(j_type(0, sext(offset, 12)))
+        },
+        (0b01, 0b110) | (0b01, 0b111) => {                                                  // C.BEQZ, C.BNEZ
+            let offset = bit(ir, 12, 8) | ((ir >> 10) & 0x3) << 3 | ((ir >> 5) & 0x3) << 6
+                       | ((ir >> 3) & 0x3) << 1 | bit(ir, 2, 5);
+            Some(b_type(func3 & 1, rs1_c, 0, sext(offset, 9)))
+        },
+
+        // Quadrant 2
+        (0b10, 0b000) => Some(i_type(OP_IMM, rd, 0b001, rd, imm6 as i32)),                 // C.SLLI
+        (0b10, 0b010) => {                                                                  // C.LWSP
+            if rd == 0 { return None; }
+            let uimm = bit(ir, 12, 5) | ((ir >> 4) & 0x7) << 2 | ((ir >> 2) & 0x3) << 6;
+            Some(i_type(OP_LOAD, rd, 0b010, 2, uimm as i32))
+        },
+        (0b10, 0b011) => {                                                                  // C.LDSP
+            if rd == 0 { return None; }
+            let uimm = bit(ir, 12, 5) | ((ir >> 5) & 0x3) << 3 | ((ir >> 2) & 0x7) << 6;
+            Some(i_type(OP_LOAD, rd, 0b011, 2, uimm as i32))
+        },
+        (0b10, 0b100) => match (bit(ir, 12, 0), rd, rs2) {
+            (0, 0, 0)  => None,
+            (0, _, 0)  => Some(i_type(OP_JALR, 0, 0b000, rd, 0)),                          // C.JR
+            (0, _, _)  => Some(r_type(OP, rd, 0b000, 0, rs2, 0)),                          // C.MV
+            (_, 0, 0)  => Some(i_type(OP_SYSTEM, 0, 0b000, 0, 1)),                         // C.EBREAK
+            (_, _, 0)  => Some(i_type(OP_JALR, 1, 0b000, rd, 0)),                          // C.JALR
+            (_, _, _)  => Some(r_type(OP, rd, 0b000, rd, rs2, 0)),                         // C.ADD
+        },
+        (0b10, 0b110) => {                                                                  // C.SWSP
+            let uimm = ((ir >> 9) & 0xf) << 2 | ((ir >> 7) & 0x3) << 6;
+            Some(s_type(OP_STORE, 0b010, 2, rs2, uimm as i32))
+        },
+        (0b10, 0b111) => {                                                                  // C.SDSP
+            let uimm = ((ir >> 10) & 0x7) << 3 | ((ir >> 7) & 0x7) << 6;
+            Some(s_type(OP_STORE, 0b011, 2, rs2, uimm as i32))
+        },
+
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_to_the_encoding_of_the_32_bit_instruction() {
+        // both columns from llvm-mc -show-encoding, with and without +c
+        let cases = [
+            (0x1fe0, 0x3fc10413), // c.addi4spn s0, sp, 1020
+            (0x5d7c, 0x07c52783), // c.lw a5, 124(a0)
+            (0x7d7c, 0x0f853783), // c.ld a5, 248(a0)
+            (0xc0dc, 0x00f4a223), // c.sw a5, 4(s1)
+            (0xe49c, 0x00f4b423), // c.sd a5, 8(s1)
+            (0x0001, 0x00000013), // c.nop
+            (0x1501, 0xfe050513), // c.addi a0, -32
+            (0x257d, 0x01f5051b), // c.addiw a0, 31
+            (0x55fd, 0xfff00593), // c.li a1, -1
+            (0x7101, 0xe0010113), // c.addi16sp sp, -512
+            (0x7601, 0xfffe0637), // c.lui a2, 0xfffe0
+            (0x907d, 0x03f45413), // c.srli s0, 63
+            (0x8405, 0x40145413), // c.srai s0, 1
+            (0x98fd, 0xfff4f493), // c.andi s1, -1
+            (0x8d0d, 0x40b50533), // c.sub a0, a1
+            (0x8d2d, 0x00b54533), // c.xor a0, a1
+            (0x8d4d, 0x00b56533), // c.or a0, a1
+            (0x8d6d, 0x00b57533), // c.and a0, a1
+            (0x9d0d, 0x40b5053b), // c.subw a0, a1
+            (0x9d2d, 0x00b5053b), // c.addw a0, a1
+            (0xb001, 0x801ff06f), // c.j -2048
+            (0xd101, 0xf00500e3), // c.beqz a0, -256
+            (0xeffd, 0x0e079f63), // c.bnez a5, 254
+            (0x1282, 0x02029293), // c.slli t0, 32
+            (0x50fe, 0x0fc12083), // c.lwsp ra, 252(sp)
+            (0x737e, 0x1f813303), // c.ldsp t1, 504(sp)
+            (0x8082, 0x00008067), // c.jr ra
+            (0x857e, 0x01f00533), // c.mv a0, t6
+            (0x9002, 0x00100073), // c.ebreak
+            (0x9282, 0x000280e7), // c.jalr t0
+            (0x94b2, 0x00c484b3), // c.add s1, a2
+            (0xdfb6, 0x0ed12e23), // c.swsp a3, 252(sp)
+            (0xffb6, 0x1ed13c23), // c.sdsp a3, 504(sp)
+        ];
+        for (ir, expected) in cases {
+            assert_eq!(expand_compressed(ir), Some(expected), "0x{:04x}", ir);
+        }
+    }
+
+    #[test]
+    fn reserved_and_floating_point_encodings_are_illegal() {
+        let cases = [
+            0x0000, // all zero
+            0x0004, // c.addi4spn with a zero immediate
+            0x2000, // c.fld
+            0x6101, // c.addi16sp with a zero immediate
+            0x6601, // c.lui with a zero immediate
+            0x4002, // c.lwsp to x0
+            0x8002, // c.jr x0
+            0x2002, // c.fldsp
+            0xa002, // c.fsdsp
+        ];
+        for ir in cases {
+            assert_eq!(expand_compressed(ir), None, "0x{:04x}", ir);
+        }
+    }
+}
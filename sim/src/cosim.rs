@@ -0,0 +1,294 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::sim::*;
+use crate::commit_log::*;
+use crate::debug::*;
+
+/*
+ * Lockstep co-simulation, "--cosim trace.log": every instruction ar64 retires is compared with the next line
+ * of a reference trace in the --log-commits format, recorded by Spike (spike --log-commits) or an RTL testbench.
+ *
+ *      pc              has to be the same
+ *      x registers     the same registers written with the same values, x0 and CSR writes are not compared
+ *      stores          the same addresses, widths and values, in the same order
+ *
+ * The first difference stops the machine with a report of both lines and of the registers that differ.
+ * Lines of other HARTs are matched per HART, so the interleaving of HARTs in the trace does not matter.
+ * Reference lines before the first pc ar64 runs are skipped, that is the boot ROM of Spike.
+ * Lines that are not commit lines (e.g. Spike's exception messages) are ignored, a malformed commit line stops the
+ * machine like a difference does.
+ */
+
+// reference lines skipped looking for the first pc before giving up
+const SYNC_LIMIT: usize = 1000;
+
+#[derive(Debug)]
+pub struct Cosim {
+    path:           String,
+    lines:          Lines<BufReader<File>>,
+    line_number:    usize,
+    // lines of other HARTs that were read ahead: line number, text, commit
+    ahead:          HashMap<u64, VecDeque<(usize, String, Commit)>>,
+    synced:         HashSet<u64>, // HARTs that found their first pc in the trace
+    // register file of each HART as the reference sees it
+    reference_regs: HashMap<u64, Vec<u64>>,
+    pub matched:    u64, // instructions compared
+    pub ended:      bool, // the reference trace ended before ar64 did
+    pub divergence: Option<String>, // report of the first difference
+}
+
+pub fn new_cosim(path: &str) -> Result<Cosim, String> {
+    let file = File::open(path).map_err(|e| format!("failed to read {}: {:?}", path, e))?;
+    return Ok(Cosim {
+        path:           String::from(path),
+        lines:          BufReader::new(file).lines(),
+        line_number:    0,
+        ahead:          HashMap::new(),
+        synced:         HashSet::new(),
+        reference_regs: HashMap::new(),
+        matched:        0,
+        ended:          false,
+        divergence:     None,
+    });
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    return u64::from_str_radix(text.strip_prefix("0x")?, 16).ok();
+}
+
+/*
+ * A commit line, "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000 mem 0x... 0x...".
+ * Lines without a privilege mode after the HART are not commit lines (None), e.g. Spike's "core   0: exception ...",
+ * a commit line that does not parse is an error: skipping it would compare the next line with the wrong instruction.
+ */
+pub fn parse_commit(line: &str) -> Result<Option<Commit>, String> {
+    let (hart, rest) = match line.trim().strip_prefix("core").and_then(|l| l.split_once(':')) {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let mut tokens = rest.split_whitespace().peekable();
+    let privilege = match tokens.peek().map(|t| t.parse::<u8>()) {
+        Some(Ok(privilege)) => privilege,
+        _ => return Ok(None),
+    };
+    tokens.next();
+    let invalid = |what: &str, token: Option<&str>| format!("invalid {}: {}", what, token.unwrap_or("end of line"));
+    if privilege > 3 {
+        return Err(invalid("privilege mode", Some(&privilege.to_string())));
+    }
+    let hart = hart.trim().parse().map_err(|_| invalid("HART", Some(hart.trim())))?;
+    let token = tokens.next();
+    let pc = token.and_then(parse_hex).ok_or_else(|| invalid("pc", token))?;
+    let token = tokens.next();
+    let bits = token.and_then(|t| t.strip_prefix('(')?.strip_suffix(')')).filter(|b| b.len() > 2).ok_or_else(|| invalid("instruction", token))?;
+    let mut commit = Commit {
+        hart:      hart,
+        privilege: privilege,
+        pc:        pc,
+        raw:       parse_hex(bits).filter(|r| *r <= u32::MAX as u64).ok_or_else(|| invalid("instruction", token))? as u32,
+        ilen:      if bits.len() - 2 <= 4 { 2 } else { 4 },
+        ..Commit::default()
+    };
+    while let Some(token) = tokens.next() {
+        if token == "mem" {
+            let next = tokens.next();
+            let address = next.and_then(parse_hex).ok_or_else(|| invalid("address", next))?;
+            match tokens.next_if(|t| t.starts_with("0x")) {
+                Some(value) => commit.stores.push((address, (value.len() as u64 - 2) / 2, parse_hex(value).ok_or_else(|| invalid("value", Some(value)))?)),
+                None => commit.loads.push(address),
+            }
+            continue;
+        }
+        let next = tokens.next();
+        let value = next.and_then(parse_hex).ok_or_else(|| invalid(&format!("value of {}", token), next))?;
+        if let Some(number) = token.strip_prefix('x').and_then(|n| n.parse::<u32>().ok()) {
+            commit.regs.push(('x', number, value));
+        } else if let Some(number) = token.strip_prefix('c').and_then(|c| c.split_once('_')).and_then(|(n, _)| n.parse::<u32>().ok()) {
+            commit.regs.push(('c', number, value));
+        }
+        // floating point and vector registers are not compared
+    }
+    return Ok(Some(commit));
+}
+
+impl Cosim {
+    // the next reference line of a HART, None at the end of the trace
+    fn next_line(&mut self, hart: u64) -> Result<Option<(usize, String, Commit)>, String> {
+        if let Some(line) = self.ahead.get_mut(&hart).and_then(|lines| lines.pop_front()) {
+            return Ok(Some(line));
+        }
+        loop {
+            let text = match self.lines.next() {
+                Some(Ok(text)) => text,
+                _ => return Ok(None),
+            };
+            self.line_number += 1;
+            let commit = parse_commit(&text).map_err(|e| format!("line {} of {}: {}\n    {}", self.line_number, self.path, e, text.trim()))?;
+            if let Some(commit) = commit {
+                if commit.hart == hart {
+                    return Ok(Some((self.line_number, text, commit)));
+                }
+                self.ahead.entry(commit.hart).or_default().push_back((self.line_number, text, commit));
+            }
+        }
+    }
+}
+
+fn register_writes(commit: &Commit) -> Vec<(u32, u64)> {
+    let mut writes: Vec<(u32, u64)> = commit.regs.iter().filter(|(p, n, _)| *p == 'x' && *n != 0).map(|(_, n, v)| (*n, *v)).collect();
+    writes.sort();
+    return writes;
+}
+
+// compares an instruction ar64 retired, state is its HART after the instruction
+pub fn cosim_check(cosim: &mut Cosim, commit: &Commit, state: &CpuState) {
+    if cosim.ended || cosim.divergence.is_some() {
+        return;
+    }
+    let hart = commit.hart;
+    let mut skipped = 0;
+    let (line_number, text, reference) = loop {
+        let line = match cosim.next_line(hart) {
+            Ok(Some(line)) => line,
+            Err(e) => {
+                cosim.divergence = Some(e);
+                return;
+            },
+            Ok(None) if cosim.matched == 0 => {
+                cosim.divergence = Some(format!("pc 0x{:X} of HART {} is not in {}", commit.pc, hart, cosim.path));
+                return;
+            },
+            Ok(None) => {
+                cosim.ended = true;
+                return;
+            },
+        };
+        if cosim.synced.contains(&hart) || line.2.pc == commit.pc {
+            break line;
+        }
+        skipped += 1;
+        if skipped == SYNC_LIMIT {
+            cosim.divergence = Some(format!("pc 0x{:X} of HART {} is not in the first {} lines of {}", commit.pc, hart, SYNC_LIMIT, cosim.path));
+            return;
+        }
+    };
+    if cosim.synced.insert(hart) {
+        if skipped > 0 {
+            println!("INFO cosim: skipped {} reference lines of HART {} before pc 0x{:X}", skipped, hart, commit.pc);
+        }
+        cosim.reference_regs.insert(hart, state.regs.clone());
+    }
+    let regs = cosim.reference_regs.get_mut(&hart).unwrap();
+    for (number, value) in register_writes(&reference) {
+        if let Some(reg) = regs.get_mut(number as usize) {
+            *reg = value;
+        }
+    }
+
+    let mut differences = vec![];
+    if reference.pc != commit.pc {
+        differences.push(format!("pc         ar64 0x{:016X}, reference 0x{:016X}", commit.pc, reference.pc));
+    }
+    if register_writes(&reference) != register_writes(commit) {
+        differences.push(String::from("register writes differ"));
+    }
+    if reference.stores != commit.stores {
+        differences.push(String::from("stores differ"));
+    }
+    if differences.is_empty() {
+        cosim.matched += 1;
+        return;
+    }
+    for (i, (ours, theirs)) in state.regs.iter().zip(regs.iter()).enumerate() {
+        if ours != theirs {
+            differences.push(format!("x{:<2} {:<5} ar64 0x{:016X}, reference 0x{:016X}", i, ABI_NAMES[i], ours, theirs));
+        }
+    }
+    cosim.divergence = Some(format!(
+        "divergence after {} matching instructions, line {} of {}\n    reference: {}\n    ar64:      {}\n    {}",
+        cosim.matched, line_number, cosim.path, text.trim(), format_commit(commit), differences.join("\n    ")));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(regs: Vec<(char, u32, u64)>, stores: Vec<(u64, u64, u64)>) -> Commit {
+        return Commit { hart: 1, privilege: 3, pc: 0x80000000, raw: 0x00000297, ilen: 4, regs: regs, stores: stores, ..Commit::default() };
+    }
+
+    // a trace file with lines, removed when the test is done with it
+    fn cosim_over(name: &str, lines: &[&str]) -> Cosim {
+        let path = std::env::temp_dir().join(format!("ar64-cosim-{}-{}.log", name, std::process::id()));
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        let cosim = new_cosim(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        return cosim;
+    }
+
+    #[test]
+    fn format_and_parse_round_trip() {
+        let commits = [
+            commit(vec![('x', 5, 0x80000000)], vec![]),
+            commit(vec![('c', 0x305, 0x80000020), ('c', 0x7ff, 1)], vec![]),
+            commit(vec![], vec![(0x80001000, 1, 0xff), (0x80001002, 2, 0xbeef), (0x80001004, 4, 1), (0x80001008, 8, u64::MAX)]),
+            Commit { raw: 0x4388, ilen: 2, privilege: 1, regs: vec![('x', 10, 1)], loads: vec![0x80001000], ..Commit::default() },
+        ];
+        for commit in commits {
+            let line = format_commit(&commit);
+            assert_eq!(parse_commit(&line), Ok(Some(commit)), "{}", line);
+        }
+        // as Spike writes it, floating point registers are skipped
+        let line = "core   0: 3 0x0000000080000028 (0x4388) x10 0x0000000000000001 f1  0x0000000000000000 mem 0x0000000080001000";
+        let parsed = parse_commit(line).unwrap().unwrap();
+        assert_eq!((parsed.hart, parsed.ilen, parsed.regs, parsed.loads), (0, 2, vec![('x', 10, 1)], vec![0x80001000]));
+    }
+
+    #[test]
+    fn other_lines_are_skipped_and_broken_commit_lines_are_errors() {
+        for line in ["", "bbl loader", "core   0: exception trap_illegal_instruction, epc 0x0000000080000004", "core   0:           tval 0x0000000000000000"] {
+            assert_eq!(parse_commit(line), Ok(None), "{}", line);
+        }
+        for line in [
+            "core   0: 4 0x0000000080000000 (0x00000297)",
+            "core   x: 3 0x0000000080000000 (0x00000297)",
+            "core   0: 3 80000000 (0x00000297)",
+            "core   0: 3 0x0000000080000000 (0x00000297",
+            "core   0: 3 0x0000000080000000 (0x1234567890)",
+            "core   0: 3 0x0000000080000000",
+            "core   0: 3 0x0000000080000000 (0x00000297) x5",
+            "core   0: 3 0x0000000080000000 (0x00000297) x5  0xzz",
+            "core   0: 3 0x0000000080000000 (0x00000297) mem",
+            "core   0: 3 0x0000000080000000 (0x00000297) mem 0x0000000080001000 0xq1",
+        ] {
+            assert!(parse_commit(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn a_different_value_is_a_divergence_and_a_broken_line_says_where_it_is() {
+        let state = default_cpu_state();
+        let ours = Commit { hart: 0, ..commit(vec![('x', 5, 0x80000000)], vec![]) };
+        let mut cosim = cosim_over("differs", &[
+            "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000",
+            "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000004",
+        ]);
+        cosim_check(&mut cosim, &ours, &state);
+        assert_eq!((cosim.matched, cosim.divergence.is_none()), (1, true));
+        cosim_check(&mut cosim, &ours, &state);
+        let report = cosim.divergence.unwrap();
+        assert!(report.starts_with("divergence after 1 matching instructions, line 2"), "{}", report);
+        assert!(report.contains("register writes differ"), "{}", report);
+
+        let mut cosim = cosim_over("broken", &[
+            "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000",
+            "core   0: 3 0x0000000080000000 (0x00000297) x5",
+        ]);
+        cosim_check(&mut cosim, &ours, &state);
+        cosim_check(&mut cosim, &ours, &state);
+        let report = cosim.divergence.unwrap();
+        assert!(report.starts_with("line 2 of") && report.contains("invalid value of x5: end of line"), "{}", report);
+    }
+}
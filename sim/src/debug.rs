@@ -0,0 +1,477 @@
+use serde::{Serialize, Deserialize};
+
+use crate::sim::*;
+use crate::test_finisher::*;
+
+/*
+ * Breakpoints and watchpoints, shared by the debugger front ends (the GDB stub and the JSON server).
+ *
+ * Breakpoints are PC addresses, checked by the run loop between instructions, nothing is written to memory.
+ * A breakpoint with a condition only stops when the condition holds for the HART at it, e.g.
+ *
+ *      a0 == 5 && sp < 0x80001000
+ *      (x10 & 0xff) != 0 || pc == ra
+ *      [sp + 8] == 0x1234
+ *
+ * operands are registers (x0-x31, ABI names, pc), numbers and [address], the 8 bytes at a virtual address as the
+ * HART's loads see them, compared and computed as unsigned 64 bit. A condition that can not be evaluated holds.
+ * Watchpoints are ranges of virtual addresses, the loads and stores of the HARTs that succeeded are checked against them
+ * and the first access that hits one is recorded in watch_hit. The instruction completes, the run loop stops after it.
+ * Traps the HARTs take (not the ones the simulator handles itself) are recorded in trap_hit.
+ */
+
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access, // read or write
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub start: u64,
+    pub end:   u64, // exclusive
+    pub kind:  WatchKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct WatchHit {
+    pub hart:       usize,
+    pub address:    u64,
+    pub write:      bool,
+    pub watchpoint: Watchpoint,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub address:   u64,
+    pub condition: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TrapHit {
+    pub hart:  usize,
+    pub cause: u64,
+    pub tval:  u64,
+    pub pc:    u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub watch_hit:   Option<WatchHit>,
+    pub trap_hit:    Option<TrapHit>,
+}
+
+pub fn default_debugger() -> Debugger {
+    return Debugger {
+        breakpoints: vec![],
+        watchpoints: vec![],
+        watch_hit:   None,
+        trap_hit:    None,
+    };
+}
+
+impl Debugger {
+    // a breakpoint at an address that already has one replaces it
+    pub fn add_breakpoint(&mut self, address: u64, condition: Option<String>) -> Result<(), String> {
+        if let Some(condition) = &condition {
+            parse_condition(condition)?;
+        }
+        self.breakpoints.retain(|b| b.address != address);
+        self.breakpoints.push(Breakpoint { address: address, condition: condition });
+        return Ok(());
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u64) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|b| b.address != address);
+        return self.breakpoints.len() != count;
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watchpoint);
+        return self.watchpoints.len() != count;
+    }
+
+    // called after every load and store that succeeded, the first hit is kept until the run loop takes it
+    pub fn check_access(&mut self, hart: usize, address: u64, size: u64, write: bool) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        let end = address.wrapping_add(size);
+        let hit = self.watchpoints.iter().find(|w| {
+            let kind = match w.kind {
+                WatchKind::Read   => !write,
+                WatchKind::Write  => write,
+                WatchKind::Access => true,
+            };
+            kind && address < w.end && w.start < end
+        });
+        if let Some(watchpoint) = hit {
+            self.watch_hit = Some(WatchHit { hart: hart, address: address, write: write, watchpoint: *watchpoint });
+        }
+    }
+}
+
+// first HART whose pc is at a breakpoint whose condition holds
+pub fn breakpoint_hart(sim: &Simulator) -> Option<usize> {
+    if sim.debugger.breakpoints.is_empty() {
+        return None;
+    }
+    return (0..sim.states.len()).find(|hart| {
+        sim.debugger.breakpoints.iter().any(|b| {
+            b.address == sim.states[*hart].pc && b.condition.as_ref().is_none_or(|c| evaluate_condition(c, sim, *hart).unwrap_or(true))
+        })
+    });
+}
+
+/*
+ * Why run_until stopped. hart and pc are the ones of the HART that caused the stop, HART 0 otherwise,
+ * address is the accessed address of a watchpoint hit and the tval of a trap.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StopEvent {
+    pub reason:  String, // "breakpoint", "watchpoint", "trap", "exit", "halt", "steps", "begin" going backwards, "stop" on request
+    pub steps:   u64,
+    pub hart:    usize,
+    pub pc:      u64,
+    pub address: Option<u64>,
+    pub detail:  String,
+    #[serde(skip)]
+    pub trap:    Option<TrapHit>, // the trap of a "trap" stop
+}
+
+/*
+ * Runs up to max_steps steps, stops early at a breakpoint (after the first step, so that running from a breakpoint
+ * moves on), after an access that hits a watchpoint, after a trap when stop_on_trap is set, or when the machine stops.
+ */
+pub fn run_until(sim: &mut Simulator, max_steps: u64, stop_on_trap: bool) -> StopEvent {
+    sim.debugger.watch_hit = None;
+    sim.debugger.trap_hit = None;
+    let event = |sim: &Simulator, reason: &str, steps: u64, hart: usize, address: Option<u64>, detail: String| StopEvent {
+        reason:  String::from(reason),
+        steps:   steps,
+        hart:    hart,
+        pc:      sim.states[hart].pc,
+        address: address,
+        detail:  detail,
+        trap:    None,
+    };
+    for steps in 1..=max_steps {
+        let running = step(sim);
+        if let Some(hit) = sim.debugger.watch_hit.take() {
+            let access = if hit.write { "write" } else { "read" };
+            return event(sim, "watchpoint", steps, hit.hart, Some(hit.address), format!("{} of 0x{:X}", access, hit.address));
+        }
+        if !running {
+            return match guest_exit_status(sim) {
+                Some(FinisherStatus::Pass) => event(sim, "exit", steps, 0, None, String::from("pass")),
+                Some(FinisherStatus::Fail(code)) => event(sim, "exit", steps, 0, None, format!("fail, code {}", code)),
+                _ => event(sim, "halt", steps, 0, None, sim.log.clone()),
+            };
+        }
+        if let Some(trap) = sim.debugger.trap_hit.take().filter(|_| stop_on_trap) {
+            let detail = format!("{} (cause 0x{:X}) at 0x{:X}", cause_name(trap.cause), trap.cause, trap.pc);
+            return StopEvent { trap: Some(trap), ..event(sim, "trap", steps, trap.hart, Some(trap.tval), detail) };
+        }
+        if let Some(hart) = breakpoint_hart(sim) {
+            return event(sim, "breakpoint", steps, hart, None, String::new());
+        }
+    }
+    return event(sim, "steps", max_steps, 0, None, String::new());
+}
+
+// register by name: x0-x31 or an ABI name (fp is s0), 32 for pc
+fn register_index(name: &str) -> Option<usize> {
+    match name {
+        "pc" => return Some(32),
+        "fp" => return Some(8),
+        _ => {},
+    }
+    if let Some(index) = name.strip_prefix('x').and_then(|n| n.parse::<usize>().ok()).filter(|n| *n < 32) {
+        return Some(index);
+    }
+    return ABI_NAMES.iter().position(|n| *n == name);
+}
+
+/*
+ * Breakpoint conditions, by increasing precedence:
+ *
+ *      or      := and ("||" and)*
+ *      and     := compare ("&&" compare)*
+ *      compare := sum (("==" | "!=" | "<" | "<=" | ">" | ">=") sum)?
+ *      sum     := operand (("+" | "-" | "&") operand)*
+ *      operand := number | register | "(" or ")" | "[" or "]"
+ *
+ * The parser and the evaluation recurse, conditions are limited to MAX_CONDITION_LENGTH characters and
+ * MAX_CONDITION_DEPTH nested parentheses and brackets so that they can not run out of stack.
+ */
+const MAX_CONDITION_LENGTH: usize = 1024;
+const MAX_CONDITION_DEPTH:  usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u64),
+    Name(String),
+    Operator(String),
+}
+
+#[derive(Debug)]
+enum Expression {
+    Number(u64),
+    Register(usize),
+    Memory(Box<Expression>),
+    Binary(String, Box<Expression>, Box<Expression>),
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            let number = match word.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => word.parse::<u64>().ok(),
+            };
+            tokens.push(match number {
+                Some(n) => Token::Number(n),
+                None if c.is_ascii_digit() => return Err(format!("invalid number: {}", word)),
+                None => Token::Name(word),
+            });
+        } else {
+            let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            let operator = if ["==", "!=", "<=", ">=", "&&", "||"].contains(&two.as_str()) { two } else { c.to_string() };
+            if !["==", "!=", "<=", ">=", "&&", "||", "<", ">", "+", "-", "&", "(", ")", "[", "]"].contains(&operator.as_str()) {
+                return Err(format!("unexpected character: {}", c));
+            }
+            i += operator.len();
+            tokens.push(Token::Operator(operator));
+        }
+    }
+    return Ok(tokens);
+}
+
+struct Parser {
+    tokens:   Vec<Token>,
+    position: usize,
+    depth:    usize, // open parentheses and brackets
+}
+
+impl Parser {
+    fn accept(&mut self, operators: &[&str]) -> Option<String> {
+        if let Some(Token::Operator(op)) = self.tokens.get(self.position) {
+            if operators.contains(&op.as_str()) {
+                self.position += 1;
+                return Some(op.clone());
+            }
+        }
+        return None;
+    }
+
+    // left associative chain of operators on one precedence level
+    fn binary(&mut self, operators: &[&str], next: fn(&mut Parser) -> Result<Expression, String>) -> Result<Expression, String> {
+        let mut left = next(self)?;
+        while let Some(op) = self.accept(operators) {
+            let right = next(self)?;
+            left = Expression::Binary(op, Box::new(left), Box::new(right));
+        }
+        return Ok(left);
+    }
+
+    fn or(&mut self) -> Result<Expression, String> {
+        return self.binary(&["||"], Parser::and);
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        return self.binary(&["&&"], Parser::compare);
+    }
+
+    fn compare(&mut self) -> Result<Expression, String> {
+        let left = self.sum()?;
+        return match self.accept(&["==", "!=", "<", "<=", ">", ">="]) {
+            Some(op) => Ok(Expression::Binary(op, Box::new(left), Box::new(self.sum()?))),
+            None => Ok(left),
+        };
+    }
+
+    fn sum(&mut self) -> Result<Expression, String> {
+        return self.binary(&["+", "-", "&"], Parser::operand);
+    }
+
+    fn operand(&mut self) -> Result<Expression, String> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        return match token {
+            Some(Token::Number(n)) => Ok(Expression::Number(n)),
+            Some(Token::Name(name)) => {
+                match register_index(&name) {
+                    Some(index) => Ok(Expression::Register(index)),
+                    None => Err(format!("unknown register: {}", name)),
+                }
+            },
+            Some(Token::Operator(op)) if op == "(" || op == "[" => {
+                if self.depth == MAX_CONDITION_DEPTH {
+                    return Err(format!("parentheses nested deeper than {}", MAX_CONDITION_DEPTH));
+                }
+                self.depth += 1;
+                let inner = self.or()?;
+                self.depth -= 1;
+                let close = if op == "(" { ")" } else { "]" };
+                if self.accept(&[close]).is_none() {
+                    return Err(format!("missing {}", close));
+                }
+                Ok(if op == "(" { inner } else { Expression::Memory(Box::new(inner)) })
+            },
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err(String::from("unexpected end of condition")),
+        };
+    }
+}
+
+fn parse_condition(text: &str) -> Result<Expression, String> {
+    if text.len() > MAX_CONDITION_LENGTH {
+        return Err(format!("condition longer than {} characters", MAX_CONDITION_LENGTH));
+    }
+    let mut parser = Parser { tokens: tokenize(text)?, position: 0, depth: 0 };
+    let expression = parser.or()?;
+    if parser.position != parser.tokens.len() {
+        return Err(format!("unexpected {:?}", parser.tokens[parser.position]));
+    }
+    return Ok(expression);
+}
+
+// None when memory it reads can not be read
+fn evaluate(expression: &Expression, sim: &Simulator, hart: usize) -> Option<u64> {
+    let state = &sim.states[hart];
+    return match expression {
+        Expression::Number(n) => Some(*n),
+        Expression::Register(32) => Some(state.pc),
+        Expression::Register(index) => Some(state.regs[*index]),
+        Expression::Memory(address) => {
+            let bytes = debug_read_memory(sim, hart, evaluate(address, sim, hart)?, 8)?;
+            Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+        },
+        Expression::Binary(op, left, right) => {
+            let (l, r) = (evaluate(left, sim, hart)?, evaluate(right, sim, hart)?);
+            let value = match op.as_str() {
+                "+"  => return Some(l.wrapping_add(r)),
+                "-"  => return Some(l.wrapping_sub(r)),
+                "&"  => return Some(l & r),
+                "==" => l == r,
+                "!=" => l != r,
+                "<"  => l < r,
+                "<=" => l <= r,
+                ">"  => l > r,
+                ">=" => l >= r,
+                "&&" => l != 0 && r != 0,
+                _    => l != 0 || r != 0,
+            };
+            Some(value as u64)
+        },
+    };
+}
+
+// true when the condition holds for the HART or reads memory that can not be read, an error when it does not parse
+pub fn evaluate_condition(text: &str, sim: &Simulator, hart: usize) -> Result<bool, String> {
+    return Ok(evaluate(&parse_condition(text)?, sim, hart).is_none_or(|value| value != 0));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::tests::*;
+
+    fn value(text: &str, sim: &Simulator) -> Option<u64> {
+        return evaluate(&parse_condition(text).unwrap(), sim, 0);
+    }
+
+    #[test]
+    fn tokenizes_numbers_names_and_operators() {
+        let operator = |op: &str| Token::Operator(String::from(op));
+        assert_eq!(tokenize("a0==0x10&&[sp]").unwrap(), vec![
+            Token::Name(String::from("a0")), operator("=="), Token::Number(16), operator("&&"),
+            operator("["), Token::Name(String::from("sp")), operator("]"),
+        ]);
+        assert_eq!(tokenize("x1 <= 7 < 8").unwrap()[1], operator("<="));
+        assert!(tokenize("0x1g == 1").is_err());
+        assert!(tokenize("a0 * 2").is_err());
+    }
+
+    #[test]
+    fn parses_by_precedence() {
+        let sim = new_sim(1, 0, 0x1000);
+        assert_eq!(value("2 - 1 - 1", &sim), Some(0));
+        assert_eq!(value("6 & 3 == 2", &sim), Some(1));
+        assert_eq!(value("1 + 2 == 3 && 0 || 1", &sim), Some(1));
+        assert_eq!(value("1 || 0 && 0", &sim), Some(1));
+        assert_eq!(value("(1 || 0) && 0", &sim), Some(0));
+        assert_eq!(value("0 - 1 > 5", &sim), Some(1));
+    }
+
+    #[test]
+    fn rejects_what_does_not_parse() {
+        for text in ["", "a0 ==", "(a0", "[a0", "a0)", "a0 a1", "foo == 1", "== 1", "a0 == 1 == 2"] {
+            assert!(parse_condition(text).is_err(), "{}", text);
+        }
+        let nested = format!("{}1{}", "(".repeat(MAX_CONDITION_DEPTH), ")".repeat(MAX_CONDITION_DEPTH));
+        assert!(parse_condition(&nested).is_ok());
+        let nested = format!("{}1{}", "[".repeat(MAX_CONDITION_DEPTH + 1), "]".repeat(MAX_CONDITION_DEPTH + 1));
+        assert!(parse_condition(&nested).is_err());
+        assert!(parse_condition(&"1+".repeat(MAX_CONDITION_LENGTH)).is_err());
+    }
+
+    #[test]
+    fn reads_registers_and_memory() {
+        let mut sim = new_sim(1, 0, 0x1000);
+        sim.states[0].regs[2] = 0x100;
+        sim.states[0].regs[8] = 5;
+        sim.states[0].pc = 0x40;
+        sim.mem[0x108..0x110].copy_from_slice(&0x1122334455667788u64.to_le_bytes());
+        assert_eq!(value("s0 == 5 && fp == x8 && x0 == zero", &sim), Some(1));
+        assert_eq!(value("pc", &sim), Some(0x40));
+        assert_eq!(value("[sp + 8]", &sim), Some(0x1122334455667788));
+        assert_eq!(value("[[sp + 8] & 0xff]", &sim), Some(0));
+        // unreadable memory, the breakpoint stops rather than being missed
+        assert_eq!(value("[0x10000] == 1", &sim), None);
+        assert_eq!(evaluate_condition("[0x10000] == 1", &sim, 0), Ok(true));
+        assert_eq!(evaluate_condition("[sp + 8] == 1", &sim, 0), Ok(false));
+    }
+
+    #[test]
+    fn watchpoints_only_see_accesses_that_succeed() {
+        // the store outside of RAM faults, the one after the trap hits the watchpoint
+        let mut sim = assembled_sim("la t0, 1f\ncsrw mtvec, t0\nli t1, 0x20000\nsd t1, 0(t1)\n.align 2\n1: li t1, 0x800\nsd t1, 0(t1)\nj 1b", 1);
+        sim.debugger.add_watchpoint(Watchpoint { start: 0x20000, end: 0x20008, kind: WatchKind::Write });
+        sim.debugger.add_watchpoint(Watchpoint { start: 0x800, end: 0x808, kind: WatchKind::Write });
+        for _ in 0..10 {
+            step(&mut sim);
+            if sim.debugger.watch_hit.is_some() {
+                break;
+            }
+        }
+        assert_eq!(sim.debugger.watch_hit.map(|hit| hit.address), Some(0x800));
+    }
+}
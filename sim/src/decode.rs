@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+
+use crate::compressed::*;
+
+/*
+ * Decoding, separate from execution: the bits of an instruction become a typed Instruction with its operands
+ * taken apart and its immediates sign extended. step executes it, the disassembler and the trace tools print it.
+ *
+ * Only what the encoding alone makes illegal is Illegal here, e.g. a reserved func3 or a shift amount out of range.
+ * What depends on the state of the HART (privilege mode, mstatus.TSR/TW/TVM, CSR access) is checked when it runs.
+ *
+ * DecodeCache keeps decoded instructions per physical address, so that an instruction that runs again is not fetched
+ * and decoded again. A store to a page drops the cached instructions of that page, FENCE.I drops all of them.
+ *
+ * The RISC-V Instruction Set Manual, Volume I, chapter RV32/64G Instruction Set Listings
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp {
+    Add, Sub, Sll, Slt, Sltu, Xor, Srl, Sra, Or, And,
+    Mul, Mulh, Mulhsu, Mulhu, Div, Divu, Rem, Remu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchOp {
+    Eq, Ne, Lt, Ge, Ltu, Geu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrOp {
+    Write, Set, Clear, // CSRRW CSRRS CSRRC
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmoOp {
+    Swap, Add, Xor, And, Or, Min, Max, Minu, Maxu,
+}
+
+// registers are 0-31, sizes are in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Lui       { rd: u8, imm: i64 },
+    Auipc     { rd: u8, imm: i64 },
+    Jal       { rd: u8, offset: i64 },
+    Jalr      { rd: u8, rs1: u8, offset: i64 },
+    Branch    { op: BranchOp, rs1: u8, rs2: u8, offset: i64 },
+    Load      { rd: u8, rs1: u8, offset: i64, size: u8, signed: bool },
+    Store     { rs1: u8, rs2: u8, offset: i64, size: u8 },
+    OpImm     { op: AluOp, rd: u8, rs1: u8, imm: i64 }, // imm is the shift amount of the shifts
+    Op        { op: AluOp, rd: u8, rs1: u8, rs2: u8 },
+    OpImm32   { op: AluOp, rd: u8, rs1: u8, imm: i64 },
+    Op32      { op: AluOp, rd: u8, rs1: u8, rs2: u8 },
+    Fence     { predecessor: u8, successor: u8, tso: bool },
+    FenceI,
+    Ecall,
+    Ebreak,
+    Sret,
+    Mret,
+    Wfi,
+    SfenceVma { rs1: u8, rs2: u8 },
+    Csr       { op: CsrOp, rd: u8, csr: u32, rs1: u8 },
+    CsrImm    { op: CsrOp, rd: u8, csr: u32, uimm: u8 },
+    Lr        { rd: u8, rs1: u8, size: u8, aq: bool, rl: bool },
+    Sc        { rd: u8, rs1: u8, rs2: u8, size: u8, aq: bool, rl: bool },
+    Amo       { op: AmoOp, rd: u8, rs1: u8, rs2: u8, size: u8, aq: bool, rl: bool },
+    Illegal,
+}
+
+// an instruction as it is in memory: raw is 16 bits wide for compressed instructions, length 2 or 4
+#[derive(Debug, Clone, Copy)]
+pub struct Decoded {
+    pub instruction: Instruction,
+    pub raw:         u32,
+    pub length:      u8,
+}
+
+// the bits of an instruction, compressed instructions are expanded first
+pub fn decode_raw(raw: u32) -> Decoded {
+    if raw & 0b11 != 0b11 {
+        let instruction = match expand_compressed(raw as u16) {
+            Some(ir) => decode(ir),
+            None => Instruction::Illegal,
+        };
+        return Decoded { instruction: instruction, raw: raw & 0xffff, length: 2 };
+    }
+    return Decoded { instruction: decode(raw), raw: raw, length: 4 };
+}
+
+// a 32 bit instruction
+pub fn decode(ir: u32) -> Instruction {
+    let rd     = ((ir >> 7) & 0x1f) as u8;
+    let func3  = (ir >> 12) & 0x7;
+    let rs1    = ((ir >> 15) & 0x1f) as u8;
+    let rs2    = ((ir >> 20) & 0x1f) as u8;
+    let func7  = ir >> 25;
+    let imm_i  = ((ir as i32) >> 20) as i64;
+    let imm_s  = (((ir as i32) >> 25) << 5 | ((ir >> 7) & 0x1f) as i32) as i64;
+    let imm_b  = (((ir as i32) >> 31) << 12 | (((ir >> 7) & 1) << 11 | ((ir >> 25) & 0x3f) << 5 | ((ir >> 8) & 0xf) << 1) as i32) as i64;
+    let imm_u  = ((ir & 0xfffff000) as i32) as i64;
+    let imm_j  = (((ir as i32) >> 31) << 20 | (((ir >> 12) & 0xff) << 12 | ((ir >> 20) & 1) << 11 | ((ir >> 21) & 0x3ff) << 1) as i32) as i64;
+    let csr    = ir >> 20;
+    use Instruction::*;
+
+    if ir & 0b11 != 0b11 {
+        return Illegal;
+    }
+    return match ir & 0x7f {
+        0b0110111 => Lui { rd: rd, imm: imm_u },
+        0b0010111 => Auipc { rd: rd, imm: imm_u },
+        0b1101111 => Jal { rd: rd, offset: imm_j },
+        0b1100111 if func3 == 0 => Jalr { rd: rd, rs1: rs1, offset: imm_i },
+        0b1100011 => {
+            let op = match func3 {
+                0b000 => BranchOp::Eq,
+                0b001 => BranchOp::Ne,
+                0b100 => BranchOp::Lt,
+                0b101 => BranchOp::Ge,
+                0b110 => BranchOp::Ltu,
+                0b111 => BranchOp::Geu,
+                _ => return Illegal,
+            };
+            Branch { op: op, rs1: rs1, rs2: rs2, offset: imm_b }
+        },
+        // LB LH LW LD LBU LHU LWU
+        0b0000011 if func3 != 0b111 => Load { rd: rd, rs1: rs1, offset: imm_i, size: 1 << (func3 & 0b11), signed: func3 & 0b100 == 0 },
+        // SB SH SW SD
+        0b0100011 if func3 <= 0b011 => Store { rs1: rs1, rs2: rs2, offset: imm_s, size: 1 << func3 },
+        0b0010011 => {
+            // the upper bits of the shift immediates select the shift
+            let shamt = imm_i & 0x3f;
+            match (func3, ir >> 26) {
+                (0b000, _) => OpImm { op: AluOp::Add, rd: rd, rs1: rs1, imm: imm_i },
+                (0b010, _) => OpImm { op: AluOp::Slt, rd: rd, rs1: rs1, imm: imm_i },
+                (0b011, _) => OpImm { op: AluOp::Sltu, rd: rd, rs1: rs1, imm: imm_i },
+                (0b100, _) => OpImm { op: AluOp::Xor, rd: rd, rs1: rs1, imm: imm_i },
+                (0b110, _) => OpImm { op: AluOp::Or, rd: rd, rs1: rs1, imm: imm_i },
+                (0b111, _) => OpImm { op: AluOp::And, rd: rd, rs1: rs1, imm: imm_i },
+                (0b001, 0) => OpImm { op: AluOp::Sll, rd: rd, rs1: rs1, imm: shamt },
+                (0b101, 0) => OpImm { op: AluOp::Srl, rd: rd, rs1: rs1, imm: shamt },
+                (0b101, 0x10) => OpImm { op: AluOp::Sra, rd: rd, rs1: rs1, imm: shamt },
+                _ => Illegal,
+            }
+        },
+        0b0011011 => {
+            let shamt = imm_i & 0x1f;
+            match (func3, func7) {
+                (0b000, _) => OpImm32 { op: AluOp::Add, rd: rd, rs1: rs1, imm: imm_i },
+                (0b001, 0) => OpImm32 { op: AluOp::Sll, rd: rd, rs1: rs1, imm: shamt },
+                (0b101, 0) => OpImm32 { op: AluOp::Srl, rd: rd, rs1: rs1, imm: shamt },
+                (0b101, 0x20) => OpImm32 { op: AluOp::Sra, rd: rd, rs1: rs1, imm: shamt },
+                _ => Illegal,
+            }
+        },
+        0b0110011 => {
+            let op = match (func7, func3) {
+                (0, 0b000) => AluOp::Add,
+                (0x20, 0b000) => AluOp::Sub,
+                (0, 0b001) => AluOp::Sll,
+                (0, 0b010) => AluOp::Slt,
+                (0, 0b011) => AluOp::Sltu,
+                (0, 0b100) => AluOp::Xor,
+                (0, 0b101) => AluOp::Srl,
+                (0x20, 0b101) => AluOp::Sra,
+                (0, 0b110) => AluOp::Or,
+                (0, 0b111) => AluOp::And,
+                (1, 0b000) => AluOp::Mul,
+                (1, 0b001) => AluOp::Mulh,
+                (1, 0b010) => AluOp::Mulhsu,
+                (1, 0b011) => AluOp::Mulhu,
+                (1, 0b100) => AluOp::Div,
+                (1, 0b101) => AluOp::Divu,
+                (1, 0b110) => AluOp::Rem,
+                (1, _) => AluOp::Remu,
+                _ => return Illegal,
+            };
+            Op { op: op, rd: rd, rs1: rs1, rs2: rs2 }
+        },
+        0b0111011 => {
+            let op = match (func7, func3) {
+                (0, 0b000) => AluOp::Add,
+                (0x20, 0b000) => AluOp::Sub,
+                (0, 0b001) => AluOp::Sll,
+                (0, 0b101) => AluOp::Srl,
+                (0x20, 0b101) => AluOp::Sra,
+                (1, 0b000) => AluOp::Mul,
+                (1, 0b100) => AluOp::Div,
+                (1, 0b101) => AluOp::Divu,
+                (1, 0b110) => AluOp::Rem,
+                (1, 0b111) => AluOp::Remu,
+                _ => return Illegal,
+            };
+            Op32 { op: op, rd: rd, rs1: rs1, rs2: rs2 }
+        },
+        0b0101111 => decode_atomic(ir, rd, func3, rs1, rs2),
+        0b0001111 => match func3 {
+            // FENCE.TSO is fm 1000 with RW,RW, the other fm 1000 fences are plain ones
+            0b000 => Fence { predecessor: ((ir >> 24) & 0xf) as u8, successor: ((ir >> 20) & 0xf) as u8, tso: ir >> 28 == 0b1000 && (ir >> 20) & 0xff == 0x33 },
+            0b001 => FenceI,
+            _ => Illegal,
+        },
+        0b1110011 => match func3 {
+            0b000 => {
+                if rd != 0 || (rs1 != 0 && func7 != 0b0001001) {
+                    return Illegal;
+                }
+                match csr {
+                    0b000000000000 => Ecall,
+                    0b000000000001 => Ebreak,
+                    0b000100000010 => Sret,
+                    0b001100000010 => Mret,
+                    0b000100000101 => Wfi,
+                    _ if func7 == 0b0001001 => SfenceVma { rs1: rs1, rs2: rs2 },
+                    _ => Illegal,
+                }
+            },
+            0b100 => Illegal,
+            _ => {
+                let op = match func3 & 0b11 {
+                    0b01 => CsrOp::Write,
+                    0b10 => CsrOp::Set,
+                    _ => CsrOp::Clear,
+                };
+                if func3 & 0b100 != 0 {
+                    CsrImm { op: op, rd: rd, csr: csr, uimm: rs1 }
+                } else {
+                    Csr { op: op, rd: rd, csr: csr, rs1: rs1 }
+                }
+            },
+        },
+        _ => Illegal,
+    };
+}
+
+// LR, SC and the AMOs, aq and rl are bits 1 and 0 of func7
+fn decode_atomic(ir: u32, rd: u8, func3: u32, rs1: u8, rs2: u8) -> Instruction {
+    let size = match func3 {
+        0b010 => 4,
+        0b011 => 8,
+        _ => return Instruction::Illegal,
+    };
+    let aq = ir & (1 << 26) != 0;
+    let rl = ir & (1 << 25) != 0;
+    let op = match ir >> 27 {
+        0b00010 if rs2 == 0 => return Instruction::Lr { rd: rd, rs1: rs1, size: size, aq: aq, rl: rl },
+        0b00011 => return Instruction::Sc { rd: rd, rs1: rs1, rs2: rs2, size: size, aq: aq, rl: rl },
+        0b00001 => AmoOp::Swap,
+        0b00000 => AmoOp::Add,
+        0b00100 => AmoOp::Xor,
+        0b01100 => AmoOp::And,
+        0b01000 => AmoOp::Or,
+        0b10000 => AmoOp::Min,
+        0b10100 => AmoOp::Max,
+        0b11000 => AmoOp::Minu,
+        0b11100 => AmoOp::Maxu,
+        _ => return Instruction::Illegal,
+    };
+    return Instruction::Amo { op: op, rd: rd, rs1: rs1, rs2: rs2, size: size, aq: aq, rl: rl };
+}
+
+const PAGE_SLOTS: usize = 4096 / 2; // an instruction can start at every halfword
+
+// decoded instructions by physical address, one table per page that holds code
+#[derive(Debug, Default)]
+pub struct DecodeCache {
+    pages: HashMap<u64, Vec<Option<Decoded>>>,
+}
+
+pub fn new_decode_cache() -> DecodeCache {
+    return DecodeCache { pages: HashMap::new() };
+}
+
+impl DecodeCache {
+    pub fn get(&self, pa: u64) -> Option<Decoded> {
+        return self.pages.get(&(pa >> 12))?[((pa & 0xfff) >> 1) as usize];
+    }
+
+    pub fn insert(&mut self, pa: u64, decoded: Decoded) {
+        let page = self.pages.entry(pa >> 12).or_insert_with(|| vec![None; PAGE_SLOTS]);
+        page[((pa & 0xfff) >> 1) as usize] = Some(decoded);
+    }
+
+    // a store to [pa, pa + length) drops the pages it touches
+    pub fn invalidate(&mut self, pa: u64, length: u64) {
+        if self.pages.is_empty() || length == 0 {
+            return;
+        }
+        let last = pa.wrapping_add(length - 1) >> 12;
+        let mut page = pa >> 12;
+        loop {
+            self.pages.remove(&page);
+            if page == last {
+                break;
+            }
+            page = page.wrapping_add(1);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+}
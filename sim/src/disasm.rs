@@ -0,0 +1,347 @@
+
+use serde::Serialize;
+
+use crate::sim::*;
+use crate::decode::*;
+use crate::debug::*;
+use crate::elf::*;
+
+/*
+ * Disassembler for everything the engine executes: RV64I, M, A, C, Zicsr, Zifencei and the privileged instructions.
+ *
+ * The output reads like GNU objdump: ABI register names, the common pseudo-instructions (nop, li, mv, not, neg,
+ * sext.w, seqz, snez, beqz..., j, jr, ret, csrr, csrw...), and branch and jump targets as absolute addresses
+ * followed by the closest symbol, "j 0x80000010 <loop>".
+ * The text is made from the same decoded Instruction the engine executes, so it is what was executed.
+ * Compressed instructions are shown as the instruction they expand to.
+ *
+ * The RISC-V Instruction Set Manual, Volume I, chapter RV32/64G Instruction Set Listings
+ * https://github.com/riscv-non-isa/riscv-asm-manual/blob/main/src/asm-manual.adoc
+ */
+
+// one line of the "disassemble" server action and of -D
+#[derive(Serialize, Debug)]
+pub struct DisassembledInstruction {
+    pub address: u64,
+    pub bits:    String, // hex, 4 digits for compressed instructions
+    pub text:    String,
+    pub symbol:  Option<String>, // set at the first address of a symbol
+}
+
+fn reg(number: u32) -> &'static str {
+    return ABI_NAMES[(number & 0x1f) as usize];
+}
+
+// "0x80000010 <loop>"
+fn target(symbols: &[Symbol], address: u64) -> String {
+    return match symbolize(symbols, address) {
+        Some(name) => format!("0x{:x} <{}>", address, name),
+        None => format!("0x{:x}", address),
+    };
+}
+
+fn csr_name(address: u32) -> String {
+    return match csr_info(address) {
+        Some(info) => info.name.to_lowercase(),
+        None => format!("0x{:x}", address),
+    };
+}
+
+// the successor and predecessor sets of FENCE, "iorw", "0" when empty
+fn fence_set(bits: u32) -> String {
+    if bits == 0 {
+        return String::from("0");
+    }
+    return ["i", "o", "r", "w"].iter().enumerate()
+        .filter(|(i, _)| bits & (8 >> i) != 0)
+        .map(|(_, name)| *name)
+        .collect();
+}
+
+// the text of an instruction at pc, raw is 16 bits wide for compressed instructions
+pub fn disassemble(sim: &Simulator, raw: u32, pc: u64) -> String {
+    return disassemble_instruction(sim, &decode_raw(raw), pc);
+}
+
+fn alu_name(op: AluOp) -> &'static str {
+    return match op {
+        AluOp::Add    => "add",
+        AluOp::Sub    => "sub",
+        AluOp::Sll    => "sll",
+        AluOp::Slt    => "slt",
+        AluOp::Sltu   => "sltu",
+        AluOp::Xor    => "xor",
+        AluOp::Srl    => "srl",
+        AluOp::Sra    => "sra",
+        AluOp::Or     => "or",
+        AluOp::And    => "and",
+        AluOp::Mul    => "mul",
+        AluOp::Mulh   => "mulh",
+        AluOp::Mulhsu => "mulhsu",
+        AluOp::Mulhu  => "mulhu",
+        AluOp::Div    => "div",
+        AluOp::Divu   => "divu",
+        AluOp::Rem    => "rem",
+        AluOp::Remu   => "remu",
+    };
+}
+
+// ".aq", ".rl", ".aqrl" or nothing
+fn ordering(aq: bool, rl: bool) -> &'static str {
+    return match (aq, rl) {
+        (true, true) => ".aqrl",
+        (true, false) => ".aq",
+        (false, true) => ".rl",
+        _ => "",
+    };
+}
+
+// the text of a decoded instruction at pc
+pub fn disassemble_instruction(sim: &Simulator, decoded: &Decoded, pc: u64) -> String {
+    let symbols = &sim.symbols;
+    let width = |size: u8| if size == 4 { "w" } else { "d" };
+
+    return match decoded.instruction {
+        Instruction::Illegal if decoded.length == 2 && decoded.raw == 0 => String::from("c.unimp"),
+        Instruction::Illegal if decoded.length == 2 => String::from("illegal"),
+        Instruction::Illegal => String::from("unknown"),
+        Instruction::Lui { rd, imm } => format!("lui {}, 0x{:x}", reg(rd as u32), (imm as u64 >> 12) & 0xfffff),
+        Instruction::Auipc { rd, imm } => format!("auipc {}, 0x{:x}", reg(rd as u32), (imm as u64 >> 12) & 0xfffff),
+        Instruction::Jal { rd, offset } => {
+            let address = target(symbols, pc.wrapping_add(offset as u64));
+            match rd {
+                0 => format!("j {}", address),
+                1 => format!("jal {}", address),
+                _ => format!("jal {}, {}", reg(rd as u32), address),
+            }
+        },
+        Instruction::Jalr { rd, rs1, offset } => match (rd, rs1, offset) {
+            (0, 1, 0) => String::from("ret"),
+            (0, _, 0) => format!("jr {}", reg(rs1 as u32)),
+            (1, _, 0) => format!("jalr {}", reg(rs1 as u32)),
+            _ => format!("jalr {}, {}({})", reg(rd as u32), offset, reg(rs1 as u32)),
+        },
+        Instruction::Branch { op, rs1, rs2, offset } => {
+            let address = target(symbols, pc.wrapping_add(offset as u64));
+            let (rs1, rs2) = (rs1 as u32, rs2 as u32);
+            match (op, rs1, rs2) {
+                (BranchOp::Eq, _, 0) => format!("beqz {}, {}", reg(rs1), address),
+                (BranchOp::Ne, _, 0) => format!("bnez {}, {}", reg(rs1), address),
+                (BranchOp::Lt, _, 0) => format!("bltz {}, {}", reg(rs1), address),
+                (BranchOp::Ge, _, 0) => format!("bgez {}, {}", reg(rs1), address),
+                (BranchOp::Lt, 0, _) => format!("bgtz {}, {}", reg(rs2), address),
+                (BranchOp::Ge, 0, _) => format!("blez {}, {}", reg(rs2), address),
+                _ => {
+                    let name = match op {
+                        BranchOp::Eq => "beq",
+                        BranchOp::Ne => "bne",
+                        BranchOp::Lt => "blt",
+                        BranchOp::Ge => "bge",
+                        BranchOp::Ltu => "bltu",
+                        BranchOp::Geu => "bgeu",
+                    };
+                    format!("{} {}, {}, {}", name, reg(rs1), reg(rs2), address)
+                },
+            }
+        },
+        Instruction::Load { rd, rs1, offset, size, signed } => {
+            let name = match (size, signed) {
+                (1, true) => "lb",
+                (2, true) => "lh",
+                (4, true) => "lw",
+                (8, _) => "ld",
+                (1, false) => "lbu",
+                (2, false) => "lhu",
+                _ => "lwu",
+            };
+            format!("{} {}, {}({})", name, reg(rd as u32), offset, reg(rs1 as u32))
+        },
+        Instruction::Store { rs1, rs2, offset, size } => {
+            let name = match size { 1 => "sb", 2 => "sh", 4 => "sw", _ => "sd" };
+            format!("{} {}, {}({})", name, reg(rs2 as u32), offset, reg(rs1 as u32))
+        },
+        Instruction::OpImm { op, rd, rs1, imm } => {
+            let (rd, rs1) = (reg(rd as u32), rs1 as u32);
+            match (op, imm) {
+                (AluOp::Add, 0) if rd == "zero" && rs1 == 0 => String::from("nop"),
+                (AluOp::Add, _) if rs1 == 0 => format!("li {}, {}", rd, imm),
+                (AluOp::Add, 0) => format!("mv {}, {}", rd, reg(rs1)),
+                (AluOp::Sltu, 1) => format!("seqz {}, {}", rd, reg(rs1)),
+                (AluOp::Xor, -1) => format!("not {}, {}", rd, reg(rs1)),
+                (AluOp::Sltu, _) => format!("sltiu {}, {}, {}", rd, reg(rs1), imm),
+                _ => format!("{}i {}, {}, {}", alu_name(op), rd, reg(rs1), imm),
+            }
+        },
+        Instruction::OpImm32 { op, rd, rs1, imm } => match (op, imm) {
+            (AluOp::Add, 0) => format!("sext.w {}, {}", reg(rd as u32), reg(rs1 as u32)),
+            _ => format!("{}iw {}, {}, {}", alu_name(op), reg(rd as u32), reg(rs1 as u32), imm),
+        },
+        Instruction::Op { op, rd, rs1, rs2 } => match (op, rs1) {
+            (AluOp::Sub, 0) => format!("neg {}, {}", reg(rd as u32), reg(rs2 as u32)),
+            (AluOp::Sltu, 0) => format!("snez {}, {}", reg(rd as u32), reg(rs2 as u32)),
+            _ => format!("{} {}, {}, {}", alu_name(op), reg(rd as u32), reg(rs1 as u32), reg(rs2 as u32)),
+        },
+        Instruction::Op32 { op, rd, rs1, rs2 } => match (op, rs1) {
+            (AluOp::Sub, 0) => format!("negw {}, {}", reg(rd as u32), reg(rs2 as u32)),
+            _ => format!("{}w {}, {}, {}", alu_name(op), reg(rd as u32), reg(rs1 as u32), reg(rs2 as u32)),
+        },
+        Instruction::Lr { rd, rs1, size, aq, rl } => {
+            format!("lr.{}{} {}, ({})", width(size), ordering(aq, rl), reg(rd as u32), reg(rs1 as u32))
+        },
+        Instruction::Sc { rd, rs1, rs2, size, aq, rl } => {
+            format!("sc.{}{} {}, {}, ({})", width(size), ordering(aq, rl), reg(rd as u32), reg(rs2 as u32), reg(rs1 as u32))
+        },
+        Instruction::Amo { op, rd, rs1, rs2, size, aq, rl } => {
+            let name = match op {
+                AmoOp::Swap => "amoswap",
+                AmoOp::Add  => "amoadd",
+                AmoOp::Xor  => "amoxor",
+                AmoOp::And  => "amoand",
+                AmoOp::Or   => "amoor",
+                AmoOp::Min  => "amomin",
+                AmoOp::Max  => "amomax",
+                AmoOp::Minu => "amominu",
+                AmoOp::Maxu => "amomaxu",
+            };
+            format!("{}.{}{} {}, {}, ({})", name, width(size), ordering(aq, rl), reg(rd as u32), reg(rs2 as u32), reg(rs1 as u32))
+        },
+        Instruction::Fence { tso: true, .. } => String::from("fence.tso"),
+        Instruction::Fence { predecessor: 0xf, successor: 0xf, .. } => String::from("fence"),
+        Instruction::Fence { predecessor, successor, .. } => {
+            format!("fence {}, {}", fence_set(predecessor as u32), fence_set(successor as u32))
+        },
+        Instruction::FenceI => String::from("fence.i"),
+        Instruction::Ecall => String::from("ecall"),
+        Instruction::Ebreak => String::from("ebreak"),
+        Instruction::Sret => String::from("sret"),
+        Instruction::Mret => String::from("mret"),
+        Instruction::Wfi => String::from("wfi"),
+        Instruction::SfenceVma { rs1: 0, rs2: 0 } => String::from("sfence.vma"),
+        Instruction::SfenceVma { rs1, rs2 } => format!("sfence.vma {}, {}", reg(rs1 as u32), reg(rs2 as u32)),
+        Instruction::Csr { op, rd, csr, rs1 } => {
+            let name = csr_name(csr);
+            match (op, rd, rs1) {
+                (CsrOp::Write, 0, _) => format!("csrw {}, {}", name, reg(rs1 as u32)),
+                (CsrOp::Set, _, 0) => format!("csrr {}, {}", reg(rd as u32), name),
+                (CsrOp::Set, 0, _) => format!("csrs {}, {}", name, reg(rs1 as u32)),
+                (CsrOp::Clear, 0, _) => format!("csrc {}, {}", name, reg(rs1 as u32)),
+                _ => format!("{} {}, {}, {}", csr_op_name(op), reg(rd as u32), name, reg(rs1 as u32)),
+            }
+        },
+        Instruction::CsrImm { op, rd, csr, uimm } => {
+            let name = csr_name(csr);
+            match (op, rd) {
+                (CsrOp::Write, 0) => format!("csrwi {}, {}", name, uimm),
+                (CsrOp::Set, 0) => format!("csrsi {}, {}", name, uimm),
+                (CsrOp::Clear, 0) => format!("csrci {}, {}", name, uimm),
+                _ => format!("{}i {}, {}, {}", csr_op_name(op), reg(rd as u32), name, uimm),
+            }
+        },
+    };
+}
+
+fn csr_op_name(op: CsrOp) -> &'static str {
+    return match op {
+        CsrOp::Write => "csrrw",
+        CsrOp::Set   => "csrrs",
+        CsrOp::Clear => "csrrc",
+    };
+}
+
+/*
+ * Disassembles count instructions from address as the HART sees memory, stops early where memory can not be read.
+ * Only RAM is read, like the debugger does.
+ */
+pub fn disassemble_range(sim: &mut Simulator, hart: usize, address: u64, count: usize) -> Vec<DisassembledInstruction> {
+    let mut instructions = vec![];
+    let mut pc = address;
+    while instructions.len() < count {
+        let low = match debug_read_memory(sim, hart, pc, 2) {
+            Some(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+            None => break,
+        };
+        let (raw, length) = if low & 0b11 == 0b11 {
+            match debug_read_memory(sim, hart, pc.wrapping_add(2), 2) {
+                Some(bytes) => (low | (u16::from_le_bytes([bytes[0], bytes[1]]) as u32) << 16, 4),
+                None => break,
+            }
+        } else {
+            (low, 2)
+        };
+        // $x and $d only tell code from data
+        let symbol = sim.symbols.iter().find(|s| s.value == pc && !s.name.is_empty() && !s.name.starts_with('$')).map(|s| s.name.clone());
+        instructions.push(DisassembledInstruction {
+            address: pc,
+            bits:    if length == 2 { format!("{:04x}", raw) } else { format!("{:08x}", raw) },
+            text:    disassemble(sim, raw, pc),
+            symbol:  symbol,
+        });
+        pc = pc.wrapping_add(length);
+    }
+    return instructions;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::*;
+
+    // the major opcodes of the 32 bit instructions the decoder knows
+    const OPCODES: [u32; 13] = [0x37, 0x17, 0x6f, 0x67, 0x63, 0x03, 0x23, 0x13, 0x33, 0x1b, 0x3b, 0x0f, 0x73];
+
+    // what the text of raw assembles back to, as a word
+    fn reassembled(sim: &Simulator, raw: u32, pc: u64) -> Result<u32, String> {
+        let text = disassemble(sim, raw, pc);
+        let assembly = assemble(&text, pc, 4096).map_err(|e| format!("{}: {}", text, e))?;
+        return match assembly.bytes.len() {
+            2 => Ok(u16::from_le_bytes(assembly.bytes[..2].try_into().unwrap()) as u32),
+            4 => Ok(u32::from_le_bytes(assembly.bytes[..4].try_into().unwrap())),
+            _ => Err(format!("{}: {} bytes", text, assembly.bytes.len())),
+        };
+    }
+
+    #[test]
+    fn every_encoding_assembles_back_from_its_text() {
+        let sim = new_sim(1, 0, 0x1000);
+        let pc = 0x8000_0000;
+        // every compressed instruction, then random fields under every major opcode
+        let mut words: Vec<u32> = (0..0x10000).filter(|raw| raw & 0b11 != 0b11).collect();
+        let mut seed: u64 = 0x2545_F491_4F6C_DD1D;
+        for _ in 0..200_000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            words.push((seed as u32) & !0x7f | OPCODES[(seed >> 40) as usize % OPCODES.len()]);
+        }
+        let mut failures = vec![];
+        for raw in words {
+            let decoded = decode_raw(raw);
+            if decoded.instruction == Instruction::Illegal {
+                continue;
+            }
+            match reassembled(&sim, raw, pc) {
+                // compressed instructions read as what they expand to, FENCE and FENCE.I have fields that are ignored
+                Ok(word) if decode_raw(word).instruction == decoded.instruction && (decoded.length == 2 || raw & 0x7f == 0x0f || word == raw) => {},
+                Ok(word) => failures.push(format!("{:08x} {} -> {:08x}", raw, disassemble(&sim, raw, pc), word)),
+                Err(e) => failures.push(format!("{:08x} {}", raw, e)),
+            }
+        }
+        assert!(failures.is_empty(), "{} failures:\n{}", failures.len(), failures[..failures.len().min(20)].join("\n"));
+    }
+
+    #[test]
+    fn reads_like_objdump() {
+        let mut sim = new_sim(1, 0, 0x1000);
+        let assembly = assemble("sltiu s0, a0, 5\nadd a0, zero, a1\nfence 0, w", 0, 4096).unwrap();
+        sim.mem[..assembly.bytes.len()].copy_from_slice(&assembly.bytes);
+        // mapping symbols only tell code from data
+        sim.symbols = vec![
+            Symbol { name: String::from("$x"), value: 0, size: 0 },
+            Symbol { name: String::from("start"), value: 0, size: 0 },
+        ];
+        let instructions = disassemble_range(&mut sim, 0, 0, 3);
+        let texts: Vec<&str> = instructions.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(texts, ["sltiu s0, a0, 5", "add a0, zero, a1", "fence 0, w"]);
+        assert_eq!(instructions[0].symbol.as_deref(), Some("start"));
+    }
+}
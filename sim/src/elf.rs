@@ -0,0 +1,267 @@
+use serde::{Serialize, Deserialize};
+
+/*
+ * Minimal ELF64 little-endian reader, only what the simulator needs.
+ *
+ * https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+ */
+
+const ELF_MAGIC:   &[u8] = &[0x7f, b'E', b'L', b'F'];
+const ELFCLASS64:  u8  = 2;
+const ELFDATA2LSB: u8  = 1;
+const EM_RISCV:    u16 = 243;
+
+pub const PT_LOAD:    u32 = 1;
+pub const PT_INTERP:  u32 = 3;
+const PT_PHDR:        u32 = 6;
+const SHT_SYMTAB:     u32 = 2;
+const STT_NOTYPE:     u8  = 0;
+const STT_OBJECT:     u8  = 1;
+const STT_FUNC:       u8  = 2;
+const SHN_UNDEF:      u16 = 0;
+const SHN_LORESERVE:  u16 = 0xff00; // SHN_ABS, SHN_COMMON and the other special section indices
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Segment {
+    pub kind:   u32,
+    pub offset: u64,
+    pub vaddr:  u64,
+    pub paddr:  u64,
+    pub filesz: u64,
+    pub memsz:  u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Symbol {
+    pub name:  String,
+    pub value: u64,
+    pub size:  u64,
+}
+
+#[derive(Debug)]
+pub struct Elf {
+    pub entry:    u64,
+    pub phoff:    u64, // file offset of the program headers
+    pub segments: Vec<Segment>,
+    pub symbols:  Vec<Symbol>,
+}
+
+pub fn is_elf(file: &[u8]) -> bool {
+    return file.starts_with(ELF_MAGIC);
+}
+
+fn read_u16(file: &[u8], offset: u64) -> Result<u16, String> {
+    let o = offset as usize;
+    match file.get(o..o + 2) {
+        Some(b) => Ok(u16::from_le_bytes(b.try_into().unwrap())),
+        None => Err(format!("ELF truncated at 0x{:X}", offset)),
+    }
+}
+
+fn read_u32(file: &[u8], offset: u64) -> Result<u32, String> {
+    let o = offset as usize;
+    match file.get(o..o + 4) {
+        Some(b) => Ok(u32::from_le_bytes(b.try_into().unwrap())),
+        None => Err(format!("ELF truncated at 0x{:X}", offset)),
+    }
+}
+
+fn read_u64(file: &[u8], offset: u64) -> Result<u64, String> {
+    let o = offset as usize;
+    match file.get(o..o + 8) {
+        Some(b) => Ok(u64::from_le_bytes(b.try_into().unwrap())),
+        None => Err(format!("ELF truncated at 0x{:X}", offset)),
+    }
+}
+
+fn read_str(file: &[u8], offset: u64) -> String {
+    let start = (offset as usize).min(file.len());
+    let end = file[start..].iter().position(|b| *b == 0).map_or(file.len(), |p| start + p);
+    return String::from_utf8_lossy(&file[start..end]).to_string();
+}
+
+pub fn parse_elf(file: &[u8]) -> Result<Elf, String> {
+    if !is_elf(file) || file.len() < 64 {
+        return Err(String::from("not an ELF file"));
+    }
+    if file[4] != ELFCLASS64 || file[5] != ELFDATA2LSB {
+        return Err(String::from("only little-endian ELF64 is supported"));
+    }
+    if read_u16(file, 0x12)? != EM_RISCV {
+        return Err(String::from("not a RISC-V ELF"));
+    }
+
+    let entry     = read_u64(file, 0x18)?;
+    let phoff     = read_u64(file, 0x20)?;
+    let shoff     = read_u64(file, 0x28)?;
+    let phentsize = read_u16(file, 0x36)? as u64;
+    let phnum     = read_u16(file, 0x38)? as u64;
+    let shentsize = read_u16(file, 0x3A)? as u64;
+    let shnum     = read_u16(file, 0x3C)? as u64;
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let ph = phoff + i * phentsize;
+        segments.push(Segment {
+            kind:   read_u32(file, ph)?,
+            offset: read_u64(file, ph + 8)?,
+            vaddr:  read_u64(file, ph + 16)?,
+            paddr:  read_u64(file, ph + 24)?,
+            filesz: read_u64(file, ph + 32)?,
+            memsz:  read_u64(file, ph + 40)?,
+        });
+    }
+
+    let mut symbols = Vec::new();
+    for i in 0..shnum {
+        let sh = shoff + i * shentsize;
+        if read_u32(file, sh + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let offset  = read_u64(file, sh + 24)?;
+        let size    = read_u64(file, sh + 32)?;
+        let link    = read_u32(file, sh + 40)? as u64;
+        let entsize = read_u64(file, sh + 56)?.max(24);
+        let strtab  = read_u64(file, shoff + link * shentsize + 24)?;
+
+        for s in 1..size / entsize {
+            let sym = offset + s * entsize;
+            let name = read_str(file, strtab + read_u32(file, sym)? as u64);
+            let kind = (read_u16(file, sym + 4)? & 0xf) as u8; // st_info, st_other is the high byte
+            let shndx = read_u16(file, sym + 6)?;
+            // code and data defined in a section: not files, sections, absolute values or $x/$d mapping symbols
+            let defined = shndx != SHN_UNDEF && shndx < SHN_LORESERVE;
+            if name.is_empty() || name.starts_with('$') || !defined || ![STT_NOTYPE, STT_OBJECT, STT_FUNC].contains(&kind) {
+                continue;
+            }
+            symbols.push(Symbol {
+                name:  name,
+                value: read_u64(file, sym + 8)?,
+                size:  read_u64(file, sym + 16)?,
+            });
+        }
+    }
+
+    return Ok(Elf {
+        entry:    entry,
+        phoff:    phoff,
+        segments: segments,
+        symbols:  symbols,
+    });
+}
+
+impl Elf {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        return self.symbols.iter().find(|s| s.name == name);
+    }
+
+    // where the program headers are in memory once loaded, for AT_PHDR
+    pub fn phdr_address(&self) -> Option<u64> {
+        if let Some(phdr) = self.segments.iter().find(|s| s.kind == PT_PHDR) {
+            return Some(phdr.vaddr);
+        }
+        return self.segments.iter()
+            .find(|s| s.kind == PT_LOAD && s.offset <= self.phoff && self.phoff < s.offset + s.filesz)
+            .map(|s| s.vaddr + self.phoff - s.offset);
+    }
+
+    // lowest physical address that is loaded, where `objcopy -O binary` output starts
+    pub fn image_base(&self) -> Option<u64> {
+        return self.segments.iter()
+            .filter(|s| s.kind == PT_LOAD && s.filesz > 0)
+            .map(|s| s.paddr)
+            .min();
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // a symbol of build_elf: name, st_info, section index, value
+    pub type TestSymbol<'a> = (&'a str, u8, u16, u64);
+
+    /*
+     * A little-endian RISC-V ELF64 with a PT_LOAD per segment (address, file bytes, memsz) and a symbol table.
+     * The file offset of a segment can be pushed past the end of the file with bad_offset.
+     */
+    pub fn build_elf(entry: u64, segments: &[(u64, &[u8], u64)], symbols: &[TestSymbol], bad_offset: bool) -> Vec<u8> {
+        let mut file = vec![0u8; 64];
+        file[..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', ELFCLASS64, ELFDATA2LSB, 1, 0]);
+        file[0x10..0x12].copy_from_slice(&2u16.to_le_bytes());
+        file[0x12..0x14].copy_from_slice(&EM_RISCV.to_le_bytes());
+        file[0x18..0x20].copy_from_slice(&entry.to_le_bytes());
+        file[0x20..0x28].copy_from_slice(&64u64.to_le_bytes());
+        file[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        file[0x38..0x3A].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        file[0x3A..0x3C].copy_from_slice(&64u16.to_le_bytes());
+
+        let mut offset = 64 + 56 * segments.len() as u64;
+        let mut data = vec![];
+        for (address, bytes, memsz) in segments {
+            let file_offset = if bad_offset { 1 << 20 } else { offset };
+            // p_type and p_flags (R+X) share the first 8 bytes
+            for value in [PT_LOAD as u64 | 5 << 32, file_offset, *address, *address, bytes.len() as u64, *memsz, 0x1000] {
+                file.extend_from_slice(&value.to_le_bytes());
+            }
+            data.extend_from_slice(bytes);
+            offset += bytes.len() as u64;
+        }
+        file.extend_from_slice(&data);
+
+        let mut strtab = vec![0u8];
+        let mut symtab = vec![0u8; 24];
+        for (name, info, shndx, value) in symbols {
+            symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+            symtab.extend_from_slice(&[*info, 0]);
+            symtab.extend_from_slice(&shndx.to_le_bytes());
+            symtab.extend_from_slice(&value.to_le_bytes());
+            symtab.extend_from_slice(&4u64.to_le_bytes());
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+        let symtab_offset = file.len() as u64;
+        file.extend_from_slice(&symtab);
+        let strtab_offset = file.len() as u64;
+        file.extend_from_slice(&strtab);
+
+        let shoff = file.len() as u64;
+        file[0x28..0x30].copy_from_slice(&shoff.to_le_bytes());
+        file[0x3C..0x3E].copy_from_slice(&3u16.to_le_bytes());
+        let section = |kind: u32, offset: u64, size: u64, link: u32| {
+            let mut header = vec![0u8; 64];
+            header[4..8].copy_from_slice(&kind.to_le_bytes());
+            header[24..32].copy_from_slice(&offset.to_le_bytes());
+            header[32..40].copy_from_slice(&size.to_le_bytes());
+            header[40..44].copy_from_slice(&link.to_le_bytes());
+            header[56..64].copy_from_slice(&24u64.to_le_bytes());
+            return header;
+        };
+        file.extend_from_slice(&section(0, 0, 0, 0));
+        file.extend_from_slice(&section(SHT_SYMTAB, symtab_offset, symtab.len() as u64, 2));
+        file.extend_from_slice(&section(3, strtab_offset, strtab.len() as u64, 0));
+        return file;
+    }
+
+    #[test]
+    fn keeps_the_symbols_of_code_and_data() {
+        const STT_SECTION: u8 = 3;
+        const STT_FILE:    u8 = 4;
+        const GLOBAL:      u8 = 0x10;
+        let symbols: [TestSymbol; 8] = [
+            ("main", GLOBAL | STT_FUNC, 1, 0x1000),
+            ("table", STT_OBJECT, 1, 0x1100),
+            ("loop", STT_NOTYPE, 1, 0x1010),
+            ("$x", STT_NOTYPE, 1, 0x1000),
+            ("start.c", STT_FILE, 0xfff1, 0),
+            ("", STT_SECTION, 1, 0x1000),
+            ("SIZE", STT_NOTYPE, 0xfff1, 0x40),
+            ("printf", GLOBAL | STT_FUNC, 0, 0),
+        ];
+        let elf = parse_elf(&build_elf(0x1000, &[(0x1000, &[0x13, 0, 0, 0], 4)], &symbols, false)).unwrap();
+        let names: Vec<&str> = elf.symbols.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["main", "table", "loop"]);
+        assert_eq!(elf.segments[0].kind, PT_LOAD);
+        assert_eq!((elf.segments[0].vaddr, elf.segments[0].filesz, elf.segments[0].memsz), (0x1000, 4, 4));
+    }
+}
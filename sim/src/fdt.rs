@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+
+use crate::sim::*;
+use crate::test_finisher::*;
+use crate::clint::*;
+use crate::plic::*;
+use crate::uart::*;
+
+/*
+ * Flattened device tree (DTB) writer and the description of the simulated machine.
+ *
+ * Layout of the blob, all values big-endian:
+ *      header | memory reservation block | structure block | strings block
+ *
+ * https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html
+ */
+
+const FDT_MAGIC:      u32 = 0xd00dfeed;
+const FDT_VERSION:    u32 = 17;
+const FDT_LAST_COMP:  u32 = 16;
+const FDT_HEADER_LEN: u32 = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE:   u32 = 2;
+const FDT_PROP:       u32 = 3;
+const FDT_END:        u32 = 9;
+
+// frequency of the time CSR and the CLINT mtime register
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+
+pub struct FdtBuilder {
+    structure:      Vec<u8>,
+    strings:        Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    next_phandle:   u32,
+}
+
+pub fn new_fdt_builder() -> FdtBuilder {
+    return FdtBuilder {
+        structure:      Vec::new(),
+        strings:        Vec::new(),
+        string_offsets: HashMap::new(),
+        next_phandle:   1,
+    };
+}
+
+impl FdtBuilder {
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(String::from(name), offset);
+        return offset;
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+
+    pub fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for v in values {
+            value.extend_from_slice(v.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    // reg = <address size> with #address-cells = #size-cells = 2
+    pub fn property_reg(&mut self, address: u64, size: u64) {
+        self.property_cells("reg", &[(address >> 32) as u32, address as u32, (size >> 32) as u32, size as u32]);
+    }
+
+    pub fn allocate_phandle(&mut self) -> u32 {
+        let phandle = self.next_phandle;
+        self.next_phandle += 1;
+        return phandle;
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.push_u32(FDT_END);
+
+        let off_mem_rsvmap = FDT_HEADER_LEN;
+        let rsvmap_len     = 16; // only the terminating entry
+        let off_dt_struct  = off_mem_rsvmap + rsvmap_len;
+        let off_dt_strings = off_dt_struct + self.structure.len() as u32;
+        let totalsize      = off_dt_strings + self.strings.len() as u32;
+
+        let mut blob = Vec::with_capacity(totalsize as usize);
+        for value in [
+            FDT_MAGIC,
+            totalsize,
+            off_dt_struct,
+            off_dt_strings,
+            off_mem_rsvmap,
+            FDT_VERSION,
+            FDT_LAST_COMP,
+            0, // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&value.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        return blob;
+    }
+}
+
+// ISA string for the riscv,isa property, derived from MISA
+pub fn isa_string(sim: &Simulator) -> String {
+    let misa = sim.states.first().and_then(|s| s.csr.get(csr_address::MISA)).unwrap_or(0);
+    let mut isa = String::from("rv64");
+    // canonical order, S and U are privilege modes and not part of the string
+    for letter in "imafdqcbvh".chars() {
+        if misa & (1 << (letter as u8 - b'a')) != 0 {
+            isa.push(letter);
+        }
+    }
+    isa.push_str("_zicsr_zifencei");
+    return isa;
+}
+
+// Builds the device tree of the machine as it is currently configured
+pub fn generate_device_tree(sim: &Simulator, bootargs: &str) -> Vec<u8> {
+    let mut fdt = new_fdt_builder();
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "ar64,virt");
+    fdt.property_string("model", "ar64,virt");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", UART_BASE));
+    if !bootargs.is_empty() {
+        fdt.property_string("bootargs", bootargs);
+    }
+    if let Some((start, end)) = sim.initrd {
+        fdt.property_u64("linux,initrd-start", start);
+        fdt.property_u64("linux,initrd-end", end);
+    }
+    fdt.end_node();
+
+    let isa = isa_string(sim);
+    let mut intcs = Vec::new();
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+    for hart in 0..sim.states.len() {
+        fdt.begin_node(&format!("cpu@{:x}", hart));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hart as u32);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &isa);
+        fdt.property_string("mmu-type", "riscv,sv39");
+
+        let intc = fdt.allocate_phandle();
+        intcs.push(intc);
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", intc);
+        fdt.end_node();
+
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", sim.mem_base));
+    fdt.property_string("device_type", "memory");
+    fdt.property_reg(sim.mem_base, sim.mem.len() as u64);
+    fdt.end_node();
+
+    if sim.devices.htif.is_some() {
+        fdt.begin_node("htif");
+        fdt.property_string("compatible", "ucb,htif0");
+        fdt.end_node();
+    }
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+
+    // interrupts-extended: <&cpu-intc irq> for every HART
+    let per_hart = |irqs: &[u64]| -> Vec<u32> {
+        intcs.iter().flat_map(|intc| irqs.iter().flat_map(move |irq| [*intc, *irq as u32])).collect()
+    };
+
+    fdt.begin_node(&format!("clint@{:x}", sim.devices.clint.base));
+    fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+    fdt.property_reg(sim.devices.clint.base, CLINT_SIZE);
+    fdt.property_cells("interrupts-extended", &per_hart(&[IRQ_M_SOFT, IRQ_M_TIMER]));
+    fdt.end_node();
+
+    let plic = fdt.allocate_phandle();
+    fdt.begin_node(&format!("plic@{:x}", sim.devices.plic.base));
+    fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    fdt.property_reg(sim.devices.plic.base, PLIC_SIZE);
+    fdt.property_u32("#address-cells", 0);
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_u32("riscv,ndev", PLIC_SOURCES - 1);
+    // context 2*hart is M-mode, 2*hart + 1 is S-mode
+    fdt.property_cells("interrupts-extended", &per_hart(&[IRQ_M_EXT, IRQ_S_EXT]));
+    fdt.property_u32("phandle", plic);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("serial@{:x}", UART_BASE));
+    fdt.property_string("compatible", "ns16550a");
+    fdt.property_reg(UART_BASE, UART_SIZE);
+    fdt.property_u32("clock-frequency", 3686400);
+    fdt.property_u32("interrupt-parent", plic);
+    fdt.property_u32("interrupts", UART_IRQ);
+    fdt.end_node();
+
+    if let Some(finisher) = &sim.devices.test_finisher {
+        let syscon = fdt.allocate_phandle();
+        fdt.begin_node(&format!("test@{:x}", finisher.base));
+        fdt.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+        fdt.property_reg(finisher.base, TEST_FINISHER_SIZE);
+        fdt.property_u32("phandle", syscon);
+        fdt.end_node();
+
+        fdt.begin_node("poweroff");
+        fdt.property_string("compatible", "syscon-poweroff");
+        fdt.property_u32("regmap", syscon);
+        fdt.property_u32("offset", 0);
+        fdt.property_u32("value", FINISHER_PASS as u32);
+        fdt.end_node();
+
+        fdt.begin_node("reboot");
+        fdt.property_string("compatible", "syscon-reboot");
+        fdt.property_u32("regmap", syscon);
+        fdt.property_u32("offset", 0);
+        fdt.property_u32("value", FINISHER_RESET as u32);
+        fdt.end_node();
+    }
+
+    if let Some(fb) = &sim.devices.framebuffer {
+        fdt.begin_node(&format!("framebuffer@{:x}", fb.base));
+        fdt.property_string("compatible", "simple-framebuffer");
+        fdt.property_reg(fb.base, fb.size());
+        fdt.property_u32("width", fb.width);
+        fdt.property_u32("height", fb.height);
+        fdt.property_u32("stride", fb.stride);
+        fdt.property_string("format", fb.format.name());
+        fdt.end_node();
+    }
+
+    fdt.end_node(); // soc
+    fdt.end_node(); // root
+    return fdt.finish();
+}
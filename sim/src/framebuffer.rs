@@ -0,0 +1,382 @@
+use serde::{Serialize, Deserialize};
+
+/*
+ * Linear framebuffer, compatible with the linux "simple-framebuffer" binding.
+ *
+ * The device is nothing more than a block of memory mapped at `base`,
+ * the guest writes pixels to it and the host reads them back out as an image.
+ * Every row is `stride` bytes long, pixels are stored little-endian.
+ *
+ * https://www.kernel.org/doc/Documentation/devicetree/bindings/display/simple-framebuffer.yaml
+ */
+
+pub const DEFAULT_FRAMEBUFFER_BASE: u64 = 0x50000000;
+const MAX_FRAMEBUFFER_SIZE:         u64 = 256 << 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    R5G6B5,
+    R8G8B8,
+    X8R8G8B8,
+    A8R8G8B8,
+    X8B8G8R8,
+    A8B8G8R8,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> u64 {
+        return match self {
+            PixelFormat::R5G6B5 => 2,
+            PixelFormat::R8G8B8 => 3,
+            _ => 4,
+        };
+    }
+
+    // name as used by the "format" property of simple-framebuffer
+    pub fn name(&self) -> &'static str {
+        return match self {
+            PixelFormat::R5G6B5   => "r5g6b5",
+            PixelFormat::R8G8B8   => "r8g8b8",
+            PixelFormat::X8R8G8B8 => "x8r8g8b8",
+            PixelFormat::A8R8G8B8 => "a8r8g8b8",
+            PixelFormat::X8B8G8R8 => "x8b8g8r8",
+            PixelFormat::A8B8G8R8 => "a8b8g8r8",
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<PixelFormat> {
+        return match name {
+            "r5g6b5"   => Some(PixelFormat::R5G6B5),
+            "r8g8b8"   => Some(PixelFormat::R8G8B8),
+            "x8r8g8b8" => Some(PixelFormat::X8R8G8B8),
+            "a8r8g8b8" => Some(PixelFormat::A8R8G8B8),
+            "x8b8g8r8" => Some(PixelFormat::X8B8G8R8),
+            "a8b8g8r8" => Some(PixelFormat::A8B8G8R8),
+            _ => None,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Framebuffer {
+    pub base:   u64,
+    pub width:  u32,
+    pub height: u32,
+    pub stride: u32, // bytes per row
+    pub format: PixelFormat,
+    #[serde(skip)] // "framebuffer sixel" has the picture, the simulator JSON does not need the bytes
+    pub pixels: Vec<u8>,
+}
+
+// parse_framebuffer_config keeps the size below MAX_FRAMEBUFFER_SIZE
+pub fn new_framebuffer(base: u64, width: u32, height: u32, format: PixelFormat) -> Framebuffer {
+    let stride = width as u64 * format.bytes_per_pixel();
+    return Framebuffer {
+        base:   base,
+        width:  width,
+        height: height,
+        stride: stride as u32,
+        format: format,
+        pixels: vec![0; (stride * height as u64) as usize],
+    };
+}
+
+/*
+ * Parses a framebuffer description as given on the command line:
+ *      WIDTHxHEIGHT[:format][@base]
+ * e.g. "320x240", "640x480:a8r8g8b8" or "320x240:r5g6b5@0x50000000"
+ */
+pub fn parse_framebuffer_config(config: &str) -> Result<Framebuffer, String> {
+    let (config, base) = match config.split_once('@') {
+        Some((c, b)) => {
+            let b = b.trim_start_matches("0x");
+            match u64::from_str_radix(b, 16) {
+                Ok(base) => (c, base),
+                Err(_) => return Err(format!("invalid framebuffer base address: {}", b)),
+            }
+        }
+        None => (config, DEFAULT_FRAMEBUFFER_BASE),
+    };
+    let (size, format) = match config.split_once(':') {
+        Some((s, f)) => match PixelFormat::from_name(f) {
+            Some(format) => (s, format),
+            None => return Err(format!("unknown pixel format: {}", f)),
+        },
+        None => (config, PixelFormat::X8R8G8B8),
+    };
+    let (width, height) = match size.split_once('x') {
+        Some((w, h)) => match (w.parse::<u32>(), h.parse::<u32>()) {
+            (Ok(w), Ok(h)) if w > 0 && h > 0 => (w, h),
+            _ => return Err(format!("invalid framebuffer size: {}", size)),
+        },
+        None => return Err(format!("invalid framebuffer size: {}", size)),
+    };
+    let bytes = (width as u64).checked_mul(format.bytes_per_pixel()).and_then(|stride| stride.checked_mul(height as u64));
+    match bytes {
+        Some(bytes) if bytes <= MAX_FRAMEBUFFER_SIZE && base.checked_add(bytes).is_some() => {},
+        _ => return Err(format!("framebuffer {} is larger than {} MiB or does not fit at 0x{:X}", size, MAX_FRAMEBUFFER_SIZE >> 20, base)),
+    }
+    return Ok(new_framebuffer(base, width, height, format));
+}
+
+impl Framebuffer {
+    pub fn size(&self) -> u64 {
+        return self.pixels.len() as u64;
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        return address >= self.base && address < self.base + self.size();
+    }
+
+    // reads `size` bytes little-endian, bytes outside of the framebuffer read as zero
+    pub fn read(&self, address: u64, size: u64) -> u64 {
+        let offset = address - self.base;
+        let mut value = 0;
+        for i in 0..size {
+            if let Some(b) = self.pixels.get((offset + i) as usize) {
+                value |= (*b as u64) << (8 * i);
+            }
+        }
+        return value;
+    }
+
+    pub fn write(&mut self, address: u64, size: u64, value: u64) {
+        let offset = address - self.base;
+        for i in 0..size {
+            if let Some(b) = self.pixels.get_mut((offset + i) as usize) {
+                *b = (value >> (8 * i)) as u8;
+            }
+        }
+    }
+
+    pub fn pixel_rgb(&self, x: u32, y: u32) -> [u8; 3] {
+        let offset = (y * self.stride) as usize + (x as u64 * self.format.bytes_per_pixel()) as usize;
+        let p = &self.pixels[offset..];
+        return match self.format {
+            PixelFormat::R5G6B5 => {
+                let v = u16::from_le_bytes([p[0], p[1]]);
+                let r = ((v >> 11) & 0x1f) as u8;
+                let g = ((v >>  5) & 0x3f) as u8;
+                let b = ( v        & 0x1f) as u8;
+                [(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2)]
+            }
+            PixelFormat::R8G8B8 | PixelFormat::X8R8G8B8 | PixelFormat::A8R8G8B8 => [p[2], p[1], p[0]],
+            PixelFormat::X8B8G8R8 | PixelFormat::A8B8G8R8 => [p[0], p[1], p[2]],
+        };
+    }
+
+    // packed 8 bit RGB, row by row
+    pub fn to_rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity((self.width * self.height * 3) as usize);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                rgb.extend_from_slice(&self.pixel_rgb(x, y));
+            }
+        }
+        return rgb;
+    }
+
+    // binary portable pixmap (P6)
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut out = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        out.extend(self.to_rgb());
+        return out;
+    }
+
+    /*
+     * PNG with an uncompressed (stored) deflate stream.
+     * The images are small and this avoids pulling in a compression library.
+     */
+    pub fn to_png(&self) -> Vec<u8> {
+        let rgb = self.to_rgb();
+        let row_len = (self.width * 3) as usize;
+        let mut raw = Vec::with_capacity((row_len + 1) * self.height as usize);
+        for row in rgb.chunks(row_len) {
+            raw.push(0); // filter: none
+            raw.extend_from_slice(row);
+        }
+
+        let mut zlib = vec![0x78, 0x01];
+        let mut blocks = raw.chunks(0xffff).peekable();
+        if blocks.peek().is_none() {
+            zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        while let Some(block) = blocks.next() {
+            let len = block.len() as u16;
+            zlib.push(if blocks.peek().is_none() { 1 } else { 0 });
+            zlib.extend_from_slice(&len.to_le_bytes());
+            zlib.extend_from_slice(&(!len).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&self.width.to_be_bytes());
+        ihdr.extend_from_slice(&self.height.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bit, truecolour, deflate, no filter, no interlace
+
+        let mut out = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+        png_chunk(&mut out, b"IHDR", &ihdr);
+        png_chunk(&mut out, b"IDAT", &zlib);
+        png_chunk(&mut out, b"IEND", &[]);
+        return out;
+    }
+
+    /*
+     * DEC sixel stream, can be printed directly to a terminal that supports sixel graphics.
+     * Colours are quantized to a fixed 6x6x6 colour cube.
+     */
+    pub fn to_sixel(&self) -> String {
+        let level = |c: u8| ((c as u32 * 5 + 127) / 255) as usize;
+        let rgb = self.to_rgb();
+        let colour_index: Vec<usize> = rgb.chunks(3)
+            .map(|p| level(p[0]) * 36 + level(p[1]) * 6 + level(p[2]))
+            .collect();
+
+        let mut out = String::from("\x1bPq");
+        out.push_str(&format!("\"1;1;{};{}", self.width, self.height));
+        let mut defined = [false; 216];
+        for i in colour_index.iter() {
+            if !defined[*i] {
+                defined[*i] = true;
+                // sixel colour components are percentages
+                out.push_str(&format!("#{};2;{};{};{}", i, (i / 36) * 20, ((i / 6) % 6) * 20, (i % 6) * 20));
+            }
+        }
+
+        let width = self.width as usize;
+        for band in 0..self.height.div_ceil(6) as usize {
+            let rows = band * 6..((band + 1) * 6).min(self.height as usize);
+            let mut used = [false; 216];
+            for y in rows.clone() {
+                for x in 0..width {
+                    used[colour_index[y * width + x]] = true;
+                }
+            }
+
+            let mut first = true;
+            for colour in (0..216).filter(|c| used[*c]) {
+                if !first {
+                    out.push('$'); // carriage return
+                }
+                first = false;
+                out.push_str(&format!("#{}", colour));
+
+                let mut run_char = '?';
+                let mut run_len = 0;
+                for x in 0..width {
+                    let mut bits = 0;
+                    for y in rows.clone() {
+                        if colour_index[y * width + x] == colour {
+                            bits |= 1 << (y - band * 6);
+                        }
+                    }
+                    let c = (b'?' + bits) as char;
+                    if c == run_char {
+                        run_len += 1;
+                    } else {
+                        push_sixel_run(&mut out, run_char, run_len);
+                        run_char = c;
+                        run_len = 1;
+                    }
+                }
+                push_sixel_run(&mut out, run_char, run_len);
+            }
+            out.push('-'); // next band
+        }
+        out.push_str("\x1b\\");
+        return out;
+    }
+}
+
+fn push_sixel_run(out: &mut String, c: char, len: usize) {
+    match len {
+        0 => {},
+        1..=3 => for _ in 0..len { out.push(c) },
+        _ => out.push_str(&format!("!{}{}", len, c)),
+    }
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    return !crc;
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for d in data {
+        a = (a + *d as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    return (b << 16) | a;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2x2: red, green / blue, white
+    fn small() -> Framebuffer {
+        let mut fb = new_framebuffer(DEFAULT_FRAMEBUFFER_BASE, 2, 2, PixelFormat::X8R8G8B8);
+        for (i, pixel) in [0xff0000u32, 0x00ff00, 0x0000ff, 0xffffff].iter().enumerate() {
+            fb.write(fb.base + 4 * i as u64, 4, *pixel as u64);
+        }
+        return fb;
+    }
+
+    #[test]
+    fn ppm_is_a_header_and_rgb_rows() {
+        let mut expected = b"P6\n2 2\n255\n".to_vec();
+        expected.extend_from_slice(&[255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255]);
+        assert_eq!(small().to_ppm(), expected);
+    }
+
+    #[test]
+    fn png_has_a_stored_deflate_stream_and_checksums() {
+        // made with zlib.crc32 and zlib.adler32, zlib.decompress reads the IDAT back
+        let expected: &[u8] = &[
+            0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+            0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x08, 0x02, 0x00, 0x00, 0x00, 0xfd, 0xd4, 0x9a,
+            0x73, 0x00, 0x00, 0x00, 0x19, 0x49, 0x44, 0x41, 0x54, 0x78, 0x01, 0x01, 0x0e, 0x00, 0xf1, 0xff,
+            0x00, 0xff, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0x1f, 0xee,
+            0x05, 0xfb, 0xde, 0xdd, 0xec, 0x2b, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42,
+            0x60, 0x82,
+        ];
+        assert_eq!(small().to_png(), expected);
+    }
+
+    #[test]
+    fn sixel_defines_the_colours_and_draws_each_one_per_band() {
+        let expected = "\x1bPq\"1;1;2;2#180;2;100;0;0#30;2;0;100;0#5;2;0;0;100#215;2;100;100;100\
+                        #5A?$#30?@$#180@?$#215?A-\x1b\\";
+        assert_eq!(small().to_sixel(), expected);
+        // a run of more than 3 columns is counted, 7 rows take two bands
+        let fb = new_framebuffer(0, 5, 7, PixelFormat::R5G6B5);
+        assert_eq!(fb.to_sixel(), "\x1bPq\"1;1;5;7#0;2;0;0;0#0!5~-#0!5@-\x1b\\");
+    }
+
+    #[test]
+    fn r5g6b5_expands_to_8_bits() {
+        let mut fb = new_framebuffer(0, 3, 1, PixelFormat::R5G6B5);
+        fb.write(0, 2, 0xf800);
+        fb.write(2, 2, 0x07e0);
+        fb.write(4, 2, 0x8410);
+        assert_eq!(fb.to_rgb(), vec![255, 0, 0, 0, 255, 0, 132, 130, 132]);
+    }
+}
@@ -0,0 +1,538 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::sim::*;
+use crate::debug::*;
+use crate::test_finisher::*;
+use crate::history::*;
+
+/*
+ * GDB remote serial protocol stub, "-g port": the machine waits for GDB (or LLDB) on a TCP port
+ * and runs under its control.
+ *
+ *      target remote :port
+ *
+ * Every HART is a thread, thread ids are hartid + 1. All-stop mode: continue and step run all HARTs,
+ * a step advances every HART by one instruction.
+ *
+ *      ?                       last stop reason
+ *      g G p P                 registers, the target description lists x0-x31, pc, the CSRs and priv
+ *      m M                     memory, virtual addresses of the selected HART, RAM only
+ *      c s vCont               continue, single step, Ctrl-C stops a continue
+ *      bc bs                   reverse continue and reverse step with --history, replaylog:begin when the recording runs out
+ *      Z0 Z1 z0 z1             breakpoints, software and hardware ones are the same
+ *      Z2 Z3 Z4 z2 z3 z4       write, read and access watchpoints
+ *      H T qC qfThreadInfo     threads
+ *      qXfer:features:read     target.xml
+ *      D k                     detach (the machine runs on) and kill
+ *
+ * https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+ * https://sourceware.org/gdb/current/onlinedocs/gdb.html/RISC_002dV-Features.html
+ */
+
+// GDB register numbers of the RISC-V target
+const GDB_PC:       usize = 32;
+const GDB_CSR_BASE: usize = 65;
+const GDB_PRIV:     usize = GDB_CSR_BASE + 4096;
+
+const SIGINT:  u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// instructions run between checks for Ctrl-C and calls of poll
+const POLL_INTERVAL: u32 = 10000;
+
+const MAX_PACKET: usize = 0x4000;
+
+enum Stop {
+    Signal(u8, usize),
+    Watch(WatchHit),
+    Exited(u8),
+    Halted(String),
+    ReplayBegin(usize),
+    Disconnected,
+}
+
+struct GdbSession {
+    stream: TcpStream,
+    input:  Vec<u8>,
+    hart:   usize, // selected with Hg, for registers and memory
+    last:   String, // stop reply for "?"
+}
+
+fn to_hex(data: &[u8]) -> String {
+    return data.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    return (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect();
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    return u64::from_str_radix(text, 16).ok();
+}
+
+// "addr,length" of m, M, Z and z packets
+fn address_length(text: &str) -> Option<(u64, u64)> {
+    let (address, length) = text.split_once(',')?;
+    return Some((parse_hex(address)?, parse_hex(length)?));
+}
+
+impl GdbSession {
+    fn send(&mut self, data: &str) -> bool {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+        return self.stream.write_all(packet.as_bytes()).is_ok();
+    }
+
+    // next packet, acknowledged. None when GDB went away.
+    fn receive(&mut self) -> Option<String> {
+        loop {
+            // drop acks and anything before the start of a packet, a lone Ctrl-C while stopped is ignored
+            if let Some(start) = self.input.iter().position(|b| *b == b'$') {
+                self.input.drain(..start);
+                if let Some(end) = self.input.iter().position(|b| *b == b'#') {
+                    if self.input.len() >= end + 3 {
+                        let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+                        let data = &packet[1..end];
+                        let checksum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+                        let expected = std::str::from_utf8(&packet[end + 1..]).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+                        if expected != Some(checksum) {
+                            self.stream.write_all(b"-").ok()?;
+                            continue;
+                        }
+                        self.stream.write_all(b"+").ok()?;
+                        return Some(String::from_utf8_lossy(data).to_string());
+                    }
+                }
+            } else {
+                self.input.clear();
+            }
+            let mut buffer = [0u8; 4096];
+            match self.stream.read(&mut buffer) {
+                Ok(0) | Err(_) => return None,
+                Ok(count) => self.input.extend_from_slice(&buffer[..count]),
+            }
+        }
+    }
+
+    // true when GDB sent Ctrl-C, checked without waiting while the machine runs
+    fn interrupted(&mut self) -> Option<bool> {
+        let mut buffer = [0u8; 256];
+        _ = self.stream.set_nonblocking(true);
+        let result = self.stream.read(&mut buffer);
+        _ = self.stream.set_nonblocking(false);
+        return match result {
+            Ok(0) => None,
+            Ok(count) => {
+                self.input.extend_from_slice(&buffer[..count]);
+                let interrupt = self.input.contains(&0x03);
+                self.input.retain(|b| *b != 0x03);
+                Some(interrupt)
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Some(false),
+            Err(_) => None,
+        };
+    }
+}
+
+/*
+ * Waits for GDB on port and serves it until it detaches, kills the machine or the guest exits.
+ * poll is called while the machine runs, e.g. to move console input and output.
+ * Returns true when the machine should run on without the debugger.
+ */
+pub fn gdb_serve(sim: &mut Simulator, port: u16, poll: &mut dyn FnMut(&mut Simulator)) -> bool {
+    let listener = match TcpListener::bind(format!("127.0.0.1:{}", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("ERROR: GDB stub can not listen on port {}: {:?}", port, e);
+            return false;
+        }
+    };
+    println!("INFO waiting for GDB on port {}, target remote :{}", port, port);
+    let stream = match listener.accept() {
+        Ok((stream, address)) => {
+            println!("INFO GDB connected from {}", address);
+            stream
+        },
+        Err(e) => {
+            println!("ERROR: GDB stub accept failed: {:?}", e);
+            return false;
+        }
+    };
+    _ = stream.set_nodelay(true);
+    let mut session = GdbSession {
+        stream: stream,
+        input:  vec![],
+        hart:   0,
+        last:   format!("T{:02x}thread:1;", SIGTRAP),
+    };
+
+    while let Some(packet) = session.receive() {
+        let reply = match packet.as_bytes().first() {
+            Some(b'c') | Some(b's') => {
+                let stop = run(sim, &mut session, packet.starts_with('s'), poll);
+                match report_stop(&mut session, stop) {
+                    Some(running) => return running,
+                    None => continue,
+                }
+            },
+            Some(b'v') if packet.starts_with("vCont;") => {
+                // all HARTs move together, any step action makes it a single step
+                let step = packet[6..].split(';').any(|action| action.starts_with('s') || action.starts_with('S'));
+                let stop = run(sim, &mut session, step, poll);
+                match report_stop(&mut session, stop) {
+                    Some(running) => return running,
+                    None => continue,
+                }
+            },
+            Some(b'b') if packet == "bc" || packet == "bs" => {
+                if sim.history.is_none() {
+                    String::from("E01")
+                } else {
+                    let stop = run_backwards(sim, &session, packet == "bs");
+                    match report_stop(&mut session, stop) {
+                        Some(running) => return running,
+                        None => continue,
+                    }
+                }
+            },
+            Some(b'D') => {
+                session.send("OK");
+                sim.debugger = default_debugger();
+                println!("INFO GDB detached");
+                return true;
+            },
+            Some(b'k') => {
+                println!("INFO killed by GDB");
+                sim.log = String::from("killed by GDB");
+                return false;
+            },
+            _ => handle_packet(sim, &mut session, &packet),
+        };
+        if !session.send(&reply) {
+            break;
+        }
+    }
+    println!("INFO GDB disconnected");
+    return true;
+}
+
+// sends the stop reply, Some(run on) when the session is over
+fn report_stop(session: &mut GdbSession, stop: Stop) -> Option<bool> {
+    let reply = match stop {
+        Stop::Signal(signal, hart) => {
+            session.hart = hart;
+            format!("T{:02x}thread:{:x};", signal, hart + 1)
+        },
+        Stop::Watch(hit) => {
+            session.hart = hit.hart;
+            let kind = match hit.watchpoint.kind {
+                WatchKind::Write  => "watch",
+                WatchKind::Read   => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            format!("T{:02x}thread:{:x};{}:{:x};", SIGTRAP, hit.hart + 1, kind, hit.address)
+        },
+        Stop::Halted(message) => {
+            // the machine can not go on, GDB can still look at it
+            session.send(&format!("O{}", to_hex(format!("{}\n", message).as_bytes())));
+            format!("T{:02x}thread:{:x};", SIGTRAP, session.hart + 1)
+        },
+        Stop::ReplayBegin(hart) => {
+            session.hart = hart;
+            format!("T{:02x}thread:{:x};replaylog:begin;", SIGTRAP, hart + 1)
+        },
+        Stop::Exited(code) => {
+            session.send(&format!("W{:02x}", code));
+            return Some(false);
+        },
+        Stop::Disconnected => return Some(true),
+    };
+    session.last = reply.clone();
+    session.send(&reply);
+    return None;
+}
+
+fn run(sim: &mut Simulator, session: &mut GdbSession, single_step: bool, poll: &mut dyn FnMut(&mut Simulator)) -> Stop {
+    sim.debugger.watch_hit = None;
+    let mut count: u32 = 0;
+    loop {
+        let running = step(sim);
+        if let Some(hit) = sim.debugger.watch_hit.take() {
+            return Stop::Watch(hit);
+        }
+        if !running {
+            poll(sim);
+            return match guest_exit_status(sim) {
+                Some(FinisherStatus::Pass) => Stop::Exited(0),
+                Some(FinisherStatus::Fail(code)) => Stop::Exited(code as u8),
+                _ => Stop::Halted(sim.log.clone()),
+            };
+        }
+        if single_step {
+            return Stop::Signal(SIGTRAP, session.hart);
+        }
+        if let Some(hart) = breakpoint_hart(sim) {
+            return Stop::Signal(SIGTRAP, hart);
+        }
+        count += 1;
+        if count == POLL_INTERVAL {
+            count = 0;
+            poll(sim);
+            match session.interrupted() {
+                Some(true) => return Stop::Signal(SIGINT, session.hart),
+                Some(false) => {},
+                None => return Stop::Disconnected,
+            }
+        }
+    }
+}
+
+// bc and bs, going back through the steps recorded by --history
+fn run_backwards(sim: &mut Simulator, session: &GdbSession, single_step: bool) -> Stop {
+    let event = if single_step { reverse_steps(sim, 1) } else { reverse_continue(sim, u64::MAX) };
+    return match event.reason.as_str() {
+        "begin" => Stop::ReplayBegin(session.hart),
+        "watchpoint" => {
+            let address = event.address.unwrap_or(event.pc);
+            match sim.debugger.watchpoints.iter().find(|w| w.start <= address && address < w.end) {
+                Some(watchpoint) => Stop::Watch(WatchHit { hart: event.hart, address: address, write: true, watchpoint: *watchpoint }),
+                None => Stop::Signal(SIGTRAP, event.hart),
+            }
+        },
+        "breakpoint" => Stop::Signal(SIGTRAP, event.hart),
+        _ => Stop::Signal(SIGTRAP, session.hart),
+    };
+}
+
+fn handle_packet(sim: &mut Simulator, session: &mut GdbSession, packet: &str) -> String {
+    let harts = sim.states.len();
+    let hart = session.hart.min(harts - 1);
+    let error = String::from("E01");
+    let ok = String::from("OK");
+    if packet.is_empty() {
+        return String::new();
+    }
+
+    let (kind, rest) = packet.split_at(1);
+    return match kind {
+        "?" => session.last.clone(),
+        "g" => {
+            let state = &sim.states[hart];
+            let mut registers: Vec<u8> = state.regs.iter().flat_map(|r| r.to_le_bytes()).collect();
+            registers.extend_from_slice(&state.pc.to_le_bytes());
+            to_hex(&registers)
+        },
+        "G" => match from_hex(rest) {
+            Some(bytes) if bytes.len() >= 33 * 8 => {
+                history_edited(sim);
+                let state = &mut sim.states[hart];
+                for (i, chunk) in bytes.chunks(8).take(33).enumerate() {
+                    let value = u64::from_le_bytes(chunk.try_into().unwrap());
+                    match i {
+                        0 => {},
+                        GDB_PC => state.pc = value,
+                        _ => state.regs[i] = value,
+                    }
+                }
+                ok
+            },
+            _ => error,
+        },
+        "p" => match parse_hex(rest).map(|n| read_register(sim, hart, n as usize)) {
+            Some(Some(value)) => value,
+            _ => error,
+        },
+        "P" => {
+            let written = rest.split_once('=')
+                .and_then(|(n, value)| Some((parse_hex(n)? as usize, from_hex(value)?)))
+                .is_some_and(|(n, value)| write_register(sim, hart, n, &value));
+            if written { ok } else { error }
+        },
+        "m" => match address_length(rest) {
+            Some((address, length)) if length as usize <= MAX_PACKET / 2 => {
+                match debug_read_memory(sim, hart, address, length) {
+                    Some(data) => to_hex(&data),
+                    None => String::from("E14"),
+                }
+            },
+            _ => error,
+        },
+        "M" => {
+            let written = rest.split_once(':')
+                .and_then(|(range, data)| Some((address_length(range)?, from_hex(data)?)))
+                .is_some_and(|((address, _), data)| debug_write_memory(sim, hart, address, &data));
+            if written { ok } else { String::from("E14") }
+        },
+        "Z" | "z" => {
+            let insert = kind == "Z";
+            let mut fields = rest.splitn(3, ',');
+            let (which, address, length) = (fields.next(), fields.next().and_then(parse_hex), fields.next().and_then(parse_hex));
+            match (which, address, length) {
+                (Some("0"), Some(address), _) | (Some("1"), Some(address), _) => {
+                    if insert {
+                        _ = sim.debugger.add_breakpoint(address, None);
+                    } else {
+                        sim.debugger.remove_breakpoint(address);
+                    }
+                    ok
+                },
+                (Some(which @ ("2" | "3" | "4")), Some(address), Some(length)) => {
+                    let kind = match which {
+                        "2" => WatchKind::Write,
+                        "3" => WatchKind::Read,
+                        _   => WatchKind::Access,
+                    };
+                    let watchpoint = Watchpoint { start: address, end: address.wrapping_add(length.max(1)), kind: kind };
+                    if insert {
+                        sim.debugger.add_watchpoint(watchpoint);
+                    } else {
+                        sim.debugger.remove_watchpoint(watchpoint);
+                    }
+                    ok
+                },
+                _ => String::new(),
+            }
+        },
+        "H" => {
+            // Hg selects the thread for registers and memory, Hc is meaningless in lockstep
+            match rest.strip_prefix('g').map(|t| i64::from_str_radix(t, 16)) {
+                Some(Ok(thread)) if thread > 0 && (thread as usize) <= harts => {
+                    session.hart = thread as usize - 1;
+                    ok
+                },
+                Some(Ok(_)) => ok,
+                Some(Err(_)) => error,
+                None => ok,
+            }
+        },
+        "T" => match parse_hex(rest) {
+            Some(thread) if thread > 0 && thread as usize <= harts => ok,
+            _ => error,
+        },
+        "q" => handle_query(sim, session, packet),
+        "v" if packet == "vCont?" => String::from("vCont;c;C;s;S"),
+        _ => String::new(),
+    };
+}
+
+fn handle_query(sim: &Simulator, session: &GdbSession, packet: &str) -> String {
+    let harts = sim.states.len();
+    if packet.starts_with("qSupported") {
+        let reverse = if sim.history.is_some() { ";ReverseStep+;ReverseContinue+" } else { "" };
+        return format!("PacketSize={:x};qXfer:features:read+;vContSupported+{}", MAX_PACKET, reverse);
+    } else if packet == "qAttached" {
+        return String::from("1");
+    } else if packet == "qC" {
+        return format!("QC{:x}", session.hart + 1);
+    } else if packet == "qfThreadInfo" {
+        let threads: Vec<String> = (1..=harts).map(|t| format!("{:x}", t)).collect();
+        return format!("m{}", threads.join(","));
+    } else if packet == "qsThreadInfo" {
+        return String::from("l");
+    } else if let Some(thread) = packet.strip_prefix("qThreadExtraInfo,") {
+        let hart = parse_hex(thread).unwrap_or(1).saturating_sub(1);
+        return to_hex(format!("hart {}", hart).as_bytes());
+    } else if let Some(request) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+        let xml = target_xml();
+        return match address_length(request) {
+            Some((offset, length)) => {
+                let start = (offset as usize).min(xml.len());
+                let end = (start + length as usize).min(xml.len());
+                let more = if end < xml.len() { "m" } else { "l" };
+                format!("{}{}", more, &xml[start..end])
+            },
+            None => String::from("E01"),
+        };
+    } else if packet == "qSymbol::" {
+        return String::from("OK");
+    }
+    return String::new();
+}
+
+// the registers GDB asks for with p, hex in target byte order
+fn read_register(sim: &Simulator, hart: usize, number: usize) -> Option<String> {
+    let state = &sim.states[hart];
+    let value = match number {
+        0..=31 => state.regs[number],
+        GDB_PC => state.pc,
+        GDB_PRIV => return Some(format!("{:02x}", state.priviledge_mode)),
+        // like a CSR instruction would read it, the supervisor registers are views of the machine ones
+        n if (GDB_CSR_BASE..GDB_PRIV).contains(&n) => csr_read(state, sim.devices.clint.mtime, (n - GDB_CSR_BASE) as u32)?,
+        _ => return None,
+    };
+    return Some(to_hex(&value.to_le_bytes()));
+}
+
+fn write_register(sim: &mut Simulator, hart: usize, number: usize, value: &[u8]) -> bool {
+    history_edited(sim);
+    let state = &mut sim.states[hart];
+    let mut bytes = [0u8; 8];
+    let length = value.len().min(8);
+    bytes[..length].copy_from_slice(&value[..length]);
+    let value = u64::from_le_bytes(bytes);
+    match number {
+        0 => {},
+        1..=31 => state.regs[number] = value,
+        GDB_PC => state.pc = value,
+        GDB_PRIV if value <= PRIV_M as u64 && value != 2 => state.priviledge_mode = value as u8,
+        // through the same masks and WARL rules as a CSR instruction, without its privilege checks
+        n if (GDB_CSR_BASE..GDB_PRIV).contains(&n) && state.csr.contains((n - GDB_CSR_BASE) as u32) => {
+            csr_write(state, (n - GDB_CSR_BASE) as u32, value);
+        },
+        _ => return false,
+    }
+    return true;
+}
+
+// target description: the integer registers, every CSR the HARTs have and the privilege mode
+fn target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n");
+    xml += "<architecture>riscv:rv64</architecture>\n<feature name=\"org.gnu.gdb.riscv.cpu\">\n";
+    for (i, name) in ABI_NAMES.iter().enumerate() {
+        let kind = match i {
+            1 => "code_ptr",
+            2 | 8 => "data_ptr",
+            _ => "int",
+        };
+        xml += &format!("  <reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>\n", name, kind, i);
+    }
+    xml += &format!("  <reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/>\n</feature>\n", GDB_PC);
+
+    xml += "<feature name=\"org.gnu.gdb.riscv.csr\">\n";
+    let mut csrs: Vec<&CsrInfo> = csr_address::CSR_INFO.iter().collect();
+    csrs.sort_by_key(|info| info.address);
+    for info in csrs {
+        xml += &format!("  <reg name=\"{}\" bitsize=\"64\" regnum=\"{}\" group=\"csr\"/>\n", info.name.to_lowercase(), GDB_CSR_BASE + info.address as usize);
+    }
+    xml += "</feature>\n<feature name=\"org.gnu.gdb.riscv.virtual\">\n";
+    xml += &format!("  <reg name=\"priv\" bitsize=\"8\" regnum=\"{}\" group=\"general\"/>\n</feature>\n</target>\n", GDB_PRIV);
+    return xml;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csr_number(address: u32) -> usize {
+        return GDB_CSR_BASE + address as usize;
+    }
+
+    #[test]
+    fn csr_registers_read_and_write_like_csr_instructions() {
+        let mut sim = new_sim(1, 0, 0x1000);
+        sim.states[0].csr.set(csr_address::MSTATUS, MSTATUS_SIE);
+        assert_eq!(read_register(&sim, 0, csr_number(csr_address::SSTATUS)), Some(to_hex(&MSTATUS_SIE.to_le_bytes())));
+
+        // sstatus is a view of mstatus
+        assert!(write_register(&mut sim, 0, csr_number(csr_address::SSTATUS), &0u64.to_le_bytes()));
+        assert_eq!(sim.states[0].csr[csr_address::MSTATUS] & MSTATUS_SIE, 0);
+        // MPP is WARL, the reserved mode 2 reads as U
+        assert!(write_register(&mut sim, 0, csr_number(csr_address::MSTATUS), &(0b10u64 << 11).to_le_bytes()));
+        assert_eq!(sim.states[0].csr[csr_address::MSTATUS] & (0b11 << 11), 0);
+        assert_eq!(read_register(&sim, 0, csr_number(0x7ff)), None);
+    }
+}
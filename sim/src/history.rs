@@ -0,0 +1,483 @@
+use std::collections::VecDeque;
+
+use crate::sim::*;
+use crate::debug::*;
+use crate::clint::*;
+use crate::plic::*;
+use crate::uart::*;
+use crate::sched::*;
+
+/*
+ * Time-travel debugging: an undo log of the last `capacity` steps and periodic checkpoints, enabled with --history
+ * or the "history" action.
+ *
+ * Every step records what it changed: the registers and CSRs that changed value, pc, privilege mode,
+ * reservation and WFI state of every HART, the state of the scheduler, and the old contents of every byte of RAM it wrote,
+ * including what the host wrote on behalf of the guest (syscalls, semihosting). RAM a debugger writes between two steps
+ * is undone with the step before it. mtime is recorded for every step,
+ * the CLINT, PLIC and UART as they were before the step when it changed them: MMIO writes, reads with side effects
+ * (the UART receiver, PLIC claims), SBI timer calls. Undoing a step puts all of it back, device state is exact.
+ * Host side effects (console output, host files) are not undone, neither are the A and D bits set by page table walks.
+ *
+ * Every `capacity` steps a checkpoint keeps the whole machine: HARTs, RAM, the CLINT, PLIC and UART and the scheduler,
+ * the last MAX_CHECKPOINTS of them. Going back restores the nearest checkpoint at or before the target and runs forward
+ * to it when that takes fewer steps than undoing, or when the target is older than the undo log. The console output of
+ * the steps run again is dropped. With semihosting or Linux syscalls, whose host files would be written again, only
+ * the undo log is used. A debugger that changes registers or memory between steps drops the checkpoints, running forward
+ * would not make its change again.
+ *
+ *      reverse_steps       step back N steps
+ *      reverse_continue    step back until a breakpoint, the write that hit a watchpoint, or the oldest recorded step
+ *      last_write          the most recent recorded step that wrote to an address range
+ */
+
+// HART state before a step, the registers and CSRs only where the step changed them
+#[derive(Debug)]
+struct HartRecord {
+    pc:              u64,
+    priviledge_mode: u8,
+    reservation:     Option<u64>,
+    waiting:         bool,
+    irq_lines:       u64,
+    regs:            Vec<(usize, u64)>,
+    csrs:            Vec<(u32, u64)>,
+}
+
+#[derive(Debug)]
+struct MemoryWrite {
+    hart: usize,
+    va:   u64,
+    pa:   u64,
+    old:  Vec<u8>,
+}
+
+// the devices a step can change, mtime aside
+#[derive(Debug, Clone)]
+struct DeviceRecord {
+    clint: Clint,
+    plic:  Plic,
+    uart:  Uart,
+}
+
+#[derive(Debug)]
+struct StepRecord {
+    step:      u64, // number of steps executed before this one
+    harts:     Vec<HartRecord>,
+    memory:    Vec<MemoryWrite>,
+    scheduler: Scheduler, // going back and forth again picks the same HARTs
+    mtime:     u64,
+    devices:   Option<DeviceRecord>, // before the step, when the step changed them
+}
+
+// the full HART and device state before a step, compared with the state after it
+pub struct Snapshot {
+    harts:     Vec<(Vec<u64>, CsrFile)>,
+    scheduler: Scheduler,
+    devices:   DeviceRecord,
+}
+
+// the machine before step `step`
+#[derive(Debug)]
+struct Checkpoint {
+    step:      u64,
+    states:    Vec<CpuState>,
+    mem:       Vec<u8>,
+    devices:   DeviceRecord, // mtime included
+    scheduler: Scheduler,
+}
+
+const MAX_CHECKPOINTS: usize = 4; // each one is a copy of RAM
+
+#[derive(Debug)]
+pub struct History {
+    pub capacity: usize,
+    pub step:     u64, // steps executed since recording started
+    records:      VecDeque<StepRecord>,
+    pending:      Vec<MemoryWrite>, // writes of the step that is running
+    checkpoints:  VecDeque<Checkpoint>, // oldest first, one every `capacity` steps
+}
+
+pub fn new_history(capacity: usize) -> History {
+    return History {
+        capacity:    capacity,
+        step:        0,
+        records:     VecDeque::new(),
+        pending:     vec![],
+        checkpoints: VecDeque::new(),
+    };
+}
+
+fn device_record(sim: &Simulator) -> DeviceRecord {
+    return DeviceRecord {
+        clint: sim.devices.clint.clone(),
+        plic:  sim.devices.plic.clone(),
+        uart:  sim.devices.uart.clone(),
+    };
+}
+
+// called before a step while history is on
+pub fn history_snapshot(sim: &mut Simulator) -> Snapshot {
+    let history = sim.history.as_mut().unwrap();
+    // writes between two steps, a debugger's, are undone with the step before them
+    let between = std::mem::take(&mut history.pending);
+    if !between.is_empty() {
+        history.checkpoints.clear();
+    }
+    if let Some(record) = history.records.back_mut() {
+        record.memory.extend(between);
+    }
+    let due = history.step.is_multiple_of(history.capacity.max(1) as u64) && history.checkpoints.back().is_none_or(|c| c.step != history.step);
+    if due {
+        let checkpoint = Checkpoint {
+            step:      history.step,
+            states:    sim.states.clone(),
+            mem:       sim.mem.clone(),
+            devices:   device_record(sim),
+            scheduler: sim.scheduler.clone(),
+        };
+        let history = sim.history.as_mut().unwrap();
+        history.checkpoints.push_back(checkpoint);
+        if history.checkpoints.len() > MAX_CHECKPOINTS {
+            history.checkpoints.pop_front();
+        }
+    }
+    let harts = sim.states.iter().map(|s| (s.regs.clone(), s.csr.clone())).collect();
+    return Snapshot { harts: harts, scheduler: sim.scheduler.clone(), devices: device_record(sim) };
+}
+
+// called after the step with the snapshot taken before it
+pub fn history_record(sim: &mut Simulator, snapshot: Snapshot, scalars: Vec<(u64, u8, Option<u64>, bool, u64)>) {
+    let mut harts = Vec::with_capacity(sim.states.len());
+    for ((state, (regs, csr)), (pc, priviledge_mode, reservation, waiting, irq_lines)) in sim.states.iter().zip(snapshot.harts).zip(scalars) {
+        let changed_regs = regs.iter().enumerate()
+            .filter(|(i, value)| state.regs[*i] != **value)
+            .map(|(i, value)| (i, *value))
+            .collect();
+        let changed_csrs = state.csr.iter().zip(csr.iter())
+            .filter(|((_, now), (_, before))| now != before)
+            .map(|(_, before)| before)
+            .collect();
+        harts.push(HartRecord {
+            pc:              pc,
+            priviledge_mode: priviledge_mode,
+            reservation:     reservation,
+            waiting:         waiting,
+            irq_lines:       irq_lines,
+            regs:            changed_regs,
+            csrs:            changed_csrs,
+        });
+    }
+    // mtime advances every step, the rest of the devices only changes now and then
+    let mut devices = snapshot.devices;
+    let mtime = devices.clint.mtime;
+    devices.clint.mtime = sim.devices.clint.mtime;
+    let unchanged = devices.clint == sim.devices.clint && devices.plic == sim.devices.plic && devices.uart == sim.devices.uart;
+
+    let history = sim.history.as_mut().unwrap();
+    let memory = std::mem::take(&mut history.pending);
+    history.records.push_back(StepRecord {
+        step:      history.step,
+        harts:     harts,
+        memory:    memory,
+        scheduler: snapshot.scheduler,
+        mtime:     mtime,
+        devices:   if unchanged { None } else { Some(devices) },
+    });
+    history.step += 1;
+    while history.records.len() > history.capacity {
+        history.records.pop_front();
+    }
+}
+
+// the scalar HART fields a step can change, taken before the step
+pub fn history_scalars(sim: &Simulator) -> Vec<(u64, u8, Option<u64>, bool, u64)> {
+    return sim.states.iter().map(|s| (s.pc, s.priviledge_mode, s.reservation, s.waiting, s.irq_lines)).collect();
+}
+
+// keeps the old contents of RAM the running step is about to write
+pub fn history_memory_write(sim: &mut Simulator, hart: usize, va: u64, pa: u64, length: u64) {
+    let range = match ram_range(sim, pa, length) {
+        Some(range) if sim.history.is_some() => range,
+        _ => return,
+    };
+    let old = sim.mem[range].to_vec();
+    sim.history.as_mut().unwrap().pending.push(MemoryWrite { hart: hart, va: va, pa: pa, old: old });
+}
+
+// the debugger changed registers between steps, running forward from a checkpoint would not make that change again
+pub fn history_edited(sim: &mut Simulator) {
+    if let Some(history) = sim.history.as_mut() {
+        history.checkpoints.clear();
+    }
+}
+
+// undoes the last recorded step, the record is returned so that callers can look at what it wrote
+fn undo_step(sim: &mut Simulator) -> Option<StepRecord> {
+    let record = sim.history.as_mut()?.records.pop_back()?;
+    for (state, hart) in sim.states.iter_mut().zip(record.harts.iter()) {
+        state.pc              = hart.pc;
+        state.priviledge_mode = hart.priviledge_mode;
+        state.reservation     = hart.reservation;
+        state.waiting         = hart.waiting;
+        state.irq_lines       = hart.irq_lines;
+        for (i, value) in hart.regs.iter() {
+            state.regs[*i] = *value;
+        }
+        for (address, value) in hart.csrs.iter() {
+            state.csr.set(*address, *value);
+        }
+    }
+    // newest write first, so that a byte written twice ends up with its oldest value
+    for write in record.memory.iter().rev() {
+        code_written(sim, write.pa, write.old.len() as u64);
+        if let Some(range) = ram_range(sim, write.pa, write.old.len() as u64) {
+            sim.mem[range].copy_from_slice(&write.old);
+        }
+    }
+    if let Some(devices) = record.devices.as_ref() {
+        sim.devices.clint = devices.clint.clone();
+        sim.devices.plic  = devices.plic.clone();
+        sim.devices.uart  = devices.uart.clone();
+    }
+    sim.devices.clint.mtime = record.mtime;
+    sim.scheduler = record.scheduler.clone();
+    sim.history.as_mut().unwrap().step = record.step;
+    return Some(record);
+}
+
+// the guest exit after going back in time
+fn finish_reverse(sim: &mut Simulator, undone: u64) {
+    if undone == 0 {
+        return;
+    }
+    // the machine stops when the guest exits, so the exit is always in the undone part
+    clear_guest_exit(sim);
+    sim.debugger.watch_hit = None;
+    sim.log = format!("went back {} steps", undone);
+}
+
+fn reverse_event(sim: &Simulator, reason: &str, steps: u64, hart: usize, address: Option<u64>, detail: String) -> StopEvent {
+    return StopEvent {
+        reason:  String::from(reason),
+        steps:   steps,
+        hart:    hart,
+        pc:      sim.states[hart].pc,
+        address: address,
+        detail:  detail,
+        trap:    None,
+    };
+}
+
+// running the steps again would repeat what they did to host files
+fn can_replay(sim: &Simulator) -> bool {
+    return sim.devices.semihosting.is_none() && sim.devices.syscalls.is_none();
+}
+
+// puts the machine back to checkpoint `index` and runs forward to step target, console output made again is dropped
+fn replay(sim: &mut Simulator, index: usize, target: u64) {
+    let history = sim.history.as_mut().unwrap();
+    let start = history.checkpoints[index].step;
+    // the records and checkpoints after it are made again on the way
+    history.records.retain(|r| r.step < start);
+    history.checkpoints.truncate(index + 1);
+    history.pending.clear();
+    history.step = start;
+    let checkpoint = &history.checkpoints[index];
+    sim.states = checkpoint.states.clone();
+    sim.mem.copy_from_slice(&checkpoint.mem);
+    sim.devices.clint = checkpoint.devices.clint.clone();
+    sim.devices.plic  = checkpoint.devices.plic.clone();
+    sim.devices.uart  = checkpoint.devices.uart.clone();
+    sim.scheduler = checkpoint.scheduler.clone();
+    code_written(sim, sim.mem_base, sim.mem.len() as u64);
+
+    let console = sim.uart_out.len();
+    for _ in start..target {
+        step(sim);
+    }
+    sim.uart_out.truncate(console);
+}
+
+// goes back up to steps steps, "begin" when the oldest recorded step or checkpoint was reached first
+pub fn reverse_steps(sim: &mut Simulator, steps: u64) -> StopEvent {
+    let history = sim.history.as_ref().unwrap();
+    let now = history.step;
+    let oldest_record = history.records.front().map_or(now, |r| r.step);
+    // the newest checkpoint at or before the target that is worth running forward from
+    let target = now.saturating_sub(steps);
+    // the newest checkpoint at or before the target, the oldest one when the target is older than all of them
+    let index = history.checkpoints.iter().rposition(|c| c.step <= target).or(if history.checkpoints.is_empty() { None } else { Some(0) });
+    // it is used when it gets further back than the undo log can, or when running forward is shorter than undoing
+    let checkpoint = index.map(|i| (i, history.checkpoints[i].step)).filter(|(i, step)| {
+        let reached = target.max(*step);
+        history.checkpoints[*i].mem.len() == sim.mem.len()
+            && (reached < oldest_record || (reached == target && target - step < now - target))
+    });
+    let undone = match checkpoint {
+        Some((index, step)) if can_replay(sim) => {
+            let target = target.max(step);
+            replay(sim, index, target);
+            now - target
+        },
+        _ => {
+            let mut undone = 0;
+            while undone < steps && undo_step(sim).is_some() {
+                undone += 1;
+            }
+            undone
+        },
+    };
+    finish_reverse(sim, undone);
+    let reason = if undone < steps { "begin" } else { "steps" };
+    return reverse_event(sim, reason, undone, 0, None, String::new());
+}
+
+/*
+ * Goes back until a HART is at a breakpoint, or until the step that wrote to a write or access watchpoint has been undone,
+ * the HART is then at the instruction that made the write. Read watchpoints can not be seen going backwards.
+ */
+pub fn reverse_continue(sim: &mut Simulator, max_steps: u64) -> StopEvent {
+    let mut undone = 0;
+    let mut event = None;
+    while undone < max_steps {
+        let record = match undo_step(sim) {
+            Some(record) => record,
+            None => break,
+        };
+        undone += 1;
+        let watched = record.memory.iter().find_map(|write| {
+            let end = write.va.wrapping_add(write.old.len() as u64);
+            sim.debugger.watchpoints.iter()
+                .find(|w| w.kind != WatchKind::Read && write.va < w.end && w.start < end)
+                .map(|_| write)
+        });
+        if let Some(write) = watched {
+            let detail = format!("write of 0x{:X}", write.va);
+            event = Some(reverse_event(sim, "watchpoint", undone, write.hart, Some(write.va), detail));
+            break;
+        }
+        if let Some(hart) = breakpoint_hart(sim) {
+            event = Some(reverse_event(sim, "breakpoint", undone, hart, None, String::new()));
+            break;
+        }
+    }
+    finish_reverse(sim, undone);
+    return match event {
+        Some(mut event) => {
+            event.pc = sim.states[event.hart].pc;
+            event
+        },
+        None if undone < max_steps => reverse_event(sim, "begin", undone, 0, None, String::new()),
+        None => reverse_event(sim, "steps", undone, 0, None, String::new()),
+    };
+}
+
+// most recent recorded write to [address, address + length): (steps ago, hart, pc of the instruction, address written)
+pub fn last_write(sim: &Simulator, address: u64, length: u64) -> Option<(u64, usize, u64, u64)> {
+    let history = sim.history.as_ref()?;
+    let end = address.wrapping_add(length.max(1));
+    for record in history.records.iter().rev() {
+        for write in record.memory.iter().rev() {
+            if write.va < end && address < write.va.wrapping_add(write.old.len() as u64) {
+                let ago = history.step - record.step;
+                return Some((ago, write.hart, record.harts[write.hart].pc, write.va));
+            }
+        }
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::tests::*;
+
+    // a store, a CSR write and a drop to S mode, then a loop
+    const PROGRAM: &str = "
+        li t0, 0x8000
+        li t1, 0x1234
+        sd t1, 0(t0)
+        csrw mscratch, t1
+        li t2, 0x800
+        csrs mstatus, t2
+        la t3, 1f
+        csrw mepc, t3
+        mret
+    1:  addi t1, t1, 1
+        j 1b
+    ";
+
+    fn stepped(steps: usize, history: usize) -> Simulator {
+        let mut sim = assembled_sim(PROGRAM, 1);
+        if history > 0 {
+            sim.history = Some(new_history(history));
+        }
+        for _ in 0..steps {
+            step(&mut sim);
+        }
+        return sim;
+    }
+
+    #[test]
+    fn undoing_steps_restores_memory_csrs_and_privilege() {
+        let mut sim = stepped(20, 100);
+        assert_eq!(sim.states[0].priviledge_mode, PRIV_S);
+        // back to before the store, undone one record at a time
+        let event = reverse_steps(&mut sim, 18);
+        assert_eq!(event.reason, "steps");
+        assert_eq!(sim.history.as_ref().unwrap().step, 2);
+        assert_eq!(sim.states[0].priviledge_mode, PRIV_M);
+        assert_eq!(&sim.mem[0x8000..0x8008], &[0; 8]);
+        assert_eq!(sim.states[0].csr.get(csr_address::MSCRATCH), Some(0));
+        assert_same_machine(&sim, &stepped(2, 0));
+        // and forward again to the same machine
+        for _ in 0..18 {
+            step(&mut sim);
+        }
+        assert_same_machine(&sim, &stepped(20, 0));
+    }
+
+    #[test]
+    fn going_back_runs_forward_from_a_checkpoint() {
+        // checkpoints at steps 16, 20, 24 and 28, the undo log only has the last 4 steps
+        let mut sim = stepped(30, 4);
+        assert_eq!(reverse_steps(&mut sim, 12).reason, "steps");
+        assert_eq!(sim.history.as_ref().unwrap().step, 18);
+        assert_same_machine(&sim, &stepped(18, 0));
+        // further back than the oldest checkpoint stops there
+        let event = reverse_steps(&mut sim, 100);
+        assert_eq!(event.reason, "begin");
+        assert_eq!(event.steps, 2);
+        assert_same_machine(&sim, &stepped(16, 0));
+        // a debugger write between steps drops the checkpoints
+        assert!(debug_write_memory(&mut sim, 0, 0x8000, &[1]));
+        step(&mut sim);
+        assert_eq!(reverse_steps(&mut sim, 1).steps, 1);
+        assert_eq!(reverse_steps(&mut sim, 1).reason, "begin");
+        assert_eq!(sim.mem[0x8000], 1);
+    }
+
+    #[test]
+    fn undoing_a_code_write_runs_the_old_instruction_again() {
+        // the first pass overwrites the addi with a jump to itself, the loop then spins there
+        let mut sim = assembled_sim("
+            la t0, 2f
+            lw t1, 0(t0)
+            la t0, 1f
+        1:  addi a1, a1, 1
+            sw t1, 0(t0)
+            j 1b
+        2:  j 2b
+        ", 1);
+        sim.history = Some(new_history(100));
+        for _ in 0..20 {
+            step(&mut sim);
+        }
+        assert_eq!(sim.states[0].regs[11], 1);
+        // back to before the addi, the jump that replaced it was decoded and must not run
+        reverse_steps(&mut sim, 15);
+        assert_eq!(sim.states[0].regs[11], 0);
+        step(&mut sim);
+        assert_eq!(sim.states[0].regs[11], 1);
+    }
+}
@@ -0,0 +1,132 @@
+
+use serde::{Serialize, Deserialize};
+
+/*
+ * Host Target InterFace, the tohost/fromhost mailbox used by riscv-tests and the proxy kernel.
+ *
+ * tohost and fromhost are two 64 bit words in guest memory. A command written to tohost is
+ *      [63:56] device, [55:48] command, [47:0] payload
+ * the host handles it, clears tohost and, if the command has a response, writes it to fromhost.
+ *
+ *  device 0 (syscall proxy), command 0
+ *      payload & 1 == 1: exit with code payload >> 1 (riscv-tests: the failing test number)
+ *      payload & 1 == 0: pointer to magic_mem = [syscall number, a0, a1, a2, ...],
+ *                        the return value is written to magic_mem[0], fromhost is set to 1
+ *  device 1 (console)
+ *      command 0: getchar, the character is returned in fromhost
+ *      command 1: putchar, payload & 0xff
+ *
+ * https://github.com/riscv-software-src/riscv-isa-sim/blob/master/fesvr/htif.cc
+ */
+
+const SYS_READ:  u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT:  u64 = 93;
+
+const ENOSYS: i64 = 38;
+const EBADF:  i64 = 9;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Htif {
+    pub tohost:    u64,
+    pub fromhost:  Option<u64>,
+    pub exit_code: Option<u64>,
+}
+
+pub fn new_htif(tohost: u64, fromhost: Option<u64>) -> Htif {
+    return Htif {
+        tohost:    tohost,
+        fromhost:  fromhost,
+        exit_code: None,
+    };
+}
+
+// guest physical addresses, RAM starts at mem_base
+fn mem_read_u64(mem: &[u8], mem_base: u64, address: u64) -> Option<u64> {
+    let a = address.wrapping_sub(mem_base) as usize;
+    return mem.get(a..a.saturating_add(8)).map(|b| u64::from_le_bytes(b.try_into().unwrap()));
+}
+
+fn mem_write_u64(mem: &mut [u8], mem_base: u64, address: u64, value: u64) {
+    let a = address.wrapping_sub(mem_base) as usize;
+    if let Some(b) = mem.get_mut(a..a.saturating_add(8)) {
+        b.copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+impl Htif {
+    // true when a store to [address, address+size) touches tohost
+    pub fn touches_tohost(&self, address: u64, size: u64) -> bool {
+        return address < self.tohost + 8 && self.tohost < address + size;
+    }
+
+    fn respond(&self, mem: &mut [u8], mem_base: u64, device: u64, command: u64, payload: u64) {
+        match self.fromhost {
+            Some(fromhost) => mem_write_u64(mem, mem_base, fromhost, device << 56 | command << 48 | (payload & 0xffffffffffff)),
+            None => println!("WARN: HTIF response without a fromhost address"),
+        }
+    }
+
+    // handles the command in tohost, if any
+    pub fn poll(&mut self, mem: &mut [u8], mem_base: u64, console_out: &mut Vec<u8>) {
+        let tohost = match mem_read_u64(mem, mem_base, self.tohost) {
+            Some(v) if v != 0 => v,
+            _ => return,
+        };
+        mem_write_u64(mem, mem_base, self.tohost, 0);
+
+        let device  = tohost >> 56;
+        let command = (tohost >> 48) & 0xff;
+        let payload = tohost & 0xffffffffffff;
+        match (device, command) {
+            (0, 0) if payload & 1 == 1 => {
+                self.exit_code = Some(payload >> 1);
+            },
+            (0, 0) => {
+                let ret = self.syscall(mem, mem_base, payload, console_out);
+                mem_write_u64(mem, mem_base, payload, ret as u64);
+                self.respond(mem, mem_base, 0, 0, 1);
+            },
+            (1, 0) => {
+                // no input is connected, report end of file
+                self.respond(mem, mem_base, 1, 0, u64::MAX);
+            },
+            (1, 1) => {
+                console_out.push(payload as u8);
+            },
+            _ => println!("WARN: unknown HTIF command, device: {}, command: {}, payload: 0x{:X}", device, command, payload),
+        }
+    }
+
+    fn syscall(&mut self, mem: &mut [u8], mem_base: u64, magic_mem: u64, console_out: &mut Vec<u8>) -> i64 {
+        let arg = |i: u64| mem_read_u64(mem, mem_base, magic_mem + 8 * i).unwrap_or(0);
+        let (number, a0, a1, a2) = (arg(0), arg(1), arg(2), arg(3));
+        match number {
+            SYS_WRITE => {
+                if a0 != 1 && a0 != 2 {
+                    return -EBADF;
+                }
+                let start = a1.wrapping_sub(mem_base) as usize;
+                match mem.get(start..start.saturating_add(a2 as usize)) {
+                    Some(buf) => {
+                        console_out.extend_from_slice(buf);
+                        return a2 as i64;
+                    },
+                    None => return -EBADF,
+                }
+            },
+            SYS_READ => {
+                // stdin is not connected
+                return 0;
+            },
+            SYS_EXIT => {
+                self.exit_code = Some(a0);
+                return 0;
+            },
+            _ => {
+                println!("WARN: unsupported HTIF syscall {}", number);
+                return -ENOSYS;
+            },
+        }
+    }
+}
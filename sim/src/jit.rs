@@ -0,0 +1,788 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::sim::*;
+use crate::decode::*;
+
+/*
+ * Dynamic binary translation of guest code to x86-64, built with --features jit.
+ *
+ * batch_step runs a block of translated code instead of a single step when it can. A block is straight-line guest code
+ * within one page, up to and including a branch or jump. Translated code works on the registers in CpuState and only
+ * ever does what a run of steps of the interpreter would do, the Simulator stays what the debugger and checkpoints see:
+ *
+ *  - the first time a block is seen it runs on the interpreter, it is translated once it has run HOT_THRESHOLD times
+ *  - blocks are keyed by pc and physical address, the fetch is translated and checked before a block is entered.
+ *    A block ends in a jump to a block of the same page once that block has been translated (block chaining), the
+ *    mapping of a page can only change through instructions that are not translated (SFENCE.VMA, CSRs, traps)
+ *  - loads and stores go to RAM through ram_load and ram_store. Anything else leaves the block before the instruction:
+ *    a fault, a device, an instruction that is not translated. The interpreter then runs it, exceptions stay precise
+ *  - interrupts are taken between blocks, a block is only started when its instructions all run before the timer fires
+ *  - a store to translated code drops all translations once the block that stored has ended. Code is tracked
+ *    in CODE_LINE bytes, data next to code does not drop anything
+ *
+ * Runs with more than one HART, the debugger, --history, --log-commits, --cosim or --trace stay on the interpreter.
+ *
+ * Intel 64 and IA-32 Architectures Software Developer's Manual, Volume 2, Instruction Set Reference
+ * https://www.intel.com/content/www/us/en/developer/articles/technical/intel-sdm.html
+ */
+
+#[cfg(not(all(target_arch = "x86_64", unix)))]
+compile_error!("the jit feature translates to x86-64 and needs a unix host for executable memory");
+
+const CODE_SIZE:       usize = 16 << 20;
+const MAX_BLOCK:       usize = 64;         // instructions per block
+const MAX_BLOCK_BYTES: usize = 64 * 128;   // room a block needs in the code buffer
+const MAX_BATCH:       u64   = 1024;       // instructions per batch_step, between two checks for interrupts
+const HOT_THRESHOLD:   u32   = 16;
+const CODE_LINE:       u64   = 64;         // granularity of the translated code stores are checked against
+
+// what the load and store helpers tell translated code
+const ACCESS_DONE:  u64 = 0;
+const ACCESS_LEAVE: u64 = 1; // the interpreter has to do the access, leave before the instruction
+const ACCESS_STOP:  u64 = 2; // done, but translations are dropped, leave after the instruction
+
+// x86-64 registers: rbx holds the context and r12 the guest registers while translated code runs
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R8:  u8 = 8;
+const R12: u8 = 12;
+
+// shared by translated code and the helpers it calls
+#[repr(C)]
+struct JitContext {
+    regs:       *mut u64,
+    executed:   u64,   // instructions retired so far
+    limit:      u64,   // a block is only entered when all of its instructions fit below this
+    exit_pc:    u64,
+    chain_site: u64,   // address of the jump to patch when the block at exit_pc gets translated, 0 when none
+    loaded:     u64,
+    sim:        *mut Simulator,
+    state:      *mut CpuState,
+}
+
+const CONTEXT_REGS:       i32 = std::mem::offset_of!(JitContext, regs) as i32;
+const CONTEXT_EXECUTED:   i32 = std::mem::offset_of!(JitContext, executed) as i32;
+const CONTEXT_LIMIT:      i32 = std::mem::offset_of!(JitContext, limit) as i32;
+const CONTEXT_EXIT_PC:    i32 = std::mem::offset_of!(JitContext, exit_pc) as i32;
+const CONTEXT_CHAIN_SITE: i32 = std::mem::offset_of!(JitContext, chain_site) as i32;
+const CONTEXT_LOADED:     i32 = std::mem::offset_of!(JitContext, loaded) as i32;
+
+#[derive(Debug, Clone, Copy)]
+enum Block {
+    Cold(u32),         // times it ran on the interpreter
+    Translated(usize), // offset of its code
+    Untranslated,      // its first instruction can not be translated
+}
+
+// a block that ended in a jump to a block of its page that was not translated yet
+#[derive(Debug)]
+struct Chain {
+    site:       usize,
+    pc:         u64,
+    page:       u64,
+    generation: u64,
+}
+
+#[derive(Debug)]
+pub struct Jit {
+    code:              *mut u8, // executable buffer, the enter and exit stubs followed by the blocks
+    used:              usize,
+    exit:              usize,   // offset of the exit stub
+    blocks:            HashMap<(u64, u64), Block>, // by pc and physical address
+    code_lines:        HashSet<u64>, // physical addresses / CODE_LINE of translated code
+    pending_chain:     Option<Chain>,
+    generation:        u64, // flushes so far
+    pub flush_pending: bool,
+    pub translated:    u64,
+}
+
+// the Jit owns its mapping, whichever thread holds the Jit (and the Simulator it is part of) is the only one using it
+unsafe impl Send for Jit {}
+
+extern "C" {
+    fn mmap(address: *mut u8, length: usize, protection: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn munmap(address: *mut u8, length: usize) -> i32;
+}
+
+const PROT_READ:     i32 = 1;
+const PROT_WRITE:    i32 = 2;
+const PROT_EXEC:     i32 = 4;
+const MAP_PRIVATE:   i32 = 0x02;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+pub fn new_jit() -> Result<Jit, String> {
+    let code = unsafe { mmap(std::ptr::null_mut(), CODE_SIZE, PROT_READ | PROT_WRITE | PROT_EXEC, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0) };
+    if code as isize == -1 {
+        return Err(String::from("can not map executable memory for the JIT"));
+    }
+    let mut jit = Jit {
+        code:          code,
+        used:          0,
+        exit:          0,
+        blocks:        HashMap::new(),
+        code_lines:    HashSet::new(),
+        pending_chain: None,
+        generation:    0,
+        flush_pending: false,
+        translated:    0,
+    };
+
+    // enter(context, block): saves the callee saved registers the blocks use and jumps to the block
+    let mut e = new_emitter(code as usize);
+    e.bytes(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]); // push rbx, r12, r13, r14, r15
+    e.reg(true, &[0x89], RDI, RBX);                                  // mov rbx, rdi
+    e.mem(true, &[0x8B], R12, RBX, CONTEXT_REGS);                    // mov r12, [rbx + regs]
+    e.reg(false, &[0xFF], 4, RSI);                                   // jmp rsi
+    jit.exit = e.code.len();
+    e.bytes(&[0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5B, 0xC3]); // pop r15, r14, r13, r12, rbx; ret
+    install(&mut jit, &e);
+    return Ok(jit);
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        unsafe { munmap(self.code, CODE_SIZE) };
+    }
+}
+
+// drops every translation, the stubs stay
+fn flush(jit: &mut Jit) {
+    jit.used = jit.exit + 10;
+    jit.blocks.clear();
+    jit.code_lines.clear();
+    jit.pending_chain = None;
+    jit.generation += 1;
+    jit.flush_pending = false;
+}
+
+// memory_written: a write to translated code drops the translations, as soon as no block runs
+pub fn jit_memory_written(jit: &mut Jit, pa: u64, length: u64) {
+    let last = pa.wrapping_add(length.max(1) - 1);
+    for line in (pa / CODE_LINE)..=(last / CODE_LINE) {
+        if jit.code_lines.contains(&line) {
+            jit.flush_pending = true;
+        }
+    }
+}
+
+// what translated code can not reproduce exactly, it needs the interpreter
+fn jit_usable(sim: &Simulator) -> bool {
+    return sim.states.len() == 1 && !sim.trace && sim.history.is_none() && sim.commit_log.is_none()
+        && sim.debugger.breakpoints.is_empty() && sim.debugger.watchpoints.is_empty();
+}
+
+/*
+ * One step of a batch run: a translated block, or a step() of the interpreter when the HART is about to take
+ * an interrupt, the block is not translated (yet) or it left before its first instruction.
+ */
+pub fn jit_step(sim: &mut Simulator) -> bool {
+    let budget = steps_before_timer(sim, 0).min(MAX_BATCH);
+    if !jit_usable(sim) || budget == 0 || !runs_uninterrupted(sim, 0) {
+        if let Some(jit) = sim.jit.as_mut() {
+            jit.pending_chain = None;
+        }
+        return step(sim);
+    }
+
+    let mut jit = sim.jit.take().unwrap();
+    let entry = with_hart(sim, 0, |sim, state| block_entry(&mut jit, sim, state));
+    sim.jit = Some(jit);
+    let (entry, page) = match entry {
+        Some(entry) => entry,
+        None => return step(sim),
+    };
+
+    let (executed, chain_site) = with_hart(sim, 0, |sim, state| run_block(sim, state, entry, budget));
+    retire_batch(sim, 0, executed);
+    if chain_site != 0 {
+        let jit = sim.jit.as_mut().unwrap();
+        jit.pending_chain = Some(Chain { site: chain_site as usize, pc: sim.states[0].pc, page: page, generation: jit.generation });
+    }
+    if executed == 0 {
+        return step(sim);
+    }
+    return true;
+}
+
+// code offset and physical page of the translated block at the pc, translates it when it got hot
+fn block_entry(jit: &mut Jit, sim: &mut Simulator, state: &CpuState) -> Option<(usize, u64)> {
+    if jit.flush_pending {
+        flush(jit);
+    }
+    let chain = jit.pending_chain.take();
+    let pc = state.pc;
+    let pa = fetch_address(sim, state, pc)?;
+
+    let block = jit.blocks.get(&(pc, pa)).copied().unwrap_or(Block::Cold(0));
+    let entry = match block {
+        Block::Translated(entry) => entry,
+        Block::Untranslated => return None,
+        Block::Cold(count) if count + 1 < HOT_THRESHOLD => {
+            jit.blocks.insert((pc, pa), Block::Cold(count + 1));
+            return None;
+        },
+        Block::Cold(_) => {
+            let translated = translate(jit, sim, state, pc, pa);
+            jit.blocks.insert((pc, pa), translated.map_or(Block::Untranslated, Block::Translated));
+            translated?
+        },
+    };
+
+    // the block that jumped here jumps straight to it from now on
+    if let Some(chain) = chain.filter(|c| c.pc == pc && c.page == pa >> 12 && c.generation == jit.generation) {
+        let rel = (jit.code as usize + entry) as i64 - (chain.site + 5) as i64;
+        unsafe { std::ptr::write_unaligned((chain.site + 1) as *mut i32, rel as i32) };
+    }
+    return Some((entry, pa >> 12));
+}
+
+// runs translated code from the block at entry, returns the instructions it retired and the chain site it left at
+fn run_block(sim: &mut Simulator, state: &mut CpuState, entry: usize, budget: u64) -> (u64, u64) {
+    let code = sim.jit.as_ref().unwrap().code;
+    let mut context = JitContext {
+        regs:       state.regs.as_mut_ptr(),
+        executed:   0,
+        limit:      budget,
+        exit_pc:    state.pc,
+        chain_site: 0,
+        loaded:     0,
+        sim:        sim,
+        state:      state,
+    };
+    // the enter stub is at the start of the buffer
+    let enter: extern "sysv64" fn(*mut JitContext, *const u8) = unsafe { std::mem::transmute(code) };
+    enter(&mut context, unsafe { code.add(entry) });
+    state.pc = context.exit_pc;
+    return (context.executed, context.chain_site);
+}
+
+extern "sysv64" fn jit_load(context: *mut JitContext, address: u64, size: u64) -> u64 {
+    let context = unsafe { &mut *context };
+    let (sim, state) = unsafe { (&mut *context.sim, &*context.state) };
+    match ram_load(sim, state, address, size) {
+        Some(value) => {
+            context.loaded = value;
+            return ACCESS_DONE;
+        },
+        None => return ACCESS_LEAVE,
+    }
+}
+
+extern "sysv64" fn jit_store(context: *mut JitContext, address: u64, value: u64, size: u64) -> u64 {
+    let context = unsafe { &mut *context };
+    let (sim, state) = unsafe { (&mut *context.sim, &*context.state) };
+    if !ram_store(sim, state, address, size, value) {
+        return ACCESS_LEAVE;
+    }
+    if sim.jit.as_ref().is_some_and(|jit| jit.flush_pending) {
+        return ACCESS_STOP;
+    }
+    return ACCESS_DONE;
+}
+
+// the operations that are not worth spelling out in x86-64, op is an index into ALU_OPS, bit 8 is set for the W versions
+const ALU_OPS: [AluOp; 18] = [
+    AluOp::Add, AluOp::Sub, AluOp::Sll, AluOp::Slt, AluOp::Sltu, AluOp::Xor, AluOp::Srl, AluOp::Sra, AluOp::Or, AluOp::And,
+    AluOp::Mul, AluOp::Mulh, AluOp::Mulhsu, AluOp::Mulhu, AluOp::Div, AluOp::Divu, AluOp::Rem, AluOp::Remu,
+];
+
+extern "sysv64" fn jit_alu(rs1: u64, rs2: u64, op: u64) -> u64 {
+    let alu_op = ALU_OPS[(op & 0xff) as usize];
+    if op & 0x100 != 0 {
+        return alu_word(alu_op, rs1 as u32, rs2 as u32);
+    }
+    return alu(alu_op, rs1, rs2);
+}
+
+// x86-64 machine code for the buffer at base
+struct Emitter {
+    code: Vec<u8>,
+    base: usize,
+}
+
+fn new_emitter(base: usize) -> Emitter {
+    return Emitter { code: Vec::with_capacity(MAX_BLOCK_BYTES), base: base };
+}
+
+impl Emitter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn address(&self) -> usize {
+        return self.base + self.code.len();
+    }
+
+    fn rex(&mut self, wide: bool, reg: u8, rm: u8) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3 & 1) << 2 | (rm >> 3 & 1);
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    // opcode reg, [base + disp32]
+    fn mem(&mut self, wide: bool, opcode: &[u8], reg: u8, base: u8, disp: i32) {
+        self.rex(wide, reg, base);
+        self.bytes(opcode);
+        self.code.push(0x80 | (reg & 7) << 3 | (base & 7));
+        if base & 7 == 4 {
+            self.code.push(0x24); // SIB: rsp and r12 as a base need one
+        }
+        self.u32(disp as u32);
+    }
+
+    // opcode reg, rm
+    fn reg(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: u8) {
+        self.rex(wide, reg, rm);
+        self.bytes(opcode);
+        self.code.push(0xC0 | (reg & 7) << 3 | (rm & 7));
+    }
+
+    fn mov_imm(&mut self, reg: u8, value: u64) {
+        if value as i64 == value as i32 as i64 {
+            self.reg(true, &[0xC7], 0, reg); // mov r64, sign extended imm32
+            self.u32(value as u32);
+        } else {
+            self.rex(true, 0, reg);
+            self.code.push(0xB8 + (reg & 7)); // mov r64, imm64
+            self.code.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn load_guest(&mut self, reg: u8, guest: u8) {
+        if guest == 0 {
+            self.reg(false, &[0x31], reg, reg); // xor r32, r32
+        } else {
+            self.mem(true, &[0x8B], reg, R12, 8 * guest as i32);
+        }
+    }
+
+    fn store_guest(&mut self, reg: u8, guest: u8) {
+        if guest != 0 {
+            self.mem(true, &[0x89], reg, R12, 8 * guest as i32);
+        }
+    }
+
+    fn call(&mut self, function: usize) {
+        self.mov_imm(RAX, function as u64);
+        self.reg(false, &[0xFF], 2, RAX); // call rax
+    }
+
+    // jcc rel32 to be patched, returns the offset of rel32
+    fn jcc(&mut self, condition: u8) -> usize {
+        self.bytes(&[0x0F, 0x80 | condition]);
+        self.u32(0);
+        return self.code.len() - 4;
+    }
+
+    fn patch(&mut self, rel: usize, target: usize) {
+        let value = target as i64 - (rel + 4) as i64;
+        self.code[rel .. rel + 4].copy_from_slice(&(value as i32).to_le_bytes());
+    }
+
+    fn jmp_absolute(&mut self, target: usize) {
+        self.code.push(0xE9);
+        let value = target as i64 - (self.address() + 4) as i64;
+        self.u32(value as i32 as u32);
+    }
+}
+
+const CC_B:  u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E:  u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_A:  u8 = 0x7;
+const CC_L:  u8 = 0xC;
+const CC_GE: u8 = 0xD;
+
+// a way out of a block: where the guest continues, how many of the instructions counted at the entry did not run
+struct SideExit {
+    rel:       usize,
+    pc:        u64,
+    remaining: u64,
+}
+
+/*
+ * Leaves the block for the pc. A chained exit starts with a jump that goes to the next instruction
+ * until the block at pc gets translated, then it is patched to go there directly.
+ */
+fn emit_exit(jit: &Jit, e: &mut Emitter, pc: u64, remaining: u64, chained: bool) {
+    let mut site = 0;
+    if chained {
+        site = e.address();
+        e.code.push(0xE9);
+        e.u32(0);
+    }
+    if remaining > 0 {
+        e.mem(true, &[0x81], 5, RBX, CONTEXT_EXECUTED); // sub qword [rbx + executed], imm32
+        e.u32(remaining as u32);
+    }
+    e.mov_imm(RAX, pc);
+    e.mem(true, &[0x89], RAX, RBX, CONTEXT_EXIT_PC);
+    e.mov_imm(RAX, site as u64);
+    e.mem(true, &[0x89], RAX, RBX, CONTEXT_CHAIN_SITE);
+    e.jmp_absolute(jit.code as usize + jit.exit);
+}
+
+// what a block can hold, and what ends it
+fn translatable(instruction: &Instruction) -> bool {
+    return matches!(instruction,
+        Instruction::Lui { .. } | Instruction::Auipc { .. } | Instruction::Jal { .. } | Instruction::Jalr { .. } |
+        Instruction::Branch { .. } | Instruction::Load { .. } | Instruction::Store { .. } | Instruction::OpImm { .. } |
+        Instruction::Op { .. } | Instruction::OpImm32 { .. } | Instruction::Op32 { .. } | Instruction::Fence { .. });
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    return matches!(instruction, Instruction::Jal { .. } | Instruction::Jalr { .. } | Instruction::Branch { .. });
+}
+
+// rax = rax op rcx
+fn emit_alu(e: &mut Emitter, op: AluOp) {
+    match op {
+        AluOp::Add  => e.reg(true, &[0x01], RCX, RAX),
+        AluOp::Sub  => e.reg(true, &[0x29], RCX, RAX),
+        AluOp::Xor  => e.reg(true, &[0x31], RCX, RAX),
+        AluOp::Or   => e.reg(true, &[0x09], RCX, RAX),
+        AluOp::And  => e.reg(true, &[0x21], RCX, RAX),
+        // x86-64 masks the shift amount in cl to 6 bits like RV64 does
+        AluOp::Sll  => e.reg(true, &[0xD3], 4, RAX),
+        AluOp::Srl  => e.reg(true, &[0xD3], 5, RAX),
+        AluOp::Sra  => e.reg(true, &[0xD3], 7, RAX),
+        AluOp::Slt | AluOp::Sltu => {
+            e.reg(true, &[0x39], RCX, RAX);                                      // cmp rax, rcx
+            e.bytes(&[0x0F, if op == AluOp::Slt { 0x9C } else { 0x92 }, 0xC0]); // setl al / setb al
+            e.bytes(&[0x0F, 0xB6, 0xC0]);                                        // movzx eax, al
+        },
+        AluOp::Mul  => e.reg(true, &[0x0F, 0xAF], RAX, RCX),
+        AluOp::Mulh | AluOp::Mulhu => {
+            e.reg(true, &[0xF7], if op == AluOp::Mulh { 5 } else { 4 }, RCX); // imul rcx / mul rcx: rdx:rax
+            e.reg(true, &[0x89], RDX, RAX);
+        },
+        AluOp::Mulhsu => {
+            // the unsigned high half, less rs2 when rs1 is negative
+            e.reg(true, &[0x89], RAX, R8);
+            e.reg(true, &[0xF7], 4, RCX);
+            e.reg(true, &[0xC1], 7, R8); // sar r8, 63
+            e.code.push(63);
+            e.reg(true, &[0x21], RCX, R8);
+            e.reg(true, &[0x29], R8, RDX);
+            e.reg(true, &[0x89], RDX, RAX);
+        },
+        AluOp::Div | AluOp::Divu | AluOp::Rem | AluOp::Remu => emit_alu_call(e, op, false),
+    }
+}
+
+// rax = sign extended 32 bit rax op rcx
+fn emit_alu_word(e: &mut Emitter, op: AluOp) {
+    match op {
+        AluOp::Add => e.reg(false, &[0x01], RCX, RAX),
+        AluOp::Sub => e.reg(false, &[0x29], RCX, RAX),
+        AluOp::Sll => e.reg(false, &[0xD3], 4, RAX),
+        AluOp::Srl => e.reg(false, &[0xD3], 5, RAX),
+        AluOp::Sra => e.reg(false, &[0xD3], 7, RAX),
+        AluOp::Mul => e.reg(false, &[0x0F, 0xAF], RAX, RCX),
+        _ => return emit_alu_call(e, op, true),
+    }
+    e.reg(true, &[0x63], RAX, RAX); // movsxd rax, eax
+}
+
+fn emit_alu_call(e: &mut Emitter, op: AluOp, word: bool) {
+    let index = ALU_OPS.iter().position(|o| *o == op).unwrap() as u64;
+    e.reg(true, &[0x89], RAX, RDI);
+    e.reg(true, &[0x89], RCX, RSI);
+    e.mov_imm(RDX, index | (word as u64) << 8);
+    e.call(jit_alu as *const () as usize);
+}
+
+// rsi = rs1 + offset
+fn emit_address(e: &mut Emitter, rs1: u8, offset: i64) {
+    e.load_guest(RSI, rs1);
+    e.mov_imm(RAX, offset as u64);
+    e.reg(true, &[0x01], RAX, RSI);
+}
+
+/*
+ * Translates the block at pc, None when its first instruction can not be translated. The block stays
+ * within the page of pc, it is entered only after that page was translated to pa.
+ */
+fn translate(jit: &mut Jit, sim: &mut Simulator, state: &CpuState, pc: u64, pa: u64) -> Option<usize> {
+    let page = pc & !0xfff;
+    let mut instructions = vec![];
+    let mut at = pc;
+    while instructions.len() < MAX_BLOCK && at & !0xfff == page {
+        let decoded = match fetch_decoded(sim, state, at) {
+            Ok(decoded) => decoded,
+            Err(_) => break,
+        };
+        if !translatable(&decoded.instruction) || (decoded.length == 4 && at & 0xfff == 0xffe) {
+            break;
+        }
+        instructions.push((at, decoded));
+        at = at.wrapping_add(decoded.length as u64);
+        if ends_block(&decoded.instruction) {
+            break;
+        }
+    }
+    if instructions.is_empty() {
+        return None;
+    }
+
+    if jit.used + MAX_BLOCK_BYTES > CODE_SIZE {
+        flush(jit);
+    }
+    let entry = jit.used;
+    let count = instructions.len() as u64;
+    let same_page = |target: u64| target & !0xfff == page;
+    let mut e = new_emitter(jit.code as usize + entry);
+    let mut exits: Vec<SideExit> = vec![];
+
+    // enter only when all of the block runs within the limit
+    e.mem(true, &[0x8B], RAX, RBX, CONTEXT_EXECUTED);
+    e.reg(true, &[0x81], 0, RAX); // add rax, imm32
+    e.u32(count as u32);
+    e.mem(true, &[0x3B], RAX, RBX, CONTEXT_LIMIT);
+    let rel = e.jcc(CC_A);
+    exits.push(SideExit { rel: rel, pc: pc, remaining: 0 });
+    e.mem(true, &[0x89], RAX, RBX, CONTEXT_EXECUTED);
+
+    let mut ended = false;
+    for (k, (ipc, decoded)) in instructions.iter().enumerate() {
+        let (ipc, k) = (*ipc, k as u64);
+        let next = ipc.wrapping_add(decoded.length as u64);
+        match decoded.instruction {
+            Instruction::Lui { rd, imm } => {
+                e.mov_imm(RAX, imm as u64);
+                e.store_guest(RAX, rd);
+            },
+            Instruction::Auipc { rd, imm } => {
+                e.mov_imm(RAX, ipc.wrapping_add(imm as u64));
+                e.store_guest(RAX, rd);
+            },
+            Instruction::OpImm { op, rd, rs1, imm } => {
+                e.load_guest(RAX, rs1);
+                e.mov_imm(RCX, imm as u64);
+                emit_alu(&mut e, op);
+                e.store_guest(RAX, rd);
+            },
+            Instruction::Op { op, rd, rs1, rs2 } => {
+                e.load_guest(RAX, rs1);
+                e.load_guest(RCX, rs2);
+                emit_alu(&mut e, op);
+                e.store_guest(RAX, rd);
+            },
+            Instruction::OpImm32 { op, rd, rs1, imm } => {
+                e.load_guest(RAX, rs1);
+                e.mov_imm(RCX, imm as u64);
+                emit_alu_word(&mut e, op);
+                e.store_guest(RAX, rd);
+            },
+            Instruction::Op32 { op, rd, rs1, rs2 } => {
+                e.load_guest(RAX, rs1);
+                e.load_guest(RCX, rs2);
+                emit_alu_word(&mut e, op);
+                e.store_guest(RAX, rd);
+            },
+            Instruction::Load { rd, rs1, offset, size, signed } => {
+                emit_address(&mut e, rs1, offset);
+                e.reg(true, &[0x89], RBX, RDI);
+                e.mov_imm(RDX, size as u64);
+                e.call(jit_load as *const () as usize);
+                e.reg(false, &[0x85], RAX, RAX); // test eax, eax
+                let rel = e.jcc(CC_NE);
+                exits.push(SideExit { rel: rel, pc: ipc, remaining: count - k });
+                e.mem(true, &[0x8B], RAX, RBX, CONTEXT_LOADED);
+                match (size, signed) {
+                    (1, true)  => e.bytes(&[0x48, 0x0F, 0xBE, 0xC0]), // movsx rax, al
+                    (2, true)  => e.bytes(&[0x48, 0x0F, 0xBF, 0xC0]), // movsx rax, ax
+                    (4, true)  => e.bytes(&[0x48, 0x63, 0xC0]),       // movsxd rax, eax
+                    (1, false) => e.bytes(&[0x0F, 0xB6, 0xC0]),       // movzx eax, al
+                    (2, false) => e.bytes(&[0x0F, 0xB7, 0xC0]),       // movzx eax, ax
+                    (4, false) => e.bytes(&[0x89, 0xC0]),             // mov eax, eax
+                    _          => {},
+                }
+                e.store_guest(RAX, rd);
+            },
+            Instruction::Store { rs1, rs2, offset, size } => {
+                emit_address(&mut e, rs1, offset);
+                e.load_guest(RDX, rs2);
+                e.mov_imm(RCX, size as u64);
+                e.reg(true, &[0x89], RBX, RDI);
+                e.call(jit_store as *const () as usize);
+                e.reg(false, &[0x83], 7, RAX); // cmp eax, ACCESS_LEAVE
+                e.code.push(ACCESS_LEAVE as u8);
+                let rel = e.jcc(CC_E);
+                exits.push(SideExit { rel: rel, pc: ipc, remaining: count - k });
+                e.reg(false, &[0x85], RAX, RAX); // ACCESS_STOP
+                let rel = e.jcc(CC_NE);
+                exits.push(SideExit { rel: rel, pc: next, remaining: count - k - 1 });
+            },
+            Instruction::Fence { .. } => {},
+            Instruction::Jal { rd, offset } => {
+                let target = ipc.wrapping_add(offset as u64);
+                e.mov_imm(RAX, next);
+                e.store_guest(RAX, rd);
+                emit_exit(jit, &mut e, target, 0, same_page(target));
+                ended = true;
+            },
+            Instruction::Jalr { rd, rs1, offset } => {
+                e.load_guest(RAX, rs1);
+                e.mov_imm(RCX, offset as u64);
+                e.reg(true, &[0x01], RCX, RAX);
+                e.reg(true, &[0x83], 4, RAX); // and rax, -2
+                e.code.push(0xFE);
+                e.mov_imm(RCX, next);
+                e.store_guest(RCX, rd);
+                e.mem(true, &[0x89], RAX, RBX, CONTEXT_EXIT_PC);
+                e.mem(true, &[0xC7], 0, RBX, CONTEXT_CHAIN_SITE); // mov qword [rbx + chain_site], 0
+                e.u32(0);
+                e.jmp_absolute(jit.code as usize + jit.exit);
+                ended = true;
+            },
+            Instruction::Branch { op, rs1, rs2, offset } => {
+                let target = ipc.wrapping_add(offset as u64);
+                e.load_guest(RAX, rs1);
+                e.load_guest(RCX, rs2);
+                e.reg(true, &[0x39], RCX, RAX); // cmp rax, rcx
+                let condition = match op {
+                    BranchOp::Eq  => CC_E,
+                    BranchOp::Ne  => CC_NE,
+                    BranchOp::Lt  => CC_L,
+                    BranchOp::Ge  => CC_GE,
+                    BranchOp::Ltu => CC_B,
+                    BranchOp::Geu => CC_AE,
+                };
+                let taken = e.jcc(condition);
+                emit_exit(jit, &mut e, next, 0, same_page(next));
+                let here = e.code.len();
+                e.patch(taken, here);
+                emit_exit(jit, &mut e, target, 0, same_page(target));
+                ended = true;
+            },
+            _ => unreachable!("not translatable"),
+        }
+    }
+    if !ended {
+        let next = at;
+        emit_exit(jit, &mut e, next, 0, same_page(next));
+    }
+    for exit in exits {
+        let here = e.code.len();
+        e.patch(exit.rel, here);
+        emit_exit(jit, &mut e, exit.pc, exit.remaining, false);
+    }
+
+    assert!(e.code.len() <= MAX_BLOCK_BYTES, "JIT block too large");
+    install(jit, &e);
+    let end = pa + (at - pc);
+    for line in (pa / CODE_LINE)..=((end - 1) / CODE_LINE) {
+        jit.code_lines.insert(line);
+    }
+    jit.translated += 1;
+    return Some(entry);
+}
+
+// copies emitted code to the buffer at the offset it was emitted for
+fn install(jit: &mut Jit, e: &Emitter) {
+    let offset = e.base - jit.code as usize;
+    unsafe { std::ptr::copy_nonoverlapping(e.code.as_ptr(), jit.code.add(offset), e.code.len()) };
+    jit.used = offset + e.code.len();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::tests::*;
+    use crate::test_finisher::*;
+
+    // the same program on the interpreter and translated, both have to end in the same machine
+    fn compare_with_interpreter(source: &str) -> u64 {
+        let source = format!("{}\n{}", source, PASS);
+        let mut reference = assembled_sim(&source, 1);
+        assert_eq!(run_to_exit(&mut reference), Some(FinisherStatus::Pass));
+        let mut sim = assembled_sim(&source, 1);
+        sim.jit = Some(new_jit().unwrap());
+        assert_eq!(run_to_exit(&mut sim), Some(FinisherStatus::Pass));
+        assert_same_machine(&sim, &reference);
+        return sim.jit.as_ref().unwrap().translated;
+    }
+
+    #[test]
+    fn alu_memory_and_branches_match_the_interpreter() {
+        let translated = compare_with_interpreter("
+            li s0, 2000
+            li a0, 0x12345678
+            la s1, buffer
+        1:  slli t0, a0, 13       # xorshift
+            xor a0, a0, t0
+            srli t0, a0, 7
+            xor a0, a0, t0
+            slli t0, a0, 17
+            xor a0, a0, t0
+            add a1, a1, a0
+            mulw t1, a0, a1
+            mulhu t2, a0, a1
+            divu t3, a1, s0
+            remw t4, a0, s0
+            sraiw t5, a0, 3
+            sltu a6, a0, a1
+            add a7, a7, t1
+            xor a7, a7, t2
+            sub a7, a7, t3
+            or a7, a7, t4
+            and s2, a7, t5
+            andi t6, a0, 0xff
+            slli t6, t6, 3
+            add t6, t6, s1
+            ld a2, 0(t6)
+            add a2, a2, a0
+            sd a2, 0(t6)
+            sb a0, 1(t6)
+            lh a3, 0(t6)
+            lwu a4, 4(t6)
+            add a5, a5, a3
+            add a5, a5, a4
+            bge a0, zero, 2f
+            addi s3, s3, 1
+        2:  addi s0, s0, -1
+            bnez s0, 1b
+            j end
+        .balign 8
+        buffer: .zero 2048
+        end:
+        ");
+        assert!(translated > 0, "nothing was translated");
+    }
+
+    #[test]
+    fn stores_to_translated_code_match_the_interpreter() {
+        let translated = compare_with_interpreter("
+            li s0, 100
+        1:  call patched
+            addi s0, s0, -1
+            li t0, 50
+            bne s0, t0, 2f
+            la t1, patched
+            la t2, replacement
+            lw t2, 0(t2)
+            sw t2, 0(t1)          # addi a6, a6, 1 becomes addi a6, a6, 2
+            fence.i
+        2:  bnez s0, 1b
+            j end
+        patched:
+            addi a6, a6, 1
+            ret
+        replacement:
+            addi a6, a6, 2
+        end:
+        ");
+        assert!(translated > 0, "nothing was translated");
+    }
+}
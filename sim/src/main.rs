@@ -1,34 +1,111 @@
-use std::{
-    io::{prelude::*, BufReader, ErrorKind},
-    net::{TcpListener, TcpStream},
-};
-use std::{panic, fs};
-use std::{thread, time};
-use std::collections::HashMap;
+use std::io::prelude::*;
+use std::fs;
+use std::thread;
 use std::env;
 use std::process::ExitCode;
-
-use serde_json;
+use std::sync::mpsc;
 
 mod sim;
+mod framebuffer;
+mod test_finisher;
+mod htif;
+mod elf;
+mod fdt;
+mod clint;
+mod plic;
+mod uart;
+mod compressed;
+mod sbi;
+mod syscall;
+mod semihosting;
+mod debug;
+mod gdb;
+mod history;
+mod commit_log;
+mod cosim;
+mod disasm;
+mod decode;
+mod asm;
+mod smp;
+mod sched;
+mod server;
+mod stream;
+mod websocket;
+#[cfg(feature = "jit")]
+mod jit;
 use crate::sim::*;
-
-/*
- * There are a couple types of packets, these are disambiguited with "action".
- *
- *  actions:
- *      i) "init":   Creates a new device returns a device key, initializes the device to a default state
- *      i) "load":   Loads from a default file
- *      i) "step":   Steps 1 clock cycle
- *
- * The requests are in a Json string
- *
- */
+use crate::framebuffer::*;
+use crate::test_finisher::*;
+use crate::htif::*;
+use crate::elf::*;
+use crate::fdt::*;
+use crate::sbi::*;
+use crate::syscall::*;
+use crate::semihosting::*;
+use crate::gdb::*;
+use crate::history::*;
+use crate::commit_log::*;
+use crate::disasm::*;
+use crate::asm::*;
+use crate::smp::*;
+use crate::sched::*;
+use crate::server::*;
+#[cfg(feature = "jit")]
+use crate::jit::*;
 
 fn cli_help() {
     println!("Usage:
     -H port  HTML server
-    -T path  Self Test
+    -T path  Self Test, path is a RISC-V ELF, an assembly source (.s/.S) or a raw binary loaded at address 0. The console (UART, HTIF, semihosting) goes to stdout.
+             Exits with the code the guest reports through the test finisher, or 125 when the simulator stops without the guest reporting a result. Guest codes that would be read as 0 or as
+             a simulator status (101 panic, 124 cosim divergence or timeout, 125, 126, 127) exit with 1 instead
+    -B path  Boot firmware (e.g. OpenSBI fw_jump, ELF or raw) in M-mode with RAM at 0x80000000, the UART is connected to stdin/stdout.
+             Exits like -T once the guest powers off
+    -B builtin
+             Boot the --kernel in S-mode on the built-in SBI instead of firmware
+    -U path [args...]
+             Run a static riscv64 Linux program in U-mode, its syscalls are carried out on the host like qemu-user.
+             Everything after path is passed to the program, exits with the code the program exits with
+    -D path  Disassemble the image (ELF or raw at address 0) like objdump -d, with the decoder the engine runs
+
+Options:
+    --fb WIDTHxHEIGHT[:format][@base]  add a simple-framebuffer device, default format x8r8g8b8
+    --fb-dump path                     write the framebuffer to path (.png or .ppm) when the self test ends
+    --fb-sixel                         print the framebuffer as sixel when the self test ends
+    --symbols path.elf                 ELF a raw image was made from, used to find tohost/fromhost
+    --tohost addr                      address of the HTIF tohost word, overrides --symbols
+    --fromhost addr                    address of the HTIF fromhost word, overrides --symbols
+    --dtb path.dtb                     pass this device tree to the guest instead of the generated one
+    --dump-dtb path.dtb                write the generated device tree to path, works without -T as well
+    --bootargs args                    kernel command line, /chosen/bootargs in the generated device tree
+    --kernel path                      kernel Image loaded at RAM base + kernel offset, where fw_jump jumps to
+    --kernel-offset offset             offset of the kernel from the RAM base, default 0x200000
+    --initrd path                      initramfs, placed in the upper half of RAM and described in /chosen
+    --mem size[K|M|G]                  RAM size, default 128M with -B, 256M with -U
+    --harts n                          number of HARTs, default 1
+    --newlib                           service ECALLs of a bare-metal newlib/picolibc program on the host with -T
+                                       (write, read, exit, brk, ...), exit(0) passes, any other code fails
+    --semihosting dir                  RISC-V semihosting (slli/ebreak/srai), files are opened below dir, the console is stdin/stdout
+    --cmdline args                     what SYS_GET_CMDLINE returns, default the image path
+    -g port                            with -T, -U or -B: wait for GDB on port (target remote :port) and run under its control
+    --history steps                    record the last steps steps so that GDB can run backwards (reverse-stepi, reverse-continue)
+                                       and keep a copy of the machine every steps steps, the last 4, to go back further
+    --log-commits path                 write a trace of every retired instruction to path, in the format of spike --log-commits
+    --log-start pc                     start the trace at the instruction at pc
+    --log-stop pc                      stop the trace after the instruction at pc
+    --cosim trace.log                  compare every retired instruction with a reference trace in the --log-commits format
+                                       (spike --log-commits, RTL), stop at the first difference and exit with 124
+    --trace                            print every step and keep its disassembly, slow; without it batch runs only report events
+    --no-jit                           run -T, -B and -U on the interpreter when built with --features jit
+    --parallel quantum                 with -T or -B and more than one HART: run every HART on a host thread, synchronised
+                                       every quantum steps (e.g. 100000). Faster on a multi-core host, not deterministic
+    --sched policy                     which HARTs a step runs: lockstep (default, all of them), round-robin[:quantum],
+                                       random or pct[:depth[:steps]] (priority scheduling with depth-1 random changes)
+    --seed n                           seed of the random and pct schedulers, default from the clock. The seed is printed,
+                                       the same seed runs the same interleaving again
+    --allow-origin origin              with -H: a web page origin (e.g. http://localhost:5173) that may use the server, can be
+                                       given more than once. Default the dev server of the GUI, other pages are refused
+    --image-dir dir                    with -H: the directory \"load image\" loads from, default the working directory
 ");
 }
 
@@ -36,110 +113,721 @@ enum SimMode {
     None,
     HtmlServer,
     SelfTest,
+    Boot,
+    User,
+    Disassemble,
+}
+
+struct CliOptions {
+    sim_mode:      SimMode,
+    mode_arg:      String,
+    framebuffer:   Option<String>,
+    fb_dump:       Option<String>,
+    fb_sixel:      bool,
+    symbols:       Option<String>,
+    tohost:        Option<u64>,
+    fromhost:      Option<u64>,
+    dtb:           Option<String>,
+    dump_dtb:      Option<String>,
+    bootargs:      String,
+    kernel:        Option<String>,
+    kernel_offset: u64,
+    initrd:        Option<String>,
+    mem_size:      Option<usize>,
+    harts:         usize,
+    guest_args:    Vec<String>,
+    newlib:        bool,
+    semihosting:   Option<String>,
+    cmdline:       Option<String>,
+    gdb_port:      Option<u16>,
+    history:       usize,
+    log_commits:   Option<String>,
+    log_start:     Option<u64>,
+    log_stop:      Option<u64>,
+    cosim:         Option<String>,
+    trace:         bool,
+    no_jit:        bool,
+    parallel:      Option<u64>,
+    sched:         Policy,
+    seed:          Option<u64>,
+    server:        ServerConfig,
+}
+
+fn parse_address(value: &str) -> Result<u64, String> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse::<u64>(),
+    };
+    return parsed.map_err(|_| format!("invalid address: {}", value));
+}
+
+// "64M", "1G", "65536K" or a plain number of bytes
+fn parse_size(value: &str) -> Result<usize, String> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&value[..i], 1 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&value[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    return match parse_address(number) {
+        Ok(n) if n > 0 => Ok(n as usize * unit),
+        _ => Err(format!("invalid size: {}", value)),
+    };
+}
+
+fn parse_args(args: &[String]) -> Result<CliOptions, String> {
+    let mut options = CliOptions {
+        sim_mode:      SimMode::None,
+        mode_arg:      String::from(""),
+        framebuffer:   None,
+        fb_dump:       None,
+        fb_sixel:      false,
+        symbols:       None,
+        tohost:        None,
+        fromhost:      None,
+        dtb:           None,
+        dump_dtb:      None,
+        bootargs:      String::from(""),
+        kernel:        None,
+        kernel_offset: DEFAULT_KERNEL_OFFSET,
+        initrd:        None,
+        mem_size:      None,
+        harts:         1,
+        guest_args:    vec![],
+        newlib:        false,
+        semihosting:   None,
+        cmdline:       None,
+        gdb_port:      None,
+        history:       0,
+        log_commits:   None,
+        log_start:     None,
+        log_stop:      None,
+        cosim:         None,
+        trace:         false,
+        no_jit:        false,
+        parallel:      None,
+        sched:         Policy::Lockstep,
+        seed:          None,
+        server:        default_server_config(),
+    };
+    let mut origins = vec![];
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || match args.next() {
+            Some(v) => Ok(v.clone()),
+            None => Err(format!("missing value for {}", arg)),
+        };
+        match arg.as_str() {
+            "-H" => {options.sim_mode = SimMode::HtmlServer; options.mode_arg = value()?;},
+            "-T" => {options.sim_mode = SimMode::SelfTest;   options.mode_arg = value()?;},
+            "-B" => {options.sim_mode = SimMode::Boot;       options.mode_arg = value()?;},
+            "-D" => {options.sim_mode = SimMode::Disassemble; options.mode_arg = value()?;},
+            "-U" => {
+                options.sim_mode = SimMode::User;
+                options.mode_arg = value()?;
+                // the rest of the command line belongs to the guest
+                options.guest_args = args.by_ref().cloned().collect();
+            },
+            "--fb"       => options.framebuffer = Some(value()?),
+            "--fb-dump"  => options.fb_dump     = Some(value()?),
+            "--fb-sixel" => options.fb_sixel    = true,
+            "--symbols"  => options.symbols     = Some(value()?),
+            "--tohost"   => options.tohost      = Some(parse_address(&value()?)?),
+            "--fromhost" => options.fromhost    = Some(parse_address(&value()?)?),
+            "--dtb"      => options.dtb         = Some(value()?),
+            "--dump-dtb" => options.dump_dtb    = Some(value()?),
+            "--bootargs" => options.bootargs    = value()?,
+            "--kernel"        => options.kernel        = Some(value()?),
+            "--kernel-offset" => options.kernel_offset = parse_address(&value()?)?,
+            "--initrd"        => options.initrd        = Some(value()?),
+            "--mem"           => options.mem_size      = Some(parse_size(&value()?)?),
+            "--newlib"        => options.newlib        = true,
+            "--semihosting"   => options.semihosting   = Some(value()?),
+            "--cmdline"       => options.cmdline       = Some(value()?),
+            "-g"              => {
+                let v = value()?;
+                options.gdb_port = Some(v.parse::<u16>().map_err(|_| format!("invalid port: {}", v))?);
+            },
+            "--log-commits"   => options.log_commits   = Some(value()?),
+            "--log-start"     => options.log_start     = Some(parse_address(&value()?)?),
+            "--log-stop"      => options.log_stop      = Some(parse_address(&value()?)?),
+            "--cosim"         => options.cosim         = Some(value()?),
+            "--trace"         => options.trace         = true,
+            "--no-jit"        => options.no_jit        = true,
+            "--parallel"      => {
+                let v = value()?;
+                options.parallel = match v.parse::<u64>() {
+                    Ok(n) if n > 0 => Some(n),
+                    _ => return Err(format!("invalid quantum: {}", v)),
+                };
+            },
+            "--sched"         => options.sched         = parse_policy(&value()?)?,
+            "--seed"          => options.seed          = Some(parse_address(&value()?).map_err(|_| String::from("invalid seed"))?),
+            "--allow-origin"  => origins.push(value()?.trim_end_matches('/').to_string()),
+            "--image-dir"     => options.server.image_dir = std::path::PathBuf::from(value()?),
+            "--history"       => {
+                let v = value()?;
+                options.history = v.parse::<usize>().map_err(|_| format!("invalid number of steps: {}", v))?;
+            },
+            "--harts"         => {
+                let v = value()?;
+                options.harts = match v.parse::<usize>() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("invalid number of HARTs: {}", v)),
+                };
+            },
+            _ => return Err(format!("unknown argument: {}", arg)),
+        }
+    }
+    if !origins.is_empty() {
+        options.server.allowed_origins = origins;
+    }
+    return Ok(options);
 }
 
 fn main() -> ExitCode {
     let args: Vec<String> = env::args().collect();
     println!("args: {:?}", args);
-    let mut sim_mode = SimMode::None;
-
-    match args.len() {
-        3 => {
-            match args[1].as_str() {
-                "-H" => sim_mode=SimMode::HtmlServer,
-                "-T" => sim_mode=SimMode::SelfTest,
-                _ => cli_help(),
-            }
-        }
-        1 | 2 | _ => {
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("ERROR: {}", e);
             cli_help();
-        },
-    }
+            return ExitCode::from(1);
+        }
+    };
+
     let mut exit_code = ExitCode::from(1);
-    match sim_mode {
+    match options.sim_mode {
         SimMode::HtmlServer => {
-            match args[2].parse() {
-                Ok(port_number) => server_loop(port_number),
+            match options.mode_arg.parse::<u16>() {
+                Ok(port_number) => match server_loop(port_number, options.server) {
+                    Ok(()) => exit_code = ExitCode::SUCCESS,
+                    Err(e) => println!("ERROR: {}", e),
+                },
                 _ => cli_help()
             }
             },
-        SimMode::SelfTest => {exit_code = self_test(args[2].as_str());},
-        _ => {}
+        SimMode::SelfTest => {exit_code = self_test(&options);},
+        SimMode::Boot => {exit_code = boot(&options);},
+        SimMode::User => {exit_code = user_mode(&options);},
+        SimMode::Disassemble => {exit_code = disassemble_image(&options);},
+        SimMode::None if options.dump_dtb.is_some() => {
+            let result = configure_sim(&options).and_then(|mut sim| configure_device_tree(&mut sim, &options));
+            match result {
+                Ok(()) => exit_code = ExitCode::SUCCESS,
+                Err(e) => println!("ERROR: {}", e),
+            }
+        },
+        _ => cli_help(),
+    }
+    return exit_code;
+}
+
+// builds the machine described by the command line options
+fn configure_sim(options: &CliOptions) -> Result<Simulator, String> {
+    let mut sim = match options.sim_mode {
+        SimMode::Boot => new_sim(options.harts, BOOT_RAM_BASE, options.mem_size.unwrap_or(BOOT_RAM_SIZE)),
+        SimMode::User => new_sim(1, USER_RAM_BASE, options.mem_size.unwrap_or(USER_RAM_SIZE)),
+        _ => new_sim(options.harts, 0, options.mem_size.unwrap_or(8192)),
+    };
+    if let Some(config) = &options.framebuffer {
+        let fb = parse_framebuffer_config(config)?;
+        println!("INFO framebuffer {}x{} {} at 0x{:X}", fb.width, fb.height, fb.format.name(), fb.base);
+        sim.devices.framebuffer = Some(fb);
+    }
+    if let Some(root) = &options.semihosting {
+        if !std::path::Path::new(root).is_dir() {
+            return Err(format!("semihosting directory {} does not exist", root));
+        }
+        let cmdline = options.cmdline.clone().unwrap_or(options.mode_arg.clone());
+        println!("INFO semihosting, files below {}", root);
+        sim.devices.semihosting = Some(new_semihosting(root, &cmdline));
+    }
+    if options.history > 0 {
+        println!("INFO recording the last {} steps", options.history);
+        sim.history = Some(new_history(options.history));
+    }
+    if options.log_commits.is_some() || options.cosim.is_some() {
+        if let Some(path) = &options.log_commits {
+            println!("INFO commit log written to {}", path);
+        }
+        if let Some(path) = &options.cosim {
+            println!("INFO co-simulation against {}", path);
+        }
+        let log = new_commit_log(options.log_commits.as_deref(), options.log_start, options.log_stop, options.cosim.as_deref())?;
+        sim.commit_log = Some(log);
+    }
+    sim.trace = options.trace;
+    #[cfg(feature = "jit")]
+    if !options.no_jit {
+        sim.jit = Some(new_jit()?);
+        println!("INFO JIT, hot code runs translated to x86-64");
+    }
+    if let Some(quantum) = options.parallel {
+        let harts = sim.states.len();
+        if harts > 1 {
+            println!("INFO parallel, {} HARTs on host threads, synchronised every {} steps", harts, quantum);
+            sim.parallel = Some(new_parallel(harts, quantum));
+        } else {
+            println!("WARN --parallel needs more than one HART (--harts), running sequentially");
+        }
+    }
+    if options.sched != Policy::Lockstep {
+        let harts = sim.states.len();
+        if sim.parallel.is_some() {
+            return Err(String::from("--sched picks the HARTs of sequential steps, it does not go with --parallel"));
+        }
+        if harts > 1 {
+            let seed = options.seed.unwrap_or_else(clock_seed);
+            let name = policy_name(&options.sched);
+            println!("INFO scheduler {}, seed 0x{:X} (--sched {} --seed 0x{:X} replays this run)", name, seed, name, seed);
+            sim.scheduler = new_scheduler(options.sched.clone(), seed, harts);
+        } else {
+            println!("WARN --sched needs more than one HART (--harts), running the one in lockstep");
+        }
+    }
+
+    return Ok(sim);
+}
+
+/*
+ * Places the generated (or --dtb) device tree in RAM and points a1 at it.
+ * Generating it after loading the image means the memory node describes the final RAM window.
+ */
+fn configure_device_tree(sim: &mut Simulator, options: &CliOptions) -> Result<(), String> {
+    let mut generated = generate_device_tree(sim, &options.bootargs);
+    if let Some(path) = &options.dump_dtb {
+        fs::write(path, &generated).map_err(|e| format!("failed to write {}: {:?}", path, e))?;
+        println!("INFO device tree written to {}", path);
+    }
+
+    let dtb = match &options.dtb {
+        Some(path) => fs::read(path).map_err(|e| format!("failed to read {}: {:?}", path, e))?,
+        None => generated.clone(),
+    };
+    let mem_len = sim.mem.len();
+    let mut address = place_device_tree(sim, &dtb);
+    if options.dtb.is_none() && sim.mem.len() != mem_len {
+        // RAM grew to make room, the memory node has to follow
+        generated = generate_device_tree(sim, &options.bootargs);
+        address = place_device_tree(sim, &generated);
+    }
+    println!("INFO device tree ({} bytes) at 0x{:X}", dtb.len(), address);
+    return Ok(());
+}
+
+// HTIF is enabled when tohost can be found, from the loaded ELF, --symbols or --tohost
+fn configure_htif(sim: &mut Simulator, options: &CliOptions) -> Result<(), String> {
+    let mut tohost   = symbol_address(sim, "tohost");
+    let mut fromhost = symbol_address(sim, "fromhost");
+    if let Some(path) = &options.symbols {
+        let file = fs::read(path).map_err(|e| format!("failed to read {}: {:?}", path, e))?;
+        let elf = parse_elf(&file)?;
+        // the raw image is loaded at address 0, not at the address it was linked at
+        let base = elf.image_base().unwrap_or(0);
+        tohost   = elf.symbol("tohost").map(|s| s.value - base);
+        fromhost = elf.symbol("fromhost").map(|s| s.value - base);
+    }
+    tohost   = options.tohost.or(tohost);
+    fromhost = options.fromhost.or(fromhost);
+    if let Some(tohost) = tohost {
+        println!("INFO HTIF tohost at 0x{:X}, fromhost at {:X?}", tohost, fromhost);
+        sim.devices.htif = Some(new_htif(tohost, fromhost));
+    }
+    return Ok(());
+}
+
+// exit code of a self test that stopped without the guest reporting pass or fail
+const SIM_STOPPED_EXIT_CODE: u8 = 125;
+
+// exit code of --cosim when ar64 and the reference trace disagree
+const COSIM_DIVERGENCE_EXIT_CODE: u8 = 124;
+
+// exit codes that already mean something else to the caller: 101 is a Rust panic, 124 and 125 are
+// ours (124 also from timeout(1)), 126 and 127 come from the shell
+const RESERVED_EXIT_CODES: [u8; 5] = [101, COSIM_DIVERGENCE_EXIT_CODE, SIM_STOPPED_EXIT_CODE, 126, 127];
+
+// exit code of a guest failure, never 0 and never one of the reserved codes
+fn guest_failure_exit_code(code: u16) -> u8 {
+    let exit_code = code as u8;
+    if exit_code == 0 || RESERVED_EXIT_CODES.contains(&exit_code) {
+        println!("WARN guest code {} can not be told apart from {} as an exit code, exiting with 1", code, if exit_code == 0 { "success" } else { "a simulator status" });
+        return 1;
     }
     return exit_code;
 }
 
-fn self_test(test_binary_location: &str) -> ExitCode {
-    let mut sim = default_sim();
-    match load_image(&mut sim, test_binary_location) {
-        Err(()) => {return ExitCode::FAILURE;},
-        _ => {}
+fn self_test(options: &CliOptions) -> ExitCode {
+    let mut sim = match configure_sim(options) {
+        Ok(sim) => sim,
+        Err(e) => {
+            println!("ERROR: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if load_image(&mut sim, options.mode_arg.as_str()).is_err() {
+        return ExitCode::FAILURE;
+    }
+    if let Err(e) = configure_htif(&mut sim, options).and_then(|_| configure_device_tree(&mut sim, options)) {
+        println!("ERROR: {}", e);
+        return ExitCode::FAILURE;
+    }
+    if options.newlib {
+        start_bare_metal(&mut sim);
+        println!("INFO newlib syscalls, heap at 0x{:X}", sim.image_end);
     }
 
-    let mut should_continue = true;
+    let mut should_continue = match options.gdb_port {
+        Some(port) => gdb_serve(&mut sim, port, &mut flush_console),
+        None => true,
+    };
     let mut step_index = 0;
     while should_continue {
-        println!("INFO: step index {}", step_index);
-        should_continue = step(&mut sim);
+        if sim.trace {
+            println!("INFO: step index {}", step_index);
+        }
+        should_continue = batch_step(&mut sim);
+        flush_console(&mut sim);
         step_index += 1;
     }
 
+    if dump_framebuffer(&sim, options).is_err() {
+        return ExitCode::FAILURE;
+    }
+    return guest_exit_code(&sim);
+}
 
-    ExitCode::SUCCESS
+// -D: the loaded image from its first to its last byte, symbols start a new block like in objdump
+fn disassemble_image(options: &CliOptions) -> ExitCode {
+    let mut sim = match configure_sim(options) {
+        Ok(sim) => sim,
+        Err(e) => {
+            println!("ERROR: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if load_image(&mut sim, options.mode_arg.as_str()).is_err() {
+        return ExitCode::FAILURE;
+    }
+    let mut pc = sim.mem_base;
+    while pc < sim.image_end {
+        let instruction = match disassemble_range(&mut sim, 0, pc, 1).pop() {
+            Some(instruction) => instruction,
+            None => break,
+        };
+        if let Some(symbol) = &instruction.symbol {
+            println!("\n{:016x} <{}>:", instruction.address, symbol);
+        }
+        println!("{:>12x}:\t{:<8}\t{}", instruction.address, instruction.bits, instruction.text);
+        pc += instruction.bits.len() as u64 / 2;
+    }
+    return ExitCode::SUCCESS;
 }
 
-fn server_loop(port_number: u32) {
-    let listener = TcpListener::bind(format!("127.0.0.1:{:}", port_number)).unwrap();
-    listener.set_nonblocking(true).expect("Cannot set non-blocking");
-
-    let mut simulators = HashMap::new();
-    let mut next_index = 0;
-    for stream in listener.incoming() {
-        //println!("GOT A REQUEST! 1");
-        match stream {
-            Ok(stream) => {
-                //println!("GOT A REQUEST!");
-        
-                handle_connection(&mut next_index, stream, &mut simulators);
-                //println!("GOT A REQUEST! finished");
-            },
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                // wait until network socket is ready, typically implemented
-                // via platform-specific APIs such as epoll or IOCP
-                //wait_for_fd();
-                thread::sleep(time::Duration::from_millis(10));
-                continue;
-            },
-            Err(e) => panic!("encountered IO error: {e}"),
+// prints what the guest wrote to its console (UART, SBI, HTIF, semihosting) since the last call
+fn flush_console(sim: &mut Simulator) {
+    if !sim.uart_out.is_empty() {
+        let mut stdout = std::io::stdout();
+        _ = stdout.write_all(&sim.uart_out);
+        _ = stdout.flush();
+        sim.uart_out.clear();
+    }
+}
+
+// what the guest reported through the test finisher or HTIF, turned into the exit code of the simulator
+fn guest_exit_code(sim: &Simulator) -> ExitCode {
+    #[cfg(feature = "jit")]
+    if let Some(jit) = &sim.jit {
+        println!("INFO JIT: {} blocks translated", jit.translated);
+    }
+    if let Some(parallel) = &sim.parallel {
+        println!("INFO parallel: {} quanta", parallel.quanta);
+    }
+    if sim.scheduler.policy != Policy::Lockstep {
+        let scheduler = &sim.scheduler;
+        println!("INFO scheduler {}: {} steps, seed 0x{:X}", policy_name(&scheduler.policy), scheduler.steps, scheduler.seed);
+    }
+    if let Some(cosim) = sim.commit_log.as_ref().and_then(|log| log.cosim.as_ref()) {
+        if let Some(report) = &cosim.divergence {
+            println!("ERROR cosim: {}", report);
+            return ExitCode::from(COSIM_DIVERGENCE_EXIT_CODE);
+        }
+        println!("INFO cosim: {} instructions match the reference trace", cosim.matched);
+        if cosim.ended {
+            println!("INFO cosim: the reference trace ends here");
+            return ExitCode::SUCCESS;
         }
+    }
+    return match guest_exit_status(sim) {
+        Some(FinisherStatus::Pass) => {
+            println!("INFO guest reported pass");
+            ExitCode::SUCCESS
+        },
+        Some(FinisherStatus::Fail(code)) => {
+            if sim.devices.htif.is_some() {
+                // riscv-tests report the number of the failing test
+                println!("INFO guest reported failure, code {} (riscv-tests: test {} failed)", code, code);
+            } else if sim.devices.syscalls.as_ref().is_some_and(|s| s.bare_metal) && code & 1 == 1 {
+                // sim/doc self test convention: a0 = test number << 1 | 1
+                println!("INFO guest exited with code {} (self test: test {} failed)", code, code >> 1);
+            } else if sim.devices.syscalls.is_some() {
+                println!("INFO guest exited with code {}", code);
+            } else {
+                println!("INFO guest reported failure, code {}", code);
+            }
+            ExitCode::from(guest_failure_exit_code(code))
+        },
+        _ => {
+            println!("ERROR simulator stopped without a result from the guest: {}", sim.log);
+            ExitCode::from(SIM_STOPPED_EXIT_CODE)
+        },
+    };
+}
 
+// where the machine booted with -B has its RAM, like the QEMU virt machine
+const BOOT_RAM_BASE: u64 = 0x80000000;
+const BOOT_RAM_SIZE: usize = 128 << 20;
+
+// fw_jump jumps to FW_JUMP_ADDR, 0x80200000 on the generic platform
+const DEFAULT_KERNEL_OFFSET: u64 = 0x200000;
+
+// -B argument that selects the built-in SBI instead of firmware
+const BUILTIN_SBI: &str = "builtin";
+
+// instructions run between polls of the console
+const BOOT_STEP_BATCH: u32 = 10000;
+
+/*
+ * Boots firmware in M-mode, by default OpenSBI fw_jump which then enters the kernel in S-mode:
+ *
+ *      0x80000000                      firmware, -B
+ *      0x80000000 + kernel offset      kernel Image, --kernel
+ *      upper half of RAM               initramfs, --initrd
+ *      top of RAM                      device tree, pointed at by a1
+ *
+ * The UART is the console: its output is copied to stdout, stdin is fed into its receive FIFO.
+ * With "-B builtin" there is no firmware, the simulator implements SBI and the HARTs start in S-mode at the kernel.
+ */
+fn boot(options: &CliOptions) -> ExitCode {
+    let mut sim = match boot_machine(options) {
+        Some(sim) => sim,
+        None => return ExitCode::FAILURE,
+    };
+
+    // stdin is read on its own thread, so that waiting for input does not stop the machine
+    let (sender, receiver) = mpsc::channel::<u8>();
+    thread::spawn(move || {
+        let mut buffer = [0u8; 256];
+        loop {
+            let count = match std::io::stdin().read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(count) => count,
+            };
+            if buffer[..count].iter().any(|byte| sender.send(*byte).is_err()) {
+                break;
+            }
+        }
+    });
+
+    let mut console = |sim: &mut Simulator| {
+        while let Ok(byte) = receiver.try_recv() {
+            sim.devices.uart.rx.push_back(byte);
+        }
+        flush_console(sim);
+    };
+    let mut running = match options.gdb_port {
+        Some(port) => gdb_serve(&mut sim, port, &mut console),
+        None => true,
+    };
+    while running {
+        for _ in 0..BOOT_STEP_BATCH {
+            running = batch_step(&mut sim);
+            if !running {
+                break;
+            }
+        }
+        console(&mut sim);
+    }
+    println!();
+    return guest_exit_code(&sim);
+}
+
+// the machine with the firmware, kernel, initramfs and device tree loaded, None after printing why it could not be set up
+fn boot_machine(options: &CliOptions) -> Option<Simulator> {
+    let mut sim = match configure_sim(options) {
+        Ok(sim) => sim,
+        Err(e) => {
+            println!("ERROR: {}", e);
+            return None;
+        }
+    };
+    if options.mode_arg == BUILTIN_SBI {
+        if options.kernel.is_none() {
+            println!("ERROR: -B {} needs a --kernel", BUILTIN_SBI);
+            return None;
+        }
+        sim.devices.sbi = Some(new_sbi(sim.states.len()));
+        sim.reset_vector = sim.mem_base + options.kernel_offset;
+        println!("INFO built-in SBI, the kernel starts in S-mode");
+    } else if load_image(&mut sim, options.mode_arg.as_str()).is_err() {
+        return None;
+    }
+    let result = load_kernel(&mut sim, options)
+        .and_then(|_| load_initrd(&mut sim, options))
+        .and_then(|_| configure_device_tree(&mut sim, options));
+    if let Err(e) = result {
+        println!("ERROR: {}", e);
+        return None;
+    }
+    return Some(sim);
+}
+
+// where a static program is linked by default (ld -z separate-code), its stack goes at the top of RAM
+const USER_RAM_BASE: u64 = 0x10000;
+const USER_RAM_SIZE: usize = 256 << 20;
+
+/*
+ * Runs a statically linked riscv64 Linux program without a kernel, like qemu-user:
+ * the ELF is loaded, the initial stack is built like execve does and every ECALL is a Linux syscall
+ * carried out on the host (see syscall.rs). The program sees the host environment and working directory.
+ */
+fn user_mode(options: &CliOptions) -> ExitCode {
+    let path = options.mode_arg.as_str();
+    let result = fs::read(path).map_err(|e| format!("failed to read {}: {:?}", path, e)).and_then(|file| {
+        if !is_elf(&file) {
+            return Err(format!("{} is not an ELF file", path));
+        }
+        let elf = parse_elf(&file)?;
+        if elf.segments.iter().any(|s| s.kind == PT_INTERP) {
+            return Err(format!("{} is dynamically linked, only static programs are supported (link with -static)", path));
+        }
+        let mut sim = configure_sim(options)?;
+        load_elf(&mut sim, path, &file).map_err(|_| sim.log.clone())?;
+        let args: Vec<String> = std::iter::once(path.to_string()).chain(options.guest_args.iter().cloned()).collect();
+        let env: Vec<String> = env::vars().map(|(key, value)| format!("{}={}", key, value)).collect();
+        start_user_process(&mut sim, &elf, &args, &env)?;
+        return Ok(sim);
+    });
+    let mut sim = match result {
+        Ok(sim) => sim,
+        Err(e) => {
+            println!("ERROR: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let running = match options.gdb_port {
+        Some(port) => gdb_serve(&mut sim, port, &mut |_| {}),
+        None => true,
+    };
+    if running {
+        while batch_step(&mut sim) {}
+    }
+    return guest_exit_code(&sim);
+}
+
+// the kernel Image is position independent and is copied as it is, vmlinux (an ELF) is refused
+fn load_kernel(sim: &mut Simulator, options: &CliOptions) -> Result<(), String> {
+    let path = match &options.kernel {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let file = fs::read(path).map_err(|e| format!("failed to read {}: {:?}", path, e))?;
+    if is_elf(&file) {
+        return Err(format!("{} is an ELF file, use the raw Image (arch/riscv/boot/Image) instead of vmlinux", path));
+    }
+    // the RISC-V Image header carries "RISCV\0\0\0" at 0x30 and "RSC\x05" at 0x38
+    if file.get(0x38..0x3c) != Some(b"RSC\x05".as_slice()) {
+        println!("WARN {} has no RISC-V Image header, loading it anyway", path);
     }
+    let address = sim.mem_base + options.kernel_offset;
+    copy_to_ram(sim, address, &file).map_err(|e| format!("kernel {}: {}", path, e))?;
+    println!("INFO kernel ({}, {} bytes) at 0x{:X}", path, file.len(), address);
+    return Ok(());
+}
+
+// the initramfs goes in the upper half of RAM, or right after the kernel when that does not leave it enough room
+fn load_initrd(sim: &mut Simulator, options: &CliOptions) -> Result<(), String> {
+    let path = match &options.initrd {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let file = fs::read(path).map_err(|e| format!("failed to read {}: {:?}", path, e))?;
+    let half = sim.mem_base + sim.mem.len() as u64 / 2;
+    let address = (half.max(sim.image_end) + 0xfff) & !0xfff;
+    copy_to_ram(sim, address, &file).map_err(|e| format!("initrd {}: {}", path, e))?;
+    sim.initrd = Some((address, address + file.len() as u64));
+    println!("INFO initrd ({}, {} bytes) at 0x{:X}", path, file.len(), address);
+    return Ok(());
+}
+
+fn copy_to_ram(sim: &mut Simulator, address: u64, data: &[u8]) -> Result<(), String> {
+    let end = address + data.len() as u64;
+    if address < sim.mem_base || end > sim.mem_base + sim.mem.len() as u64 {
+        return Err(format!("0x{:X}..0x{:X} does not fit in RAM (0x{:X} bytes at 0x{:X}), use --mem", address, end, sim.mem.len(), sim.mem_base));
+    }
+    let offset = (address - sim.mem_base) as usize;
+    sim.mem[offset..offset + data.len()].copy_from_slice(data);
+    sim.image_end = sim.image_end.max(end);
+    return Ok(());
+}
+
+fn dump_framebuffer(sim: &Simulator, options: &CliOptions) -> Result<(), ()> {
+    let fb = match &sim.devices.framebuffer {
+        Some(fb) => fb,
+        None => {
+            if options.fb_dump.is_some() || options.fb_sixel {
+                println!("ERROR: no framebuffer configured, use --fb");
+                return Err(());
+            }
+            return Ok(());
+        }
+    };
+
+    if let Some(path) = &options.fb_dump {
+        let image = if path.ends_with(".ppm") { fb.to_ppm() } else { fb.to_png() };
+        if let Err(e) = fs::write(path, image) {
+            println!("ERROR: failed to write framebuffer to {}: {:?}", path, e);
+            return Err(());
+        }
+        println!("INFO framebuffer written to {}", path);
+    }
+    if options.fb_sixel {
+        println!("{}", fb.to_sixel());
+    }
+    return Ok(());
 }
 
 fn load_image(sim: &mut Simulator, path: &str) -> Result<(), ()>{
+    sim.decode_cache.clear();
     let res = &fs::read(path);
     match res {
         Err(e) => {
-            let p = String::from(format!("ERRROR: failed to load image! {:?}", e));
+            let p = format!("ERRROR: failed to load image! {:?}", e);
             println!("{}", p);
             sim.log = p;
             return Err(());
         },
+        Ok(file) if is_elf(file) => {
+            return load_elf(sim, path, file);
+        },
+        Ok(file) if path.ends_with(".s") || path.ends_with(".S") => {
+            return load_assembly(sim, path, &String::from_utf8_lossy(file));
+        },
         Ok(file) => {
-            //sim.mem = file.to_vec();
-            if sim.mem.len() >= file.len() {
-                for i in 0..file.len() {
-                    sim.mem[i] = file[i]
-                }
-                for i in file.len()..sim.mem.len() {
-                    sim.mem[i] = 0
-                }
-                let p = String::from(format!("INFO file ({}) is loaded", path));
+            let (base, size) = ram_window(sim);
+            if size >= file.len() {
+                clear_ram(sim, base, size);
+                sim.mem[..file.len()].copy_from_slice(file);
+                start_image(sim, base, base + file.len() as u64, vec![]);
+                let p = format!("INFO file ({}) is loaded", path);
                 println!("{}", p);
                 sim.log = p;
                 return Ok(());
             } else {
-                let p = String::from(format!("ERROR file size ({}) is larger than the memory of the sim({})!", file.len(), sim.mem.len()));
+                let p = format!("ERROR file size ({}) is larger than the memory of the sim({})!", file.len(), size);
                 println!("{}", p);
                 sim.log = p;
                 return Err(());
@@ -148,116 +836,234 @@ fn load_image(sim: &mut Simulator, path: &str) -> Result<(), ()>{
     }
 }
 
-fn handle_connection(next_index: &mut i32, mut stream: TcpStream, simulators: &mut HashMap<i32, Simulator>) {
-    let buf_reader = BufReader::new(&mut stream);
-    let mut last_line_non_empty = true;
-
-    //println!("[handle_connection]");
-    let http_request: Vec<_> = buf_reader
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| {
-            //println!("line: {:?}", line);
-            if !line.is_empty() {last_line_non_empty = true; return true;}
-            if last_line_non_empty {last_line_non_empty = false; return true;}
-            return false;
-        }
-        )
-        .collect();
-
-        
-    //println!("[handle_connection] Request: {:#?}", http_request);
-
-    // INSANE HACK
-    let str_body = &http_request[http_request.len()-2];
-    //println!("request body: {:?}", str_body);
-    if str_body.starts_with('{') {
-        let mut body: serde_json::Value = serde_json::from_str(str_body).unwrap();
-
-
-        // let mut debug = match body.get_mut("debug"){
-        //     Some(debug_text) => {
-        //         println!("[handle_connection] debug_text: {:#?}", debug_text);
-        //         debug_text == "1"
-        //     }
-        //     _ => false
-        // };
-
-        let simulator_key = match body.get_mut("device_index"){
-            Some(device_index) => {
-                let index: i32 = device_index.to_string().parse::<i32>().unwrap();
-                index
-            }
-            _ => 0
-        };
-        if !simulators.contains_key(&simulator_key){
-            println!("[handle_connection - WARN] device_index: {:#?} is not in map", simulator_key);
+// the RAM the machine was configured with, loads start from it again after an ELF moved it
+fn ram_window(sim: &Simulator) -> (u64, usize) {
+    return match sim.ram_window {
+        (_, 0) => (sim.mem_base, sim.mem.len()),
+        window => window,
+    };
+}
+
+// zeroed RAM of size bytes at base, whatever was decoded or translated from the old contents is stale
+fn clear_ram(sim: &mut Simulator, base: u64, size: usize) {
+    code_written(sim, sim.mem_base, sim.mem.len() as u64);
+    if sim.mem_base != base || sim.mem.len() != size {
+        println!("INFO RAM at 0x{:X}, size 0x{:X}", base, size);
+        sim.mem_base = base;
+        sim.mem = vec![0; size];
+    } else {
+        sim.mem.fill(0);
+    }
+    code_written(sim, sim.mem_base, sim.mem.len() as u64);
+}
+
+// every HART starts at entry of the image that was just loaded, nothing is left of the previous one
+fn start_image(sim: &mut Simulator, entry: u64, end: u64, symbols: Vec<Symbol>) {
+    sim.reset_vector = entry;
+    sim.image_end = end;
+    sim.symbols = symbols;
+    for state in sim.states.iter_mut() {
+        state.pc = entry;
+    }
+}
+
+// the most RAM an ELF can ask for by the addresses of its segments
+const MAX_ELF_SPAN: u64 = 1 << 30;
+
+/*
+ * Places every PT_LOAD segment at its physical address, zeroes the BSS part
+ * (memsz beyond filesz) and starts every HART at the entry point.
+ * When the segments do not fit the RAM window, the window is moved to cover them, up to MAX_ELF_SPAN bytes.
+ */
+fn load_elf(sim: &mut Simulator, path: &str, file: &[u8]) -> Result<(), ()> {
+    let elf = match parse_elf(file) {
+        Ok(elf) => elf,
+        Err(e) => {
+            let p = format!("ERROR failed to parse ELF ({}): {}", path, e);
+            println!("{}", p);
+            sim.log = p;
+            return Err(());
         }
+    };
 
-        //println!("[handle_connection] device_index: {:#?}", simulator_key);
-        let possible_sim = &mut simulators.get_mut(&simulator_key);
-
-        if let Some(action_name) = body["action"]["name"].as_str() {
-        
-            match action_name {
-                "init" => {
-                    println!("INIT at {:}", next_index);
-                    simulators.insert(*next_index, default_sim());
-                }
-                "step" => {
-                    //println!("STEP");
-                    match possible_sim {
-                        Some(ref mut sim) => _ = step(sim),
-                        _ => println!("ERROR"),
-                    }
-                    
-                }
-                "load image" => {
-                    let location = body["action"]["location"].as_str().unwrap();
-                    println!("load image at: {:?}", location);
-                    
-                    let mut sim = &mut simulators.get_mut(&simulator_key).unwrap();
-                    _ = load_image(&mut sim, location);                    
-                }
-                _ => {
-                    println!("ERROR unknown request");
-                }
-            }
-        } else {
-            println!("ERROR unknown status");
+    // the MMU is off at reset, segments are loaded at their physical address
+    let load_address = |s: &Segment| if s.paddr == 0 && s.vaddr != 0 { s.vaddr } else { s.paddr };
+    let segments: Vec<&Segment> = elf.segments.iter().filter(|s| s.kind == PT_LOAD && s.memsz > 0).collect();
+    let fail = |sim: &mut Simulator, p: String| {
+        println!("{}", p);
+        sim.log = p;
+        return Err(());
+    };
+    // everything is checked before the machine changes, a broken ELF leaves the previous image as it was
+    let mut contents = Vec::with_capacity(segments.len());
+    for s in segments.iter() {
+        if s.filesz > s.memsz {
+            return fail(sim, format!("ERROR segment at 0x{:X} has more file bytes (0x{:X}) than memory (0x{:X})", load_address(s), s.filesz, s.memsz));
         }
+        let end = match load_address(s).checked_add(s.memsz) {
+            Some(end) => end,
+            None => return fail(sim, format!("ERROR segment at 0x{:X} runs past the end of the address space", load_address(s))),
+        };
+        let data = match s.offset.checked_add(s.filesz).and_then(|file_end| file.get(s.offset as usize..file_end as usize)) {
+            Some(data) => data,
+            None => return fail(sim, format!("ERROR segment at 0x{:X} lies outside of the ELF file", load_address(s))),
+        };
+        contents.push((load_address(s), end, data));
+    }
+    let (base, size) = ram_window(sim);
+    let low  = contents.iter().map(|(start, _, _)| *start).min().unwrap_or(base);
+    let high = contents.iter().map(|(_, end, _)| *end).max().unwrap_or(base);
 
-        
-        let mut sim_contents = String::from("");
-        let possible_sim2 = &mut simulators.get_mut(&simulator_key);
-        match possible_sim2 {
-            Some(ref mut sim) => sim_contents = (&*serde_json::to_string(&sim).unwrap()).to_string(),
-            _ => println!("ERROR simulator not available"),
+    let (base, size) = if low < base || high > base + size as u64 {
+        if high - (low & !0xfff) > MAX_ELF_SPAN {
+            return fail(sim, format!("ERROR the segments span 0x{:X}-0x{:X}, more than 0x{:X} bytes", low, high, MAX_ELF_SPAN));
         }
-        let http_contents = format!("{{\"simulator_key\": {simulator_key},\r\n\"sim\": {sim_contents}\r\n}}");
+        let moved = low & !0xfff;
+        (moved, (((high - moved + 0xfff) & !0xfff) as usize).max(size))
+    } else {
+        (base, size)
+    };
+    clear_ram(sim, base, size);
+    for (start, _, data) in contents {
+        let offset = (start - base) as usize;
+        sim.mem[offset..offset + data.len()].copy_from_slice(data);
+        // the rest up to memsz (BSS) is already zero
+    }
 
-        // RETURN
-        // PACKET
-    
-        let status_line = "HTTP/1.1 200 OK";
-        let http_length = http_contents.len();
+    // e_entry is virtual, translate it in case the segment is linked at a different address
+    let entry = segments.iter()
+        .find(|s| elf.entry.wrapping_sub(s.vaddr) < s.memsz)
+        .map_or(elf.entry, |s| elf.entry - s.vaddr + load_address(s));
+    start_image(sim, entry, high, elf.symbols);
 
-        let response = format!("{status_line}\r\nContent-Length: {http_length}\r\n\r\n{http_contents}");
+    let p = format!("INFO ELF ({}) is loaded, entry 0x{:X}, {} symbols", path, entry, sim.symbols.len());
+    println!("{}", p);
+    sim.log = p;
+    return Ok(());
+}
 
-        //println!("[handle_connection] response: {:#?}", response);
-        stream.write_all(response.as_bytes()).unwrap();
-    } else {
-        let status_line = "HTTP/1.1 404";
-        let response = format!(
-            "{status_line}\r\n\r\n"
-        );
-    
-        println!("[ERROR - handle_connection] response: {:#?}", response);
-        stream.write_all(response.as_bytes()).unwrap();
+// an assembly source, assembled at the start of RAM, its labels become the symbols (tohost for HTIF)
+fn load_assembly(sim: &mut Simulator, path: &str, source: &str) -> Result<(), ()> {
+    // the source has to fit the memory of the sim
+    let (base, size) = ram_window(sim);
+    let assembly = match assemble(source, base, size as u64) {
+        Ok(assembly) => assembly,
+        Err(e) => {
+            let p = format!("ERROR {}: {}", path, e);
+            println!("{}", p);
+            sim.log = p;
+            return Err(());
+        },
+    };
+    clear_ram(sim, base, size);
+    sim.mem[..assembly.bytes.len()].copy_from_slice(&assembly.bytes);
+    start_image(sim, base, base + assembly.bytes.len() as u64, assembly.labels);
+
+    let p = format!("INFO assembly ({}) is loaded, {} bytes, {} labels", path, assembly.bytes.len(), sim.symbols.len());
+    println!("{}", p);
+    sim.log = p;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::tests::*;
+    use crate::elf::tests::*;
+
+    const BASE: u64 = 0x8000_0000;
+
+    #[test]
+    fn a_broken_elf_leaves_the_machine_as_it_was() {
+        let mut sim = new_sim(2, BASE, 0x10000);
+        let code: &[u8] = &[0x13, 0, 0, 0];
+        let main: [TestSymbol; 1] = [("main", 0x12, 1, 0x1000)];
+        assert!(load_elf(&mut sim, "low.elf", &build_elf(0x1000, &[(0x1000, code, 0x20)], &main, false)).is_ok());
+        // RAM moved down to the segment and kept its size
+        assert_eq!((sim.mem_base, sim.mem.len()), (0x1000, 0x10000));
+        assert!(sim.states.iter().all(|s| s.pc == 0x1000));
+        assert_eq!(&sim.mem[..4], code);
+        sim.mem[0x100] = 0xaa;
+
+        let broken = [
+            build_elf(BASE, &[(BASE, code, 4), (BASE + 0x100, code, 4)], &[], true),
+            build_elf(BASE, &[(u64::MAX - 2, code, 16)], &[], false),
+            build_elf(BASE, &[(BASE, code, 2)], &[], false),
+        ];
+        for file in broken {
+            assert!(load_elf(&mut sim, "broken.elf", &file).is_err());
+            assert_eq!((sim.mem_base, sim.mem.len(), sim.mem[0x100], sim.reset_vector), (0x1000, 0x10000, 0xaa, 0x1000));
+            assert_eq!(sim.symbols.len(), 1);
+        }
     }
-    
 
+    #[test]
+    fn other_images_forget_the_elf_before_them() {
+        let mut sim = new_sim(2, BASE, 0x10000);
+        let main: [TestSymbol; 1] = [("main", 0x12, 1, 0x1000)];
+        assert!(load_elf(&mut sim, "low.elf", &build_elf(0x1010, &[(0x1000, &[0x13, 0, 0, 0], 0x20)], &main, false)).is_ok());
+        assert_eq!((sim.reset_vector, sim.states[1].pc), (0x1010, 0x1010));
+
+        assert!(load_assembly(&mut sim, "a.s", "start: nop").is_ok());
+        assert_eq!((sim.mem_base, sim.mem.len(), sim.reset_vector, sim.states[1].pc), (BASE, 0x10000, BASE, BASE));
+        assert_eq!(sim.symbols.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), ["start"]);
+
+        assert!(load_elf(&mut sim, "low.elf", &build_elf(0x1010, &[(0x1000, &[0x13, 0, 0, 0], 0x20)], &main, false)).is_ok());
+        let path = std::env::temp_dir().join(format!("ar64-raw-{}.bin", std::process::id()));
+        fs::write(&path, [0x13, 0, 0, 0]).unwrap();
+        assert!(load_image(&mut sim, path.to_str().unwrap()).is_ok());
+        fs::remove_file(&path).unwrap();
+        assert_eq!((sim.mem_base, sim.mem.len(), sim.reset_vector, sim.states[0].pc), (BASE, 0x10000, BASE, BASE));
+        assert_eq!((sim.image_end, sim.symbols.len()), (BASE + 4, 0));
+    }
 
+    // in S-mode on the built-in SBI: checks the device tree, waits for an SBI timer interrupt, writes the UART, shuts down
+    const KERNEL: &str = "
+        lwu t0, 0(a1)
+        li t1, 0xedfe0dd0
+        bne t0, t1, fail
+        la t0, trap
+        csrw stvec, t0
+        li t0, 0x20
+        csrs sie, t0
+        csrsi sstatus, 2
+        csrr a0, time
+        addi a0, a0, 100
+        li a7, 0x54494D45
+        li a6, 0
+        ecall
+    1:  wfi
+        j 1b
+    trap:
+        li t0, 0x10000000
+        li t1, 111
+        sb t1, 0(t0)
+        li t1, 107
+        sb t1, 0(t0)
+        li a1, 0
+        j shutdown
+    fail:
+        li a1, 1
+    shutdown:
+        li a7, 0x53525354
+        li a6, 0
+        li a0, 0
+        ecall
+    ";
 
-    
-}
\ No newline at end of file
+    #[test]
+    fn boots_a_kernel_on_the_builtin_sbi() {
+        let path = std::env::temp_dir().join(format!("ar64-kernel-{}.bin", std::process::id()));
+        let args: Vec<String> = ["ar64", "-B", "builtin", "--kernel", path.to_str().unwrap(), "--mem", "16M", "--harts", "2"]
+            .iter().map(|a| a.to_string()).collect();
+        let options = parse_args(&args).unwrap();
+        let kernel = assemble(KERNEL, BOOT_RAM_BASE + options.kernel_offset, 0x1000).unwrap();
+        fs::write(&path, &kernel.bytes).unwrap();
+        let sim = boot_machine(&options);
+        fs::remove_file(&path).unwrap();
+        let mut sim = sim.unwrap();
+        assert_eq!(run_to_exit(&mut sim), Some(FinisherStatus::Pass));
+        assert_eq!(sim.uart_out, b"ok");
+        assert_eq!(sim.states[0].priviledge_mode, PRIV_S);
+    }
+}
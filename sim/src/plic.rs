@@ -0,0 +1,212 @@
+use serde::{Serialize, Deserialize};
+
+/*
+ * Platform-Level Interrupt Controller, SiFive layout as in the QEMU virt machine.
+ *
+ *      0x000000 + 4*source                 priority of the source, 0 disables it
+ *      0x001000                            pending bits
+ *      0x002000 + 0x80*context             enable bits of the context
+ *      0x200000 + 0x1000*context           priority threshold of the context
+ *      0x200004 + 0x1000*context           claim (read) / complete (write)
+ *
+ * Context 2*hart is the M-mode external interrupt of the hart, 2*hart + 1 the S-mode one.
+ * Sources are level triggered: a source stays pending while its line is high and it is not being serviced.
+ *
+ * https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc
+ */
+
+pub const PLIC_BASE: u64 = 0xc000000;
+pub const PLIC_SIZE: u64 = 0x600000;
+
+// number of interrupt sources, source 0 does not exist
+pub const PLIC_SOURCES: u32 = 32;
+
+pub const UART_IRQ: u32 = 10;
+
+const PENDING_OFFSET:   u64 = 0x1000;
+const ENABLE_OFFSET:    u64 = 0x2000;
+const ENABLE_STRIDE:    u64 = 0x80;
+const CONTEXT_OFFSET:   u64 = 0x200000;
+const CONTEXT_STRIDE:   u64 = 0x1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Plic {
+    pub base:       u64,
+    pub priority:   Vec<u32>,
+    pub pending:    u32,
+    pub in_service: u32, // claimed and not yet completed
+    pub levels:     u32, // input lines of the sources
+    pub enable:     Vec<u32>,
+    pub threshold:  Vec<u32>,
+}
+
+pub fn new_plic(harts: usize) -> Plic {
+    return Plic {
+        base:       PLIC_BASE,
+        priority:   vec![0; PLIC_SOURCES as usize],
+        pending:    0,
+        in_service: 0,
+        levels:     0,
+        enable:     vec![0; 2 * harts],
+        threshold:  vec![0; 2 * harts],
+    };
+}
+
+impl Plic {
+    pub fn contains(&self, address: u64) -> bool {
+        return address >= self.base && address < self.base + PLIC_SIZE;
+    }
+
+    // drives the interrupt line of a source
+    pub fn set_level(&mut self, source: u32, high: bool) {
+        if high {
+            self.levels |= 1 << source;
+        } else {
+            self.levels &= !(1 << source);
+        }
+        self.update();
+    }
+
+    fn update(&mut self) {
+        self.pending |= self.levels & !self.in_service;
+    }
+
+    // highest priority source that may interrupt the context, lowest id wins ties
+    fn best(&self, context: usize) -> u32 {
+        let mut best = 0;
+        let mut best_priority = self.threshold[context];
+        for source in 1..PLIC_SOURCES {
+            let candidate = self.pending & self.enable[context] & (1 << source) != 0;
+            if candidate && self.priority[source as usize] > best_priority {
+                best = source;
+                best_priority = self.priority[source as usize];
+            }
+        }
+        return best;
+    }
+
+    // external interrupt line of the context
+    pub fn interrupt_pending(&self, context: usize) -> bool {
+        return context < self.enable.len() && self.best(context) != 0;
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        let source = self.best(context);
+        if source != 0 {
+            self.pending &= !(1 << source);
+            self.in_service |= 1 << source;
+        }
+        return source;
+    }
+
+    fn complete(&mut self, source: u32) {
+        if source < PLIC_SOURCES {
+            self.in_service &= !(1 << source);
+            self.update();
+        }
+    }
+
+    // all registers are 32 bit wide
+    pub fn read(&mut self, address: u64, _size: u64) -> u64 {
+        let offset = address - self.base;
+        let contexts = self.enable.len() as u64;
+        let value = if offset < PENDING_OFFSET {
+            self.priority.get((offset / 4) as usize).copied().unwrap_or(0)
+        } else if offset == PENDING_OFFSET {
+            self.pending
+        } else if offset >= ENABLE_OFFSET && offset < ENABLE_OFFSET + ENABLE_STRIDE * contexts {
+            let context = (offset - ENABLE_OFFSET) / ENABLE_STRIDE;
+            if (offset - ENABLE_OFFSET).is_multiple_of(ENABLE_STRIDE) { self.enable[context as usize] } else { 0 }
+        } else if offset >= CONTEXT_OFFSET && offset < CONTEXT_OFFSET + CONTEXT_STRIDE * contexts {
+            let context = ((offset - CONTEXT_OFFSET) / CONTEXT_STRIDE) as usize;
+            match (offset - CONTEXT_OFFSET) % CONTEXT_STRIDE {
+                0 => self.threshold[context],
+                4 => self.claim(context),
+                _ => 0,
+            }
+        } else {
+            0
+        };
+        return value as u64;
+    }
+
+    pub fn write(&mut self, address: u64, _size: u64, value: u64) {
+        let offset = address - self.base;
+        let contexts = self.enable.len() as u64;
+        let value = value as u32;
+        if offset < PENDING_OFFSET {
+            if let Some(priority) = self.priority.get_mut((offset / 4) as usize) {
+                *priority = value & 0x7;
+            }
+        } else if offset >= ENABLE_OFFSET && offset < ENABLE_OFFSET + ENABLE_STRIDE * contexts {
+            let context = (offset - ENABLE_OFFSET) / ENABLE_STRIDE;
+            if (offset - ENABLE_OFFSET).is_multiple_of(ENABLE_STRIDE) {
+                self.enable[context as usize] = value & !1; // source 0 does not exist
+            }
+        } else if offset >= CONTEXT_OFFSET && offset < CONTEXT_OFFSET + CONTEXT_STRIDE * contexts {
+            let context = ((offset - CONTEXT_OFFSET) / CONTEXT_STRIDE) as usize;
+            match (offset - CONTEXT_OFFSET) % CONTEXT_STRIDE {
+                0 => self.threshold[context] = value & 0x7,
+                4 => self.complete(value),
+                _ => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLAIM: u64 = PLIC_BASE + CONTEXT_OFFSET + 4;
+
+    // sources 3 and 5 enabled for context 0 with priority 2, the S-mode context of HART 0 has none
+    fn plic() -> Plic {
+        let mut plic = new_plic(1);
+        plic.write(PLIC_BASE + 4 * 3, 4, 2);
+        plic.write(PLIC_BASE + 4 * 5, 4, 0xa); // 3 bits
+        plic.write(PLIC_BASE + ENABLE_OFFSET, 4, (1 << 3) | (1 << 5) | 1);
+        return plic;
+    }
+
+    #[test]
+    fn claim_takes_the_highest_priority_and_complete_ends_it() {
+        let mut plic = plic();
+        assert_eq!(plic.read(PLIC_BASE + 4 * 5, 4), 2);
+        assert_eq!(plic.read(PLIC_BASE + ENABLE_OFFSET, 4), (1 << 3) | (1 << 5));
+        assert!(!plic.interrupt_pending(0));
+        assert_eq!(plic.read(CLAIM, 4), 0);
+        plic.set_level(5, true);
+        plic.set_level(3, true);
+        assert_eq!(plic.read(PLIC_BASE + PENDING_OFFSET, 4), (1 << 3) | (1 << 5));
+        assert!(plic.interrupt_pending(0) && !plic.interrupt_pending(1));
+        // same priority, the lower id first
+        assert_eq!(plic.read(CLAIM, 4), 3);
+        assert_eq!(plic.read(CLAIM, 4), 5);
+        assert!(!plic.interrupt_pending(0));
+        // a level triggered source that is still high is pending again once completed
+        plic.set_level(5, false);
+        plic.write(CLAIM, 4, 5);
+        plic.write(CLAIM, 4, 3);
+        assert_eq!(plic.pending, 1 << 3);
+        assert_eq!(plic.in_service, 0);
+    }
+
+    #[test]
+    fn the_threshold_masks_lower_priorities() {
+        let mut plic = plic();
+        plic.write(PLIC_BASE + 4 * 5, 4, 4);
+        plic.write(PLIC_BASE + CONTEXT_OFFSET, 4, 2);
+        assert_eq!(plic.read(PLIC_BASE + CONTEXT_OFFSET, 4), 2);
+        plic.set_level(3, true);
+        assert!(!plic.interrupt_pending(0));
+        plic.set_level(5, true);
+        assert_eq!(plic.read(CLAIM, 4), 5);
+        // completing a source that does not exist does nothing
+        plic.write(CLAIM, 4, PLIC_SOURCES as u64);
+        assert_eq!(plic.in_service, 1 << 5);
+        // contexts past the last HART are not there
+        assert_eq!(plic.read(PLIC_BASE + CONTEXT_OFFSET + 2 * CONTEXT_STRIDE, 4), 0);
+        assert!(!plic.interrupt_pending(2));
+    }
+}
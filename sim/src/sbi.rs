@@ -0,0 +1,306 @@
+use serde::{Serialize, Deserialize};
+
+use crate::sim::*;
+use crate::test_finisher::*;
+
+/*
+ * Built-in Supervisor Binary Interface, replaces M-mode firmware such as OpenSBI.
+ *
+ * ECALLs from S-mode are "requested traps" that the simulator handles itself: a7 holds the extension (EID),
+ * a6 the function (FID), a0-a5 the arguments. The error goes back in a0 and the value in a1.
+ *
+ *      Base        0x10        spec version (2.0), implementation, probe, mvendorid/marchid/mimpid
+ *      TIME        0x54494D45  set_timer, the CLINT comparator of the HART drives STIP
+ *      IPI         0x735049    send_ipi, sets SSIP on the target HARTs
+ *      RFENCE      0x52464E43  remote fences, there are no TLBs or caches so they only check the HART mask
+ *      HSM         0x48534D    hart_start, hart_stop, hart_get_status, hart_suspend
+ *      SRST        0x53525354  system_reset, shutdown stops the simulation, reboot resets the machine
+ *      DBCN        0x4442434E  console_write, console_read, console_write_byte on the UART
+ *      legacy      0x01, 0x02  console_putchar, console_getchar
+ *
+ * At boot the boot HART starts in S-mode at the kernel, the other HARTs wait in the stopped state
+ * for hart_start. Exceptions other than ECALLs from S-mode and the S-mode interrupts are delegated,
+ * like OpenSBI does, so M-mode is never entered.
+ *
+ * https://github.com/riscv-non-isa/riscv-sbi-doc
+ */
+
+const EID_LEGACY_PUTCHAR: u64 = 0x01;
+const EID_LEGACY_GETCHAR: u64 = 0x02;
+const EID_BASE:           u64 = 0x10;
+const EID_TIME:           u64 = 0x54494D45;
+const EID_IPI:            u64 = 0x735049;
+const EID_RFENCE:         u64 = 0x52464E43;
+const EID_HSM:            u64 = 0x48534D;
+const EID_SRST:           u64 = 0x53525354;
+const EID_DBCN:           u64 = 0x4442434E;
+
+const EXTENSIONS: [u64; 9] = [
+    EID_LEGACY_PUTCHAR, EID_LEGACY_GETCHAR, EID_BASE, EID_TIME, EID_IPI, EID_RFENCE, EID_HSM, EID_SRST, EID_DBCN,
+];
+
+const SBI_SUCCESS:               i64 = 0;
+const SBI_ERR_NOT_SUPPORTED:     i64 = -2;
+const SBI_ERR_INVALID_PARAM:     i64 = -3;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+const SBI_SPEC_VERSION: u64 = 2 << 24;
+// not a registered implementation ID
+const SBI_IMPL_ID:      u64 = 0xA64;
+const SBI_IMPL_VERSION: u64 = 1;
+
+const SUSPEND_RETENTIVE:     u64 = 0x00000000;
+const SUSPEND_NON_RETENTIVE: u64 = 0x80000000;
+
+const RESET_SHUTDOWN:    u64 = 0;
+const RESET_COLD_REBOOT: u64 = 1;
+const RESET_WARM_REBOOT: u64 = 2;
+const RESET_REASON_SYSTEM_FAILURE: u64 = 1;
+
+// HSM states as hart_get_status reports them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum HartStatus {
+    Started,
+    Stopped,
+    // resume address and opaque value of a non-retentive suspend
+    Suspended(Option<(u64, u64)>),
+}
+
+impl HartStatus {
+    fn code(&self) -> u64 {
+        return match self {
+            HartStatus::Started      => 0,
+            HartStatus::Stopped      => 1,
+            HartStatus::Suspended(_) => 4,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Sbi {
+    pub harts:  Vec<HartStatus>,
+    // set by system_reset, cleared by the simulator once handled
+    pub status: Option<FinisherStatus>,
+}
+
+pub fn new_sbi(harts: usize) -> Sbi {
+    return Sbi {
+        harts:  vec![HartStatus::Stopped; harts],
+        status: None,
+    };
+}
+
+impl Sbi {
+    pub fn stopped(&self, hart: usize) -> bool {
+        return self.harts.get(hart) == Some(&HartStatus::Stopped);
+    }
+}
+
+// exceptions the kernel handles itself, everything except ECALLs from S-mode and M-mode
+const DELEGATED_EXCEPTIONS: u64 = 0xffff & !(1 << 9 | 1 << 11);
+const DELEGATED_INTERRUPTS: u64 = 1 << IRQ_S_SOFT | 1 << IRQ_S_TIMER | 1 << IRQ_S_EXT;
+
+// what firmware would have done before jumping to the kernel, called after every reset
+pub fn sbi_boot(sim: &mut Simulator) {
+    let sbi = match sim.devices.sbi.as_mut() {
+        Some(sbi) => sbi,
+        None => return,
+    };
+    for (hartid, state) in sim.states.iter_mut().enumerate() {
+        state.csr.set(csr_address::MEDELEG, DELEGATED_EXCEPTIONS);
+        state.csr.set(csr_address::MIDELEG, DELEGATED_INTERRUPTS);
+        state.csr.set(csr_address::MCOUNTEREN, 0b111); // cycle, time, instret
+        state.priviledge_mode = PRIV_S;
+        sbi.harts[hartid] = if hartid == 0 { HartStatus::Started } else { HartStatus::Stopped };
+    }
+    sbi.status = None;
+}
+
+// a suspended HART woke up, a non-retentive suspend continues at the resume address
+pub fn sbi_resume(sbi: &mut Sbi, state: &mut CpuState) {
+    let hartid = state.csr[csr_address::MHARTID] as usize;
+    if let Some(HartStatus::Suspended(resume)) = sbi.harts.get(hartid).copied() {
+        if let Some((address, opaque)) = resume {
+            enter_supervisor(state, hartid as u64, address, opaque);
+        }
+        sbi.harts[hartid] = HartStatus::Started;
+    }
+}
+
+// the state a HART is in after hart_start or a non-retentive resume
+fn enter_supervisor(state: &mut CpuState, hartid: u64, address: u64, opaque: u64) {
+    state.pc = address;
+    state.regs[10] = hartid;
+    state.regs[11] = opaque;
+    state.priviledge_mode = PRIV_S;
+    state.csr.set(csr_address::SATP, 0);
+    let mstatus = state.csr[csr_address::MSTATUS];
+    state.csr.set(csr_address::MSTATUS, mstatus & !MSTATUS_SIE);
+    state.waiting = false;
+}
+
+// the HARTs selected by hart_mask and hart_mask_base, None when one of them does not exist
+fn selected_harts(harts: usize, mask: u64, base: u64) -> Option<Vec<usize>> {
+    if base == u64::MAX {
+        return Some((0..harts).collect());
+    }
+    let mut selected = Vec::new();
+    for bit in 0..64 {
+        if mask & (1 << bit) != 0 {
+            let hart = base.checked_add(bit)? as usize;
+            if hart >= harts {
+                return None;
+            }
+            selected.push(hart);
+        }
+    }
+    return Some(selected);
+}
+
+/*
+ * Handles the ECALL of a HART in S-mode. The HART is not in sim.states while it runs,
+ * calls that target other HARTs change sim.states, calls that target the caller change `state`.
+ */
+pub fn sbi_call(sim: &mut Simulator, state: &mut CpuState) {
+    let hartid = state.csr[csr_address::MHARTID] as usize;
+    let harts = sim.states.len();
+    let a: [u64; 6] = state.regs[10..16].try_into().unwrap();
+    let (eid, fid) = (state.regs[17], state.regs[16]);
+    state.pc = state.pc.wrapping_add(4);
+
+    // legacy extensions only return a value in a0
+    match eid {
+        EID_LEGACY_PUTCHAR => {
+            sim.uart_out.push(a[0] as u8);
+            state.regs[10] = 0;
+            return;
+        },
+        EID_LEGACY_GETCHAR => {
+            state.regs[10] = sim.devices.uart.rx.pop_front().map_or(u64::MAX, |c| c as u64);
+            return;
+        },
+        _ => {},
+    }
+
+    let (error, value): (i64, u64) = match (eid, fid) {
+        (EID_BASE, 0) => (SBI_SUCCESS, SBI_SPEC_VERSION),
+        (EID_BASE, 1) => (SBI_SUCCESS, SBI_IMPL_ID),
+        (EID_BASE, 2) => (SBI_SUCCESS, SBI_IMPL_VERSION),
+        (EID_BASE, 3) => (SBI_SUCCESS, EXTENSIONS.contains(&a[0]) as u64),
+        (EID_BASE, 4) => (SBI_SUCCESS, state.csr[csr_address::MVENDORID]),
+        (EID_BASE, 5) => (SBI_SUCCESS, state.csr[csr_address::MARCHID]),
+        (EID_BASE, 6) => (SBI_SUCCESS, state.csr[csr_address::MIMPID]),
+
+        (EID_TIME, 0) => {
+            sim.devices.clint.mtimecmp[hartid] = a[0];
+            (SBI_SUCCESS, 0)
+        },
+
+        (EID_IPI, 0) => match selected_harts(harts, a[0], a[1]) {
+            Some(targets) => {
+                for hart in targets {
+                    let target = if hart == hartid { &mut *state } else { &mut sim.states[hart] };
+                    let mip = target.csr[csr_address::MIP];
+                    target.csr.set(csr_address::MIP, mip | 1 << IRQ_S_SOFT);
+                }
+                (SBI_SUCCESS, 0)
+            },
+            None => (SBI_ERR_INVALID_PARAM, 0),
+        },
+
+        // FENCE.I, SFENCE.VMA and the ASID variants, nothing is cached
+        (EID_RFENCE, 0..=6) => match selected_harts(harts, a[0], a[1]) {
+            Some(_) => (SBI_SUCCESS, 0),
+            None => (SBI_ERR_INVALID_PARAM, 0),
+        },
+
+        (EID_HSM, 0) => { // hart_start(hartid, start_addr, opaque)
+            let target = a[0] as usize;
+            let sbi = sim.devices.sbi.as_mut().unwrap();
+            if target >= harts {
+                (SBI_ERR_INVALID_PARAM, 0)
+            } else if sbi.harts[target] != HartStatus::Stopped {
+                (SBI_ERR_ALREADY_AVAILABLE, 0)
+            } else {
+                enter_supervisor(&mut sim.states[target], target as u64, a[1], a[2]);
+                sbi.harts[target] = HartStatus::Started;
+                (SBI_SUCCESS, 0)
+            }
+        },
+        (EID_HSM, 1) => { // hart_stop(), does not return
+            sim.devices.sbi.as_mut().unwrap().harts[hartid] = HartStatus::Stopped;
+            (SBI_SUCCESS, 0)
+        },
+        (EID_HSM, 2) => match sim.devices.sbi.as_ref().unwrap().harts.get(a[0] as usize) {
+            Some(status) => (SBI_SUCCESS, status.code()),
+            None => (SBI_ERR_INVALID_PARAM, 0),
+        },
+        (EID_HSM, 3) => { // hart_suspend(type, resume_addr, opaque), waits like WFI
+            let resume = match a[0] {
+                SUSPEND_RETENTIVE => Some(None),
+                SUSPEND_NON_RETENTIVE => Some(Some((a[1], a[2]))),
+                _ => None,
+            };
+            match resume {
+                Some(resume) => {
+                    sim.devices.sbi.as_mut().unwrap().harts[hartid] = HartStatus::Suspended(resume);
+                    state.waiting = true;
+                    (SBI_SUCCESS, 0)
+                },
+                None => (SBI_ERR_INVALID_PARAM, 0),
+            }
+        },
+
+        (EID_SRST, 0) => { // system_reset(type, reason)
+            let status = match (a[0], a[1]) {
+                (RESET_SHUTDOWN, RESET_REASON_SYSTEM_FAILURE) => Some(FinisherStatus::Fail(1)),
+                (RESET_SHUTDOWN, _) => Some(FinisherStatus::Pass),
+                (RESET_COLD_REBOOT, _) | (RESET_WARM_REBOOT, _) => Some(FinisherStatus::Reset),
+                _ => None,
+            };
+            match status {
+                Some(status) => {
+                    sim.devices.sbi.as_mut().unwrap().status = Some(status);
+                    (SBI_SUCCESS, 0)
+                },
+                None => (SBI_ERR_INVALID_PARAM, 0),
+            }
+        },
+
+        (EID_DBCN, 0) | (EID_DBCN, 1) => { // console_write / console_read(num_bytes, base_lo, base_hi)
+            match ram_range(sim, a[1], a[0]) {
+                Some(range) if fid == 0 => {
+                    sim.uart_out.extend_from_slice(&sim.mem[range]);
+                    (SBI_SUCCESS, a[0])
+                },
+                Some(range) => {
+                    memory_written(sim, state.csr[csr_address::MHARTID] as usize, a[1], a[1], a[0]);
+                    let mut count = 0;
+                    for byte in sim.mem[range].iter_mut() {
+                        match sim.devices.uart.rx.pop_front() {
+                            Some(c) => *byte = c,
+                            None => break,
+                        }
+                        count += 1;
+                    }
+                    (SBI_SUCCESS, count)
+                },
+                None => (SBI_ERR_INVALID_PARAM, 0),
+            }
+        },
+        (EID_DBCN, 2) => {
+            sim.uart_out.push(a[0] as u8);
+            (SBI_SUCCESS, 0)
+        },
+
+        (eid, _) if EXTENSIONS.contains(&eid) => (SBI_ERR_NOT_SUPPORTED, 0),
+        _ => {
+            println!("WARN: unsupported SBI call, EID 0x{:X}, FID {}", eid, fid);
+            (SBI_ERR_NOT_SUPPORTED, 0)
+        },
+    };
+    state.regs[10] = error as u64;
+    if error == SBI_SUCCESS {
+        state.regs[11] = value;
+    }
+}
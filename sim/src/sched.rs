@@ -0,0 +1,278 @@
+use std::ops::Range;
+
+use serde::{Serialize, Deserialize};
+
+use crate::sim::*;
+
+/*
+ * HART schedulers, --sched policy and --seed n: which HARTs a step runs.
+ *
+ *      lockstep                    every HART runs one instruction per step, hart 0 first. The default
+ *      round-robin[:quantum]       one HART runs quantum steps (default 100), then the next one that can run
+ *      random                      every step runs a HART picked at random
+ *      pct[:depth[:steps]]         probabilistic concurrency testing: every HART gets a random priority and the HART
+ *                                  with the highest priority that can run does so. At depth-1 random steps out of
+ *                                  the first `steps` the running HART drops below all others. A bug that needs
+ *                                  depth ordering constraints is found with probability 1/(harts * steps^(depth-1))
+ *                                  per run. After `steps` steps the HARTs are picked at random, a HART that spins
+ *                                  on a lock held by one with a lower priority would otherwise never let go. depth is
+ *                                  at most MAX_PCT_DEPTH
+ *
+ * A HART can run when the SBI has not stopped it and it does not wait in WFI without an interrupt to wake it up,
+ * the other policies never pick HARTs that would not do anything. When no HART can run the step is a lockstep one.
+ * Time advances by one tick per step whatever the policy, with one HART per step the HARTs are slower.
+ *
+ * The random choices come from splitmix64 seeded with --seed, or the clock when it is not given. The seed is
+ * printed at the start and the end of a run, the same image, options and seed run the same interleaving again.
+ *
+ * Burckhardt, Kothari, Musuvathi, Nagarakatte: A Randomized Scheduler with Probabilistic Guarantees of Finding Bugs,
+ * ASPLOS 2010. https://www.microsoft.com/en-us/research/publication/a-randomized-scheduler-with-probabilistic-guarantees-of-finding-bugs/
+ */
+
+const DEFAULT_ROUND_ROBIN_QUANTUM: u64 = 100;
+const DEFAULT_PCT_DEPTH:           u64 = 3;
+const DEFAULT_PCT_STEPS:           u64 = 1_000_000;
+const MAX_PCT_DEPTH:               u64 = 100; // bugs found in practice need a handful of ordering constraints
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Policy {
+    Lockstep,
+    RoundRobin { quantum: u64 },
+    Random,
+    Pct { depth: u64, steps: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Scheduler {
+    pub policy:    Policy,
+    pub seed:      u64,
+    pub steps:     u64,            // steps scheduled so far
+    pub current:   usize,          // HART of the last step
+    rng:           u64,
+    run:           u64,            // steps the current HART ran in a row, round-robin
+    priorities:    Vec<u64>,       // pct, higher runs first
+    change_points: Vec<(u64, u64)>, // pct, (step, priority) the running HART gets at that step, by step
+}
+
+pub fn new_scheduler(policy: Policy, seed: u64, harts: usize) -> Scheduler {
+    let mut scheduler = Scheduler {
+        policy:        policy,
+        seed:          seed,
+        steps:         0,
+        current:       0,
+        rng:           seed,
+        run:           0,
+        priorities:    vec![],
+        change_points: vec![],
+    };
+    if let Policy::Pct { depth, steps } = scheduler.policy {
+        // priorities depth..depth+harts in random order, the change points give out 1..depth
+        let depth = depth.clamp(1, MAX_PCT_DEPTH);
+        let steps = steps.max(1);
+        let mut priorities: Vec<u64> = (depth..depth.saturating_add(harts as u64)).collect();
+        for i in (1..priorities.len()).rev() {
+            let j = below(&mut scheduler.rng, i as u64 + 1) as usize;
+            priorities.swap(i, j);
+        }
+        scheduler.priorities = priorities;
+        let mut change_points: Vec<(u64, u64)> = (1..depth).map(|priority| (1 + below(&mut scheduler.rng, steps), priority)).collect();
+        change_points.sort();
+        scheduler.change_points = change_points;
+    }
+    return scheduler;
+}
+
+pub fn default_scheduler() -> Scheduler {
+    return new_scheduler(Policy::Lockstep, 0, 1);
+}
+
+// "lockstep", "round-robin[:quantum]", "random" or "pct[:depth[:steps]]"
+pub fn parse_policy(text: &str) -> Result<Policy, String> {
+    let mut parts = text.split(':');
+    let name = parts.next().unwrap_or("");
+    let numbers: Result<Vec<u64>, _> = parts.map(|p| p.parse::<u64>()).collect();
+    let numbers = match numbers {
+        Ok(numbers) if numbers.iter().all(|n| *n > 0) => numbers,
+        _ => return Err(format!("invalid scheduler: {}", text)),
+    };
+    let policy = match (name, numbers.as_slice()) {
+        ("lockstep", []) => Policy::Lockstep,
+        ("round-robin", []) => Policy::RoundRobin { quantum: DEFAULT_ROUND_ROBIN_QUANTUM },
+        ("round-robin", [quantum]) => Policy::RoundRobin { quantum: *quantum },
+        ("random", []) => Policy::Random,
+        ("pct", []) => Policy::Pct { depth: DEFAULT_PCT_DEPTH, steps: DEFAULT_PCT_STEPS },
+        ("pct", [depth]) => Policy::Pct { depth: *depth, steps: DEFAULT_PCT_STEPS },
+        ("pct", [depth, steps]) => Policy::Pct { depth: *depth, steps: *steps },
+        _ => return Err(format!("invalid scheduler: {}", text)),
+    };
+    if let Policy::Pct { depth, .. } = policy {
+        if depth > MAX_PCT_DEPTH {
+            return Err(format!("invalid scheduler: {}, the pct depth is at most {}", text, MAX_PCT_DEPTH));
+        }
+    }
+    return Ok(policy);
+}
+
+// the policy in the syntax of parse_policy
+pub fn policy_name(policy: &Policy) -> String {
+    return match policy {
+        Policy::Lockstep => String::from("lockstep"),
+        Policy::RoundRobin { quantum } => format!("round-robin:{}", quantum),
+        Policy::Random => String::from("random"),
+        Policy::Pct { depth, steps } => format!("pct:{}:{}", depth, steps),
+    };
+}
+
+// a seed for runs that do not ask for one
+pub fn clock_seed() -> u64 {
+    let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    let mut state = nanos ^ ((std::process::id() as u64) << 32);
+    return splitmix64(&mut state);
+}
+
+// https://prng.di.unimi.it/splitmix64.c
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    return z ^ (z >> 31);
+}
+
+// uniform in [0, n), the modulo bias is far below anything a test run could notice
+fn below(state: &mut u64, n: u64) -> u64 {
+    return splitmix64(state) % n;
+}
+
+// the next step of the HART executes an instruction or takes an interrupt, the interrupt lines are those of the last tick
+fn can_run(sim: &Simulator, hart: usize) -> bool {
+    if sim.devices.sbi.as_ref().is_some_and(|sbi| sbi.stopped(hart)) {
+        return false;
+    }
+    let state = &sim.states[hart];
+    return !state.waiting || read_mip(state) & state.csr[csr_address::MIE] != 0;
+}
+
+// a HART picked at random from those that can run, there is at least one
+fn random_hart(scheduler: &mut Scheduler, runnable: &[bool]) -> usize {
+    let count = runnable.iter().filter(|r| **r).count() as u64;
+    let pick = below(&mut scheduler.rng, count) as usize;
+    return runnable.iter().enumerate().filter(|(_, r)| **r).nth(pick).unwrap().0;
+}
+
+// the HARTs the step runs, called after the devices ticked
+pub fn scheduled_harts(sim: &mut Simulator) -> Range<usize> {
+    let harts = sim.states.len();
+    if harts == 1 || sim.scheduler.policy == Policy::Lockstep {
+        return 0..harts;
+    }
+    let runnable: Vec<bool> = (0..harts).map(|hart| can_run(sim, hart)).collect();
+    if !runnable.contains(&true) {
+        return 0..harts;
+    }
+
+    let scheduler = &mut sim.scheduler;
+    let hart = match scheduler.policy {
+        Policy::Lockstep => unreachable!(),
+        Policy::RoundRobin { quantum } => {
+            let current = scheduler.current % harts;
+            if runnable[current] && scheduler.run < quantum {
+                current
+            } else {
+                scheduler.run = 0;
+                (1..=harts).map(|i| (current + i) % harts).find(|h| runnable[*h]).unwrap()
+            }
+        },
+        Policy::Random => random_hart(scheduler, &runnable),
+        Policy::Pct { steps, .. } if scheduler.steps >= steps => random_hart(scheduler, &runnable),
+        Policy::Pct { .. } => {
+            while let Some(&(step, priority)) = scheduler.change_points.first() {
+                if step > scheduler.steps {
+                    break;
+                }
+                // the HART that ran up to the change point loses its priority
+                if let Some(p) = scheduler.priorities.get_mut(scheduler.current) {
+                    *p = priority;
+                }
+                scheduler.change_points.remove(0);
+            }
+            (0..harts).filter(|h| runnable[*h]).max_by_key(|h| scheduler.priorities.get(*h).copied().unwrap_or(0)).unwrap()
+        },
+    };
+    scheduler.steps += 1;
+    scheduler.run += 1;
+    scheduler.current = hart;
+    return hart..hart + 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::tests::*;
+
+    // every HART takes the next slot of a log with amoadd and writes its hartid there, the log is the interleaving
+    const LOGGER: &str = "
+        csrr a0, mhartid
+        li t0, 0x8000
+        li t1, 0x8100
+        li t2, 64
+    1:  li t3, 1
+        amoadd.d t4, t3, (t0)
+        bge t4, t2, 2f
+        add t5, t1, t4
+        sb a0, 0(t5)
+        j 1b
+    2:  j 2b
+    ";
+
+    fn scheduled(policy: &Policy, seed: u64) -> Simulator {
+        let mut sim = assembled_sim(LOGGER, 4);
+        sim.scheduler = new_scheduler(policy.clone(), seed, 4);
+        for _ in 0..2000 {
+            step(&mut sim);
+        }
+        return sim;
+    }
+
+    #[test]
+    fn the_same_seed_runs_the_same_interleaving() {
+        for policy in [Policy::Random, Policy::RoundRobin { quantum: 7 }, Policy::Pct { depth: 3, steps: 500 }] {
+            let sim = scheduled(&policy, 42);
+            // the log is full, each HART took one slot past its end
+            assert_eq!(sim.mem[0x8000], 64 + 4, "{:?}", policy);
+            assert_same_machine(&sim, &scheduled(&policy, 42));
+            assert_eq!(sim.scheduler.steps, scheduled(&policy, 42).scheduler.steps);
+            if policy != (Policy::RoundRobin { quantum: 7 }) {
+                assert!(sim.mem[0x8100..0x8140] != scheduled(&policy, 43).mem[0x8100..0x8140], "{:?}", policy);
+            }
+        }
+    }
+
+    #[test]
+    fn pct_runs_the_highest_priority_until_a_change_point() {
+        let mut sim = assembled_sim(LOGGER, 4);
+        sim.scheduler = new_scheduler(Policy::Pct { depth: 2, steps: 500 }, 7, 4);
+        let (change, _) = sim.scheduler.change_points[0];
+        let first = (0..4).max_by_key(|h| sim.scheduler.priorities[*h]).unwrap();
+        let mut harts = vec![];
+        for _ in 0..change + 1 {
+            harts.push(scheduled_harts(&mut sim).start);
+        }
+        assert!(harts[..change as usize].iter().all(|h| *h == first));
+        // the running HART drops to priority 1, below all others
+        assert!(harts[change as usize] != first);
+        assert_eq!(sim.scheduler.priorities[first], 1);
+    }
+
+    #[test]
+    fn parse_policy_limits_the_pct_depth() {
+        assert_eq!(parse_policy("pct:100:5"), Ok(Policy::Pct { depth: 100, steps: 5 }));
+        assert!(parse_policy("pct:101").is_err());
+        assert!(parse_policy("pct:18446744073709551615").is_err());
+        assert!(parse_policy("pct:0").is_err());
+        assert!(parse_policy("round-robin:1:2").is_err());
+        // a policy that did not come through parse_policy is clamped
+        let scheduler = new_scheduler(Policy::Pct { depth: u64::MAX, steps: 0 }, 1, 2);
+        assert_eq!(scheduler.change_points.len() as u64, MAX_PCT_DEPTH - 1);
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+
+use crate::sim::*;
+use crate::syscall::*;
+use crate::test_finisher::*;
+
+/*
+ * RISC-V semihosting: the guest asks the host for console and file I/O with an EBREAK between two HINTs,
+ *
+ *      slli x0, x0, 0x1f       0x01f01013
+ *      ebreak                  0x00100073
+ *      srai x0, x0, 7          0x40705013
+ *
+ * all three uncompressed. a0 holds the operation, a1 a pointer to a block of XLEN words with the arguments
+ * (or the argument itself for some operations), the result goes back in a0. The operations are the ARM ones:
+ *
+ *      0x01 SYS_OPEN       [name, mode, length], ":tt" is the console     -> handle or -1
+ *      0x02 SYS_CLOSE      [handle]                                        -> 0 or -1
+ *      0x03 SYS_WRITEC     a1 points at the character
+ *      0x04 SYS_WRITE0     a1 points at a NUL terminated string
+ *      0x05 SYS_WRITE      [handle, buffer, length]                        -> bytes not written
+ *      0x06 SYS_READ       [handle, buffer, length]                        -> bytes not read
+ *      0x07 SYS_READC                                                      -> character
+ *      0x09 SYS_ISTTY      [handle]                                        -> 1 for the console
+ *      0x0A SYS_SEEK       [handle, position]                              -> 0 or -1
+ *      0x0C SYS_FLEN       [handle]                                        -> length or -1
+ *      0x0E SYS_REMOVE     [name, length]                                  -> 0 or host errno
+ *      0x10 SYS_CLOCK                                                      -> centiseconds since start
+ *      0x11 SYS_TIME                                                       -> seconds since the epoch
+ *      0x13 SYS_ERRNO                                                      -> errno of the last failed call
+ *      0x15 SYS_GET_CMDLINE [buffer, length], length is updated            -> 0 or -1
+ *      0x16 SYS_HEAPINFO   a1 points at a pointer to 4 words, all 0 (the program knows best)
+ *      0x18 SYS_EXIT       [reason, subcode], or the reason in a1 itself
+ *      0x20 SYS_EXIT_EXTENDED [reason, subcode]
+ *
+ * Console output goes to sim.uart_out like the UART's, the run loop prints it.
+ * Files are opened below the directory given with --semihosting, names that would leave it are refused.
+ * The sequence is read from RAM at the physical address of the EBREAK, semihosting is for bare-metal code.
+ *
+ * https://github.com/riscv-non-isa/riscv-semihosting
+ * https://github.com/ARM-software/abi-aa/blob/main/semihosting/semihosting.rst
+ */
+
+const SEMIHOSTING_ENTRY: u32 = 0x01f01013; // slli x0, x0, 0x1f
+const SEMIHOSTING_EBREAK: u32 = 0x00100073;
+const SEMIHOSTING_EXIT: u32 = 0x40705013;  // srai x0, x0, 7
+
+const SYS_OPEN:          u64 = 0x01;
+const SYS_CLOSE:         u64 = 0x02;
+const SYS_WRITEC:        u64 = 0x03;
+const SYS_WRITE0:        u64 = 0x04;
+const SYS_WRITE:         u64 = 0x05;
+const SYS_READ:          u64 = 0x06;
+const SYS_READC:         u64 = 0x07;
+const SYS_ISTTY:         u64 = 0x09;
+const SYS_SEEK:          u64 = 0x0A;
+const SYS_FLEN:          u64 = 0x0C;
+const SYS_REMOVE:        u64 = 0x0E;
+const SYS_CLOCK:         u64 = 0x10;
+const SYS_TIME:          u64 = 0x11;
+const SYS_ERRNO:         u64 = 0x13;
+const SYS_GET_CMDLINE:   u64 = 0x15;
+const SYS_HEAPINFO:      u64 = 0x16;
+const SYS_EXIT:          u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+// the exit reason of a normal exit, anything else is a failure
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+const CONSOLE: &str = ":tt";
+const FAILED: u64 = u64::MAX; // -1
+
+const EBADF:  i64 = 9;
+const EACCES: i64 = 13;
+const EINVAL: i64 = 22;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Semihosting {
+    pub root:        PathBuf,
+    pub cmdline:     String,
+    pub errno:       i64,
+    pub next_handle: u64,
+    pub status:      Option<FinisherStatus>,
+    #[serde(skip)]
+    pub files:       HashMap<u64, HostFile>,
+    #[serde(skip)]
+    pub start:       Option<Instant>,
+}
+
+pub fn new_semihosting(root: &str, cmdline: &str) -> Semihosting {
+    return Semihosting {
+        root:        PathBuf::from(root),
+        cmdline:     String::from(cmdline),
+        errno:       0,
+        next_handle: 1,
+        status:      None,
+        files:       HashMap::new(),
+        start:       Some(Instant::now()),
+    };
+}
+
+fn ram_word(sim: &Simulator, address: u64) -> Option<u32> {
+    let range = ram_range(sim, address, 4)?;
+    return Some(u32::from_le_bytes(sim.mem[range].try_into().unwrap()));
+}
+
+// the EBREAK at pc is the middle of the semihosting sequence
+pub fn is_semihosting_call(sim: &Simulator, pc: u64) -> bool {
+    return ram_word(sim, pc.wrapping_sub(4)) == Some(SEMIHOSTING_ENTRY)
+        && ram_word(sim, pc) == Some(SEMIHOSTING_EBREAK)
+        && ram_word(sim, pc.wrapping_add(4)) == Some(SEMIHOSTING_EXIT);
+}
+
+impl Semihosting {
+    // name relative to the root, None when it would leave the root
+    fn sandboxed(&self, name: &str) -> Option<PathBuf> {
+        let relative = Path::new(name);
+        if relative.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return None;
+        }
+        let path = self.root.join(relative);
+        // a symbolic link can lead out of the root, dangling ones too: opening them creates the target.
+        // The directories on the way may be links that stay inside, the file itself may not be one
+        if fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink()) {
+            return None;
+        }
+        let root = self.root.canonicalize().ok()?;
+        let parent = path.parent()?.canonicalize().ok()?;
+        return if parent.starts_with(&root) { Some(path) } else { None };
+    }
+
+    fn add_file(&mut self, file: HostFile) -> u64 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.files.insert(handle, file);
+        return handle;
+    }
+}
+
+fn guest_name(sim: &Simulator, address: u64, length: u64) -> Result<String, i64> {
+    return Ok(String::from_utf8_lossy(guest_bytes(sim, address, length)?).to_string());
+}
+
+// the EBREAK of the sequence, pc points at it
+pub fn semihosting_call(sim: &mut Simulator, state: &mut CpuState) {
+    // continue after the EBREAK, the srai is a HINT
+    state.pc = state.pc.wrapping_add(4);
+    let operation = state.regs[10];
+    let parameter = state.regs[11];
+    let result = match semihosting_operation(sim, operation, parameter) {
+        Ok(value) => value,
+        Err(errno) => {
+            sim.devices.semihosting.as_mut().unwrap().errno = errno;
+            FAILED
+        },
+    };
+    state.regs[10] = result;
+}
+
+fn semihosting_operation(sim: &mut Simulator, operation: u64, parameter: u64) -> Result<u64, i64> {
+    let argument = |sim: &Simulator, n: u64| guest_u64(sim, parameter + 8 * n);
+    return match operation {
+        SYS_OPEN => {
+            let name = guest_name(sim, argument(sim, 0)?, argument(sim, 2)?)?;
+            let mode = argument(sim, 1)?;
+            let semihosting = sim.devices.semihosting.as_mut().unwrap();
+            if name == CONSOLE {
+                let file = match mode {
+                    0..=3 => HostFile::Stdin,
+                    4..=7 => HostFile::Stdout,
+                    _     => HostFile::Stderr,
+                };
+                return Ok(semihosting.add_file(file));
+            }
+            let path = semihosting.sandboxed(&name).ok_or(EACCES)?;
+            // fopen modes r, rb, r+, r+b, w, wb, w+, w+b, a, ab, a+, a+b
+            let mut options = fs::OpenOptions::new();
+            let update = mode & 2 != 0;
+            match mode >> 2 {
+                0 => options.read(true).write(update),
+                1 => options.write(true).read(update).create(true).truncate(true),
+                2 => options.append(true).read(update).create(true),
+                _ => return Err(EINVAL),
+            };
+            let file = options.open(&path).map_err(host_error)?;
+            Ok(semihosting.add_file(HostFile::File(file)))
+        },
+        SYS_CLOSE => {
+            let handle = argument(sim, 0)?;
+            match sim.devices.semihosting.as_mut().unwrap().files.remove(&handle) {
+                Some(_) => Ok(0),
+                None => Err(EBADF),
+            }
+        },
+        SYS_WRITEC => {
+            let c = guest_bytes(sim, parameter, 1)?[0];
+            sim.uart_out.push(c);
+            Ok(0)
+        },
+        SYS_WRITE0 => {
+            let start = ram_range(sim, parameter, 0).ok_or(EINVAL)?.start;
+            let length = sim.mem[start..].iter().position(|b| *b == 0).ok_or(EINVAL)?;
+            sim.uart_out.extend_from_slice(&sim.mem[start..start + length]);
+            Ok(0)
+        },
+        SYS_WRITE => {
+            let (handle, buffer, length) = (argument(sim, 0)?, argument(sim, 1)?, argument(sim, 2)?);
+            let range = ram_range(sim, buffer, length).ok_or(EINVAL)?;
+            let data = &sim.mem[range];
+            let written = match sim.devices.semihosting.as_mut().unwrap().files.get_mut(&handle) {
+                Some(HostFile::Stdout) => {
+                    sim.uart_out.extend_from_slice(data);
+                    Ok(data.len())
+                },
+                Some(HostFile::Stderr) => std::io::stderr().write_all(data).map(|_| data.len()),
+                Some(HostFile::File(file)) => file.write_all(data).map(|_| data.len()),
+                _ => return Err(EBADF),
+            };
+            Ok(length - written.map_err(host_error)? as u64)
+        },
+        SYS_READ => {
+            let (handle, buffer, length) = (argument(sim, 0)?, argument(sim, 1)?, argument(sim, 2)?);
+            let range = ram_range(sim, buffer, length).ok_or(EINVAL)?;
+            memory_written(sim, 0, buffer, buffer, length);
+            let semihosting = sim.devices.semihosting.as_mut().unwrap();
+            let data = &mut sim.mem[range];
+            let count = match semihosting.files.get_mut(&handle) {
+                Some(HostFile::Stdin) => std::io::stdin().read(data),
+                Some(HostFile::File(file)) => {
+                    // fill the buffer unless the end of the file comes first
+                    let mut done = 0;
+                    loop {
+                        match file.read(&mut data[done..]) {
+                            Ok(0) => break Ok(done),
+                            Ok(count) => done += count,
+                            Err(e) => break Err(e),
+                        }
+                        if done == data.len() {
+                            break Ok(done);
+                        }
+                    }
+                },
+                _ => return Err(EBADF),
+            };
+            Ok(length - count.map_err(host_error)? as u64)
+        },
+        SYS_READC => {
+            let mut c = [0u8];
+            std::io::stdin().read_exact(&mut c).map_err(host_error)?;
+            Ok(c[0] as u64)
+        },
+        SYS_ISTTY => {
+            let handle = argument(sim, 0)?;
+            match sim.devices.semihosting.as_ref().unwrap().files.get(&handle) {
+                Some(HostFile::File(_)) => Ok(0),
+                Some(_) => Ok(1),
+                None => Err(EBADF),
+            }
+        },
+        SYS_SEEK => {
+            let (handle, position) = (argument(sim, 0)?, argument(sim, 1)?);
+            match sim.devices.semihosting.as_mut().unwrap().files.get_mut(&handle) {
+                Some(HostFile::File(file)) => file.seek(SeekFrom::Start(position)).map(|_| 0).map_err(host_error),
+                _ => Err(EBADF),
+            }
+        },
+        SYS_FLEN => {
+            let handle = argument(sim, 0)?;
+            match sim.devices.semihosting.as_ref().unwrap().files.get(&handle) {
+                Some(HostFile::File(file)) => file.metadata().map(|m| m.len()).map_err(host_error),
+                _ => Err(EBADF),
+            }
+        },
+        SYS_REMOVE => {
+            let name = guest_name(sim, argument(sim, 0)?, argument(sim, 1)?)?;
+            let semihosting = sim.devices.semihosting.as_ref().unwrap();
+            // the host error code is the result, not -1
+            let result = match semihosting.sandboxed(&name) {
+                Some(path) => fs::remove_file(path).map_or_else(|e| host_error(e) as u64, |_| 0),
+                None => EACCES as u64,
+            };
+            Ok(result)
+        },
+        SYS_CLOCK => {
+            let start = sim.devices.semihosting.as_ref().unwrap().start;
+            Ok(start.map_or(0, |s| s.elapsed().as_millis() as u64 / 10))
+        },
+        SYS_TIME => Ok(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())),
+        SYS_ERRNO => Ok(sim.devices.semihosting.as_ref().unwrap().errno as u64),
+        SYS_GET_CMDLINE => {
+            let (buffer, length) = (argument(sim, 0)?, argument(sim, 1)?);
+            let cmdline = format!("{}\0", sim.devices.semihosting.as_ref().unwrap().cmdline);
+            if cmdline.len() as u64 > length {
+                return Err(EINVAL);
+            }
+            guest_write(sim, buffer, cmdline.as_bytes())?;
+            guest_write(sim, parameter + 8, &(cmdline.len() as u64 - 1).to_le_bytes())?;
+            Ok(0)
+        },
+        SYS_HEAPINFO => {
+            let block = guest_u64(sim, parameter)?;
+            guest_write(sim, block, &[0u8; 32])?;
+            Ok(0)
+        },
+        SYS_EXIT | SYS_EXIT_EXTENDED => {
+            let (reason, code) = if operation == SYS_EXIT && parameter == ADP_STOPPED_APPLICATION_EXIT {
+                // the 32-bit form: the reason itself, no exit code
+                (parameter, 0)
+            } else {
+                (argument(sim, 0)?, argument(sim, 1)?)
+            };
+            let status = match (reason, code) {
+                (ADP_STOPPED_APPLICATION_EXIT, 0) => FinisherStatus::Pass,
+                (ADP_STOPPED_APPLICATION_EXIT, code) => FinisherStatus::Fail((code as u16).max(1)),
+                // an exception or an abort
+                (_, _) => FinisherStatus::Fail(1),
+            };
+            sim.devices.semihosting.as_mut().unwrap().status = Some(status);
+            Ok(0)
+        },
+        _ => {
+            println!("WARN: unsupported semihosting operation 0x{:X}", operation);
+            Err(EINVAL)
+        },
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_stay_inside_of_the_root() {
+        let root = std::env::temp_dir().join(format!("ar64-semihosting-{}", std::process::id()));
+        let sandbox = root.join("sandbox");
+        _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(sandbox.join("sub")).unwrap();
+        fs::write(root.join("outside"), "x").unwrap();
+        let semihosting = new_semihosting(sandbox.to_str().unwrap(), "");
+
+        assert_eq!(semihosting.sandboxed("sub/new"), Some(sandbox.join("sub/new")));
+        assert_eq!(semihosting.sandboxed("../outside"), None);
+        assert_eq!(semihosting.sandboxed(root.join("outside").to_str().unwrap()), None);
+        #[cfg(unix)]
+        {
+            // a dangling link would create its target outside of the root
+            std::os::unix::fs::symlink(root.join("created"), sandbox.join("dangling")).unwrap();
+            std::os::unix::fs::symlink(root.join("outside"), sandbox.join("link")).unwrap();
+            std::os::unix::fs::symlink(&root, sandbox.join("up")).unwrap();
+            assert_eq!(semihosting.sandboxed("dangling"), None);
+            assert_eq!(semihosting.sandboxed("link"), None);
+            assert_eq!(semihosting.sandboxed("up/outside"), None);
+            assert_eq!(semihosting.sandboxed("up/new"), None);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
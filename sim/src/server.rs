@@ -0,0 +1,854 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::OwnedWriteHalf;
+
+use crate::sim::*;
+use crate::framebuffer::*;
+use crate::debug::*;
+use crate::history::*;
+use crate::disasm::*;
+use crate::asm::*;
+use crate::sched::*;
+use crate::stream::*;
+use crate::websocket::*;
+use crate::{load_image, parse_address};
+
+/*
+ * The HTTP server of the web GUI, -H port. Every request is a POST of a JSON body:
+ *
+ *      {"device_index": 0, "action": {"name": "step"}}
+ *
+ * "device_index" picks the simulator (session) the action is for, 0 when it is left out. Successful actions answer
+ * 200 with {"simulator_key": index, "sim": the whole simulator, ...} and what the action adds after "sim".
+ *
+ *  actions:
+ *      i) "init":   Creates a new device in its default state, returns its key in "simulator_key"
+ *      i) "load image": Loads the ELF, assembly source or raw image at "location", a path below --image-dir
+ *      i) "step":   Steps 1 clock cycle
+ *      i) "framebuffer sixel": Returns the framebuffer contents as a sixel stream in "sixel"
+ *      i) "break":         Sets a breakpoint at "address", with an optional "condition" such as "a0 == 5 && [sp + 8] < 0x80001000"
+ *      i) "clear break":   Clears the breakpoint at "address"
+ *      i) "watch":         Sets a watchpoint on "length" (default 1) bytes at "address", "kind" is "read", "write" (default) or "access"
+ *      i) "clear watch":   Clears the watchpoints at "address"
+ *      i) "run":           Runs up to "steps" steps (default 100000, at most 10000000) or until a breakpoint, watchpoint, trap or halt,
+ *                          returns why it stopped in "stop". "traps": false keeps running through traps
+ *      i) "history":       Records the last "steps" steps so that they can be undone, 0 turns recording off
+ *      i) "step back":     Undoes "steps" steps (default 1), returns "stop" like "run", reason "begin" when the recording ran out
+ *      i) "reverse continue": Undoes steps until a breakpoint, the write that hit a write or access watchpoint, or the
+ *                          beginning of the recording, at most "steps" (default 100000, at most 10000000). Returns "stop" like "run"
+ *      i) "last write":    The most recent recorded write to "length" (default 1) bytes at "address", in "write":
+ *                          how many steps ago, the HART, its pc and the address written. null when none was recorded
+ *      i) "disassemble":   Disassembles "count" (default 16) instructions from "address" (default the pc) of "hart" (default 0),
+ *                          returns "disassembly", a list of address, bits, text and the symbol that starts there
+ *      i) "patch":         Assembles "source" at "address" (default the pc) of "hart" (default 0) and writes it to memory,
+ *                          returns the "disassembly" of what was written. Lines are separated by newlines or ';'
+ *      i) "schedule":      Picks the HARTs of later steps with "policy" (the --sched syntax) and "seed" (a number or "0x" hex
+ *                          string, default from the clock), returns the seed as a hex string in "seed"
+ *
+ * "init" optionally takes a "framebuffer" config string, same format as the --fb option.
+ * Addresses are numbers, "0x" hex strings or symbol names.
+ *
+ * Requests that fail answer {"error": reason} with a 4xx status: 400 for malformed HTTP, JSON or action arguments,
+ * 403 for an Origin that is not allowed and images outside --image-dir, 404 for an unknown device_index, 405 for
+ * other methods than POST and OPTIONS, 411, 413 and 431 for bodies without a length and requests that are too large.
+ * A panic in the simulator answers 500, the HART that panicked is put back as it was and the session stays usable.
+ *
+ * HTTP/1.1 on tokio, a task per connection: keep-alive (HTTP/1.0 asks for it with "Connection: keep-alive"),
+ * bodies with Content-Length or chunked transfer coding. The server can read files and runs what it is sent, web
+ * pages may only talk to it from the --allow-origin origins (default the dev server of the GUI): requests of other
+ * origins are refused before they do anything, the allowed ones get CORS headers and OPTIONS preflights.
+ * Requests without an Origin do not come from a browser page and are served, the node proxy of the GUI is one.
+ * Actions run on a blocking thread with the lock of their session, a long "run" does not hold up other sessions.
+ *
+ * GET /ws?device_index=N upgrades the connection to a WebSocket that streams the session, runs it continuously and
+ * takes console input, see stream.rs. Handshakes with an Origin outside --allow-origin are refused with 403 as well.
+ *
+ * RFC 9112 HTTP/1.1 https://www.rfc-editor.org/rfc/rfc9112
+ * Fetch standard, CORS protocol https://fetch.spec.whatwg.org/#http-cors-protocol
+ */
+
+const MAX_LINE:      usize = 8192;            // request line, header line or chunk size line
+const MAX_HEADERS:   usize = 100;
+const MAX_BODY:      usize = 16 << 20;
+const IDLE_TIMEOUT:  Duration = Duration::from_secs(60); // keep-alive connections without a request are closed
+const READ_TIMEOUT:  Duration = Duration::from_secs(30); // to read the rest of a request once it started
+const MAX_PATCH:     u64 = 1 << 20;            // bytes a "patch" source may assemble to
+
+// the vite dev server of ar64_web
+const DEFAULT_ORIGINS: [&str; 2] = ["http://localhost:5173", "http://127.0.0.1:5173"];
+
+pub struct ServerConfig {
+    pub allowed_origins: Vec<String>, // Origin headers of the pages that may use the server
+    pub image_dir:       PathBuf,     // "load image" only loads files below it
+}
+
+pub fn default_server_config() -> ServerConfig {
+    return ServerConfig {
+        allowed_origins: DEFAULT_ORIGINS.iter().map(|o| o.to_string()).collect(),
+        image_dir:       PathBuf::from("."),
+    };
+}
+
+#[derive(Debug)]
+struct HttpError {
+    status:  u16,
+    message: String,
+}
+
+fn http_error(status: u16, message: impl Into<String>) -> HttpError {
+    return HttpError { status: status, message: message.into() };
+}
+
+fn bad_request(message: impl Into<String>) -> HttpError {
+    return http_error(400, message);
+}
+
+struct Request {
+    method:  String,
+    target:  String,
+    minor:   u8, // HTTP/1.minor
+    headers: Vec<(String, String)>,
+    body:    Vec<u8>,
+}
+
+impl Request {
+    // the value of a header, names are case-insensitive. Repeated headers are joined with ", "
+    fn header(&self, name: &str) -> Option<String> {
+        let values: Vec<&str> = self.headers.iter().filter(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str()).collect();
+        if values.is_empty() {
+            return None;
+        }
+        return Some(values.join(", "));
+    }
+
+    fn has_token(&self, name: &str, token: &str) -> bool {
+        return self.header(name).is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)));
+    }
+
+    fn keep_alive(&self) -> bool {
+        if self.minor == 0 {
+            return self.has_token("connection", "keep-alive");
+        }
+        return !self.has_token("connection", "close");
+    }
+}
+
+struct Response {
+    status:  u16,
+    headers: Vec<(&'static str, String)>,
+    body:    String,
+}
+
+fn json_response(status: u16, body: String) -> Response {
+    return Response { status: status, headers: vec![("Content-Type", String::from("application/json"))], body: body };
+}
+
+fn error_response(error: &HttpError) -> Response {
+    return json_response(error.status, serde_json::json!({ "error": error.message }).to_string());
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    return match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        505 => "HTTP Version Not Supported",
+        _   => "",
+    };
+}
+
+pub fn server_loop(port_number: u16, config: ServerConfig) -> Result<(), String> {
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| format!("can not start the server: {}", e))?;
+    return runtime.block_on(serve(port_number, Arc::new(config)));
+}
+
+async fn serve(port_number: u16, config: Arc<ServerConfig>) -> Result<(), String> {
+    let listener = TcpListener::bind(("127.0.0.1", port_number)).await.map_err(|e| format!("can not listen on port {}: {}", port_number, e))?;
+    println!("INFO server listening on 127.0.0.1:{}", port_number);
+    println!("INFO server: pages of {} may use it, images are loaded from {}", config.allowed_origins.join(", "), config.image_dir.display());
+    let sessions = Arc::new(Mutex::new(Sessions { simulators: HashMap::new(), next_index: 0 }));
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve_connection(sessions.clone(), config.clone(), stream));
+            },
+            Err(e) => {
+                // out of file descriptors and the like, accepting again later may work
+                println!("WARN server: accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            },
+        }
+    }
+}
+
+// requests of one connection, one after the other until the client or an error closes it
+async fn serve_connection(sessions: SharedSessions, config: Arc<ServerConfig>, stream: TcpStream) {
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+    loop {
+        let request = match read_request(&mut reader).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(error) => {
+                // the framing of what follows is unknown, answer and close
+                if error.status != 0 {
+                    _ = write_response(&mut write, &error_response(&error), false, None).await;
+                }
+                return;
+            },
+        };
+        if request.method == "GET" && request.has_token("upgrade", "websocket") {
+            match websocket_handshake(&request, &config) {
+                Ok((key, accept)) => serve_websocket(sessions, key, accept, reader, write).await,
+                Err(response) => _ = write_response(&mut write, &response, false, None).await,
+            }
+            return;
+        }
+        let keep_alive = request.keep_alive();
+        let origin = request.header("origin");
+        let response = match origin.as_deref() {
+            Some(origin) if !config.allowed_origins.iter().any(|o| o == origin) => {
+                println!("WARN server: refused a request of {}", origin);
+                error_response(&http_error(403, format!("origin {} is not allowed, see --allow-origin", origin)))
+            },
+            _ => respond(&sessions, &config, request).await,
+        };
+        let cors = origin.filter(|origin| config.allowed_origins.contains(origin));
+        if write_response(&mut write, &response, keep_alive, cors.as_deref()).await.is_err() || !keep_alive {
+            return;
+        }
+    }
+}
+
+/*
+ * The device_index and the Sec-WebSocket-Accept of an upgrade to /ws, RFC 6455 4.2.1.
+ * CORS does not apply to WebSockets, a page of any origin could open one: the Origin is checked here (RFC 6455 10.2).
+ */
+fn websocket_handshake(request: &Request, config: &ServerConfig) -> Result<(i32, String), Response> {
+    let (path, query) = request.target.split_once('?').unwrap_or((request.target.as_str(), ""));
+    if path != "/ws" {
+        return Err(error_response(&http_error(404, format!("no WebSocket at {}, use /ws", path))));
+    }
+    if let Some(origin) = request.header("origin").filter(|origin| !config.allowed_origins.contains(origin)) {
+        println!("WARN server: refused a WebSocket of {}", origin);
+        return Err(error_response(&http_error(403, format!("origin {} is not allowed, see --allow-origin", origin))));
+    }
+    if request.header("sec-websocket-version").as_deref() != Some("13") {
+        let mut response = error_response(&http_error(426, "only WebSocket version 13 is supported"));
+        response.headers.push(("Sec-WebSocket-Version", String::from("13")));
+        return Err(response);
+    }
+    let key = request.header("sec-websocket-key").unwrap_or_default();
+    if !request.has_token("connection", "upgrade") || !valid_key(&key) {
+        return Err(error_response(&bad_request("invalid WebSocket handshake")));
+    }
+    let mut device_index = 0;
+    for parameter in query.split('&').filter(|p| !p.is_empty()) {
+        if let Some(("device_index", value)) = parameter.split_once('=') {
+            device_index = value.parse::<i32>().map_err(|_| error_response(&bad_request(format!("invalid device_index: {}", value))))?;
+        }
+    }
+    return Ok((device_index, accept_key(&key)));
+}
+
+async fn respond(sessions: &SharedSessions, config: &Arc<ServerConfig>, request: Request) -> Response {
+    match request.method.as_str() {
+        "POST" => {},
+        "OPTIONS" => {
+            // CORS preflight
+            let allowed = request.header("access-control-request-headers").unwrap_or(String::from("Content-Type"));
+            return Response {
+                status:  204,
+                headers: vec![
+                    ("Access-Control-Allow-Methods", String::from("POST, OPTIONS")),
+                    ("Access-Control-Allow-Headers", allowed),
+                    ("Access-Control-Max-Age", String::from("86400")),
+                ],
+                body:    String::new(),
+            };
+        },
+        method => {
+            let mut response = error_response(&http_error(405, format!("{} is not supported, POST a JSON action or GET /ws to stream", method)));
+            response.headers.push(("Allow", String::from("POST, OPTIONS")));
+            return response;
+        },
+    }
+
+    let sessions = sessions.clone();
+    let config = config.clone();
+    let result = tokio::task::spawn_blocking(move || api_request(&sessions, &config.image_dir, &request.body)).await;
+    return match result {
+        Ok(Ok(body)) => json_response(200, body),
+        Ok(Err(error)) => {
+            println!("ERROR server: {}", error.message);
+            error_response(&error)
+        },
+        Err(join_error) => {
+            let message = join_error.try_into_panic().map_or(String::from("unknown"), |panic| panic_message(&*panic));
+            println!("ERROR server: the simulator panicked: {}", message);
+            error_response(&http_error(500, format!("the simulator panicked: {}", message)))
+        },
+    };
+}
+
+// cors is the allowed Origin of the request, browsers only show the response to pages of that origin
+async fn write_response(write: &mut OwnedWriteHalf, response: &Response, keep_alive: bool, cors: Option<&str>) -> std::io::Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason_phrase(response.status));
+    for (name, value) in response.headers.iter() {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    head += &format!("Content-Length: {}\r\n", response.body.len());
+    if let Some(origin) = cors {
+        head += &format!("Access-Control-Allow-Origin: {}\r\nVary: Origin\r\n", origin);
+    }
+    head += if keep_alive { "Connection: keep-alive\r\n\r\n" } else { "Connection: close\r\n\r\n" };
+    write.write_all(head.as_bytes()).await?;
+    write.write_all(response.body.as_bytes()).await?;
+    return write.flush().await;
+}
+
+/*
+ * The next request of the connection, None when the client closed it between requests.
+ * Errors with status 0 are connections that broke or went idle, there is nobody to answer.
+ */
+async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Request>, HttpError> {
+    // a keep-alive connection may wait for its next request for a while, once it started it has to arrive in time
+    let request_line = loop {
+        match tokio::time::timeout(IDLE_TIMEOUT, read_line(reader, 414)).await {
+            Err(_) => return Err(http_error(0, "idle")),
+            Ok(Ok(None)) => return Ok(None),
+            // clients that end their body with an extra CRLF
+            Ok(Ok(Some(line))) if line.is_empty() => continue,
+            Ok(Ok(Some(line))) => break line,
+            Ok(Err(error)) => return Err(error),
+        }
+    };
+    return match tokio::time::timeout(READ_TIMEOUT, read_rest(reader, &request_line)).await {
+        Err(_) => Err(http_error(408, "the request did not arrive in time")),
+        Ok(result) => result.map(Some),
+    };
+}
+
+async fn read_rest<R: AsyncBufRead + Unpin>(reader: &mut R, request_line: &str) -> Result<Request, HttpError> {
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    let (method, target, version) = match parts.as_slice() {
+        [method, target, version] if !method.is_empty() && !target.is_empty() => (*method, *target, *version),
+        _ => return Err(bad_request(format!("invalid request line: {}", request_line))),
+    };
+    let minor = match version {
+        "HTTP/1.1" => 1,
+        "HTTP/1.0" => 0,
+        _ if version.starts_with("HTTP/") => return Err(http_error(505, format!("{} is not supported", version))),
+        _ => return Err(bad_request(format!("invalid request line: {}", request_line))),
+    };
+
+    let mut headers = vec![];
+    loop {
+        let line = read_line(reader, 431).await?.ok_or(http_error(0, "closed in the header"))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(http_error(431, "too many header fields"));
+        }
+        // no whitespace before the colon and no obsolete line folding
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.contains(|c: char| c.is_ascii_whitespace()) => (name, value),
+            _ => return Err(bad_request(format!("invalid header field: {}", line))),
+        };
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    let mut request = Request { method: method.to_string(), target: target.to_string(), minor: minor, headers: headers, body: vec![] };
+
+    let transfer_encoding = request.header("transfer-encoding");
+    let content_length = request.header("content-length");
+    if let Some(coding) = transfer_encoding {
+        if content_length.is_some() {
+            return Err(bad_request("Content-Length and Transfer-Encoding together"));
+        }
+        if !coding.trim().eq_ignore_ascii_case("chunked") {
+            return Err(http_error(501, format!("transfer coding {} is not supported", coding)));
+        }
+        request.body = read_chunked(reader).await?;
+    } else if let Some(length) = content_length {
+        // repeated fields have to agree
+        let mut lengths = length.split(',').map(|l| l.trim());
+        let first = lengths.next().unwrap_or("");
+        if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) || lengths.any(|l| l != first) {
+            return Err(bad_request(format!("invalid Content-Length: {}", length)));
+        }
+        let length = first.parse::<usize>().unwrap_or(usize::MAX);
+        if length > MAX_BODY {
+            return Err(http_error(413, format!("the body is larger than {} bytes", MAX_BODY)));
+        }
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).await.map_err(|_| http_error(0, "closed in the body"))?;
+    } else if request.method == "POST" {
+        return Err(http_error(411, "a POST needs a Content-Length or a chunked body"));
+    }
+    return Ok(request);
+}
+
+// RFC 9112 7.1, chunk extensions and trailer fields are read and ignored
+async fn read_chunked<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, HttpError> {
+    let mut body = vec![];
+    loop {
+        let line = read_line(reader, 400).await?.ok_or(http_error(0, "closed in a chunk"))?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| bad_request(format!("invalid chunk size: {}", line)))?;
+        if size == 0 {
+            while !read_line(reader, 431).await?.ok_or(http_error(0, "closed in the trailer"))?.is_empty() {}
+            return Ok(body);
+        }
+        if size > MAX_BODY - body.len() {
+            return Err(http_error(413, format!("the body is larger than {} bytes", MAX_BODY)));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await.map_err(|_| http_error(0, "closed in a chunk"))?;
+        if !read_line(reader, 400).await?.ok_or(http_error(0, "closed in a chunk"))?.is_empty() {
+            return Err(bad_request("a chunk is longer than its size"));
+        }
+    }
+}
+
+// a line without its CRLF (or bare LF), None at the end of the stream, too_long is the status of lines over MAX_LINE
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R, too_long: u16) -> Result<Option<String>, HttpError> {
+    let mut line = vec![];
+    let read = (&mut *reader).take(MAX_LINE as u64 + 2).read_until(b'\n', &mut line).await;
+    match read {
+        Ok(0) => return Ok(None),
+        Ok(_) => {},
+        Err(e) => return Err(http_error(0, e.to_string())),
+    }
+    if line.last() != Some(&b'\n') {
+        if line.len() > MAX_LINE {
+            return Err(http_error(too_long, format!("a line is longer than {} bytes", MAX_LINE)));
+        }
+        return Err(http_error(0, "closed in the middle of a line"));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    return String::from_utf8(line).map(Some).map_err(|_| bad_request("a line is not UTF-8"));
+}
+
+/*
+ * Runs the action of a JSON request body, returns the body of the 200 response.
+ * Runs on a blocking thread, a step or a run can take a while.
+ */
+fn api_request(sessions: &SharedSessions, image_dir: &Path, body: &[u8]) -> Result<String, HttpError> {
+    let body: serde_json::Value = serde_json::from_slice(body).map_err(|e| bad_request(format!("invalid JSON: {}", e)))?;
+    let action = &body["action"];
+    let name = action["name"].as_str().ok_or(bad_request("missing action name"))?;
+    let mut key = match &body["device_index"] {
+        serde_json::Value::Null => 0,
+        value => value.as_i64().and_then(|i| i32::try_from(i).ok()).ok_or(bad_request(format!("invalid device_index: {}", value)))?,
+    };
+
+    if name == "init" {
+        let mut sim = default_sim();
+        if let Some(config) = action["framebuffer"].as_str() {
+            sim.devices.framebuffer = Some(parse_framebuffer_config(config).map_err(bad_request)?);
+        }
+        let mut sessions = lock(sessions);
+        key = sessions.next_index;
+        sessions.next_index += 1;
+        sessions.simulators.insert(key, new_session(sim));
+        println!("INFO server: device {} created", key);
+    }
+    let session = lock(sessions).simulators.get(&key).cloned();
+    let session = session.ok_or(http_error(404, format!("no device with device_index {}", key)))?;
+    let mut sim = lock(&session.sim);
+
+    let extra_contents = match name {
+        "init" => String::new(),
+        "step" => {
+            step(&mut sim);
+            String::new()
+        },
+        "load image" => {
+            let location = action["location"].as_str().ok_or(bad_request("missing location"))?;
+            let path = image_path(image_dir, location)?;
+            if load_image(&mut sim, &path).is_err() {
+                return Err(bad_request(sim.log.clone()));
+            }
+            String::new()
+        },
+        "break" | "clear break" | "watch" | "clear watch" | "run" |
+        "history" | "step back" | "reverse continue" | "last write" | "disassemble" | "patch" | "schedule" => {
+            debug_action(&mut sim, name, action).map_err(bad_request)?
+        },
+        "framebuffer sixel" => {
+            let fb = sim.devices.framebuffer.as_ref().ok_or(bad_request("the device has no framebuffer"))?;
+            format!(",\r\n\"sixel\": {}", serde_json::to_string(&fb.to_sixel()).unwrap())
+        },
+        _ => return Err(bad_request(format!("unknown action: {}", name))),
+    };
+
+    publish_changes(&session, key, &mut sim);
+    describe_last_step(&mut sim);
+    let sim_contents = serde_json::to_string(&*sim).map_err(|e| http_error(500, format!("can not serialize the simulator: {}", e)))?;
+    return Ok(format!("{{\"simulator_key\": {key},\r\n\"sim\": {sim_contents}{extra_contents}\r\n}}"));
+}
+
+// the location of a "load image" as a path below the image directory, links and ".." are resolved first
+fn image_path(image_dir: &Path, location: &str) -> Result<String, HttpError> {
+    let dir = image_dir.canonicalize().map_err(|e| http_error(500, format!("image directory {}: {}", image_dir.display(), e)))?;
+    let path = dir.join(location).canonicalize().map_err(|e| bad_request(format!("can not load {}: {}", location, e)))?;
+    if !path.starts_with(&dir) {
+        return Err(http_error(403, format!("{} is not below the image directory {}, see --image-dir", location, dir.display())));
+    }
+    return Ok(path.to_string_lossy().to_string());
+}
+
+// number, "0x" hex or decimal string, or a symbol of the loaded ELF
+fn json_address(sim: &Simulator, value: &serde_json::Value) -> Result<u64, String> {
+    if let Some(address) = value.as_u64() {
+        return Ok(address);
+    }
+    let text = value.as_str().ok_or(String::from("missing address"))?;
+    return parse_address(text).or_else(|_| symbol_address(sim, text).ok_or(format!("unknown address or symbol: {}", text)));
+}
+
+// the breakpoint, watchpoint, run, time-travel, disassemble and scheduler actions, returns what goes into the response
+fn debug_action(sim: &mut Simulator, name: &str, action: &serde_json::Value) -> Result<String, String> {
+    match name {
+        "break" => {
+            let address = json_address(sim, &action["address"])?;
+            let condition = action["condition"].as_str().filter(|c| !c.trim().is_empty()).map(String::from);
+            sim.debugger.add_breakpoint(address, condition)?;
+        },
+        "clear break" => {
+            let address = json_address(sim, &action["address"])?;
+            if !sim.debugger.remove_breakpoint(address) {
+                return Err(format!("no breakpoint at 0x{:X}", address));
+            }
+        },
+        "watch" => {
+            let address = json_address(sim, &action["address"])?;
+            let length = action["length"].as_u64().unwrap_or(1).max(1);
+            let kind = match action["kind"].as_str().unwrap_or("write") {
+                "read"   => WatchKind::Read,
+                "write"  => WatchKind::Write,
+                "access" => WatchKind::Access,
+                kind     => return Err(format!("unknown watchpoint kind: {}", kind)),
+            };
+            sim.debugger.add_watchpoint(Watchpoint { start: address, end: address.wrapping_add(length), kind: kind });
+        },
+        "clear watch" => {
+            let address = json_address(sim, &action["address"])?;
+            let count = sim.debugger.watchpoints.len();
+            sim.debugger.watchpoints.retain(|w| w.start != address);
+            if sim.debugger.watchpoints.len() == count {
+                return Err(format!("no watchpoint at 0x{:X}", address));
+            }
+        },
+        "history" => {
+            sim.history = match action["steps"].as_u64() {
+                Some(0) => None,
+                Some(steps) => Some(new_history(steps as usize)),
+                None => return Err(String::from("missing steps")),
+            };
+        },
+        "step back" | "reverse continue" => {
+            if sim.history.is_none() {
+                return Err(String::from("history is off"));
+            }
+            let stop = match name {
+                "step back" => reverse_steps(sim, run_steps(action, 1)),
+                _ => reverse_continue(sim, run_steps(action, RUN_DEFAULT_STEPS)),
+            };
+            return Ok(format!(",\r\n\"stop\": {}", serde_json::to_string(&stop).unwrap()));
+        },
+        "disassemble" => {
+            let hart = action["hart"].as_u64().unwrap_or(0) as usize;
+            if hart >= sim.states.len() {
+                return Err(format!("no HART {}", hart));
+            }
+            let address = match action.get("address") {
+                Some(address) => json_address(sim, address)?,
+                None => sim.states[hart].pc,
+            };
+            let count = action["count"].as_u64().unwrap_or(DISASSEMBLE_DEFAULT_COUNT) as usize;
+            let instructions = disassemble_range(sim, hart, address, count.min(DISASSEMBLE_MAX_COUNT));
+            return Ok(format!(",\r\n\"disassembly\": {}", serde_json::to_string(&instructions).unwrap()));
+        },
+        "patch" => {
+            let hart = action["hart"].as_u64().unwrap_or(0) as usize;
+            if hart >= sim.states.len() {
+                return Err(format!("no HART {}", hart));
+            }
+            let address = match action.get("address") {
+                Some(address) => json_address(sim, address)?,
+                None => sim.states[hart].pc,
+            };
+            let source = action["source"].as_str().ok_or(String::from("missing source"))?.replace(';', "\n");
+            let assembly = assemble(&source, address, MAX_PATCH)?;
+            if !debug_write_memory(sim, hart, address, &assembly.bytes) {
+                return Err(format!("can not write {} bytes at 0x{:X}", assembly.bytes.len(), address));
+            }
+            let end = address.wrapping_add(assembly.bytes.len() as u64);
+            let mut instructions = disassemble_range(sim, hart, address, assembly.bytes.len() / 2);
+            instructions.retain(|i| i.address < end);
+            sim.log = format!("patched {} bytes at 0x{:X}", assembly.bytes.len(), address);
+            return Ok(format!(",\r\n\"disassembly\": {}", serde_json::to_string(&instructions).unwrap()));
+        },
+        "schedule" => {
+            let policy = parse_policy(action["policy"].as_str().ok_or(String::from("missing policy"))?)?;
+            // a string keeps seeds above 2^53 exact in JavaScript
+            let seed = match &action["seed"] {
+                serde_json::Value::Null => clock_seed(),
+                serde_json::Value::String(text) => parse_address(text).map_err(|_| format!("invalid seed: {}", text))?,
+                value => value.as_u64().ok_or(format!("invalid seed: {}", value))?,
+            };
+            sim.scheduler = new_scheduler(policy, seed, sim.states.len());
+            sim.log = format!("scheduler {}, seed 0x{:X}", policy_name(&sim.scheduler.policy), seed);
+            return Ok(format!(",\r\n\"seed\": \"0x{:X}\"", seed));
+        },
+        "last write" => {
+            if sim.history.is_none() {
+                return Err(String::from("history is off"));
+            }
+            let address = json_address(sim, &action["address"])?;
+            let length = action["length"].as_u64().unwrap_or(1).max(1);
+            let write = match last_write(sim, address, length) {
+                Some((steps_ago, hart, pc, address)) => serde_json::json!({
+                    "steps_ago": steps_ago,
+                    "hart":      hart,
+                    "pc":        pc,
+                    "address":   address,
+                }),
+                None => serde_json::Value::Null,
+            };
+            return Ok(format!(",\r\n\"write\": {}", write));
+        },
+        _ => {
+            let steps = run_steps(action, RUN_DEFAULT_STEPS);
+            let stop_on_trap = action["traps"].as_bool().unwrap_or(true);
+            let stop = run_until(sim, steps, stop_on_trap);
+            return Ok(format!(",\r\n\"stop\": {}", serde_json::to_string(&stop).unwrap()));
+        },
+    }
+    return Ok(String::new());
+}
+
+// steps of a "run" that does not say how many, and the most one request runs: the session answers nothing else
+// and the request holds a worker thread while it runs, the WebSocket "run" is for running longer
+const RUN_DEFAULT_STEPS: u64 = 100000;
+const RUN_MAX_STEPS:     u64 = 10_000_000;
+
+fn run_steps(action: &serde_json::Value, default: u64) -> u64 {
+    return action["steps"].as_u64().unwrap_or(default).min(RUN_MAX_STEPS);
+}
+
+// instructions of a "disassemble" that does not say how many, and the most it returns
+const DISASSEMBLE_DEFAULT_COUNT: u64 = 16;
+const DISASSEMBLE_MAX_COUNT: usize = 4096;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::tcp::OwnedReadHalf;
+
+    async fn parse(mut bytes: &[u8]) -> Result<Option<Request>, HttpError> {
+        return read_request(&mut bytes).await;
+    }
+
+    #[tokio::test]
+    async fn reads_content_length_and_chunked_bodies() {
+        let request = parse(b"POST / HTTP/1.1\r\nHost: x\r\ncontent-LENGTH: 5\r\n\r\nhello").await.unwrap().unwrap();
+        assert_eq!((request.method.as_str(), request.target.as_str(), request.minor), ("POST", "/", 1));
+        assert_eq!(request.body, b"hello");
+        assert_eq!(request.header("Content-Length").as_deref(), Some("5"));
+        assert!(request.keep_alive());
+
+        let chunked = b"POST /x HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4;name=value\r\nhell\r\n1\r\no\r\n0\r\nTrailer: x\r\n\r\n";
+        assert_eq!(parse(chunked).await.unwrap().unwrap().body, b"hello");
+
+        let request = parse(b"GET / HTTP/1.0\r\nA: 1\r\na: 2\r\n\r\n").await.unwrap().unwrap();
+        assert_eq!(request.header("a").as_deref(), Some("1, 2"));
+        assert!(!request.keep_alive());
+        let request = parse(b"GET / HTTP/1.1\r\nConnection: Upgrade, close\r\n\r\n").await.unwrap().unwrap();
+        assert!(!request.keep_alive());
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_come_one_after_the_other() {
+        let mut bytes: &[u8] = b"POST /1 HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}\r\nPOST /2 HTTP/1.1\nContent-Length: 0\n\n";
+        assert_eq!(read_request(&mut bytes).await.unwrap().unwrap().target, "/1");
+        // the extra CRLF after the first body is skipped
+        assert_eq!(read_request(&mut bytes).await.unwrap().unwrap().target, "/2");
+        assert!(read_request(&mut bytes).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_what_it_can_not_frame() {
+        let too_long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE));
+        let too_large = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+        let cases: [(&[u8], u16); 13] = [
+            (b"POST / HTTP/1.1\r\n\r\n", 411),
+            (b"POST / HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n", 400),
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n", 501),
+            (b"POST / HTTP/1.1\r\nContent-Length: 1a\r\n\r\n", 400),
+            (b"POST / HTTP/1.1\r\nContent-Length: 1, 2\r\n\r\nab", 400),
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabc\r\n0\r\n\r\n", 400),
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n", 400),
+            (b"GET / HTTP/2.0\r\n\r\n", 505),
+            (b"GET /\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nName : value\r\n\r\n", 400),
+            (too_long.as_bytes(), 414),
+            (too_large.as_bytes(), 413),
+            // closed in the body, nobody to answer
+            (b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc", 0),
+        ];
+        for (bytes, status) in cases {
+            let result = parse(bytes).await;
+            assert_eq!(result.err().map(|e| e.status), Some(status), "{}", String::from_utf8_lossy(&bytes[..bytes.len().min(80)]));
+        }
+        let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "A: b\r\n".repeat(MAX_HEADERS + 1));
+        assert_eq!(parse(many_headers.as_bytes()).await.err().map(|e| e.status), Some(431));
+    }
+
+    // status line, headers and body of the next response on the connection
+    async fn read_response(reader: &mut BufReader<OwnedReadHalf>) -> (String, Vec<(String, String)>, String) {
+        let status = read_line(reader, 400).await.unwrap().unwrap();
+        let mut headers = vec![];
+        loop {
+            let line = read_line(reader, 400).await.unwrap().unwrap();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+        }
+        let length = headers.iter().find(|(n, _)| n == "content-length").unwrap().1.parse::<usize>().unwrap();
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await.unwrap();
+        return (status, headers, String::from_utf8(body).unwrap());
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        return headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+    }
+
+    #[tokio::test]
+    async fn answers_allowed_origins_with_cors_and_refuses_the_others() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sessions = Arc::new(Mutex::new(Sessions { simulators: HashMap::new(), next_index: 0 }));
+        let config = Arc::new(ServerConfig { allowed_origins: vec![String::from("http://gui")], image_dir: PathBuf::from(".") });
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve_connection(sessions, config, stream).await;
+        });
+
+        let (read, mut write) = TcpStream::connect(("127.0.0.1", port)).await.unwrap().into_split();
+        let mut reader = BufReader::new(read);
+        let init = r#"{"action": {"name": "init"}}"#;
+        let post = |origin: &str| format!("POST / HTTP/1.1\r\nOrigin: {}\r\nContent-Length: {}\r\n\r\n{}", origin, init.len(), init);
+
+        // a page of another origin does not get to run anything
+        write.write_all(post("http://evil").as_bytes()).await.unwrap();
+        let (status, headers, body) = read_response(&mut reader).await;
+        assert_eq!(status, "HTTP/1.1 403 Forbidden");
+        assert_eq!(header(&headers, "access-control-allow-origin"), None);
+        assert!(body.contains("not allowed"), "{}", body);
+
+        write.write_all(b"OPTIONS / HTTP/1.1\r\nOrigin: http://gui\r\nAccess-Control-Request-Headers: content-type\r\n\r\n").await.unwrap();
+        let (status, headers, _) = read_response(&mut reader).await;
+        assert_eq!(status, "HTTP/1.1 204 No Content");
+        assert_eq!(header(&headers, "access-control-allow-origin"), Some("http://gui"));
+        assert_eq!(header(&headers, "access-control-allow-headers"), Some("content-type"));
+        assert_eq!(header(&headers, "vary"), Some("Origin"));
+
+        // pipelined: both go out before the first answer is read
+        write.write_all(format!("{}{}", post("http://gui"), "GET / HTTP/1.1\r\nConnection: close\r\n\r\n").as_bytes()).await.unwrap();
+        let (status, headers, body) = read_response(&mut reader).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(header(&headers, "access-control-allow-origin"), Some("http://gui"));
+        assert_eq!(header(&headers, "connection"), Some("keep-alive"));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["simulator_key"], 0);
+        let (status, headers, _) = read_response(&mut reader).await;
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(header(&headers, "connection"), Some("close"));
+        assert_eq!(header(&headers, "access-control-allow-origin"), None);
+        assert!(read_line(&mut reader, 400).await.unwrap().is_none(), "the connection stays open after Connection: close");
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrades_check_the_origin_before_anything_else() {
+        let config = ServerConfig { allowed_origins: vec![String::from("http://gui")], image_dir: PathBuf::from(".") };
+        let handshake = |target: &str, extra: &str| {
+            let head = format!("GET {} HTTP/1.1\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n", target, extra);
+            let config = &config;
+            async move {
+                let request = parse(head.as_bytes()).await.unwrap().unwrap();
+                return websocket_handshake(&request, config).map_err(|response| response.status);
+            }
+        };
+        let accepted = Ok((3, String::from("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")));
+        assert_eq!(handshake("/ws?device_index=3", "Sec-WebSocket-Version: 13\r\nOrigin: http://gui\r\n").await, accepted);
+        // clients that are not browsers send no Origin
+        assert_eq!(handshake("/ws?device_index=3", "Sec-WebSocket-Version: 13\r\n").await, accepted);
+        assert_eq!(handshake("/ws?device_index=3", "Sec-WebSocket-Version: 13\r\nOrigin: http://evil\r\n").await, Err(403));
+        assert_eq!(handshake("/ws?device_index=3", "Sec-WebSocket-Version: 8\r\nOrigin: http://evil\r\n").await, Err(403));
+        assert_eq!(handshake("/ws?device_index=3", "Sec-WebSocket-Version: 8\r\n").await, Err(426));
+        assert_eq!(handshake("/ws?device_index=x", "Sec-WebSocket-Version: 13\r\n").await, Err(400));
+        assert_eq!(handshake("/other", "Sec-WebSocket-Version: 13\r\n").await, Err(404));
+    }
+
+    #[test]
+    fn patches_code_on_read_execute_pages() {
+        let mut sim = crate::sim::tests::paged_sim();
+        let action = serde_json::json!({"name": "patch", "address": "0x8ff8", "source": "addi a0, a0, 1; addi a1, a1, 2"});
+        let response = debug_action(&mut sim, "patch", &action).unwrap();
+        assert!(response.contains("addi a0, a0, 1"), "{}", response);
+        assert_eq!(&sim.mem[0x5ff8..0x5ffc], &0x00150513u32.to_le_bytes());
+        // the second instruction would land on the unmapped page, nothing is written
+        let action = serde_json::json!({"name": "patch", "address": "0x8ffc", "source": "nop; nop"});
+        assert!(debug_action(&mut sim, "patch", &action).is_err());
+        assert_eq!(&sim.mem[0x5ffc..0x6000], &0x00258593u32.to_le_bytes());
+    }
+
+    #[test]
+    fn images_are_only_loaded_below_the_image_directory() {
+        let root = std::env::temp_dir().join(format!("ar64-image-dir-{}", std::process::id()));
+        let images = root.join("images");
+        _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(images.join("sub")).unwrap();
+        std::fs::write(images.join("sub").join("a.s"), "nop").unwrap();
+        std::fs::write(root.join("outside.s"), "nop").unwrap();
+
+        let expected = images.canonicalize().unwrap().join("sub").join("a.s");
+        assert_eq!(image_path(&images, "sub/a.s").unwrap(), expected.to_string_lossy());
+        assert_eq!(image_path(&images, "sub/../sub/a.s").unwrap(), expected.to_string_lossy());
+        assert_eq!(image_path(&images, "../outside.s").unwrap_err().status, 403);
+        assert_eq!(image_path(&images, root.join("outside.s").to_str().unwrap()).unwrap_err().status, 403);
+        assert_eq!(image_path(&images, "missing.s").unwrap_err().status, 400);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("outside.s"), images.join("link.s")).unwrap();
+            assert_eq!(image_path(&images, "link.s").unwrap_err().status, 403);
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -8,24 +8,44 @@ use std::collections::HashMap;
 
 use serde::{Serialize, Deserialize};
 
+use crate::framebuffer::*;
+use crate::test_finisher::*;
+use crate::htif::*;
+use crate::elf::*;
+use crate::clint::*;
+use crate::plic::*;
+use crate::uart::*;
+use crate::compressed::*;
+use crate::sbi::*;
+use crate::syscall::*;
+use crate::semihosting::*;
+use crate::debug::*;
+use crate::history::*;
+use crate::commit_log::*;
+use crate::disasm::*;
+use crate::decode::*;
+#[cfg(feature = "jit")]
+use crate::jit::*;
+use crate::smp::*;
+use crate::sched::*;
+
 macro_rules! declare_csr_consts {
     ($vis:vis $GROUP:ident : &[$T:ty] = [$($name:ident = $value:expr; $mask:expr),* $(,)?]) => {
-        mod csr_address {
-            use std::collections::HashMap;
+        pub mod csr_address {
+            use super::CsrInfo;
 
 
         $vis const $GROUP: &[$T] = &[$($name),*];
-        pub fn get_address_to_name() -> HashMap<$T, String>{
-            return HashMap::from([
-                    $(($value, String::from(stringify!($name))),)*
-            ]);
-        }
-
-        pub fn get_address_to_mask() -> HashMap<$T, u64>{
-            return HashMap::from([
-                    $(($value, $mask),)*
-            ]);
-        }
+        // what every CSR is, in the order of CSR_ADDRESSES
+        pub const CSR_INFO: &[CsrInfo] = &[
+            $(CsrInfo {
+                address:   $value,
+                name:      stringify!($name),
+                mask:      $mask,
+                privilege: (($value >> 8) & 0b11) as u8,
+                read_only: ($value >> 10) & 0b11 == 0b11,
+            },)*
+        ];
 
         $(
             $vis const $name: $T = $value;
@@ -36,59 +56,61 @@ macro_rules! declare_csr_consts {
 
 
 declare_csr_consts!(pub CSR_ADDRESSES: &[u32] = [
+    // the mask holds the bits an instruction can write (WARL), the other bits keep their value
+
     // Supervisor Trap Setup
-    SSTATUS    = 0x100; 0xFFFFFFFF, 
-    SIE        = 0x104; 0xFFFFFFFF, // interrupt-enable register
-    STVEC      = 0x105; 0xFFFFFFFF, // trap handler base address
-    SCONTEREN  = 0x106; 0xFFFFFFFF, // counter enable
+    SSTATUS    = 0x100; 0x00000000000C0122, // view of MSTATUS: SIE SPIE SPP SUM MXR
+    SIE        = 0x104; 0x0000000000000222, // interrupt-enable register, view of MIE through MIDELEG
+    STVEC      = 0x105; 0xFFFFFFFFFFFFFFFD, // trap handler base address
+    SCONTEREN  = 0x106; 0x00000000FFFFFFFF, // counter enable
 
     // Supervisor Configuration
-    SENCVFG    = 0x10A; 0xFFFFFFFF, // environment configuration register
+    SENCVFG    = 0x10A; 0x0000000000000000, // environment configuration register
 
     // Supervisor Trap Handling
-    SSCRATCH   = 0x140; 0xFFFFFFFF, // scratch reg for supervisor trap handlers
-    SEPC       = 0x141; 0xFFFFFFFF, // Exception program counter
-    SCAUSE     = 0x142; 0xFFFFFFFF, // trap cause
-    STVAL      = 0x143; 0xFFFFFFFF, // bad address or instruction
-    SIP        = 0x144; 0xFFFFFFFF, // interrupt pending
+    SSCRATCH   = 0x140; 0xFFFFFFFFFFFFFFFF, // scratch reg for supervisor trap handlers
+    SEPC       = 0x141; 0xFFFFFFFFFFFFFFFE, // Exception program counter
+    SCAUSE     = 0x142; 0xFFFFFFFFFFFFFFFF, // trap cause
+    STVAL      = 0x143; 0xFFFFFFFFFFFFFFFF, // bad address or instruction
+    SIP        = 0x144; 0x0000000000000002, // interrupt pending, view of MIP through MIDELEG
 
     // Supervisor Protection and Translation
-    SATP       = 0x180; 0xFFFFFFFF, // Address Translation and Protection
+    SATP       = 0x180; 0xF0000FFFFFFFFFFF, // Address Translation and Protection, Bare or Sv39, no ASIDs
 
     // Debut/Trace Registers
     SCONTEXT   = 0x5A8; 0xFFFFFFFF, // 
     // Hypervisor *
 
     // Machine Information Registers
-    //MVENDORID = 0xF11; 0xFFFFFFFF, // vendor ID
-    //MARCHID   = 0xF12; 0xFFFFFFFF, // arch ID
-    //MIMPID    = 0xF13; 0xFFFFFFFF, // implementation ID
-    MHARTID    = 0xF14; 0xFFFFFFFF,
+    MVENDORID  = 0xF11; 0x00000000, // vendor ID
+    MARCHID    = 0xF12; 0x00000000, // arch ID
+    MIMPID     = 0xF13; 0x00000000, // implementation ID
+    MHARTID    = 0xF14; 0x00000000,
     //MCONFIGPTR = 0xF15; 0xFFFFFFFF, // physical address of config ptr, not yet standardized!
 
     //Machine Trap Setup
-    MSTATUS    = 0x300; 0xFFFFFFFF, // HART operating state
-    MISA       = 0x301; 0xFFFFFFFF, // WARL, ISA and extensions
-    MEDELEG    = 0x302; 0xFFFFFFFF, // WARL, exception delegation reg, If AND ONLY IF S-mode exists
-    MIDELEG    = 0x303; 0xFFFFFFFF, // WARL, interrupt delegation reg, If AND ONLY IF S-mode exists
-    MIE        = 0x304; 0xFFFFFFFF, // WARL, interrupt enable
-    MTVEC      = 0x305; 0xFFFFFFFF, // WARL, trap handler base address reg
-    MCOUNTEREN = 0x306; 0xFFFFFFFF, // counter enable
+    MSTATUS    = 0x300; 0x00000000007E19AA, // HART operating state, FS = 0 and UXL = SXL = 64 bit are fixed
+    MISA       = 0x301; 0x00000000, // WARL, ISA and extensions
+    MEDELEG    = 0x302; 0x000000000000B3FF, // WARL, exception delegation reg, If AND ONLY IF S-mode exists
+    MIDELEG    = 0x303; 0x0000000000000222, // WARL, interrupt delegation reg, If AND ONLY IF S-mode exists
+    MIE        = 0x304; 0x0000000000000AAA, // WARL, interrupt enable
+    MTVEC      = 0x305; 0xFFFFFFFFFFFFFFFD, // WARL, trap handler base address reg
+    MCOUNTEREN = 0x306; 0x00000000FFFFFFFF, // counter enable
 
     // Machine Trap Handling
-    MSCRATCH  = 0x340; 0xFFFFFFFF, // register for trap handler
-    MEPC      = 0x341; 0xFFFFFFFF, // WARL, machine exception program counter
-    MCAUSE    = 0x342; 0xFFFFFFFF, // WLRL, trap cause
-    //MTVAL   = 0x343; 0xFFFFFFFF, // WARL, bad address or instruction, optional
-    MIP       = 0x344; 0xFFFFFFFF, // WARL, interrupt pending
+    MSCRATCH  = 0x340; 0xFFFFFFFFFFFFFFFF, // register for trap handler
+    MEPC      = 0x341; 0xFFFFFFFFFFFFFFFE, // WARL, machine exception program counter
+    MCAUSE    = 0x342; 0xFFFFFFFFFFFFFFFF, // WLRL, trap cause
+    MTVAL     = 0x343; 0xFFFFFFFFFFFFFFFF, // WARL, bad address or instruction
+    MIP       = 0x344; 0x0000000000000222, // WARL, interrupt pending, M-mode bits come from the CLINT and PLIC
     // MTINST = 0x34A; 0xFFFFFFFF, // Hypervisor
     // MTVAL2 = 0x34B; 0xFFFFFFFF, // Hypervisor
 
     // Machine Configuration
-    MENVCFG   = 0x30A; 0xFFFFFFFF, // environment configuration register
+    MENVCFG   = 0x30A; 0x00000000, // environment configuration register
     // MSECCFG    = 0x747; 0xFFFFFFFF, // security configuration reg
 
-    // Machine Memory Protection
+    // Machine Memory Protection, not implemented: the entries read as zero so firmware finds no PMP
     PMPCFG00  = 0x3A0; 0x00000000, // Physical memory protection configuration.
     PMPCFG02  = 0x3A2; 0x00000000,
     PMPCFG04  = 0x3A4; 0x00000000,
@@ -161,9 +183,17 @@ declare_csr_consts!(pub CSR_ADDRESSES: &[u32] = [
     PMPADDR61 = 0x3ED; 0x00000000,
     PMPADDR62 = 0x3EE; 0x00000000,
     PMPADDR63 = 0x3EF; 0x00000000,
-    // Machine Counter/Timers
+    // Machine Counter/Timers, cycle counts retired instructions
+    MCYCLE        = 0xB00; 0xFFFFFFFFFFFFFFFF,
+    MINSTRET      = 0xB02; 0xFFFFFFFFFFFFFFFF,
+
+    // Unprivileged Counter/Timers, read-only views
+    CYCLE         = 0xC00; 0x00000000,
+    TIME          = 0xC01; 0x00000000, // mtime of the CLINT
+    INSTRET       = 0xC02; 0x00000000,
 
     // Machine Counter Setup
+    MCOUNTINHIBIT = 0x320; 0x00000000,
 
     // Debug/Trace Registers
 
@@ -171,20 +201,247 @@ declare_csr_consts!(pub CSR_ADDRESSES: &[u32] = [
 ]);
 
 
+/*
+ * Static description of a CSR. privilege and read_only follow from the address,
+ * privileged spec 2.1 CSR Address Mapping Conventions.
+ */
+#[derive(Debug)]
+pub struct CsrInfo {
+    pub address:   u32,
+    pub name:      &'static str,
+    pub mask:      u64, // bits an instruction can write
+    pub privilege: u8,  // lowest privilege mode that can access it
+    pub read_only: bool,
+}
+
+const CSR_COUNT: usize = csr_address::CSR_INFO.len();
+const NO_SLOT:   u8    = u8::MAX;
+// slot in CsrFile of every 12 bit CSR address, NO_SLOT for the ones that do not exist
+const CSR_SLOTS: [u8; 4096] = csr_slots();
+const _: () = assert!(CSR_COUNT < NO_SLOT as usize);
+
+const fn csr_slots() -> [u8; 4096] {
+    let mut slots = [NO_SLOT; 4096];
+    let mut i = 0;
+    while i < CSR_COUNT {
+        slots[csr_address::CSR_INFO[i].address as usize] = i as u8;
+        i += 1;
+    }
+    return slots;
+}
+
+fn csr_slot(address: u32) -> Option<usize> {
+    return match CSR_SLOTS.get(address as usize) {
+        Some(&slot) if slot != NO_SLOT => Some(slot as usize),
+        _ => None,
+    };
+}
+
+pub fn csr_info(address: u32) -> Option<&'static CsrInfo> {
+    return csr_slot(address).map(|slot| &csr_address::CSR_INFO[slot]);
+}
+
+// the CSR called name, in any case
+pub fn csr_named(name: &str) -> Option<&'static CsrInfo> {
+    return csr_address::CSR_INFO.iter().find(|info| info.name.eq_ignore_ascii_case(name));
+}
+
+/*
+ * The CSRs of a HART, one value per entry of CSR_INFO. Indexing with an address that is not a CSR panics,
+ * get() is for addresses that come from the guest or a debugger. Serialized as a map from names to values, without
+ * the views (csr_is_view) whose slots are never used: csr_read has their values.
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "HashMap<String, u64>")]
+pub struct CsrFile {
+    values: [u64; CSR_COUNT],
+}
+
+impl CsrFile {
+    pub fn get(&self, address: u32) -> Option<u64> {
+        return csr_slot(address).map(|slot| self.values[slot]);
+    }
+
+    pub fn set(&mut self, address: u32, value: u64) {
+        self[address] = value;
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        return csr_slot(address).is_some();
+    }
+
+    // (address, value) of every CSR, in the order of CSR_INFO
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u64)> + '_ {
+        return csr_address::CSR_INFO.iter().zip(self.values.iter()).map(|(info, value)| (info.address, *value));
+    }
+}
+
+impl Default for CsrFile {
+    fn default() -> CsrFile {
+        return CsrFile { values: [0; CSR_COUNT] };
+    }
+}
+
+impl std::ops::Index<u32> for CsrFile {
+    type Output = u64;
+    fn index(&self, address: u32) -> &u64 {
+        return &self.values[csr_slot(address).expect("not a CSR")];
+    }
+}
+
+impl std::ops::IndexMut<u32> for CsrFile {
+    fn index_mut(&mut self, address: u32) -> &mut u64 {
+        return &mut self.values[csr_slot(address).expect("not a CSR")];
+    }
+}
+
+impl Serialize for CsrFile {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(None)?;
+        for (info, value) in csr_address::CSR_INFO.iter().zip(self.values.iter()) {
+            if !csr_is_view(info.address) {
+                map.serialize_entry(info.name, value)?;
+            }
+        }
+        return map.end();
+    }
+}
+
+impl From<HashMap<String, u64>> for CsrFile {
+    fn from(values: HashMap<String, u64>) -> CsrFile {
+        let mut csr = CsrFile::default();
+        for (name, value) in values {
+            if let Some(info) = csr_named(&name).filter(|info| !csr_is_view(info.address)) {
+                csr[info.address] = value;
+            }
+        }
+        return csr;
+    }
+}
+
+// privilege modes
+pub const PRIV_U: u8 = 0b00;
+pub const PRIV_S: u8 = 0b01;
+pub const PRIV_M: u8 = 0b11;
+
+// mstatus fields
+pub const MSTATUS_SIE:  u64 = 1 << 1;
+const MSTATUS_MIE:  u64 = 1 << 3;
+const MSTATUS_SPIE: u64 = 1 << 5;
+const MSTATUS_MPIE: u64 = 1 << 7;
+const MSTATUS_SPP:  u64 = 1 << 8;
+const MSTATUS_MPP:  u64 = 0b11 << 11;
+const MSTATUS_MPRV: u64 = 1 << 17;
+const MSTATUS_SUM:  u64 = 1 << 18;
+const MSTATUS_MXR:  u64 = 1 << 19;
+const MSTATUS_TVM:  u64 = 1 << 20;
+const MSTATUS_TW:   u64 = 1 << 21;
+const MSTATUS_TSR:  u64 = 1 << 22;
+const MSTATUS_UXL_SXL: u64 = 0b1010 << 32; // both 64 bit
+// bits of mstatus visible through sstatus, SD UXL MXR SUM XS FS SPP VS UBE SPIE SIE
+const SSTATUS_READ_MASK: u64 = 0x8000_0003_000D_E762;
+
+// exception codes of mcause/scause
+pub const CAUSE_FETCH_ACCESS:        u64 = 1;
+pub const CAUSE_ILLEGAL_INSTRUCTION: u64 = 2;
+pub const CAUSE_BREAKPOINT:          u64 = 3;
+pub const CAUSE_LOAD_MISALIGNED:     u64 = 4;
+pub const CAUSE_LOAD_ACCESS:         u64 = 5;
+pub const CAUSE_STORE_MISALIGNED:    u64 = 6;
+pub const CAUSE_STORE_ACCESS:        u64 = 7;
+pub const CAUSE_ECALL_U:             u64 = 8;
+pub const CAUSE_ECALL_S:             u64 = 9;
+pub const CAUSE_ECALL_M:             u64 = 11;
+pub const CAUSE_FETCH_PAGE_FAULT:    u64 = 12;
+pub const CAUSE_LOAD_PAGE_FAULT:     u64 = 13;
+pub const CAUSE_STORE_PAGE_FAULT:    u64 = 15;
+pub const CAUSE_INTERRUPT:           u64 = 1 << 63;
+
+// interrupt numbers, bits of mip/mie
+pub const IRQ_S_SOFT:  u64 = 1;
+pub const IRQ_M_SOFT:  u64 = 3;
+pub const IRQ_S_TIMER: u64 = 5;
+pub const IRQ_M_TIMER: u64 = 7;
+pub const IRQ_S_EXT:   u64 = 9;
+pub const IRQ_M_EXT:   u64 = 11;
+
+// the order in which pending interrupts are taken
+const INTERRUPT_PRIORITY: [u64; 6] = [IRQ_M_EXT, IRQ_M_SOFT, IRQ_M_TIMER, IRQ_S_EXT, IRQ_S_SOFT, IRQ_S_TIMER];
+
+const SATP_MODE_SV39: u64 = 8;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Simulator {
     pub states:              Vec<CpuState>,
     pub mem:                 Vec<u8>,
-    pub csr:                 HashMap<u32, u64>,
-    pub csr_address_to_name: HashMap<u32, String>,
+    pub mem_base:            u64, // physical address of mem[0]
+    #[serde(default)]
+    pub ram_window:          (u64, usize), // mem_base and size RAM was configured with, an ELF load can move it
+    pub reset_vector:        u64, // pc of every HART after a reset
+    pub image_end:           u64, // first address after the loaded image
+    pub dtb_address:         Option<u64>, // passed in a1 at reset
+    pub initrd:              Option<(u64, u64)>, // [start, end) of the initramfs, described in /chosen
+    pub symbols:             Vec<Symbol>,
     pub log:                 String,
     pub sim_out:             String,
     pub uart_out:            Vec<u8>,
     pub state:               bool,
+    pub devices:             Devices,
+    #[serde(default)]
+    pub debugger:            Debugger,
+    #[serde(default = "default_scheduler")]
+    pub scheduler:           Scheduler, // --sched: which HARTs a step runs
+    #[serde(skip)]
+    pub history:             Option<History>, // undo log of time-travel debugging, None when off
+    #[serde(skip)]
+    pub commit_log:          Option<CommitLog>, // --log-commits trace
+    #[serde(skip)]
+    pub decode_cache:        DecodeCache,
+    #[serde(skip)]
+    pub trace:               bool, // --trace: every step prints what it does and fills log, sim_out and last_instruction
+    #[cfg(feature = "jit")]
+    #[serde(skip)]
+    pub jit:                 Option<Jit>, // translated blocks of batch runs, None runs everything on the interpreter
+    #[serde(skip)]
+    pub parallel:            Option<Parallel>, // --parallel: batch runs give every HART a host thread, None runs them in turn
 }
 
-
+/*
+ * Memory mapped devices. The CLINT, PLIC and UART are part of every machine,
+ * the others are None when the machine is configured without them.
+ * The built-in SBI is not memory mapped, it stands in for M-mode firmware,
+ * the Linux syscalls of user-mode emulation stand in for the kernel, semihosting for a debugger.
+ */
 #[derive(Serialize, Deserialize, Debug)]
+pub struct Devices {
+    pub clint:         Clint,
+    pub plic:          Plic,
+    pub uart:          Uart,
+    pub framebuffer:   Option<Framebuffer>,
+    pub test_finisher: Option<TestFinisher>,
+    pub htif:          Option<Htif>,
+    pub sbi:           Option<Sbi>,
+    pub syscalls:      Option<Syscalls>,
+    pub semihosting:   Option<Semihosting>,
+}
+
+pub fn default_devices(harts: usize) -> Devices {
+    return Devices {
+        clint:         new_clint(harts),
+        plic:          new_plic(harts),
+        uart:          default_uart(),
+        framebuffer:   None,
+        test_finisher: Some(default_test_finisher()),
+        htif:          None,
+        sbi:           None,
+        syscalls:      None,
+        semihosting:   None,
+    };
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct CpuState {
     // x0: Zero
     // x1 - ra: Return address
@@ -196,10 +453,12 @@ pub struct CpuState {
     // x10-17 - a0-7:  Argument regs
     // x18-27 - s2-11: Callee-saved regs
     // x28-31 - t3-6:  Tmp regs.
-    pub regs : Vec<u64>, 
+    pub regs : Vec<u64>,
     pub pc   : u64,
     pub last_pc : u64,
-    pub last_instruction : String,
+    pub last_instruction : String, // disassembly of the instruction at last_pc
+    #[serde(default)]
+    pub last_raw : u32,            // its bits, 16 for compressed instructions
     /*
      * encoding:
      *      00: U
@@ -208,78 +467,255 @@ pub struct CpuState {
      *      11: M
      */
     pub priviledge_mode : u8,
-
-     
+    pub csr : CsrFile,
+    pub reservation : Option<u64>, // 8 byte granule reserved by LR
+    pub waiting : bool,            // stalled in WFI
+    pub irq_lines : u64,           // mip bits driven by the CLINT and PLIC
 }
 
 
-pub fn default_cpu_state() -> CpuState {
+pub fn new_cpu_state(hartid: u64) -> CpuState {
     return CpuState {
             regs: vec![0; 32],
             pc:   0,
             last_pc : 0,
             last_instruction : String::from(""),
-            priviledge_mode : 0b11,
+            last_raw : 0,
+            priviledge_mode : PRIV_M,
+            csr : default_csr(hartid),
+            reservation : None,
+            waiting : false,
+            irq_lines : 0,
         };
 }
 
-fn default_csr(address_to_name : &HashMap<u32, String>) -> HashMap<u32, u64> {
-    let mut csr = HashMap::new();
+pub fn default_cpu_state() -> CpuState {
+    return new_cpu_state(0);
+}
 
-    for k in address_to_name.keys() {
-        csr.insert(*k, 0);
-    }
-    // 64 bit, BV64I, S, U
-    csr.insert(csr_address::MISA, 0b10 << 62 | 1 << 8 | 1 << 18 | 1 << 20);
+fn default_csr(hartid: u64) -> CsrFile {
+    let mut csr = CsrFile::default();
+    // 64 bit, A, C, I, M, S, U
+    csr.set(csr_address::MISA, 0b10 << 62 | 1 << 0 | 1 << 2 | 1 << 8 | 1 << 12 | 1 << 18 | 1 << 20);
+    csr.set(csr_address::MSTATUS, MSTATUS_UXL_SXL);
+    csr.set(csr_address::MHARTID, hartid);
     return csr;
 }
 
-pub fn default_sim() -> Simulator {
+pub fn new_sim(harts: usize, mem_base: u64, mem_size: usize) -> Simulator {
     let mut states = Vec::new();
-    for i in 0..1 {
-        states.push(default_cpu_state());
+    for i in 0..harts {
+        states.push(new_cpu_state(i as u64));
     }
-    let address_to_name = csr_address::get_address_to_name();
     return Simulator{
         states: states,
         // fill mem with NOP
-        mem: vec![0; 8192],
-        csr: default_csr(&address_to_name),
-        csr_address_to_name: address_to_name,
+        mem: vec![0; mem_size],
+        mem_base: mem_base,
+        ram_window: (mem_base, mem_size),
+        reset_vector: mem_base,
+        image_end: mem_base,
+        dtb_address: None,
+        initrd: None,
+        symbols: vec![],
         log: String::from("OK"),
         sim_out: String::from(""),
         uart_out: vec![],
         state: true,
+        devices: default_devices(harts),
+        debugger: default_debugger(),
+        scheduler: default_scheduler(),
+        history: None,
+        commit_log: None,
+        decode_cache: new_decode_cache(),
+        trace: false,
+        #[cfg(feature = "jit")]
+        jit: None,
+        parallel: None,
     };
 }
 
-// WPRI -- Reserved:  Writes Preserve Values, Reads Ignore Values
-// WLRL -- Write legal, Read legal
-// WARL -- Write Any value, Read Legal Values 
+pub fn default_sim() -> Simulator {
+    return new_sim(1, 0, 8192);
+}
 
+// Puts every HART, their CSRs and the interrupt devices back in their reset state, memory is left untouched
+pub fn reset(sim: &mut Simulator) {
+    for (hartid, state) in sim.states.iter_mut().enumerate() {
+        *state = new_cpu_state(hartid as u64);
+    }
+    let harts = sim.states.len();
+    sim.devices.clint = new_clint(harts);
+    sim.devices.plic  = new_plic(harts);
+    sim.devices.uart  = default_uart();
+    set_boot_registers(sim);
+}
 
+/*
+ * Standard RISC-V boot protocol, what OpenSBI and Linux expect at their entry:
+ *      pc = reset vector, a0 = hartid, a1 = address of the device tree blob
+ */
+pub fn set_boot_registers(sim: &mut Simulator) {
+    for (hartid, state) in sim.states.iter_mut().enumerate() {
+        state.pc = sim.reset_vector;
+        state.regs[10] = hartid as u64;
+        state.regs[11] = sim.dtb_address.unwrap_or(0);
+    }
+    sbi_boot(sim);
+}
 
+/*
+ * Copies the device tree blob to the top of RAM, 8 byte aligned.
+ * RAM is grown when the loaded image leaves no room for it.
+ */
+pub fn place_device_tree(sim: &mut Simulator, dtb: &[u8]) -> u64 {
+    let size = (dtb.len() as u64 + 7) & !7;
+    let mem_end = sim.mem_base + sim.mem.len() as u64;
+    if sim.image_end + size > mem_end {
+        let grow = ((sim.image_end + size - mem_end + 0xfff) & !0xfff) as usize;
+        sim.mem.resize(sim.mem.len() + grow, 0);
+    }
+    let address = (sim.mem_base + sim.mem.len() as u64 - size) & !7;
+    let offset = (address - sim.mem_base) as usize;
+    sim.mem[offset..offset + dtb.len()].copy_from_slice(dtb);
+    sim.dtb_address = Some(address);
+    set_boot_registers(sim);
+    return address;
+}
 
-fn handle_trap(pc : u64, state: &mut CpuState, csr : &mut HashMap<u32, u64>) -> u64{
+// mem[] indices of [address, address + length) when all of it is RAM
+pub fn ram_range(sim: &Simulator, address: u64, length: u64) -> Option<std::ops::Range<usize>> {
+    let start = address.checked_sub(sim.mem_base)? as usize;
+    let end = start.checked_add(length as usize)?;
+    return if end <= sim.mem.len() { Some(start..end) } else { None };
+}
 
+pub fn symbol_address(sim: &Simulator, name: &str) -> Option<u64> {
+    return sim.symbols.iter().find(|s| s.name == name).map(|s| s.value);
+}
 
-    //let mcause_exepction_code : u64 = csr.insert(csr_address::MCAUSE ] & 0x7FFFFFFFFFFFFFFF;
+// "name+0xoffset" of the closest symbol at or below address
+pub fn symbolize(symbols: &[Symbol], address: u64) -> Option<String> {
+    let symbol = symbols.iter()
+        .filter(|s| s.value <= address && (s.size == 0 || address < s.value + s.size))
+        .max_by_key(|s| s.value)?;
+    return match address - symbol.value {
+        0 => Some(symbol.name.clone()),
+        offset => Some(format!("{}+0x{:X}", symbol.name, offset)),
+    };
+}
 
-    //TODO: handle MEDELEG & 
-    
+// How the guest asked the machine to stop, None while it is still running
+pub fn guest_exit_status(sim: &Simulator) -> Option<FinisherStatus> {
+    if let Some(status) = sim.devices.test_finisher.as_ref().and_then(|f| f.status) {
+        return Some(status);
+    }
+    if let Some(status) = sim.devices.sbi.as_ref().and_then(|s| s.status) {
+        return Some(status);
+    }
+    if let Some(status) = sim.devices.semihosting.as_ref().and_then(|s| s.status) {
+        return Some(status);
+    }
+    let exit_code = sim.devices.htif.as_ref().and_then(|h| h.exit_code)
+        .or(sim.devices.syscalls.as_ref().and_then(|s| s.exit_code));
+    return match exit_code {
+        Some(0)    => Some(FinisherStatus::Pass),
+        Some(code) => Some(FinisherStatus::Fail(code as u16)),
+        None       => None,
+    };
+}
+
+// A synchronous exception raised by an instruction, tval goes to mtval/stval
+#[derive(Debug, Clone, Copy)]
+pub struct Exception {
+    pub cause: u64,
+    pub tval:  u64,
+}
+
+pub fn illegal_instruction(ir: u32) -> Exception {
+    return Exception { cause: CAUSE_ILLEGAL_INSTRUCTION, tval: ir as u64 };
+}
 
+pub fn cause_name(cause: u64) -> &'static str {
+    if cause & CAUSE_INTERRUPT != 0 {
+        return match cause & !CAUSE_INTERRUPT {
+            IRQ_S_SOFT  => "supervisor software interrupt",
+            IRQ_M_SOFT  => "machine software interrupt",
+            IRQ_S_TIMER => "supervisor timer interrupt",
+            IRQ_M_TIMER => "machine timer interrupt",
+            IRQ_S_EXT   => "supervisor external interrupt",
+            IRQ_M_EXT   => "machine external interrupt",
+            _           => "unknown interrupt",
+        };
+    }
+    return match cause {
+        0  => "instruction address misaligned",
+        1  => "instruction access fault",
+        2  => "illegal instruction",
+        3  => "breakpoint",
+        4  => "load address misaligned",
+        5  => "load access fault",
+        6  => "store/AMO address misaligned",
+        7  => "store/AMO access fault",
+        8  => "environment call from U-mode",
+        9  => "environment call from S-mode",
+        11 => "environment call from M-mode",
+        12 => "instruction page fault",
+        13 => "load page fault",
+        15 => "store/AMO page fault",
+        _  => "unknown exception",
+    };
+}
+
+// WPRI -- Reserved:  Writes Preserve Values, Reads Ignore Values
+// WLRL -- Write legal, Read legal
+// WARL -- Write Any value, Read Legal Values
+
+// mip as software sees it, the M-mode bits and SEIP are driven by the devices
+pub fn read_mip(state: &CpuState) -> u64 {
+    return state.csr[csr_address::MIP] | state.irq_lines;
+}
+
+// The interrupt a HART takes before its next instruction, if any
+pub fn pending_interrupt(state: &CpuState) -> Option<u64> {
     // When a hart is executing in privilege mode x, interrupts are globally enabled when xIE=1 and globally disabled when xIE=0
     // nterrupts for lower-privilege modes, w<x, are always globally disabled
     // regardless of the setting of any global wIE bit for the lower-privilege mode. Interrupts for higher-
     // privilege modes, y>x, are always globally enabled regardless of the setting of the global yIE bit for the
     // higher-privilege mode
-    //
+    let pending = read_mip(state) & state.csr[csr_address::MIE];
+    if pending == 0 {
+        return None;
+    }
+    let mstatus = state.csr[csr_address::MSTATUS];
+    let mideleg = state.csr[csr_address::MIDELEG];
+    let privilege = state.priviledge_mode;
+
+    let m_enabled = privilege < PRIV_M || mstatus & MSTATUS_MIE != 0;
+    let s_enabled = privilege < PRIV_S || (privilege == PRIV_S && mstatus & MSTATUS_SIE != 0);
+    let mut enabled = 0;
+    if m_enabled {
+        enabled |= pending & !mideleg;
+    }
+    if s_enabled {
+        enabled |= pending & mideleg;
+    }
+    return INTERRUPT_PRIORITY.iter()
+        .find(|irq| enabled & (1 << **irq) != 0)
+        .map(|irq| CAUSE_INTERRUPT | irq);
+}
+
+/*
+ * Takes a trap into M-mode, or into S-mode when MEDELEG/MIDELEG delegate it and the HART is not in M-mode.
+ * Returns false for a fatal trap: the target trap vector is 0, there is no handler to run.
+ */
+fn handle_trap(sim: &mut Simulator, state: &mut CpuState, cause: u64, tval: u64) -> bool {
     // xPIE:    holds the value of the interrupt-enable bit active prior to the trap
     // xPP:     holds the previous privilege mode up to mode x
     // MPP is 2 bits wide
     // SPP is 1 bit wide
 
-    // When a trap is taken from privilege mode y 
+    // When a trap is taken from privilege mode y
     // into privilege mode x, xPIE is set to the value of xIE; xIE is set to 0; and xPP is set to y.
 
     /*
@@ -290,55 +726,184 @@ fn handle_trap(pc : u64, state: &mut CpuState, csr : &mut HashMap<u32, u64>) ->
         * 3) Invisible:  Trap is handled transparantly by the execution environment and execution resumes normally after trap is handled
         * 4) Fatal trap: Causes the execution environment to terminate execution
         *
-        * By default all traps, at any priveledge level, are handled in machine mode. 
+        * By default all traps, at any priveledge level, are handled in machine mode.
         * These can be redirected with MRET to the appropriate level.
-        * Alternatively, with MEDELEG and MIDELEG can delegate the trap to the S-mode trap handler, 
+        * Alternatively, with MEDELEG and MIDELEG can delegate the trap to the S-mode trap handler,
         *  when this occurs, the delegated inturrupts are masked at the delegator level.
-        * 
+        *
         * When a trap is taken into M-mode, MEPC is written with the virtual address of the instruction that was interrupted or that encountered the exception.
         * When a trap is taken into M-mode, MCAUSE is written with a code indicating the event that caused the trap.
         *
         */
+    // requested trap: the built-in SBI stands in for M-mode firmware and handles ECALLs from S-mode itself
+    if cause == CAUSE_ECALL_S && sim.devices.sbi.is_some() {
+        sbi_call(sim, state);
+        return true;
+    }
+    // requested trap: an EBREAK in the semihosting sequence asks the host for I/O
+    if cause == CAUSE_BREAKPOINT && sim.devices.semihosting.is_some() && is_semihosting_call(sim, state.pc) {
+        semihosting_call(sim, state);
+        return true;
+    }
+    // requested trap: in user-mode emulation ECALLs from U-mode are Linux syscalls,
+    // a bare-metal newlib program makes them from M-mode
+    let bare_metal = sim.devices.syscalls.as_ref().is_some_and(|s| s.bare_metal);
+    if (cause == CAUSE_ECALL_U || (bare_metal && cause == CAUSE_ECALL_M)) && sim.devices.syscalls.is_some() {
+        syscall(sim, state);
+        return true;
+    }
 
-    let mtvec : u64 = csr[&csr_address::MTVEC ];
-    let mtvec_mode  : u8  =(mtvec &  0b11) as u8;
-    let mtvec_base  : u64 = mtvec & !0b11;
-    
-    let cause = match state.priviledge_mode {
-        0b00 => 8,  // Environment call from U-mode
-        0b01 => 9,  // Environment call from S-mode
-        0b11 => 10, // Environment call from H-mode
-        0b11 => 11, // Environment call from M-mode
-        _ => {unreachable!();},
-    };
+    let pc = state.pc;
+    let hart = state.csr[csr_address::MHARTID] as usize;
+    sim.debugger.trap_hit = Some(TrapHit { hart: hart, cause: cause, tval: tval, pc: pc });
+    if !enter_trap(state, cause, tval) {
+        sim.log = format!("unhandled trap: {} (cause 0x{:X}), tval: 0x{:X}, pc: 0x{:X}", cause_name(cause), cause, tval, pc);
+        println!("ERROR: line {}, {}", line!(), sim.log);
+        // a process without a signal handler dies of the signal
+        if let Some(syscalls) = sim.devices.syscalls.as_mut().filter(|s| !s.bare_metal) {
+            syscalls.exit_code = Some(128 + trap_signal(cause));
+        }
+        return false;
+    }
+    return true;
+}
 
-    let is_synchronous_exception = true;
+// The trap itself: xEPC, xCAUSE, xTVAL, mstatus and the privilege mode. false, and nothing changed, when the trap vector is 0
+pub fn enter_trap(state: &mut CpuState, cause: u64, tval: u64) -> bool {
+    let pc = state.pc;
+    let is_interrupt = cause & CAUSE_INTERRUPT != 0;
+    let code = cause & !CAUSE_INTERRUPT;
+    let delegation = if is_interrupt { state.csr[csr_address::MIDELEG] } else { state.csr[csr_address::MEDELEG] };
+    let to_supervisor = state.priviledge_mode <= PRIV_S && (delegation >> code) & 1 != 0;
+
+    let tvec = if to_supervisor { state.csr[csr_address::STVEC] } else { state.csr[csr_address::MTVEC] };
+    if tvec == 0 {
+        return false;
+    }
 
-    csr.insert(csr_address::MEPC, pc & !0b11); // IALIGN is 32 bit
+    let mstatus = state.csr[csr_address::MSTATUS];
+    let privilege = state.priviledge_mode as u64;
+    if to_supervisor {
+        state.csr.set(csr_address::SEPC, pc);
+        state.csr.set(csr_address::SCAUSE, cause);
+        state.csr.set(csr_address::STVAL, tval);
+        // SPIE = SIE, SIE = 0, SPP = current privilege mode
+        let spie = if mstatus & MSTATUS_SIE != 0 { MSTATUS_SPIE } else { 0 };
+        let spp  = if privilege == PRIV_S as u64 { MSTATUS_SPP } else { 0 };
+        let mstatus = mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP) | spie | spp;
+        state.csr.set(csr_address::MSTATUS, mstatus);
+        state.priviledge_mode = PRIV_S;
+    } else {
+        state.csr.set(csr_address::MEPC, pc);
+        state.csr.set(csr_address::MCAUSE, cause);
+        state.csr.set(csr_address::MTVAL, tval);
+        // MPIE = MIE, MIE = 0, MPP = current privilege mode
+        let mpie = if mstatus & MSTATUS_MIE != 0 { MSTATUS_MPIE } else { 0 };
+        let mstatus = mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP) | mpie | privilege << 11;
+        state.csr.set(csr_address::MSTATUS, mstatus);
+        state.priviledge_mode = PRIV_M;
+    }
 
-    /*
-        *      00: U
-        *      01: S
-        *      10: RESERVED
-        *      11: M
-        */
-    csr.insert(csr_address::MCAUSE, cause);
-    
-    let mut npc = 0;
-    match mtvec_mode {
+    let tvec_mode = tvec & 0b11;
+    let tvec_base = tvec & !0b11;
+    state.pc = match tvec_mode {
+        // Vectored, only interrupts use the vector table
+        1 if is_interrupt => tvec_base + 4 * code,
         // Direct
-        0 => {npc = mtvec_base;},
-        //Vectored
-        1 => {
-            if (is_synchronous_exception){
-                npc = mtvec_base;
-            } else {
-                npc = mtvec_base + 4*cause;
-            }
-        },
-        _ => {unreachable!();},
+        _ => tvec_base,
+    };
+    return true;
+}
+
+// xRET: returns from a trap handler in M-mode (MRET) or S-mode (SRET)
+pub fn trap_return(state: &mut CpuState, from: u8) -> u64 {
+    let mstatus = state.csr[csr_address::MSTATUS];
+    let (privilege, mut mstatus, epc) = if from == PRIV_M {
+        // MIE = MPIE, MPIE = 1, MPP = U
+        let mpp  = (mstatus & MSTATUS_MPP) >> 11;
+        let mie  = if mstatus & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+        (mpp as u8, mstatus & !(MSTATUS_MIE | MSTATUS_MPP) | mie | MSTATUS_MPIE, state.csr[csr_address::MEPC])
+    } else {
+        // SIE = SPIE, SPIE = 1, SPP = U
+        let spp  = if mstatus & MSTATUS_SPP != 0 { PRIV_S } else { PRIV_U };
+        let sie  = if mstatus & MSTATUS_SPIE != 0 { MSTATUS_SIE } else { 0 };
+        (spp, mstatus & !(MSTATUS_SIE | MSTATUS_SPP) | sie | MSTATUS_SPIE, state.csr[csr_address::SEPC])
+    };
+    if privilege != PRIV_M {
+        mstatus &= !MSTATUS_MPRV;
     }
-    return npc;
+    state.csr.set(csr_address::MSTATUS, mstatus);
+    state.priviledge_mode = privilege;
+    return epc;
+}
+
+/*
+ * Zicsr reads. SSTATUS, SIE and SIP are views of the machine registers, the counters are shared.
+ * None when the CSR does not exist.
+ */
+// the CSRs csr_read computes from other registers, their own slots stay 0
+pub fn csr_is_view(address: u32) -> bool {
+    return matches!(address, csr_address::SSTATUS | csr_address::SIE | csr_address::SIP
+        | csr_address::CYCLE | csr_address::INSTRET | csr_address::TIME);
+}
+
+pub fn csr_read(state: &CpuState, mtime: u64, address: u32) -> Option<u64> {
+    let mideleg = state.csr[csr_address::MIDELEG];
+    return match address {
+        csr_address::SSTATUS => Some(state.csr[csr_address::MSTATUS] & SSTATUS_READ_MASK),
+        csr_address::SIE     => Some(state.csr[csr_address::MIE] & mideleg),
+        csr_address::SIP     => Some(read_mip(state) & mideleg),
+        csr_address::MIP     => Some(read_mip(state)),
+        csr_address::CYCLE   => Some(state.csr[csr_address::MCYCLE]),
+        csr_address::INSTRET => Some(state.csr[csr_address::MINSTRET]),
+        csr_address::TIME    => Some(mtime),
+        _ => state.csr.get(address),
+    };
+}
+
+pub fn csr_write(state: &mut CpuState, address: u32, value: u64) {
+    let mask = csr_info(address).map_or(0, |info| info.mask);
+    let (target, mask) = match address {
+        csr_address::SSTATUS => (csr_address::MSTATUS, mask),
+        csr_address::SIE     => (csr_address::MIE, mask & state.csr[csr_address::MIDELEG]),
+        csr_address::SIP     => (csr_address::MIP, mask & state.csr[csr_address::MIDELEG]),
+        // writing an unsupported translation mode has no effect at all
+        csr_address::SATP if value >> 60 != 0 && value >> 60 != SATP_MODE_SV39 => return,
+        _ => (address, mask),
+    };
+    let old = state.csr[target];
+    let mut new = (old & !mask) | (value & mask);
+    if target == csr_address::MSTATUS && (new & MSTATUS_MPP) >> 11 == 0b10 {
+        // MPP is WARL, the reserved mode reads as U
+        new &= !MSTATUS_MPP;
+    }
+    state.csr.set(target, new);
+}
+
+// CSR access rules of the privileged spec, 2.1 CSR Address Mapping Conventions
+pub fn csr_accessible(state: &CpuState, address: u32, write: bool) -> bool {
+    let privilege = state.priviledge_mode;
+    let info = match csr_info(address) {
+        Some(info) => info,
+        None => return false,
+    };
+    if privilege < info.privilege || (write && info.read_only) {
+        return false;
+    }
+    let mstatus = state.csr[csr_address::MSTATUS];
+    if address == csr_address::SATP && privilege == PRIV_S && mstatus & MSTATUS_TVM != 0 {
+        return false;
+    }
+    if (csr_address::CYCLE..=csr_address::INSTRET).contains(&address) {
+        let bit = 1 << (address - csr_address::CYCLE);
+        if privilege < PRIV_M && state.csr[csr_address::MCOUNTEREN] & bit == 0 {
+            return false;
+        }
+        if privilege == PRIV_U && state.csr[csr_address::SCONTEREN] & bit == 0 {
+            return false;
+        }
+    }
+    return true;
 }
 
 /*
@@ -347,8 +912,8 @@ fn handle_trap(pc : u64, state: &mut CpuState, csr : &mut HashMap<u32, u64>) ->
         PTE = page table entries
         PPN = physical page number
         VPN = virtual page number
-        PMA = 
-        PMP = 
+        PMA =
+        PMP =
         va  = virtual address
 
         WPRI = reserved Writes Preserve values, Reads Ignore values
@@ -358,544 +923,1268 @@ fn handle_trap(pc : u64, state: &mut CpuState, csr : &mut HashMap<u32, u64>) ->
     CSRs listed in table 2.2 etc
 */
 
-// access_type
-// 0 read
-// 1 write
-// 2 execute
-fn translate_address(csr : HashMap<u32, u64>, mem : Vec<u8>, va : u64, access_type : u8) -> u64{
-    // Sv39
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+    Debug, // a debugger looking at memory like the HART's loads would: no permission checks, no A and D bits
+}
+
+fn access_fault(access: Access, address: u64) -> Exception {
+    let cause = match access {
+        Access::Read | Access::Debug => CAUSE_LOAD_ACCESS,
+        Access::Write   => CAUSE_STORE_ACCESS,
+        Access::Execute => CAUSE_FETCH_ACCESS,
+    };
+    return Exception { cause: cause, tval: address };
+}
+
+fn page_fault(access: Access, address: u64) -> Exception {
+    let cause = match access {
+        Access::Read | Access::Debug => CAUSE_LOAD_PAGE_FAULT,
+        Access::Write   => CAUSE_STORE_PAGE_FAULT,
+        Access::Execute => CAUSE_FETCH_PAGE_FAULT,
+    };
+    return Exception { cause: cause, tval: address };
+}
+
+// privilege loads and stores are done with, MPRV makes M-mode use the one in MPP
+fn data_privilege(state: &CpuState) -> u8 {
+    let mstatus = state.csr[csr_address::MSTATUS];
+    if state.priviledge_mode == PRIV_M && mstatus & MSTATUS_MPRV != 0 {
+        return ((mstatus & MSTATUS_MPP) >> 11) as u8;
+    }
+    return state.priviledge_mode;
+}
+
+// what the page walk needs of RAM: the machine owns it, HARTs that run in parallel share it (see smp.rs)
+pub trait PageTableMemory {
+    fn size(&self) -> usize;
+    fn read_pte(&self, offset: usize) -> u64;
+    fn set_pte_bits(&mut self, offset: usize, bits: u64);
+}
+
+impl PageTableMemory for Vec<u8> {
+    fn size(&self) -> usize {
+        return self.len();
+    }
+
+    fn read_pte(&self, offset: usize) -> u64 {
+        return u64::from_le_bytes(self[offset..offset + 8].try_into().unwrap());
+    }
+
+    fn set_pte_bits(&mut self, offset: usize, bits: u64) {
+        let pte = self.read_pte(offset) | bits;
+        self[offset..offset + 8].copy_from_slice(&pte.to_le_bytes());
+    }
+}
+
+// page tables as a debugger walks them, Access::Debug never sets a bit
+struct DebugMemory<'a>(&'a [u8]);
+
+impl PageTableMemory for DebugMemory<'_> {
+    fn size(&self) -> usize {
+        return self.0.len();
+    }
+
+    fn read_pte(&self, offset: usize) -> u64 {
+        return u64::from_le_bytes(self.0[offset..offset + 8].try_into().unwrap());
+    }
+
+    fn set_pte_bits(&mut self, _offset: usize, _bits: u64) {
+        unreachable!("a debugger walk sets no A or D bits");
+    }
+}
+
+/*
+ * Sv39 page walk, 4.3.2 Virtual Address Translation Process.
+ * The A and D bits are set by the walk instead of raising a page fault.
+ */
+pub fn translate_address<M: PageTableMemory>(mem: &mut M, mem_base: u64, state: &CpuState, va: u64, access: Access) -> Result<u64, Exception> {
     const PAGESIZE: u64 = 4096;
     const LEVELS:   u64 = 3;
     const PTESIZE:  u64 = 8;
 
+    const PTE_V: u64 = 1 << 0;
+    const PTE_R: u64 = 1 << 1;
+    const PTE_W: u64 = 1 << 2;
+    const PTE_X: u64 = 1 << 3;
+    const PTE_U: u64 = 1 << 4;
+    const PTE_A: u64 = 1 << 6;
+    const PTE_D: u64 = 1 << 7;
+    const PPN_MASK: u64 = 0xFFF_FFFF_FFFF; // 44 bits
 
+    let privilege = if access == Access::Execute { state.priviledge_mode } else { data_privilege(state) };
     // Supervisor Address Translation and Protection register
-    let satp    = csr[&csr_address::SATP    ];
-    let mstatus = csr[&csr_address::MSTATUS ];
+    let satp = state.csr[csr_address::SATP];
+    //the effecitve privilege mode must be S or U
+    if privilege == PRIV_M || satp >> 60 != SATP_MODE_SV39 {
+        return Ok(va);
+    }
+    // bits 63:39 have to be copies of bit 38
+    if (((va << 25) as i64) >> 25) as u64 != va {
+        return Err(page_fault(access, va));
+    }
+    let mstatus = state.csr[csr_address::MSTATUS];
+    let sum = mstatus & MSTATUS_SUM != 0; // permit Supervisor User Memory access
+    let mxr = mstatus & MSTATUS_MXR != 0; // Make eXecutable Readable
+
+    let mut a = (satp & PPN_MASK) * PAGESIZE;
+    let mut i = LEVELS - 1;
+    loop {
+        let vpn_i = (va >> (12 + 9 * i)) & 0x1ff;
+        let pte_address = a + vpn_i * PTESIZE;
+        let pte_offset = pte_address.wrapping_sub(mem_base);
+        if !in_ram(pte_offset, PTESIZE, mem.size()) {
+            return Err(access_fault(access, va));
+        }
+        let pte_offset = pte_offset as usize;
+        let pte = mem.read_pte(pte_offset);
 
-    // physical page number
-    let satp_ppn   = satp & 0xfffffffffff; // bottom 44 bits
+        // N, PBMT and the reserved bits are not supported
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte >> 54 != 0 {
+            return Err(page_fault(access, va));
+        }
+        let ppn = (pte >> 10) & PPN_MASK;
+        if pte & (PTE_R | PTE_X) == 0 {
+            // pointer to the next level
+            if i == 0 {
+                return Err(page_fault(access, va));
+            }
+            i -= 1;
+            a = ppn * PAGESIZE;
+            continue;
+        }
 
-    // address space identifier
-    //satp_asid: 44 to 59
+        // leaf PTE
+        let permitted = match access {
+            Access::Execute => pte & PTE_X != 0,
+            Access::Read    => pte & PTE_R != 0 || (mxr && pte & PTE_X != 0),
+            Access::Write   => pte & PTE_W != 0,
+            Access::Debug   => true,
+        };
+        let user_page = pte & PTE_U != 0;
+        let privilege_ok = match privilege {
+            PRIV_U => user_page,
+            // S-mode never executes user pages, SUM allows loads and stores to them
+            _ => !user_page || (sum && access != Access::Execute) || access == Access::Debug,
+        };
+        if !permitted || !privilege_ok {
+            return Err(page_fault(access, va));
+        }
+        // misaligned superpage
+        if i > 0 && ppn & ((1 << (9 * i)) - 1) != 0 {
+            return Err(page_fault(access, va));
+        }
+        if access != Access::Debug && (pte & PTE_A == 0 || (access == Access::Write && pte & PTE_D == 0)) {
+            let dirty = if access == Access::Write { PTE_D } else { 0 };
+            mem.set_pte_bits(pte_offset, PTE_A | dirty);
+        }
+        let offset_mask = (1u64 << (12 + 9 * i)) - 1;
+        return Ok(((ppn << 12) & !offset_mask) | (va & offset_mask));
+    }
+}
 
-    
-    //let satp_mode  = satp & 0xF000000000000000 >> 60;// 60 to 63
+// true when [offset, offset + size) lies inside of RAM
+pub fn in_ram(offset: u64, size: u64, mem_len: usize) -> bool {
+    return offset < mem_len as u64 && mem_len as u64 - offset >= size;
+}
 
-    
-    //the effecitve privilege mode must be S or U
-    let satp_active = false;
-    if satp_active {
-        let mut i = LEVELS - 1;
-        let mut a = satp_ppn * PAGESIZE;
-
-        while i > 0 {
-            let va_vpn = [(va >> 12) & 0x1ff, (va >> 21) & 0x1ff, (va >> 30) & 0x1ff]; 
-            
-
-            let va_vpn_i = va_vpn[i as usize];
-            
-            let address = (a + va_vpn_i*PTESIZE)  as usize;
-            let pte = u64::from_le_bytes(mem[address as usize .. (address + 8) as usize ].try_into().unwrap());
-            //TODO generate access fault if needed
-
-            let pte_v = pte & 0b00000001;
-            let pte_r = pte & 0b00000010;
-            let pte_w = pte & 0b00000100;
-            let pte_x = pte & 0b00001000;
-            let pte_u = pte & 0b00010000;
-            let pte_g = pte & 0b00100000;
-            let pte_a = pte & 0b01000000;
-            let pte_d = pte & 0b10000000;
-
-            let pte_unsupported = pte & 0xFFC0000000000000;
-            if (pte_v != 0) || (pte_r != 0 && pte_w != 1)  ||  (pte_unsupported != 0) {
-                println!("errored on: {}", line!());
-                return 0;
-            }
+// Physical read of size bytes, zero extended. None when nothing answers at address.
+fn load(mem: &mut [u8], mem_base: u64, devices: &mut Devices, address: u64, size: u64) -> Option<u64> {
+    // offset into RAM, wraps around to a huge value below mem_base
+    let offset = address.wrapping_sub(mem_base);
+    if in_ram(offset, size, mem.len()) {
+        let mut bytes = [0u8; 8];
+        bytes[..size as usize].copy_from_slice(&mem[offset as usize .. (offset + size) as usize]);
+        return Some(u64::from_le_bytes(bytes));
+    } else if devices.uart.contains(address) {
+        return Some(devices.uart.read(address, size));
+    } else if devices.clint.contains(address) {
+        return Some(devices.clint.read(address, size));
+    } else if devices.plic.contains(address) {
+        return Some(devices.plic.read(address, size));
+    } else if let Some(fb) = devices.framebuffer.as_ref().filter(|fb| fb.contains(address)) {
+        return Some(fb.read(address, size));
+    } else if let Some(finisher) = devices.test_finisher.as_ref().filter(|f| f.contains(address)) {
+        return Some(finisher.read(address, size));
+    }
+    println!("errored on: {}, address: {:X}", line!(), address);
+    return None;
+}
 
-            // PTE is valid :D
-            if (pte_r != 0) || (pte_x != 0) {
-                // step 5
+// Physical write of the low size bytes of value. false when nothing answers at address.
+fn store(mem: &mut [u8], mem_base: u64, devices: &mut Devices, address: u64, size: u64, value: u64, uart_out: &mut Vec<u8>) -> bool {
+    let offset = address.wrapping_sub(mem_base);
+    if in_ram(offset, size, mem.len()) {
+        mem[offset as usize .. (offset + size) as usize].copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        if let Some(htif) = devices.htif.as_mut().filter(|h| h.touches_tohost(address, size)) {
+            htif.poll(mem, mem_base, uart_out);
+        }
+    } else if devices.uart.contains(address) {
+        devices.uart.write(address, size, value, uart_out);
+    } else if devices.clint.contains(address) {
+        devices.clint.write(address, size, value);
+    } else if devices.plic.contains(address) {
+        devices.plic.write(address, size, value);
+    } else if let Some(fb) = devices.framebuffer.as_mut().filter(|fb| fb.contains(address)) {
+        fb.write(address, size, value);
+    } else if let Some(finisher) = devices.test_finisher.as_mut().filter(|f| f.contains(address)) {
+        finisher.write(address, size, value);
+    } else {
+        println!("errored on: {}, address: 0x{:X}", line!(), address);
+        return false;
+    }
+    return true;
+}
 
-                let sum = (mstatus & 0x40000) != 1; // permit Supervisor User Memory access
-                let mxr = (mstatus & 0x80000) != 1; // Make eXecutable Readable
+// A store to a reserved granule makes the SC of the other HARTs fail
+fn clear_reservations(states: &mut [CpuState], address: u64) {
+    for state in states.iter_mut() {
+        if state.reservation == Some(address & !7) {
+            state.reservation = None;
+        }
+    }
+}
 
-                // TODO
-                // MXR = 1 --> loads from either readable or executable will succeed
-                // MXR = 0 --> only loads from readable will succeed
-                // MXR has no effect if page based virtual memory is NOT in effect.
+// Virtual read, watchpoints see it once it succeeded
+fn read_memory(sim: &mut Simulator, state: &CpuState, va: u64, size: u64, access: Access) -> Result<u64, Exception> {
+    let value = read_virtual(sim, state, va, size, access)?;
+    if !sim.debugger.watchpoints.is_empty() {
+        sim.debugger.check_access(state.csr[csr_address::MHARTID] as usize, va, size, false);
+    }
+    return Ok(value);
+}
 
-                // SUM = 0 --> S-mode memory accesses to pages that are accessible by U-mode (U=1) will fault.
-                // SUM = 1 --> permitted
+// accesses that cross a page boundary are translated a byte at a time
+fn read_virtual(sim: &mut Simulator, state: &CpuState, va: u64, size: u64, access: Access) -> Result<u64, Exception> {
+    if (va & 0xfff) + size > 0x1000 {
+        let mut value = 0;
+        for i in 0..size {
+            value |= read_virtual(sim, state, va + i, 1, access)? << (8 * i);
+        }
+        return Ok(value);
+    }
+    let pa = translate_address(&mut sim.mem, sim.mem_base, state, va, access)?;
+    return load(&mut sim.mem, sim.mem_base, &mut sim.devices, pa, size).ok_or(access_fault(access, va));
+}
 
-                //step 6
+fn write_memory(sim: &mut Simulator, state: &CpuState, va: u64, size: u64, value: u64) -> Result<(), Exception> {
+    write_virtual(sim, state, va, size, value)?;
+    if !sim.debugger.watchpoints.is_empty() {
+        sim.debugger.check_access(state.csr[csr_address::MHARTID] as usize, va, size, true);
+    }
+    return Ok(());
+}
 
-                let pte_ppn_2 = pte & 0x3FFFFFF0000000;
-                let pte_ppn_1 = pte & 0x0000000FF80000;
-                let pte_ppn_0 = pte & 0x0000000007FC00;
-                let pte_ppn = match i {
-                    0 => 0,
-                    1 => pte_ppn_0,
-                    2 => pte_ppn_0 | pte_ppn_1,
-                    3 => pte_ppn_0 | pte_ppn_1 | pte_ppn_2,
-                    _ => unreachable!(),
-                };
+fn write_virtual(sim: &mut Simulator, state: &CpuState, va: u64, size: u64, value: u64) -> Result<(), Exception> {
+    if (va & 0xfff) + size > 0x1000 {
+        // translate every byte first, a fault must not leave a partial store behind
+        for i in 0..size {
+            translate_address(&mut sim.mem, sim.mem_base, state, va + i, Access::Write)?;
+        }
+        for i in 0..size {
+            write_virtual(sim, state, va + i, 1, value >> (8 * i))?;
+        }
+        return Ok(());
+    }
+    let pa = translate_address(&mut sim.mem, sim.mem_base, state, va, Access::Write)?;
+    memory_written(sim, state.csr[csr_address::MHARTID] as usize, va, pa, size);
+    if !store(&mut sim.mem, sim.mem_base, &mut sim.devices, pa, size, value, &mut sim.uart_out) {
+        return Err(access_fault(Access::Write, va));
+    }
+    clear_reservations(&mut sim.states, pa);
+    return Ok(());
+}
 
+/*
+ * Loads and stores of translated code, they only do what needs no device and raises no exception:
+ * None (false) leaves the access to the interpreter, which faults, reaches the device or splits it across pages.
+ */
+pub fn ram_load(sim: &mut Simulator, state: &CpuState, va: u64, size: u64) -> Option<u64> {
+    if (va & 0xfff) + size > 0x1000 {
+        return None;
+    }
+    let pa = translate_address(&mut sim.mem, sim.mem_base, state, va, Access::Read).ok()?;
+    let range = ram_range(sim, pa, size)?;
+    let mut bytes = [0u8; 8];
+    bytes[..size as usize].copy_from_slice(&sim.mem[range]);
+    return Some(u64::from_le_bytes(bytes));
+}
 
-                if i>0 && pte_ppn != 0 {
-                    // page fault
-                    println!("errored on: {}, misaligned superpage", line!());
-                    return 0;
-                }
+pub fn ram_store(sim: &mut Simulator, state: &CpuState, va: u64, size: u64, value: u64) -> bool {
+    if (va & 0xfff) + size > 0x1000 {
+        return false;
+    }
+    let pa = match translate_address(&mut sim.mem, sim.mem_base, state, va, Access::Write) {
+        Ok(pa) => pa,
+        Err(_) => return false,
+    };
+    let range = match ram_range(sim, pa, size) {
+        Some(range) => range,
+        None => return false,
+    };
+    if sim.devices.htif.as_ref().is_some_and(|h| h.touches_tohost(pa, size)) {
+        return false;
+    }
+    memory_written(sim, state.csr[csr_address::MHARTID] as usize, va, pa, size);
+    sim.mem[range].copy_from_slice(&value.to_le_bytes()[..size as usize]);
+    clear_reservations(&mut sim.states, pa);
+    return true;
+}
 
-                // step 7
-                if pte_a == 0 || ((access_type == 1) && (pte_d == 0)) {
-                    println!("errored on: {}, page fault", line!());
-                    return 0;
-                }
+/*
+ * Memory as a debugger sees it from a HART: virtual addresses are translated like the HART's loads would, but
+ * without permission checks and without setting A and D bits, so that code on execute-only or read-only pages
+ * can be read and patched. Only RAM can be accessed so that reading a device register never has side effects.
+ */
+fn debug_ranges(sim: &Simulator, hart: usize, va: u64, length: u64) -> Option<Vec<(u64, u64, std::ops::Range<usize>)>> {
+    let mut ranges = vec![];
+    let mut done = 0;
+    while done < length {
+        let page_va = va.wrapping_add(done);
+        let size = (0x1000 - (page_va & 0xfff)).min(length - done);
+        let pa = translate_address(&mut DebugMemory(&sim.mem), sim.mem_base, &sim.states[hart], page_va, Access::Debug).ok()?;
+        ranges.push((page_va, pa, ram_range(sim, pa, size)?));
+        done += size;
+    }
+    return Some(ranges);
+}
 
-                // step 8
-                let mut pa : u64 = va & 0xfff; // set page offset
-                pa |= match i {
-                    0 => pte_ppn_0 | pte_ppn_1 | pte_ppn_2,
-                    1 => va_vpn[0] | pte_ppn_1 | pte_ppn_2,
-                    2 => va_vpn[0] | va_vpn[1] | pte_ppn_2,
-                    _ => unreachable!(),
-                };
-                return  pa;
-            }
-            i = i-1;
-            let pte_ppn = pte & 0x3FFFFFFFFFFC00;
-            a = pte_ppn*PAGESIZE;
+// None on a fault
+pub fn debug_read_memory(sim: &Simulator, hart: usize, va: u64, length: u64) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(length as usize);
+    for (_, _, range) in debug_ranges(sim, hart, va, length)? {
+        data.extend_from_slice(&sim.mem[range]);
+    }
+    return Some(data);
+}
+
+// writes nothing when any part of it faults, what it writes goes through memory_written like a store
+pub fn debug_write_memory(sim: &mut Simulator, hart: usize, va: u64, data: &[u8]) -> bool {
+    let ranges = match debug_ranges(sim, hart, va, data.len() as u64) {
+        Some(ranges) => ranges,
+        None => return false,
+    };
+    let mut done = 0;
+    for (page_va, pa, range) in ranges {
+        memory_written(sim, hart, page_va, pa, range.len() as u64);
+        let length = range.len();
+        sim.mem[range].copy_from_slice(&data[done..done + length]);
+        done += length;
+    }
+    return true;
+}
+
+// 16 bits of instruction memory, only RAM is executable
+fn fetch(sim: &mut Simulator, state: &CpuState, va: u64) -> Result<u16, Exception> {
+    let pa = translate_address(&mut sim.mem, sim.mem_base, state, va, Access::Execute)?;
+    let offset = pa.wrapping_sub(sim.mem_base);
+    if !in_ram(offset, 2, sim.mem.len()) {
+        return Err(access_fault(Access::Execute, va));
+    }
+    return Ok(u16::from_le_bytes(sim.mem[offset as usize .. (offset + 2) as usize].try_into().unwrap()));
+}
+
+// physical address of the instruction at va, None when fetching it faults
+pub fn fetch_address(sim: &mut Simulator, state: &CpuState, va: u64) -> Option<u64> {
+    let pa = translate_address(&mut sim.mem, sim.mem_base, state, va, Access::Execute).ok()?;
+    ram_range(sim, pa, 2)?;
+    return Some(pa);
+}
+
+// mip lines of a HART as driven by the CLINT and the PLIC
+fn interrupt_lines(devices: &Devices, hart: usize) -> u64 {
+    let mut lines = 0;
+    if devices.clint.software_pending(hart) {
+        lines |= 1 << IRQ_M_SOFT;
+    }
+    if devices.clint.timer_pending(hart) {
+        // without M-mode firmware the comparator set through SBI set_timer interrupts S-mode directly
+        lines |= if devices.sbi.is_some() { 1 << IRQ_S_TIMER } else { 1 << IRQ_M_TIMER };
+    }
+    if devices.plic.interrupt_pending(2 * hart) {
+        lines |= 1 << IRQ_M_EXT;
+    }
+    if devices.plic.interrupt_pending(2 * hart + 1) {
+        lines |= 1 << IRQ_S_EXT;
+    }
+    return lines;
+}
+
+/*
+ * Advances time by one tick and updates the interrupt lines of every HART.
+ * When every HART waits in WFI with nothing to wake it up, time jumps to the next timer interrupt.
+ */
+fn tick_devices(sim: &mut Simulator) {
+    let devices = &mut sim.devices;
+    devices.clint.mtime = devices.clint.mtime.wrapping_add(1);
+    let uart_pending = devices.uart.interrupt_pending();
+    devices.plic.set_level(UART_IRQ, uart_pending);
+    for (hart, state) in sim.states.iter_mut().enumerate() {
+        state.irq_lines = interrupt_lines(devices, hart);
+    }
+
+    let idle = sim.states.iter().all(|s| s.waiting && read_mip(s) & s.csr[csr_address::MIE] == 0);
+    let next_timer = devices.clint.mtimecmp.iter().copied().min().unwrap_or(u64::MAX);
+    if idle && next_timer != u64::MAX && next_timer > devices.clint.mtime {
+        devices.clint.mtime = next_timer;
+        for (hart, state) in sim.states.iter_mut().enumerate() {
+            state.irq_lines = interrupt_lines(devices, hart);
         }
-        // page fault
-        println!("errored on: {}", line!());
-        return 0;
-    } else {
-        return va;
-    }
-}
-
-fn load(mem: &mut Vec<u8>, func3: u8, address: u64) -> u64{
-    let mut rd = 0;
-
-    if address < 0x1000 {
-        
-        match func3 {
-            0b000 => { rd = mem[address as usize] as i8 as u64 },
-            0b001 => { rd = i16::from_le_bytes(mem[address as usize .. (address + 2) as usize ].try_into().unwrap()) as u64; },
-            0b010 => { rd = i32::from_le_bytes(mem[address as usize .. (address + 4) as usize ].try_into().unwrap()) as u64; },
-            0b100 => { rd = mem[address as usize] as u64 },
-            0b101 => { rd = u16::from_le_bytes(mem[address as usize .. (address + 2) as usize ].try_into().unwrap()) as u64; },
-            0b011 => { rd = i64::from_le_bytes(mem[address as usize .. (address + 8) as usize ].try_into().unwrap()) as u64; }
-            _     => {
-                //trap = 1;
-                println!("errored on: {}, func3: {:#b}", line!(), func3);
-            },
-        }
-        
-    } else if address == 0x10000000 {
-        // uart
-    } else if address <= 0x1000007 {
-        println!("WARN on: {}, READING UART STATUS REGISTERS IS NOT SUPPORTED, address: {:X}", line!(), address);
-    } else {
-        println!("errored on: {}, address: {:X}", line!(), address);
-        //sim.state = false;
-    }
-    return rd;
-}
-
-fn store(mem: &mut Vec<u8>, func3: u8, address: u64, rs2: u64, uart_out: &mut Vec<u8>){
-    if address < mem.len() as u64 {
-        match func3 {
-            0b000 => { 
-                mem[address as usize] = rs2 as u8;
-            },
-            0b001 => { 
-                mem[ address      as usize] =  rs2       as u8;
-                mem[(address + 1) as usize] = (rs2 >> 8) as u8;
-            },
-            0b010 => {
-                mem[ address      as usize] =  rs2        as u8;
-                mem[(address + 1) as usize] = (rs2 >>  8) as u8;
-                mem[(address + 2) as usize] = (rs2 >> 16) as u8;
-                mem[(address + 3) as usize] = (rs2 >> 24) as u8;
-            },
-            0b011 => {
-                mem[ address      as usize] =  rs2        as u8;
-                mem[(address + 1) as usize] = (rs2 >>  8) as u8;
-                mem[(address + 2) as usize] = (rs2 >> 16) as u8;
-                mem[(address + 3) as usize] = (rs2 >> 24) as u8;
-                mem[(address + 4) as usize] = (rs2 >> 32) as u8;
-                mem[(address + 5) as usize] = (rs2 >> 40) as u8;
-                mem[(address + 6) as usize] = (rs2 >> 48) as u8;
-                mem[(address + 7) as usize] = (rs2 >> 56) as u8;
-            }
-            _     => {
-                //trap = 1;
-                println!("errored on: {}", line!());
-            },
-        }
-    } else if address == 0x10000000 {
-        // UART
-        uart_out.push(rs2 as u8)
-    } else if (0x10000000..0x10000007).contains(&address)  {
-        println!("WARN on: {}, WRITING UART STATUS REGISTERS IS NOT SUPPORTED, address: {:X}, value: {:X}", line!(), address, rs2);
-    } else {
-        println!("errored on: {}, address: 0x{:X}", line!(), address);
-        //sim.state = false;
     }
 }
 
+/*
+ * true when the next step of the HART executes an instruction: the SBI has not stopped it, it does not wait in WFI
+ * and it does not take an interrupt. The interrupt lines are brought up to date first, like a tick does.
+ */
+pub fn runs_uninterrupted(sim: &mut Simulator, hart: usize) -> bool {
+    if sim.devices.sbi.as_ref().is_some_and(|sbi| sbi.stopped(hart)) {
+        return false;
+    }
+    let uart_pending = sim.devices.uart.interrupt_pending();
+    sim.devices.plic.set_level(UART_IRQ, uart_pending);
+    let lines = interrupt_lines(&sim.devices, hart);
+    let state = &mut sim.states[hart];
+    state.irq_lines = lines;
+    return !state.waiting && pending_interrupt(state).is_none();
+}
+
+// steps before the timer of the HART fires, u64::MAX when it is already pending and later steps can not change that
+pub fn steps_before_timer(sim: &Simulator, hart: usize) -> u64 {
+    let clint = &sim.devices.clint;
+    if clint.mtimecmp[hart] <= clint.mtime {
+        return u64::MAX;
+    }
+    return clint.mtimecmp[hart] - clint.mtime - 1;
+}
+
+// count instructions that ran outside of step(): time advances and the HART retires them as if they were count steps
+pub fn retire_batch(sim: &mut Simulator, hart: usize, count: u64) {
+    sim.devices.clint.mtime = sim.devices.clint.mtime.wrapping_add(count);
+    let state = &mut sim.states[hart];
+    let minstret = state.csr[csr_address::MINSTRET].wrapping_add(count);
+    state.csr.set(csr_address::MINSTRET, minstret);
+    state.csr.set(csr_address::MCYCLE, minstret);
+}
+
+// step() of batch runs, a quantum of every HART in parallel or whole blocks at a time when the JIT is built in and turned on
+pub fn batch_step(sim: &mut Simulator) -> bool {
+    if sim.parallel.is_some() {
+        return parallel_step(sim);
+    }
+    #[cfg(feature = "jit")]
+    if sim.jit.is_some() {
+        return jit_step(sim);
+    }
+    return step(sim);
+}
+
 pub fn step(sim: &mut Simulator) -> bool{
-    let states = &mut sim.states;
+    if sim.history.is_none() {
+        return step_machine(sim);
+    }
+    let snapshot = history_snapshot(sim);
+    let scalars = history_scalars(sim);
+    let should_continue = step_machine(sim);
+    history_record(sim, snapshot, scalars);
+    return should_continue;
+}
+
+// Forgets that the guest exited, after going back in time to before the exit
+pub fn clear_guest_exit(sim: &mut Simulator) {
+    if let Some(finisher) = sim.devices.test_finisher.as_mut() {
+        finisher.status = None;
+    }
+    if let Some(sbi) = sim.devices.sbi.as_mut() {
+        sbi.status = None;
+    }
+    if let Some(semihosting) = sim.devices.semihosting.as_mut() {
+        semihosting.status = None;
+    }
+    if let Some(syscalls) = sim.devices.syscalls.as_mut() {
+        syscalls.exit_code = None;
+    }
+    if let Some(htif) = sim.devices.htif.as_mut() {
+        htif.exit_code = None;
+    }
+}
 
+/*
+ * Runs f on HART i taken out of the simulator, stores can still reach the other HARTs.
+ * The HART goes back even when f panics, the server and the stream keep the session after a panic.
+ */
+pub fn with_hart<T>(sim: &mut Simulator, i: usize, f: impl FnOnce(&mut Simulator, &mut CpuState) -> T) -> T {
+    let mut state = std::mem::take(&mut sim.states[i]);
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| f(sim, &mut state)));
+    sim.states[i] = state;
+    return result.unwrap_or_else(|payload| panic::resume_unwind(payload));
+}
+
+fn step_machine(sim: &mut Simulator) -> bool{
     let should_continue = true;
+    let mut reset_requested = false;
 
+    tick_devices(sim);
 
+    for i in scheduled_harts(sim) { // step the HARTs the scheduler picked, all of them in lockstep
+        let hart_continues = with_hart(sim, i, step_hart);
+        if !hart_continues {
+            return false;
+        }
 
-    for i in 0..states.len(){ // step all HARTs
-        let mut state = &mut states[i];
-        
-        // fetch
-        let pc = state.pc;
-        let mut npc: Option<u64> = None; // new pc
-
-        state.last_pc = pc;
-        let ir: u32 = u32::from_le_bytes(sim.mem[pc as usize .. (pc + 4) as usize ].try_into().unwrap());
-        // clear sim out
-        sim.sim_out = String::from("");
-        state.last_instruction = format!("{:X}", ir);
-
-        let mut err = format!("0b{:b} ", ir);
-        err.push_str(&String::from("illegal instruction"));
-    
-
-        let mut imm:   u32 = 0;
-        let mut func3: u8  = 0;
-        let mut func7: u8  = 0;
-
-        // 'decode'
-        sim.log = String::from("decode");
-        // 130
-        let     opcode: u8 = ((ir & 0x7F) >> 2) as u8;
-        let mut rs1i:   u8 = 0;
-        let mut rs2i:   u8 = 0;
-        let mut rdi:    u8 = 0;
-        
-        //64 bit instructions
-        //if (ir ^ 0b0111111) & 0b1111111 != 0 { 
-        // 32 bit
-        if ((ir & 0b11) != 3) || ((ir & 0b11100) == 0b11100)  {
-            sim.log = err;
-            println!("ERROR: line {}", line!());
+        if let Some(code) = sim.devices.htif.as_ref().and_then(|h| h.exit_code) {
+            sim.log = format!("guest exit: HTIF, code {}", code);
             return false;
         }
+        if let Some(code) = sim.devices.syscalls.as_ref().and_then(|s| s.exit_code) {
+            sim.log = format!("guest exit: exit, code {}", code);
+            return false;
+        }
+        if let Some(cosim) = sim.commit_log.as_ref().and_then(|log| log.cosim.as_ref()) {
+            if cosim.divergence.is_some() || cosim.ended {
+                sim.log = String::from(if cosim.ended { "cosim: end of the reference trace" } else { "cosim: divergence" });
+                return false;
+            }
+        }
 
-        // decode 
-        match opcode {
-            
-            // R-type
-            0b01100 => {
-                func7 =  (ir >> 25) as u8;
-                rs2i  = ((ir >> 20) & 0b11111) as u8;
-                rs1i  = ((ir >> 15) & 0b11111) as u8;
-                func3 = ((ir >> 12) & 0b00111) as u8;
-                rdi   = ((ir >>  7) & 0b11111) as u8;
+        // the guest wrote to the test finisher, asked the built-in SBI for a system reset or exited through semihosting
+        let finisher = sim.devices.test_finisher.as_mut().map(|f| &mut f.status);
+        let sbi = sim.devices.sbi.as_mut().map(|s| &mut s.status);
+        let semihosting = sim.devices.semihosting.as_mut().map(|s| &mut s.status);
+        for status in [finisher, sbi, semihosting].into_iter().flatten() {
+            match *status {
+                Some(FinisherStatus::Pass) => {
+                    sim.log = String::from("guest exit: pass");
+                    return false;
+                },
+                Some(FinisherStatus::Fail(code)) => {
+                    sim.log = format!("guest exit: fail, code {}", code);
+                    return false;
+                },
+                Some(FinisherStatus::Reset) => {
+                    *status = None;
+                    reset_requested = true;
+                },
+                None => {},
             }
+        }
+        if reset_requested {
+            break;
+        }
+    }
+    if reset_requested {
+        reset(sim);
+        sim.log = String::from("guest reset");
+    }
+    return should_continue;
+}
 
-            // I-type [JARL | LOAD | ADD+ | ADDIW | SYSTEM
-            0b11001 | 0b00000 | 0b00100 | 0b00110 | 0b11100 => { 
-                //rs1 = state.regs[(ir & 0x00f8000) as usize];
-                imm   =   ir >> 20;
-                rs1i  = ((ir >> 15) & 0b11111) as u8;
-                func3 = ((ir >> 12) & 0b00111) as u8;
-                rdi   = ((ir >>  7) & 0b11111) as u8;
-            }, 
-
-            // S-type
-            0b01000 => { // STORES
-                imm   =  (ir >>  7) & 0b11111 | ((ir >>  25) & 0b1111111) << 5;
-                rs2i  = ((ir >> 20) & 0b11111) as u8;
-                rs1i  = ((ir >> 15) & 0b11111) as u8;
-                func3 = ((ir >> 12) & 0b00111) as u8;
-            },
-
-            // B-type [BEQ]
-            0b11000 => { 
-                imm = (ir & 0x80000000) >> 19 |
-                    (ir & 0x7e000000) >> 20 |
-                    (ir & 0x00000f00) >> 7  |
-                    (ir & 0x00000080) << 4;
-                rs2i  =((ir >> 20) & 0b11111) as u8;
-                rs1i  =((ir >> 15) & 0b11111) as u8;
-                func3 =((ir >> 12) & 0b00111) as u8;
-            },
-
-            // U-type [LUI AUIPC]
-            0b01101 | 0b00101 => { 
-                imm =   ir & 0xfffff000;
-                rdi = ((ir >>  7) & 0b11111) as u8;
-            }, 
-
-            //J-type [JAL]
-            0b11011 => { 
-                imm = (ir & 0x80000000) >> 11 |
-                    (ir & 0x7fe00000) >> 20 |
-                    (ir & 0x00100000) >> 9  |
-                    (ir & 0x000ff000);
-                rdi =((ir >>  7) & 0b11111) as u8;
-            }, 
-
-            _ => { 
-                sim.log = err;
-                println!("errored on: {}", line!());
-                return false;
+// Runs one instruction, or takes an interrupt, on one HART. false when the simulation has to stop.
+fn step_hart(sim: &mut Simulator, state: &mut CpuState) -> bool {
+    // clear sim out
+    sim.sim_out.clear();
+
+    if let Some(sbi) = sim.devices.sbi.as_mut() {
+        // HARTs wait for SBI hart_start, suspended HARTs continue once an interrupt is pending
+        if sbi.stopped(state.csr[csr_address::MHARTID] as usize) {
+            return true;
+        }
+        if state.waiting && read_mip(state) & state.csr[csr_address::MIE] != 0 {
+            sbi_resume(sbi, state);
+        }
+    }
+    if let Some(cause) = pending_interrupt(state) {
+        state.waiting = false;
+        return handle_trap(sim, state, cause, 0);
+    }
+    if state.waiting {
+        // WFI also ends on interrupts that are globally disabled
+        if read_mip(state) & state.csr[csr_address::MIE] == 0 {
+            return true;
+        }
+        state.waiting = false;
+    }
+
+    match execute(sim, state) {
+        Ok(()) => {
+            let minstret = state.csr[csr_address::MINSTRET].wrapping_add(1);
+            state.csr.set(csr_address::MINSTRET, minstret);
+            state.csr.set(csr_address::MCYCLE, minstret);
+            return true;
+        },
+        Err(exception) => {
+            if sim.trace {
+                sim.log = format!("{}, tval: 0x{:X}", cause_name(exception.cause), exception.tval);
             }
+            return handle_trap(sim, state, exception.cause, exception.tval);
+        },
+    }
+}
+
+/*
+ * The decoded instruction at pc. Instructions are cached by physical address; the translation is done every time,
+ * it checks the permissions and sets the A bit. An instruction that crosses a page is fetched and decoded every time,
+ * its second half may translate to a different physical page.
+ */
+pub fn fetch_decoded(sim: &mut Simulator, state: &CpuState, pc: u64) -> Result<Decoded, Exception> {
+    let pa = translate_address(&mut sim.mem, sim.mem_base, state, pc, Access::Execute)?;
+    if let Some(decoded) = sim.decode_cache.get(pa) {
+        return Ok(decoded);
+    }
+    let low = fetch(sim, state, pc)?;
+    if low & 0b11 != 0b11 {
+        let decoded = decode_raw(low as u32);
+        sim.decode_cache.insert(pa, decoded);
+        return Ok(decoded);
+    }
+    let high = fetch(sim, state, pc.wrapping_add(2))?;
+    let decoded = decode_raw(low as u32 | (high as u32) << 16);
+    if pc & 0xfff != 0xffe {
+        sim.decode_cache.insert(pa, decoded);
+    }
+    return Ok(decoded);
+}
+
+/*
+ * Without --trace a step keeps only the pc and bits of the instruction it ran, this fills in what a traced step
+ * would have left in last_instruction and sim_out. For whoever looks at the state, the server before every response.
+ */
+pub fn describe_last_step(sim: &mut Simulator) {
+    if sim.trace {
+        return;
+    }
+    sim.sim_out.clear();
+    for i in 0..sim.states.len() {
+        // a HART that has not run yet has no instruction to describe
+        if sim.states[i].last_raw == 0 {
+            continue;
         }
+        let decoded = decode_raw(sim.states[i].last_raw);
+        sim.states[i].last_instruction = disassemble_instruction(sim, &decoded, sim.states[i].last_pc);
+        sim.sim_out.push_str(&format!("\r\n{:?}", decoded.instruction));
+    }
+}
 
-        let mut rs1: u64 = state.regs[rs1i as usize];
-        let mut rs2: u64 = state.regs[rs2i as usize];
-        let mut rd:  u64 = 0;
-
-        // execute
-        sim.log = String::from("execute");
-
-        sim.sim_out.push_str(&*format!("\r\nrs1i: {:}, rs1: {:}, rs2i: {:}, rs2: {:}, rdi: {:}, imm: {:}, func3: {:}, func7: {:}", rs1i, rs1, rs2i, rs2, rdi, imm, func3, func7));
-
-        // Instruction Set Listings p 130
-        // TODO: sign extend to 64 not 32bits?
-        let mut trap: u8 = 0;
-        match opcode {
-            
-            0b01101 => { rd =       (ir & 0xfffff000) as u64;  }, // LUI
-            0b00101 => { rd = pc + ((ir & 0xfffff000) as i64) as u64; }, // Add upper immediate to PC
-            0b11011 => { // JAL: Jump and link
-                if imm & 0x00100000 != 0 {imm |= 0xffe00000; }
-                rd  = pc  + 4;
-                println!("JAL: imm: {}", imm as i64);
-                npc = Some(pc + imm as i32 as i64 as u64);
-                //TODO: gen instruction-address-misaligned exception if the target address is not aligned to a four-byte boundary.
-            }, 
-            0b11001 => { // JALR: Jump and link indirect
-                if imm & 0x0000800 != 0 {imm |= 0xffffe000; }
-                rd = pc + 4;
-                npc = Some( (rs1 + imm as i64 as u64) & !1);
-                //TODO: gen instruction-address-misaligned exception if the target address is not aligned to a four-byte boundary.
-            }, 
-            0b11000 => { // BEQ
-                if imm & 0x1000 != 0 {imm |= 0xffffe000; }
-                let addr = pc + imm as i64 as u64;
-
-                // BEQ BNE BLT BGE BLTU BGEU
-                println!("BEQ+: r{:}:{:} op r{:}:{:}; addr: {:X}={:X}+{:X}-4", rs1i, rs1, rs2i, rs2, addr, pc, imm);
-                match func3 {
-                    0b000 => { if  rs1 == rs2 {npc = Some(addr);} }
-                    0b001 => { if  rs1 != rs2 {npc = Some(addr);} }
-                    0b100 => { if (rs1 as i64) <  (rs2 as i64) {npc = Some(addr);} }
-                    0b101 => { if (rs1 as i64) >= (rs2 as i64) {npc = Some(addr);} }
-                    0b110 => { if (rs1 as u64) <  (rs2 as u64) {npc = Some(addr);} }
-                    0b111 => { if (rs1 as u64) >= (rs2 as u64) {npc = Some(addr);} }
-                    _     => {trap = 1;println!("errored on: {}", line!());}
-                }
-            },
-            0b00000 => { // Loads
-                // L-type
-                //TODO: ??
-                // LB LH LW LBU LHU
-
-                if imm & 0x800 != 0 {imm |= 0xfffff000; }
-                let address = rs1 + (imm as i32 as u64);
-                rd = load(&mut sim.mem, func3, address);
-            },
-            0b01000 => { // Stores
-                // S-type
-
-                // TODO pipeline out
-                if imm & 0x800 != 0 {imm |= 0xfffff000; }
-                let address = rs1 + (imm as i32 as i64 as u64);
-                println!("Stored rs{:}: {:} at (imm + r{:}): {:}+0x{:X}=0x{:X} with func3: {}", rs2i, rs2, rs1i, imm as i32 as i64, rs1, address, func3);
-                store(&mut sim.mem, func3, address, rs2, &mut sim.uart_out);
-            },
-            0b00100 | 0b01100 => {
-                // ADDI SLTI SLTIU XORI ANDI SLLI SDAI
-                // ADD SUB SLL SLT SLTU XOR SRL SRA OR AND
-
-                // Checks in the opcode
-                let is_imm: bool = (opcode & 0b01000) == 0;
-                if is_imm {
-                    if imm & 0x00000800 != 0 {imm |= 0xfffff000; }
-                    rs2 = imm as i32 as i64 as u64;
-                    println!("Used immediate {:}, {:#b}", rs2 as i64, rs2 as i64);
-                }
+// instructions decoded or translated from [pa, pa + length) are stale
+pub fn code_written(sim: &mut Simulator, pa: u64, length: u64) {
+    sim.decode_cache.invalidate(pa, length);
+    #[cfg(feature = "jit")]
+    if let Some(jit) = sim.jit.as_mut() {
+        jit_memory_written(jit, pa, length);
+    }
+    if let Some(parallel) = sim.parallel.as_mut() {
+        parallel_memory_written(parallel, pa, length);
+    }
+}
 
+// a guest, host or debugger write to [pa, pa + length): cached instructions there are stale, the undo log keeps the old bytes
+pub fn memory_written(sim: &mut Simulator, hart: usize, va: u64, pa: u64, length: u64) {
+    code_written(sim, pa, length);
+    if sim.history.is_some() {
+        history_memory_write(sim, hart, va, pa, length);
+    }
+}
 
-                //TODO RV32M
-                match func3 {
-                    0b000 => {rd = if is_imm || (ir & 0x40000000) == 0 {rs1+rs2} else {rs1-rs2}}, // ADDI ADD SUBI
-                    0b001 => {
-                        if rs2 > 63 {
-                            sim.log = String::from("Attempted to bit shift left too much!");
-                            println!("errored on: {}", line!());
-                            return false;
-                        }
-                        rd = rs1 << rs2
-                    }, //SLLI SLL
-                    0b010 => {rd = ((rs1 as i64) < (rs2 as i64)) as u64}, //SLTI SLT
-                    0b011 => {rd = ((rs1 as u64) < (rs2 as u64)) as u64}, //SLTIU SLTU
-                    0b100 => {rd = rs1 ^ rs2}, //XORI XOR
-                    0b101 => {rd = if (ir & 0x40000000) != 0 { (rs1 as i64 >> rs2) as u64 } else {rs1 >> rs2 }}, //SRLI SRAI SRL SRA
-                    0b110 => {rd = rs1 | rs2}, //ORI OR
-                    0b111 => {rd = rs1 & rs2}, //AND I AND
-                    _ => {
-                        sim.log = err;
-                        println!("ERROR! incorrect func3!, line: {}", line!());
-                        return false;
-                    }
-                }
-            },
-            0b00011 => {
-                // Fence
-
-            },
-            0b11100 => { // SYSTEM
-                let mut csr = &mut sim.csr;
-
-                // handle uimm versions
-                let is_imm2 = func3 & 0b100 != 0;
-                if is_imm2 {rs1 = rs1i as u64;};
-
-                match func3 & 0b11 {
-                    0b00 => { 
-                        // p21
-                        if        imm == 0b000000000000 { // ECALL
-                            // ECALL | EBREAK
-                            // cause a precise trap to the supporting execution environment
-                            // set epc register for the recieving privilidge mode to the address of the ECALL and EBREAK instructions themselves
-                            
-                            npc = Some(handle_trap(pc, &mut state, &mut csr));
-
-                            println!("ERROR! unimplemented, line: {}", line!());
-                            return false;
-                        } else if imm == 0b000000000001 { // EBREAK
-
-                            println!("ERROR! unimplemented, line: {}", line!());
-                            return false;
-                        } 
-                        
-                        // TODO: pop the relevant lower-privilege interrupt enable and privilege mode stack
-                        // TODO: An xRET instruction can be executed in privilege mode x or higher,
-                        else if imm == 0b000100000010 { // SRET
-
-                            // TODO: raise illegal instruction exception when TSR=1 in mstatus
-                            
-                            npc = Some(csr[&csr_address::SEPC ]);
-                            println!("ERROR! unimplemented, line: {}", line!());
-                            return false;
-                        } else if imm == 0b001100000010 { // MRET 18.6.4
-                            // new privilege mode based on MPP and MPV in mstatus ro mstatush
-                            // mpv=0
-                            // mpp=0
-                            // mie=mpie
-                            // mpie=1
-                            // priv = new_privilege_mode;
-                            npc = Some(csr[&csr_address::MEPC ]);
-                            println!("ERROR! unimplemented, line: {}", line!());
-                            return false;
-                        } else{
-                            println!("ERROR! incorrect func3!, line: {}", line!());
-                            return false;
-                        }
-                        // set cause
-                        // set pc
-                        
-                    },
-                    //---------
-                    //- Zicsr -
-                    //---------
-                    0b01 => {
-                        // CSRRW(I)
-                        if rdi != 0 {
-                            rd = sim.csr[&imm]; //TODO zero extend
-                        }
-                        sim.csr.insert(imm, rs1);
-                        println!("INFO: executed CSRRW(I) on {}", sim.csr_address_to_name[&imm]);
-                    },
-                    0b10 => {
-                        // CSRRS(I)
-                        rd = sim.csr[&imm]; //TODO zero extend
-                        if rs1i != 0 { // THIS ALSO CHECKS THE uimm AS PER THE SPEC
-                            sim.csr.insert(imm, sim.csr[&imm] | rs1);
-                        }
-                        println!("INFO: executed CSRRS(I) on {}", sim.csr_address_to_name[&imm]);
-                    },
-                    0b11 => {
-                        // CSRRC(I)
-                        rd = sim.csr[&imm]; //TODO zero extend
-                        if rs1i != 0 {
-                            sim.csr.insert(imm, sim.csr[&imm] & !rs1);
-                        }
-                        println!("INFO: executed CSRRC(I) on {}", sim.csr_address_to_name[&imm]);
+fn execute(sim: &mut Simulator, state: &mut CpuState) -> Result<(), Exception> {
+    let pc = state.pc;
+    let mut npc: Option<u64> = None; // new pc
+    let privilege_before = state.priviledge_mode;
+    let traced = commit_log_begin(sim, pc);
+
+    state.last_pc = pc;
+    let decoded = fetch_decoded(sim, state, pc)?;
+    let raw = decoded.raw;
+    let ilen = decoded.length as u64;
+    state.last_raw = raw;
+    if sim.trace {
+        state.last_instruction = disassemble_instruction(sim, &decoded, pc);
+        sim.sim_out.push_str(&format!("\r\n{:?}", decoded.instruction));
+        trace_operands(sim, state, &decoded, pc);
+    }
+
+    let mut rdi: u8  = 0;
+    let mut rd:  u64 = 0;
+
+    // Instruction Set Listings p 130
+    match decoded.instruction {
+        Instruction::Load { rd: d, rs1, offset, size, signed } => { // LB LH LW LD LBU LHU LWU
+            let address = state.regs[rs1 as usize].wrapping_add(offset as u64);
+            rdi = d;
+            rd  = extend_load(size, signed, read_memory(sim, state, address, size as u64, Access::Read)?);
+            if traced {
+                commit_log_load(sim, address);
+            }
+        },
+        Instruction::Store { rs1, rs2, offset, size } => { // SB SH SW SD
+            let address = state.regs[rs1 as usize].wrapping_add(offset as u64);
+            let value = state.regs[rs2 as usize];
+            if sim.trace {
+                println!("Stored rs{:}: {:} at (imm + r{:}): {:}+0x{:X}=0x{:X} with size: {}", rs2, value, rs1, offset, state.regs[rs1 as usize], address, size);
+            }
+            write_memory(sim, state, address, size as u64, value)?;
+            if traced {
+                commit_log_store(sim, address, size as u64, value);
+            }
+        },
+        Instruction::Fence { .. } => {
+            // memory is sequentially consistent
+        },
+        Instruction::FenceI => {
+            // instructions stored to memory become visible to this HART, and to the others as well
+            sim.decode_cache.clear();
+        },
+        Instruction::Sret | Instruction::Mret | Instruction::Wfi | Instruction::SfenceVma { .. } => {
+            npc = system_instruction(state, decoded.instruction, raw)?;
+            if traced {
+                match decoded.instruction {
+                    Instruction::Sret => {
+                        let sstatus = csr_read(state, sim.devices.clint.mtime, csr_address::SSTATUS).unwrap_or(0);
+                        commit_log_csr(sim, csr_address::SSTATUS, sstatus);
                     },
-                    _ => unreachable!(),
+                    Instruction::Mret => commit_log_csr(sim, csr_address::MSTATUS, state.csr[csr_address::MSTATUS]),
+                    _ => {},
                 }
+            }
+        },
+        //---------
+        //- Zicsr -
+        //---------
+        Instruction::Csr { rd: d, .. } | Instruction::CsrImm { rd: d, .. } => {
+            rdi = d;
+            rd  = csr_instruction(sim, state, &decoded, traced)?;
+        },
+        Instruction::Lr { .. } | Instruction::Sc { .. } | Instruction::Amo { .. } => {
+            let (d, value) = atomic(sim, state, decoded.instruction, traced)?;
+            rdi = d;
+            rd  = value;
+        },
+        _ => {
+            (rdi, rd, npc) = register_instruction(state, &decoded, pc)?;
+        },
+    }
 
-            },
+    // store
+    if rdi != 0 {
+        state.regs[rdi as usize] = rd;
+        if traced {
+            commit_log_reg(sim, rdi, rd);
+        }
+    }
 
+    state.pc = match npc {
+        Some(x) => x,
+        None    => pc.wrapping_add(ilen)
+    };
+    if traced {
+        commit_log_commit(sim, state, privilege_before, pc, raw, ilen);
+    }
 
-            //---------
-            //- RV64i -
-            //---------
-            0b00110 => {
+    if sim.trace {
+        sim.log = rd.to_string();
+    }
+    return Ok(());
+}
 
-                // SEXT
-                if imm & 0x00000800 != 0 {imm |= 0xfffff000; }
-                rs2 = imm as i32 as i64 as u64;
-                println!("Used immediate {:}, {:#b}", rs2 as i64, rs2 as i64);
+/*
+ * The instructions that only read and write registers and the pc, or trap: rd, its value and the pc a jump or a taken
+ * branch goes to. execute and the HART threads of --parallel both run them through here.
+ */
+pub fn register_instruction(state: &CpuState, decoded: &Decoded, pc: u64) -> Result<(u8, u64, Option<u64>), Exception> {
+    let regs = &state.regs;
+    let link = pc.wrapping_add(decoded.length as u64);
+    return match decoded.instruction {
+        Instruction::Lui { rd, imm } => Ok((rd, imm as u64, None)),
+        // Add upper immediate to PC
+        Instruction::Auipc { rd, imm } => Ok((rd, pc.wrapping_add(imm as u64), None)),
+        // JAL: Jump and link
+        Instruction::Jal { rd, offset } => Ok((rd, link, Some(pc.wrapping_add(offset as u64)))),
+        // JALR: Jump and link indirect
+        Instruction::Jalr { rd, rs1, offset } => Ok((rd, link, Some(regs[rs1 as usize].wrapping_add(offset as u64) & !1))),
+        // BEQ BNE BLT BGE BLTU BGEU
+        Instruction::Branch { op, rs1, rs2, offset } => {
+            let taken = branch_taken(op, regs[rs1 as usize], regs[rs2 as usize]);
+            Ok((0, 0, if taken { Some(pc.wrapping_add(offset as u64)) } else { None }))
+        },
+        // ADDI SLTI SLTIU XORI ORI ANDI SLLI SRLI SRAI
+        Instruction::OpImm { op, rd, rs1, imm } => Ok((rd, alu(op, regs[rs1 as usize], imm as u64), None)),
+        // ADD SUB SLL SLT SLTU XOR SRL SRA OR AND, MUL MULH ... REMU
+        Instruction::Op { op, rd, rs1, rs2 } => Ok((rd, alu(op, regs[rs1 as usize], regs[rs2 as usize]), None)),
+        // ADDIW SLLIW SRLIW SRAIW
+        Instruction::OpImm32 { op, rd, rs1, imm } => Ok((rd, alu_word(op, regs[rs1 as usize] as u32, imm as u32), None)),
+        // ADDW SUBW SLLW SRLW SRAW, MULW DIVW DIVUW REMW REMUW
+        Instruction::Op32 { op, rd, rs1, rs2 } => Ok((rd, alu_word(op, regs[rs1 as usize] as u32, regs[rs2 as usize] as u32), None)),
+        // a precise trap to the execution environment, epc is the address of the ECALL or EBREAK itself
+        Instruction::Ecall => Err(Exception { cause: CAUSE_ECALL_U + state.priviledge_mode as u64, tval: 0 }),
+        Instruction::Ebreak => Err(Exception { cause: CAUSE_BREAKPOINT, tval: pc }),
+        Instruction::Illegal => Err(illegal_instruction(decoded.raw)),
+        _ => unreachable!(),
+    };
+}
 
-                match func3 {
-                    0b000 => {
-                        // ADDIW
-                        rd = (rs1+rs2) & 0xffffffff;
-                        if rd & 0x80000000 != 0 {rd |= 0xffffffff00000000; }
-                    },
-                    0b001 => {
-                        //SLLIW
-                        println!("errored on: {}", line!());
-                    },
-                    0b101 => {
-                        //SRLIW & SRAIW
-                        println!("errored on: {}", line!());
-                        rd = if (ir & 0x40000000) != 0 { (rs1 as i64 >> rs2) as u64 } else {rs1 >> rs2 }
-                    },
-                    _ => {
-                        sim.log = err;
-                        println!("ERROR! incorrect func3!, line: {}", line!());
-                        return false;
-                    }
-                }
-            },
+// --trace output of the jumps, branches and immediates
+fn trace_operands(sim: &Simulator, state: &CpuState, decoded: &Decoded, pc: u64) {
+    match decoded.instruction {
+        Instruction::Jal { offset, .. } => {
+            let target = pc.wrapping_add(offset as u64);
+            println!("JAL: imm: {} {}", offset, symbolize(&sim.symbols, target).unwrap_or_default());
+        },
+        Instruction::Branch { rs1, rs2, offset, .. } => {
+            let (a, b) = (state.regs[rs1 as usize], state.regs[rs2 as usize]);
+            println!("BEQ+: r{:}:{:} op r{:}:{:}; addr: {:X}={:X}+{:X}-4", rs1, a, rs2, b, pc.wrapping_add(offset as u64), pc, offset);
+        },
+        Instruction::OpImm { imm, .. } | Instruction::OpImm32 { imm, .. } => {
+            println!("Used immediate {:}, {:#b}", imm, imm);
+        },
+        _ => {},
+    }
+}
 
-            _ => {
-                sim.log = err;
-                println!("errored on: {}", line!());
-                return false;
-            },
+// SRET MRET WFI SFENCE.VMA, they only change the HART. Returns the pc an xRET returns to
+pub fn system_instruction(state: &mut CpuState, instruction: Instruction, raw: u32) -> Result<Option<u64>, Exception> {
+    let privilege = state.priviledge_mode;
+    let mstatus = state.csr[csr_address::MSTATUS];
+    match instruction {
+        Instruction::Sret => {
+            // raise illegal instruction exception when TSR=1 in mstatus
+            if privilege < PRIV_S || (privilege == PRIV_S && mstatus & MSTATUS_TSR != 0) {
+                return Err(illegal_instruction(raw));
+            }
+            return Ok(Some(trap_return(state, PRIV_S)));
+        },
+        Instruction::Mret => { // 18.6.4
+            if privilege != PRIV_M {
+                return Err(illegal_instruction(raw));
+            }
+            return Ok(Some(trap_return(state, PRIV_M)));
+        },
+        Instruction::Wfi => {
+            if privilege == PRIV_U || (privilege == PRIV_S && mstatus & MSTATUS_TW != 0) {
+                return Err(illegal_instruction(raw));
+            }
+            state.waiting = true;
+        },
+        _ => { // SFENCE.VMA, there is no TLB to flush
+            if privilege == PRIV_U || (privilege == PRIV_S && mstatus & MSTATUS_TVM != 0) {
+                return Err(illegal_instruction(raw));
+            }
+        },
+    }
+    return Ok(None);
+}
+
+pub fn branch_taken(op: BranchOp, a: u64, b: u64) -> bool {
+    return match op {
+        BranchOp::Eq  => a == b,
+        BranchOp::Ne  => a != b,
+        BranchOp::Lt  => (a as i64) <  (b as i64),
+        BranchOp::Ge  => (a as i64) >= (b as i64),
+        BranchOp::Ltu => a <  b,
+        BranchOp::Geu => a >= b,
+    };
+}
+
+// sign or zero extends a raw little-endian value of size bytes
+pub fn extend_load(size: u8, signed: bool, raw: u64) -> u64 {
+    return match (size, signed) {
+        (1, true)  => raw as i8  as u64,
+        (2, true)  => raw as i16 as u64,
+        (4, true)  => raw as i32 as u64,
+        (1, false) => raw as u8  as u64,
+        (2, false) => raw as u16 as u64,
+        (4, false) => raw as u32 as u64,
+        _          => raw,
+    };
+}
+
+// OP and OP-IMM, rs2 is the sign extended immediate of OP-IMM
+pub fn alu(op: AluOp, rs1: u64, rs2: u64) -> u64 {
+    let shamt = rs2 & 0x3f;
+    return match op {
+        AluOp::Add    => rs1.wrapping_add(rs2),
+        AluOp::Sub    => rs1.wrapping_sub(rs2),
+        AluOp::Sll    => rs1 << shamt,
+        AluOp::Slt    => ((rs1 as i64) < (rs2 as i64)) as u64,
+        AluOp::Sltu   => (rs1 < rs2) as u64,
+        AluOp::Xor    => rs1 ^ rs2,
+        AluOp::Srl    => rs1 >> shamt,
+        AluOp::Sra    => (rs1 as i64 >> shamt) as u64,
+        AluOp::Or     => rs1 | rs2,
+        AluOp::And    => rs1 & rs2,
+        //---------
+        //- RV64M -
+        //---------
+        AluOp::Mul    => rs1.wrapping_mul(rs2),
+        AluOp::Mulh   => ((rs1 as i64 as i128 * rs2 as i64 as i128) >> 64) as u64,
+        AluOp::Mulhsu => ((rs1 as i64 as i128 * rs2 as i128) >> 64) as u64,
+        AluOp::Mulhu  => ((rs1 as u128 * rs2 as u128) >> 64) as u64,
+        // division by zero gives all ones (or the dividend for the remainder), overflow wraps
+        AluOp::Div    => if rs2 == 0 { u64::MAX } else { (rs1 as i64).wrapping_div(rs2 as i64) as u64 },
+        AluOp::Divu   => rs1.checked_div(rs2).unwrap_or(u64::MAX),
+        AluOp::Rem    => if rs2 == 0 { rs1 } else { (rs1 as i64).wrapping_rem(rs2 as i64) as u64 },
+        AluOp::Remu   => if rs2 == 0 { rs1 } else { rs1 % rs2 },
+    };
+}
+
+// OP-32 and OP-IMM-32, the 32 bit result is sign extended
+pub fn alu_word(op: AluOp, rs1: u32, rs2: u32) -> u64 {
+    let shamt = rs2 & 0x1f;
+    let result: u32 = match op {
+        AluOp::Add  => rs1.wrapping_add(rs2),
+        AluOp::Sub  => rs1.wrapping_sub(rs2),
+        AluOp::Sll  => rs1 << shamt,
+        AluOp::Srl  => rs1 >> shamt,
+        AluOp::Sra  => ((rs1 as i32) >> shamt) as u32,
+        AluOp::Mul  => rs1.wrapping_mul(rs2),
+        AluOp::Div  => if rs2 == 0 { u32::MAX } else { (rs1 as i32).wrapping_div(rs2 as i32) as u32 },
+        AluOp::Divu => rs1.checked_div(rs2).unwrap_or(u32::MAX),
+        AluOp::Rem  => if rs2 == 0 { rs1 } else { (rs1 as i32).wrapping_rem(rs2 as i32) as u32 },
+        // the decoder only produces the word versions above and REMUW
+        _           => if rs2 == 0 { rs1 } else { rs1 % rs2 },
+    };
+    return result as i32 as i64 as u64;
+}
+
+// CSRRW(I) CSRRS(I) CSRRC(I), returns the old value for rd
+fn csr_instruction(sim: &mut Simulator, state: &mut CpuState, decoded: &Decoded, traced: bool) -> Result<u64, Exception> {
+    let (old, written) = csr_access(state, decoded, sim.devices.clint.mtime)?;
+    if let Some(csr) = written {
+        if traced {
+            let value = csr_read(state, sim.devices.clint.mtime, csr).unwrap_or(0);
+            commit_log_csr(sim, csr, value);
         }
+    }
+    if sim.trace {
+        let csr = match decoded.instruction {
+            Instruction::Csr { csr, .. } | Instruction::CsrImm { csr, .. } => csr,
+            _ => unreachable!(),
+        };
+        println!("INFO: executed CSR instruction on {}", csr_info(csr).map_or("?", |info| info.name));
+    }
+    return Ok(old);
+}
+
+// what a CSR instruction does to the HART at time mtime: the old value for rd and the CSR, when it was written
+pub fn csr_access(state: &mut CpuState, decoded: &Decoded, mtime: u64) -> Result<(u64, Option<u32>), Exception> {
+    let raw = decoded.raw;
+    let (op, csr, source, source_register) = match decoded.instruction {
+        Instruction::Csr { op, csr, rs1, .. } => (op, csr, state.regs[rs1 as usize], rs1),
+        Instruction::CsrImm { op, csr, uimm, .. } => (op, csr, uimm as u64, uimm),
+        _ => unreachable!(),
+    };
+    // CSRRW(I) always writes, CSRRS(I) and CSRRC(I) only with a non-zero rs1/uimm
+    let writes = op == CsrOp::Write || source_register != 0;
+    if !csr_accessible(state, csr, writes) {
+        return Err(illegal_instruction(raw));
+    }
+    let old = match csr_read(state, mtime, csr) {
+        Some(value) => value,
+        None => return Err(illegal_instruction(raw)),
+    };
+    // read-modify-write of mip only sees the bits software can write
+    let base = match csr {
+        csr_address::MIP => state.csr[csr_address::MIP],
+        csr_address::SIP => state.csr[csr_address::MIP] & state.csr[csr_address::MIDELEG],
+        _ => old,
+    };
+    let new = match op {
+        CsrOp::Write => source,
+        CsrOp::Set   => base | source,
+        CsrOp::Clear => base & !source,
+    };
+    if !writes {
+        return Ok((old, None));
+    }
+    csr_write(state, csr, new);
+    return Ok((old, Some(csr)));
+}
 
-        // store
-        if rdi != 0 {
-            state.regs[rdi as usize] = rd;
+//---------
+//- RV64A -
+//---------
+// LR/SC and the AMOs, returns rd and the old memory value for it. aq and rl: every access is sequentially consistent anyway
+fn atomic(sim: &mut Simulator, state: &mut CpuState, instruction: Instruction, traced: bool) -> Result<(u8, u64), Exception> {
+    let (rd, rs1, rs2, size) = match instruction {
+        Instruction::Lr { rd, rs1, size, .. } => (rd, rs1, 0, size),
+        Instruction::Sc { rd, rs1, rs2, size, .. } => (rd, rs1, rs2, size),
+        Instruction::Amo { rd, rs1, rs2, size, .. } => (rd, rs1, rs2, size),
+        _ => unreachable!(),
+    };
+    let size = size as u64;
+    let address = state.regs[rs1 as usize];
+    let source = state.regs[rs2 as usize];
+    let sign_extend = |value: u64| if size == 4 { value as i32 as i64 as u64 } else { value };
+
+    if let Instruction::Lr { .. } = instruction {
+        if !address.is_multiple_of(size) {
+            return Err(Exception { cause: CAUSE_LOAD_MISALIGNED, tval: address });
         }
+        let pa = translate_address(&mut sim.mem, sim.mem_base, state, address, Access::Read)?;
+        let value = load(&mut sim.mem, sim.mem_base, &mut sim.devices, pa, size).ok_or(access_fault(Access::Read, address))?;
+        state.reservation = Some(pa & !7);
+        if traced {
+            commit_log_load(sim, address);
+        }
+        return Ok((rd, sign_extend(value)));
+    }
 
+    if !address.is_multiple_of(size) {
+        return Err(Exception { cause: CAUSE_STORE_MISALIGNED, tval: address });
+    }
+    let pa = translate_address(&mut sim.mem, sim.mem_base, state, address, Access::Write)?;
+
+    let op = match instruction {
+        Instruction::Amo { op, .. } => op,
+        _ => { // SC
+            let reserved = state.reservation == Some(pa & !7);
+            state.reservation = None;
+            if !reserved {
+                return Ok((rd, 1));
+            }
+            memory_written(sim, state.csr[csr_address::MHARTID] as usize, address, pa, size);
+            if !store(&mut sim.mem, sim.mem_base, &mut sim.devices, pa, size, source, &mut sim.uart_out) {
+                return Err(access_fault(Access::Write, address));
+            }
+            clear_reservations(&mut sim.states, pa);
+            if traced {
+                commit_log_store(sim, address, size, source);
+            }
+            return Ok((rd, 0));
+        },
+    };
 
-        state.pc = match npc {
-            Some(x) => x,
-            None    => pc + 4
-        };
-         
-        sim.log = rd.to_string();//String::from("OK");
+    memory_written(sim, state.csr[csr_address::MHARTID] as usize, address, pa, size);
+    let old = sign_extend(load(&mut sim.mem, sim.mem_base, &mut sim.devices, pa, size).ok_or(access_fault(Access::Write, address))?);
+    let new = amo_result(op, old, sign_extend(source));
+    if !store(&mut sim.mem, sim.mem_base, &mut sim.devices, pa, size, new, &mut sim.uart_out) {
+        return Err(access_fault(Access::Write, address));
     }
-    return should_continue;
-}
\ No newline at end of file
+    clear_reservations(&mut sim.states, pa);
+    if traced {
+        commit_log_load(sim, address);
+        commit_log_store(sim, address, size, new);
+    }
+    return Ok((rd, old));
+}
+
+// the value an AMO stores, old and operand are sign extended to 64 bits
+pub fn amo_result(op: AmoOp, old: u64, operand: u64) -> u64 {
+    return match op {
+        AmoOp::Swap => operand,
+        AmoOp::Add  => old.wrapping_add(operand),
+        AmoOp::Xor  => old ^ operand,
+        AmoOp::And  => old & operand,
+        AmoOp::Or   => old | operand,
+        AmoOp::Min  => if (old as i64) < (operand as i64) { old } else { operand },
+        AmoOp::Max  => if (old as i64) > (operand as i64) { old } else { operand },
+        // sign extended words keep their unsigned order
+        AmoOp::Minu => old.min(operand),
+        AmoOp::Maxu => old.max(operand),
+    };
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::asm::tests::*;
+    use crate::test_finisher::*;
+
+    #[test]
+    fn a_hart_that_panics_goes_back_into_the_simulator() {
+        let mut sim = assembled_sim(&format!("li a0, 7\n{}", PASS), 1);
+        step(&mut sim);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| with_hart(&mut sim, 0, |_, _| panic!("in the step"))));
+        assert!(result.is_err());
+        assert_eq!(sim.states[0].regs.len(), 32);
+        assert_eq!(sim.states[0].regs[10], 7);
+        assert_eq!(run_to_exit(&mut sim), Some(FinisherStatus::Pass));
+    }
+
+    #[test]
+    fn branches_and_jumps_reach_back_further_than_2_kib() {
+        let mut sim = assembled_sim(&format!("
+            li a0, 0
+            j 2f
+        1:  addi a0, a0, 1
+            la t1, 3f
+            addi t1, t1, 2040
+            jalr zero, -2040(t1) # a negative offset, to 3
+            .space 3000
+        2:  beqz a0, 1b          # a 13 bit offset with its sign bit set
+        3:  addi a0, a0, 10
+            {}", PASS), 1);
+        assert_eq!(run_to_exit(&mut sim), Some(FinisherStatus::Pass));
+        assert_eq!(sim.states[0].regs[10], 11);
+    }
+
+    #[test]
+    fn ecall_raises_the_cause_of_the_privilege_mode() {
+        // the handler logs mcause and returns to the mode in s2 after the ecall
+        let mut sim = assembled_sim(&format!("
+            la s0, log
+            la t0, trap
+            csrw mtvec, t0
+            li s1, 0
+            li s2, 0x800
+            ecall                # from M
+            li s2, 0
+            ecall                # from S
+            ecall                # from U
+            j 1f
+        trap:
+            csrr t1, mcause
+            add t2, s0, s1
+            sb t1, 0(t2)
+            addi s1, s1, 1
+            li t1, 0x1800
+            csrc mstatus, t1
+            csrs mstatus, s2
+            csrr t1, mepc
+            addi t1, t1, 4
+            csrw mepc, t1
+            mret
+        log:
+            .space 8
+        1:
+            {}", PASS), 1);
+        assert_eq!(run_to_exit(&mut sim), Some(FinisherStatus::Pass));
+        let log = sim.symbols.iter().find(|s| s.name == "log").unwrap().value as usize;
+        assert_eq!(&sim.mem[log..log + 4], &[11, 9, 8, 0]);
+        assert_eq!(sim.states[0].priviledge_mode, PRIV_U);
+    }
+
+    #[test]
+    fn sum_and_mxr_widen_what_supervisor_loads_reach() {
+        let mut sim = paged_sim();
+        // 0x9000 a user page, 0xa000 execute only, both accessed
+        write_pte(&mut sim, 0x3000 + 9 * 8, (0x6 << 10) | 0b101_0011);
+        write_pte(&mut sim, 0x3000 + 10 * 8, (0x7 << 10) | 0b100_1001);
+        let load = |sim: &mut Simulator, va: u64| translate_address(&mut sim.mem, sim.mem_base, &sim.states[0], va, Access::Read).ok();
+        assert_eq!(load(&mut sim, 0x9008), None);
+        assert_eq!(load(&mut sim, 0xa008), None);
+        sim.states[0].csr.set(csr_address::MSTATUS, MSTATUS_SUM);
+        assert_eq!(load(&mut sim, 0x9008), Some(0x6008));
+        assert_eq!(load(&mut sim, 0xa008), None);
+        sim.states[0].csr.set(csr_address::MSTATUS, MSTATUS_MXR);
+        assert_eq!(load(&mut sim, 0x9008), None);
+        assert_eq!(load(&mut sim, 0xa008), Some(0x7008));
+    }
+
+    fn write_pte(sim: &mut Simulator, address: usize, pte: u64) {
+        sim.mem[address..address + 8].copy_from_slice(&pte.to_le_bytes());
+    }
+
+    // S-mode under Sv39 with one page, va 0x8000 mapped to pa 0x5000 read and execute only, A and D clear
+    pub fn paged_sim() -> Simulator {
+        let mut sim = assembled_sim("nop\nnop", 1);
+        write_pte(&mut sim, 0x1000, (0x2 << 10) | 1);
+        write_pte(&mut sim, 0x2000, (0x3 << 10) | 1);
+        write_pte(&mut sim, 0x3000 + 8 * 8, (0x5 << 10) | 0b1011);
+        sim.states[0].csr.set(csr_address::SATP, (SATP_MODE_SV39 << 60) | 1);
+        sim.states[0].priviledge_mode = PRIV_S;
+        return sim;
+    }
+
+    #[test]
+    fn a_debugger_patches_read_execute_pages_without_setting_a_or_d() {
+        let mut sim = paged_sim();
+        assert!(debug_write_memory(&mut sim, 0, 0x8ffc, &[1, 2, 3, 4]));
+        assert_eq!(&sim.mem[0x5ffc..0x6000], &[1, 2, 3, 4]);
+        assert_eq!(debug_read_memory(&sim, 0, 0x8ffc, 4), Some(vec![1, 2, 3, 4]));
+        assert_eq!(sim.mem[0x3000 + 8 * 8], 0b1011);
+        // 0x9000 is not mapped, nothing of a write that reaches it is written
+        assert!(!debug_write_memory(&mut sim, 0, 0x8ffe, &[9, 9, 9, 9]));
+        assert_eq!(&sim.mem[0x5ffc..0x6000], &[1, 2, 3, 4]);
+        assert_eq!(debug_read_memory(&sim, 0, 0x8ffe, 4), None);
+        // a store of the HART itself still faults
+        assert!(!with_hart(&mut sim, 0, |sim, state| ram_store(sim, state, 0x8000, 4, 0)));
+    }
+
+    const ADDI_A1_1:     u32 = 0x00158593; // addi a1, a1, 1
+    const ADDI_A1_0X100: u32 = 0x10058593; // addi a1, a1, 0x100
+
+    #[test]
+    fn a_store_over_decoded_code_runs_the_new_instruction() {
+        // the first pass runs the addi, then writes the one with 0x100 over it, with and without fence.i
+        for fence in ["", "fence.i"] {
+            let mut sim = assembled_sim(&format!("
+                li a0, 0
+                la t0, 1f
+                li t1, 0x{:x}
+            1:  addi a1, a1, 1
+                bnez a0, 2f
+                sw t1, 0(t0)
+                {}
+                li a0, 1
+                j 1b
+            2:
+                {}", ADDI_A1_0X100, fence, PASS), 1);
+            assert_eq!(run_to_exit(&mut sim), Some(FinisherStatus::Pass));
+            assert_eq!(sim.states[0].regs[11], 0x101, "{}", fence);
+        }
+    }
+
+    #[test]
+    fn an_instruction_across_two_pages_is_fetched_again() {
+        // the addi at 0xffe returns to 0x1002, a store to its upper half changes the immediate
+        let mut sim = assembled_sim(&format!("
+            li t0, 0xffe
+            jalr ra, 0(t0)
+            li t1, 0x{:x}
+            sh t1, 2(t0)
+            jalr ra, 0(t0)
+            {}", ADDI_A1_0X100 >> 16, PASS), 1);
+        sim.mem[0xffe..0x1002].copy_from_slice(&ADDI_A1_1.to_le_bytes());
+        sim.mem[0x1002..0x1006].copy_from_slice(&0x00008067u32.to_le_bytes());
+        assert_eq!(run_to_exit(&mut sim), Some(FinisherStatus::Pass));
+        assert_eq!(sim.states[0].regs[11], 0x101);
+    }
+
+    #[test]
+    fn a_new_satp_runs_the_code_it_maps() {
+        // a second root maps va 0x8000 to pa 0x6000 instead of 0x5000
+        let mut sim = paged_sim();
+        write_pte(&mut sim, 0xa000, (0xb << 10) | 1);
+        write_pte(&mut sim, 0xb000, (0xc << 10) | 1);
+        write_pte(&mut sim, 0xc000 + 8 * 8, (0x6 << 10) | 0b1011);
+        sim.mem[0x5000..0x5004].copy_from_slice(&ADDI_A1_1.to_le_bytes());
+        sim.mem[0x6000..0x6004].copy_from_slice(&ADDI_A1_0X100.to_le_bytes());
+        sim.states[0].pc = 0x8000;
+        step(&mut sim);
+        sim.states[0].csr.set(csr_address::SATP, (SATP_MODE_SV39 << 60) | 0xa);
+        sim.states[0].pc = 0x8000;
+        step(&mut sim);
+        assert_eq!(sim.states[0].regs[11], 0x101);
+    }
+
+    #[test]
+    fn a_debugger_write_is_undone_with_the_step_before_it() {
+        let mut sim = paged_sim();
+        sim.history = Some(new_history(10));
+        sim.states[0].priviledge_mode = PRIV_M;
+        step(&mut sim);
+        sim.states[0].priviledge_mode = PRIV_S;
+        assert!(debug_write_memory(&mut sim, 0, 0x8000, &[0xaa; 8]));
+        step(&mut sim);
+        // undoing the step after the write keeps it
+        reverse_steps(&mut sim, 1);
+        assert_eq!(&sim.mem[0x5000..0x5008], &[0xaa; 8]);
+        reverse_steps(&mut sim, 1);
+        assert_eq!(&sim.mem[0x5000..0x5008], &[0; 8]);
+    }
+
+    #[test]
+    fn the_serialized_csr_file_leaves_the_views_out() {
+        let mut sim = paged_sim();
+        sim.states[0].csr.set(csr_address::MSTATUS, MSTATUS_SIE | MSTATUS_MIE);
+        sim.states[0].csr.set(csr_address::MIP, 1 << 9);
+        let json = serde_json::to_value(&sim.states[0].csr).unwrap();
+        assert!(json.get("sstatus").is_none() && json.get("sie").is_none() && json.get("sip").is_none());
+        assert_eq!(csr_read(&sim.states[0], 0, csr_address::SSTATUS), Some(MSTATUS_SIE));
+        let csr: CsrFile = serde_json::from_value(json).unwrap();
+        assert_eq!(csr.get(csr_address::MSTATUS), Some(MSTATUS_SIE | MSTATUS_MIE));
+        assert_eq!(csr.get(csr_address::MIP), Some(1 << 9));
+    }
+}
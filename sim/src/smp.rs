@@ -0,0 +1,635 @@
+use std::sync::atomic::{fence, AtomicU8, AtomicU64, Ordering};
+
+use crate::sim::*;
+use crate::decode::*;
+use crate::htif::*;
+
+/*
+ * Parallel execution of a machine with more than one HART, --parallel quantum: every HART gets a host thread.
+ *
+ * batch_step runs a quantum instead of a single step. In a quantum every HART that can run does so on a thread of its
+ * own for up to `quantum` steps, then the HARTs synchronise with the machine: time advances by the steps of the HART
+ * that ran longest and the devices, the SBI and the debugger see all HARTs in sim.states again.
+ *
+ *  - guest RAM is shared through atomics on the aligned 8 byte words of RAM. Loads and 8 byte stores are single atomic
+ *    accesses, smaller stores and the AMOs are compare-exchange loops on the word. An LR remembers the 8 byte granule
+ *    it reserved and the SC succeeds when the granule still holds that value, whichever HART stored in between. That
+ *    is the usual way to emulate LR/SC on a host, a store of the value that was already there goes unnoticed, which no
+ *    lock-free algorithm can tell apart
+ *  - the instructions that only use registers run through register_instruction, the interpreter's own code for them
+ *  - a HART runs the integer, memory, CSR, xRET, WFI, fence and trap instructions on its own thread. Everything else
+ *    stops its quantum before the instruction: devices, ECALLs that the built-in SBI, the syscalls or semihosting
+ *    service, accesses that cross a page, a trap without a handler. The sequential machine runs it after the quantum
+ *  - interrupts are taken between quanta, a quantum ends before the timer of any HART fires. A HART stops early when
+ *    it waits in WFI or one of its CSR writes enabled a pending interrupt
+ *  - every HART decodes into a cache of its own. A store drops the page from the cache of the HART that stored at once,
+ *    from the other caches when the quantum ends. FENCE.I drops the cache of the HART, as the ISA asks for
+ *
+ * Steps on the sequential machine are deterministic, the order in which the HARTs of a quantum access memory is not.
+ * Runs with one HART, the debugger, --history, --log-commits, --cosim or --trace stay sequential.
+ *
+ * The RISC-V Instruction Set Manual, Volume I, 13 "A" Extension for Atomic Instructions,
+ * 17 RVWMO Memory Consistency Model
+ * https://riscv.org/technical/specifications/
+ */
+
+const MIN_QUANTUM:      u64 = 64;   // fewer steps before the next timer interrupt run sequentially
+const SEQUENTIAL_STEPS: u64 = 1024; // after a quantum in which no HART got MIN_QUANTUM steps done
+
+#[derive(Debug)]
+pub struct Parallel {
+    pub quantum: u64,
+    pub quanta:  u64,              // quanta run so far
+    caches:      Vec<DecodeCache>, // one per HART
+    reserved:    Vec<u64>,         // value of the granule the LR of the HART reserved
+    sequential:  u64,              // steps left to run on the sequential machine before the next quantum
+}
+
+pub fn new_parallel(harts: usize, quantum: u64) -> Parallel {
+    return Parallel {
+        quantum:    quantum,
+        quanta:     0,
+        caches:     (0..harts).map(|_| new_decode_cache()).collect(),
+        reserved:   vec![0; harts],
+        sequential: 0,
+    };
+}
+
+// the machine or the debugger wrote to RAM between quanta
+pub fn parallel_memory_written(parallel: &mut Parallel, pa: u64, length: u64) {
+    for cache in parallel.caches.iter_mut() {
+        cache.invalidate(pa, length);
+    }
+}
+
+// guest RAM while the HARTs run in parallel, every access is atomic
+#[derive(Clone, Copy)]
+struct SharedRam<'a> {
+    bytes: &'a [AtomicU8],
+}
+
+// AtomicU8 is laid out like u8 and mem stays borrowed for as long as the shared view lives
+fn share_ram(mem: &mut [u8]) -> SharedRam<'_> {
+    let bytes = unsafe { std::slice::from_raw_parts(mem.as_mut_ptr() as *const AtomicU8, mem.len()) };
+    return SharedRam { bytes: bytes };
+}
+
+/*
+ * Every access goes through the aligned 8 byte word that holds it: the std atomics do not allow accesses of different
+ * sizes to the same bytes at the same time, guest code mixes them (a sb next to an amoadd.w). Loads within a word are
+ * single atomic loads of the word, smaller stores and the 4 byte compare-exchange are compare-exchange loops on it.
+ * Misaligned accesses that cross a word go a byte at a time, like they may on hardware. parallel_usable makes sure
+ * RAM is 8 byte aligned on the host and a whole number of words long.
+ */
+impl SharedRam<'_> {
+    // the word at an offset that is a multiple of 8, the pointer is derived from the whole slice
+    fn word(&self, offset: usize) -> &AtomicU64 {
+        assert!(offset.is_multiple_of(8) && offset + 8 <= self.bytes.len());
+        return unsafe { AtomicU64::from_ptr(self.bytes.as_ptr().add(offset) as *mut u64) };
+    }
+
+    fn load(&self, offset: usize, size: u64, order: Ordering) -> u64 {
+        let shift = 8 * (offset % 8) as u64;
+        if shift / 8 + size <= 8 {
+            let word = u64::from_le(self.word(offset & !7).load(order));
+            return (word >> shift) & mask(size);
+        }
+        let mut value = 0;
+        for i in 0..size as usize {
+            value |= self.load(offset + i, 1, order) << (8 * i);
+        }
+        return value;
+    }
+
+    fn store(&self, offset: usize, size: u64, value: u64) {
+        let shift = 8 * (offset % 8) as u64;
+        if size == 8 && shift == 0 {
+            self.word(offset).store(value.to_le(), Ordering::Relaxed);
+        } else if shift / 8 + size <= 8 {
+            let field = mask(size) << shift;
+            _ = self.word(offset & !7).fetch_update(Ordering::Relaxed, Ordering::Relaxed, |word| {
+                let word = u64::from_le(word);
+                return Some(((word & !field) | ((value << shift) & field)).to_le());
+            });
+        } else {
+            for i in 0..size as usize {
+                self.store(offset + i, 1, value >> (8 * i));
+            }
+        }
+    }
+
+    // aligned 4 or 8 bytes: replaces current by new, false when they held something else
+    fn compare_exchange(&self, offset: usize, size: u64, current: u64, new: u64) -> bool {
+        let shift = 8 * (offset % 8) as u64;
+        let field = mask(size) << shift;
+        let word = self.word(offset & !7);
+        let mut old = u64::from_le(word.load(Ordering::SeqCst));
+        loop {
+            if (old & field) >> shift != current & mask(size) {
+                return false;
+            }
+            let replaced = (old & !field) | ((new << shift) & field);
+            match word.compare_exchange(old.to_le(), replaced.to_le(), Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return true,
+                // another byte of the word changed, the field may still hold current
+                Err(now) => old = u64::from_le(now),
+            }
+        }
+    }
+
+    // aligned 4 or 8 bytes: atomic read-modify-write, returns what was there
+    fn update(&self, offset: usize, size: u64, f: impl Fn(u64) -> u64) -> u64 {
+        loop {
+            let old = self.load(offset, size, Ordering::SeqCst);
+            if self.compare_exchange(offset, size, old, f(old)) {
+                return old;
+            }
+        }
+    }
+}
+
+// the low size bytes
+fn mask(size: u64) -> u64 {
+    return if size == 8 { u64::MAX } else { (1 << (8 * size)) - 1 };
+}
+
+impl PageTableMemory for SharedRam<'_> {
+    fn size(&self) -> usize {
+        return self.bytes.len();
+    }
+
+    fn read_pte(&self, offset: usize) -> u64 {
+        return self.load(offset, 8, Ordering::Relaxed);
+    }
+
+    // the page walks of other HARTs may set A and D in the same PTE at the same time
+    fn set_pte_bits(&mut self, offset: usize, bits: u64) {
+        self.update(offset, 8, |pte| pte | bits);
+    }
+}
+
+// what the HARTs of a quantum share, everything but RAM is read only
+#[derive(Clone, Copy)]
+struct Machine<'a> {
+    ram:         SharedRam<'a>,
+    mem_base:    u64,
+    mtime:       u64,          // time when the quantum started
+    htif:        Option<&'a Htif>,
+    sbi:         bool,
+    syscalls:    Option<bool>, // bare_metal of the syscalls, when they are serviced
+    semihosting: bool,
+}
+
+// what a HART did in a quantum
+struct HartRun {
+    steps:   u64,
+    stopped: bool,     // before an instruction the machine has to run
+    written: Vec<u64>, // pages it stored to
+}
+
+enum Outcome {
+    Retired,
+    Trapped,  // raised an exception, the HART continues in its trap handler
+    Stop,     // retired, the HART waits in WFI or has an interrupt to take
+    Machine,  // not run, only the machine can run it
+}
+
+// what a quantum can not reproduce exactly, it needs the sequential machine
+fn parallel_usable(sim: &Simulator) -> bool {
+    return sim.states.len() > 1 && !sim.trace && sim.history.is_none() && sim.commit_log.is_none()
+        && sim.debugger.breakpoints.is_empty() && sim.debugger.watchpoints.is_empty()
+        && (sim.mem.as_ptr() as u64).is_multiple_of(8) && sim.mem.len().is_multiple_of(8) && sim.mem_base.is_multiple_of(8);
+}
+
+// a HART that has nothing to do until an interrupt arrives: stopped by the SBI or waiting in WFI
+fn idle(sim: &Simulator, hart: usize) -> bool {
+    let state = &sim.states[hart];
+    let stopped = sim.devices.sbi.as_ref().is_some_and(|sbi| sbi.stopped(hart));
+    return stopped || (state.waiting && read_mip(state) & state.csr[csr_address::MIE] == 0);
+}
+
+// the value of the 8 byte granule at pa, None outside of RAM
+fn granule(sim: &Simulator, pa: u64) -> Option<u64> {
+    let range = ram_range(sim, pa, 8)?;
+    return Some(u64::from_le_bytes(sim.mem[range].try_into().unwrap()));
+}
+
+/*
+ * One step of a batch run: a quantum, or a step() of the sequential machine when a HART has to take an interrupt,
+ * the timer is about to fire or the last quantum stopped at instructions only the machine can run.
+ */
+pub fn parallel_step(sim: &mut Simulator) -> bool {
+    let usable = parallel_usable(sim);
+    let parallel = sim.parallel.as_mut().unwrap();
+    if parallel.sequential > 0 || !usable {
+        parallel.sequential = parallel.sequential.saturating_sub(1);
+        return step(sim);
+    }
+    let quantum = parallel.quantum;
+
+    let harts = sim.states.len();
+    let mut runnable = vec![false; harts];
+    let mut budget = quantum;
+    for (hart, runnable) in runnable.iter_mut().enumerate() {
+        budget = budget.min(steps_before_timer(sim, hart));
+        *runnable = runs_uninterrupted(sim, hart);
+        if !*runnable && !idle(sim, hart) {
+            return step(sim);
+        }
+    }
+    if budget < MIN_QUANTUM || !runnable.contains(&true) {
+        return step(sim);
+    }
+
+    let mut parallel = sim.parallel.take().unwrap();
+    let runs = run_quantum(sim, &mut parallel, &runnable, budget);
+
+    let longest = runs.iter().flatten().map(|run| run.steps).max().unwrap_or(0);
+    sim.devices.clint.mtime = sim.devices.clint.mtime.wrapping_add(longest);
+    // an SC on the sequential machine only looks at the reservation, a reserved granule that changed is lost
+    for hart in 0..harts {
+        if let Some(pa) = sim.states[hart].reservation {
+            if granule(sim, pa) != Some(parallel.reserved[hart]) {
+                sim.states[hart].reservation = None;
+            }
+        }
+    }
+    // instructions the other HARTs decoded from what a HART stored are stale
+    let mut written: Vec<u64> = runs.iter().flatten().flat_map(|run| run.written.iter().copied()).collect();
+    written.sort_unstable();
+    written.dedup();
+    for page in written {
+        code_written(sim, page << 12, 4096);
+        parallel_memory_written(&mut parallel, page << 12, 4096);
+    }
+
+    parallel.quanta += 1;
+    if longest < MIN_QUANTUM {
+        // starting the threads costs more than the quantum did, the instructions that stopped it are frequent
+        parallel.sequential = SEQUENTIAL_STEPS;
+    } else if runs.iter().flatten().any(|run| run.stopped) {
+        parallel.sequential = 1;
+    }
+    sim.parallel = Some(parallel);
+    return true;
+}
+
+// runs the runnable HARTs for up to budget steps, each on a thread of its own
+fn run_quantum(sim: &mut Simulator, parallel: &mut Parallel, runnable: &[bool], budget: u64) -> Vec<Option<HartRun>> {
+    // reservations made on the sequential machine
+    for hart in 0..sim.states.len() {
+        if let Some(value) = sim.states[hart].reservation.and_then(|pa| granule(sim, pa)) {
+            parallel.reserved[hart] = value;
+        }
+    }
+    let machine = Machine {
+        ram:         share_ram(&mut sim.mem),
+        mem_base:    sim.mem_base,
+        mtime:       sim.devices.clint.mtime,
+        htif:        sim.devices.htif.as_ref(),
+        sbi:         sim.devices.sbi.is_some(),
+        syscalls:    sim.devices.syscalls.as_ref().map(|s| s.bare_metal),
+        semihosting: sim.devices.semihosting.is_some(),
+    };
+    let harts = sim.states.iter_mut().zip(parallel.caches.iter_mut()).zip(parallel.reserved.iter_mut()).zip(runnable);
+    return std::thread::scope(|scope| {
+        let threads: Vec<_> = harts.map(|(((state, cache), reserved), runnable)| {
+            if !*runnable {
+                return None;
+            }
+            return Some(scope.spawn(move || run_hart(machine, state, cache, reserved, budget)));
+        }).collect();
+        return threads.into_iter().map(|thread| thread.map(|t| t.join().unwrap())).collect();
+    });
+}
+
+// the thread of one HART
+fn run_hart(machine: Machine, state: &mut CpuState, cache: &mut DecodeCache, reserved: &mut u64, budget: u64) -> HartRun {
+    let mut run = HartRun { steps: 0, stopped: false, written: vec![] };
+    while run.steps < budget {
+        // the machine ticks before the HARTs step
+        let time = machine.mtime.wrapping_add(run.steps + 1);
+        let outcome = match step_local(machine, state, cache, reserved, time, &mut run.written) {
+            Ok(outcome) => outcome,
+            Err(exception) => trap_local(machine, state, exception),
+        };
+        match outcome {
+            Outcome::Machine => {
+                run.stopped = true;
+                break;
+            },
+            Outcome::Trapped => run.steps += 1,
+            Outcome::Retired | Outcome::Stop => {
+                run.steps += 1;
+                let minstret = state.csr[csr_address::MINSTRET].wrapping_add(1);
+                state.csr.set(csr_address::MINSTRET, minstret);
+                state.csr.set(csr_address::MCYCLE, minstret);
+                if let Outcome::Stop = outcome {
+                    break;
+                }
+            },
+        }
+    }
+    return run;
+}
+
+// handle_trap for the traps nobody but the guest handles
+fn trap_local(machine: Machine, state: &mut CpuState, exception: Exception) -> Outcome {
+    let requested = match exception.cause {
+        CAUSE_ECALL_S    => machine.sbi,
+        CAUSE_BREAKPOINT => machine.semihosting,
+        CAUSE_ECALL_U    => machine.syscalls.is_some(),
+        CAUSE_ECALL_M    => machine.syscalls == Some(true),
+        _                => false,
+    };
+    if requested || !enter_trap(state, exception.cause, exception.tval) {
+        return Outcome::Machine;
+    }
+    return Outcome::Trapped;
+}
+
+// offset into RAM of a data access the HART can do on its own, None when it needs the machine
+fn data_offset(machine: Machine, state: &CpuState, va: u64, size: u64, access: Access) -> Result<Option<usize>, Exception> {
+    if (va & 0xfff) + size > 0x1000 {
+        return Ok(None);
+    }
+    let mut ram = machine.ram;
+    let pa = translate_address(&mut ram, machine.mem_base, state, va, access)?;
+    let offset = pa.wrapping_sub(machine.mem_base);
+    if !in_ram(offset, size, ram.size()) {
+        return Ok(None);
+    }
+    if access == Access::Write && machine.htif.is_some_and(|h| h.touches_tohost(pa, size)) {
+        return Ok(None);
+    }
+    return Ok(Some(offset as usize));
+}
+
+fn note_write(machine: Machine, cache: &mut DecodeCache, written: &mut Vec<u64>, offset: usize, size: u64) {
+    let pa = machine.mem_base + offset as u64;
+    cache.invalidate(pa, size);
+    if written.last() != Some(&(pa >> 12)) {
+        written.push(pa >> 12);
+    }
+}
+
+// fetch_decoded on the cache of the HART, None when the instruction crosses a page or is not in RAM
+fn fetch_local(machine: Machine, state: &CpuState, cache: &mut DecodeCache, pc: u64) -> Result<Option<Decoded>, Exception> {
+    let mut ram = machine.ram;
+    let pa = translate_address(&mut ram, machine.mem_base, state, pc, Access::Execute)?;
+    if let Some(decoded) = cache.get(pa) {
+        return Ok(Some(decoded));
+    }
+    let offset = pa.wrapping_sub(machine.mem_base);
+    if !in_ram(offset, 2, ram.size()) {
+        return Ok(None);
+    }
+    let low = ram.load(offset as usize, 2, Ordering::Relaxed) as u32;
+    let raw = if low & 0b11 != 0b11 {
+        low
+    } else if pc & 0xfff != 0xffe && in_ram(offset, 4, ram.size()) {
+        low | (ram.load(offset as usize + 2, 2, Ordering::Relaxed) as u32) << 16
+    } else {
+        return Ok(None);
+    };
+    let decoded = decode_raw(raw);
+    cache.insert(pa, decoded);
+    return Ok(Some(decoded));
+}
+
+// execute() of a HART on its own thread
+fn step_local(machine: Machine, state: &mut CpuState, cache: &mut DecodeCache, reserved: &mut u64, time: u64, written: &mut Vec<u64>) -> Result<Outcome, Exception> {
+    let pc = state.pc;
+    let decoded = match fetch_local(machine, state, cache, pc)? {
+        Some(decoded) => decoded,
+        None => return Ok(Outcome::Machine),
+    };
+    let ilen = decoded.length as u64;
+    let mut outcome = Outcome::Retired;
+    let mut npc = pc.wrapping_add(ilen);
+    let mut rdi: u8  = 0;
+    let mut rd:  u64 = 0;
+
+    match decoded.instruction {
+        Instruction::Load { rd: d, rs1, offset, size, signed } => {
+            let address = state.regs[rs1 as usize].wrapping_add(offset as u64);
+            let offset = match data_offset(machine, state, address, size as u64, Access::Read)? {
+                Some(offset) => offset,
+                None => return Ok(Outcome::Machine),
+            };
+            rdi = d;
+            rd  = extend_load(size, signed, machine.ram.load(offset, size as u64, Ordering::Relaxed));
+        },
+        Instruction::Store { rs1, rs2, offset, size } => {
+            let address = state.regs[rs1 as usize].wrapping_add(offset as u64);
+            let offset = match data_offset(machine, state, address, size as u64, Access::Write)? {
+                Some(offset) => offset,
+                None => return Ok(Outcome::Machine),
+            };
+            machine.ram.store(offset, size as u64, state.regs[rs2 as usize]);
+            note_write(machine, cache, written, offset, size as u64);
+        },
+        Instruction::Fence { .. } => {
+            fence(Ordering::SeqCst);
+        },
+        Instruction::FenceI => {
+            cache.clear();
+            fence(Ordering::SeqCst);
+        },
+        Instruction::Sret | Instruction::Mret | Instruction::Wfi | Instruction::SfenceVma { .. } => {
+            if let Some(epc) = system_instruction(state, decoded.instruction, decoded.raw)? {
+                npc = epc;
+            }
+            if state.waiting || pending_interrupt(state).is_some() {
+                outcome = Outcome::Stop;
+            }
+        },
+        Instruction::Csr { rd: d, .. } | Instruction::CsrImm { rd: d, .. } => {
+            rdi = d;
+            rd  = csr_access(state, &decoded, time)?.0;
+            if pending_interrupt(state).is_some() {
+                outcome = Outcome::Stop;
+            }
+        },
+        Instruction::Lr { .. } | Instruction::Sc { .. } | Instruction::Amo { .. } => {
+            let (d, value) = match atomic_local(machine, state, cache, reserved, decoded.instruction, written)? {
+                Some(result) => result,
+                None => return Ok(Outcome::Machine),
+            };
+            rdi = d;
+            rd  = value;
+        },
+        _ => {
+            let jump;
+            (rdi, rd, jump) = register_instruction(state, &decoded, pc)?;
+            npc = jump.unwrap_or(npc);
+        },
+    }
+
+    if rdi != 0 {
+        state.regs[rdi as usize] = rd;
+    }
+    state.last_pc  = pc;
+    state.last_raw = decoded.raw;
+    state.pc = npc;
+    return Ok(outcome);
+}
+
+// atomic() on shared RAM, rd and its value. None when the machine has to do the access
+fn atomic_local(machine: Machine, state: &mut CpuState, cache: &mut DecodeCache, reserved: &mut u64, instruction: Instruction, written: &mut Vec<u64>) -> Result<Option<(u8, u64)>, Exception> {
+    let (rd, rs1, rs2, size) = match instruction {
+        Instruction::Lr { rd, rs1, size, .. } => (rd, rs1, 0, size),
+        Instruction::Sc { rd, rs1, rs2, size, .. } => (rd, rs1, rs2, size),
+        Instruction::Amo { rd, rs1, rs2, size, .. } => (rd, rs1, rs2, size),
+        _ => unreachable!(),
+    };
+    let size = size as u64;
+    let address = state.regs[rs1 as usize];
+    let source = state.regs[rs2 as usize];
+    let sign_extend = |value: u64| if size == 4 { value as i32 as i64 as u64 } else { value };
+    // the bytes of an aligned access in the value of its granule
+    let shift = 8 * (address & 7);
+    let mask = if size == 4 { 0xffff_ffff } else { u64::MAX };
+
+    if let Instruction::Lr { .. } = instruction {
+        if !address.is_multiple_of(size) {
+            return Err(Exception { cause: CAUSE_LOAD_MISALIGNED, tval: address });
+        }
+        let offset = match data_offset(machine, state, address, size, Access::Read)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        // one load for the value and the granule, an SC compares against what the LR returned
+        let value = machine.ram.load(offset & !7, 8, Ordering::SeqCst);
+        *reserved = value;
+        state.reservation = Some((machine.mem_base + offset as u64) & !7);
+        return Ok(Some((rd, sign_extend((value >> shift) & mask))));
+    }
+
+    if !address.is_multiple_of(size) {
+        return Err(Exception { cause: CAUSE_STORE_MISALIGNED, tval: address });
+    }
+    let offset = match data_offset(machine, state, address, size, Access::Write)? {
+        Some(offset) => offset,
+        None => return Ok(None),
+    };
+
+    let op = match instruction {
+        Instruction::Amo { op, .. } => op,
+        _ => { // SC
+            let reserved_here = state.reservation == Some((machine.mem_base + offset as u64) & !7);
+            state.reservation = None;
+            if !reserved_here || !machine.ram.compare_exchange(offset, size, (*reserved >> shift) & mask, source & mask) {
+                return Ok(Some((rd, 1)));
+            }
+            note_write(machine, cache, written, offset, size);
+            return Ok(Some((rd, 0)));
+        },
+    };
+
+    let operand = sign_extend(source);
+    let old = sign_extend(machine.ram.update(offset, size, |old| amo_result(op, sign_extend(old), operand) & mask));
+    note_write(machine, cache, written, offset, size);
+    return Ok(Some((rd, old)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::tests::*;
+    use crate::test_finisher::*;
+
+    const HARTS: usize = 4;
+
+    // every HART runs the loop, HART 0 waits for the others and reports pass, the others park
+    const COUNTERS: &str = "
+        .equ ITER, 1000
+            csrr a0, mhartid
+            la s0, counter
+            la s1, lrsc
+            la s2, lock
+            la s3, plain
+            la s5, bytes
+            add s5, s5, a0
+            li s4, ITER
+        loop:
+            li t0, 1
+            amoadd.d zero, t0, (s0)
+        1:  lr.d t1, (s1)
+            addi t1, t1, 1
+            sc.d t2, t1, (s1)
+            bnez t2, 1b
+        2:  li t0, 1
+            amoswap.w.aq t0, t0, (s2)
+            bnez t0, 2b
+            ld t1, 0(s3)
+            addi t1, t1, 1
+            sd t1, 0(s3)
+            amoswap.w.rl zero, zero, (s2)
+            lbu t1, 0(s5)         # a byte of its own next to the bytes of the others
+            addi t1, t1, 1
+            sb t1, 0(s5)
+            addi s4, s4, -1
+            bnez s4, loop
+            li t0, 1
+            la t1, finished
+            amoadd.w zero, t0, (t1)
+            bnez a0, park
+            li t3, 4
+        3:  lw t2, 0(t1)
+            bne t2, t3, 3b
+            j report
+        park:
+            j park
+        .balign 8
+        counter:  .dword 0
+        lrsc:     .dword 0
+        plain:    .dword 0
+        lock:     .dword 0
+        finished: .dword 0
+        bytes:    .dword 0
+        report:
+    ";
+
+    fn dword(sim: &Simulator, name: &str) -> u64 {
+        let offset = symbol_address(sim, name).unwrap() as usize;
+        return u64::from_le_bytes(sim.mem[offset..offset + 8].try_into().unwrap());
+    }
+
+    #[test]
+    fn atomics_and_locks_end_like_the_sequential_machine() {
+        let source = format!("{}\n{}", COUNTERS, PASS);
+        let mut reference = assembled_sim(&source, HARTS);
+        assert_eq!(run_to_exit(&mut reference), Some(FinisherStatus::Pass));
+        let mut sim = assembled_sim(&source, HARTS);
+        sim.parallel = Some(new_parallel(HARTS, 1000));
+        assert_eq!(run_to_exit(&mut sim), Some(FinisherStatus::Pass));
+        assert!(sim.parallel.as_ref().unwrap().quanta > 0, "nothing ran in parallel");
+
+        for name in ["counter", "lrsc", "plain"] {
+            assert_eq!(dword(&reference, name), 4000, "{} of the sequential machine", name);
+            assert_eq!(dword(&sim, name), 4000, "{} of the parallel machine", name);
+        }
+        // 1000 wraps around to 0xe8 in every byte
+        assert_eq!(dword(&sim, "bytes"), 0xe8e8e8e8);
+        // the interleaving differs, what the program leaves in RAM does not
+        assert!(sim.mem == reference.mem, "RAM differs");
+        for hart in 1..HARTS {
+            assert_eq!(sim.states[hart].regs[20], 0, "loop counter of HART {}", hart);
+        }
+    }
+
+    #[test]
+    fn a_lone_running_hart_matches_the_interpreter_exactly() {
+        // with the other HART in WFI nothing interleaves, the quanta have to end in the very same machine
+        let source = format!("li a1, 0\nli t0, 5000\n1: addi a1, a1, 3\naddi t0, t0, -1\nbnez t0, 1b\n{}", PASS);
+        let mut reference = assembled_sim(&source, 2);
+        reference.states[1].waiting = true;
+        let mut sim = assembled_sim(&source, 2);
+        sim.states[1].waiting = true;
+        sim.parallel = Some(new_parallel(2, 100));
+        assert_eq!(run_to_exit(&mut reference), Some(FinisherStatus::Pass));
+        assert_eq!(run_to_exit(&mut sim), Some(FinisherStatus::Pass));
+        assert_same_machine(&sim, &reference);
+    }
+}
@@ -0,0 +1,484 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::sim::*;
+use crate::debug::*;
+use crate::websocket::*;
+
+/*
+ * Streaming of the server sessions over WebSocket, GET /ws?device_index=N, see server.rs for the HTTP side.
+ *
+ * A session is a simulator and the clients that subscribed to it. A session runs continuously on a thread of its own
+ * after a "run" message until a "stop" message, a breakpoint, a watchpoint, a trap when asked for, the machine
+ * stops, or the simulator panics. The thread runs chunks of about CHUNK_TIME and lets go of the simulator between
+ * them, HTTP actions on the session go in between. After every chunk, and after every HTTP action, the changes go
+ * out to the subscribers.
+ *
+ * Messages are JSON text, every one of them has a "type". From the client:
+ *
+ *      {"type": "subscribe", "device_index": 0}   streams this session from now on, answered with "state"
+ *      {"type": "state"}                           the whole simulator again
+ *      {"type": "run", "traps": false}             runs continuously, "traps": true stops at the first trap
+ *      {"type": "stop"}
+ *      {"type": "uart", "data": "ls\n"}            bytes for the UART receiver, a string or a list of bytes.
+ *                                                  Binary messages go to the UART receiver as they are. Input that
+ *                                                  does not fit in UART_RX_MAX waiting bytes is dropped with an "error"
+ *
+ * To the client, all with "device_index":
+ *
+ *      "state"   "sim": the simulator like the HTTP responses, "running"
+ *      "delta"   "harts": the HARTs that changed, with "hart", "pc", "priviledge_mode", "waiting", "last_pc",
+ *                "last_instruction", "last_raw", and the registers ("regs", by number) and CSRs ("csr", by name) that changed.
+ *                "log" and "mtime". Memory is not part of it, a "state" has all of it
+ *      "uart"    "offset" into sim.uart_out and the bytes from there in "data". A "state" already holds the bytes
+ *                before the first "uart" after it, offsets tell which ones are new
+ *      "traps"   "traps": the traps the HARTs took, "hart", "cause", "name", "interrupt", "tval", "pc".
+ *                At most MAX_TRAPS per chunk, "dropped" counts the rest
+ *      "run"     the session started to run, "traps" as asked for
+ *      "stop"    it stopped, "stop" is why, like the "stop" of the HTTP "run" action, reason "stop" for a "stop" message
+ *                and "panic" with the message in "detail" when the simulator panicked. The HART that panicked
+ *                is put back as it was before the step and the session stays usable
+ *      "error"   "error": what was wrong with a message of the client
+ *
+ * A subscriber that falls more than EVENT_CAPACITY messages behind gets a "state" instead of what it missed.
+ */
+
+const CHUNK_STEPS:    u64 = 10000;
+const CHUNK_TIME:     Duration = Duration::from_millis(20);
+const MAX_TRAPS:      usize = 64;
+const EVENT_CAPACITY: usize = 1024;
+const UART_RX_MAX:    usize = 64 * 1024;
+
+// what the subscribers last heard of a HART
+struct PublishedHart {
+    pc:              u64,
+    priviledge_mode: u8,
+    waiting:         bool,
+    last_pc:         u64,
+    regs:            Vec<u64>,
+    csr:             Vec<(&'static str, u64)>,
+}
+
+struct Published {
+    harts:    Vec<PublishedHart>,
+    uart_len: usize,
+    log:      String,
+}
+
+pub struct Session {
+    pub sim:      Mutex<Simulator>,
+    events:       broadcast::Sender<Arc<String>>,
+    published:    Mutex<Option<Published>>, // taken with sim held
+    run:          AtomicU64,  // id of the continuous run, 0 when it does not run
+    stop_on_trap: AtomicBool,
+}
+
+pub struct Sessions {
+    pub simulators: HashMap<i32, Arc<Session>>,
+    pub next_index: i32,
+}
+
+pub type SharedSessions = Arc<Mutex<Sessions>>;
+
+static NEXT_RUN: AtomicU64 = AtomicU64::new(1);
+
+pub fn new_session(sim: Simulator) -> Arc<Session> {
+    return Arc::new(Session {
+        sim:          Mutex::new(sim),
+        events:       broadcast::channel(EVENT_CAPACITY).0,
+        published:    Mutex::new(None),
+        run:          AtomicU64::new(0),
+        stop_on_trap: AtomicBool::new(false),
+    });
+}
+
+// a mutex another request panicked with still holds a usable simulator
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    return mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+}
+
+// what a caught panic was raised with
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    return panic.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or(String::from("unknown"));
+}
+
+fn send_event(session: &Session, key: i32, kind: &str, mut event: serde_json::Value) {
+    event["type"] = serde_json::json!(kind);
+    event["device_index"] = serde_json::json!(key);
+    // nobody listening is fine
+    _ = session.events.send(Arc::new(event.to_string()));
+}
+
+// every CSR by name as the HART reads it, the views of the machine registers and the counters included
+fn csr_values(state: &CpuState, mtime: u64) -> Vec<(&'static str, u64)> {
+    return csr_address::CSR_INFO.iter().map(|info| (info.name, csr_read(state, mtime, info.address).unwrap_or(0))).collect();
+}
+
+/*
+ * Sends what changed since the last time to the subscribers: a "delta" of the HARTs and a "uart" of the new output.
+ * Called with the simulator of the session locked.
+ */
+pub fn publish_changes(session: &Session, key: i32, sim: &mut Simulator) {
+    if session.events.receiver_count() == 0 {
+        // the next subscriber starts with a "state", nothing to compare with then
+        *lock(&session.published) = None;
+        return;
+    }
+    describe_last_step(sim);
+    let mut published = lock(&session.published);
+
+    let mtime = sim.devices.clint.mtime;
+    let csrs: Vec<Vec<(&'static str, u64)>> = sim.states.iter().map(|state| csr_values(state, mtime)).collect();
+    let mut harts = vec![];
+    for (i, state) in sim.states.iter().enumerate() {
+        let before = published.as_ref().and_then(|p| p.harts.get(i));
+        let regs: serde_json::Map<String, serde_json::Value> = state.regs.iter().enumerate()
+            .filter(|(r, value)| before.is_none_or(|b| b.regs[*r] != **value))
+            .map(|(r, value)| (r.to_string(), serde_json::json!(value)))
+            .collect();
+        let csr: serde_json::Map<String, serde_json::Value> = csrs[i].iter().enumerate()
+            .filter(|(c, (_, value))| before.is_none_or(|b| b.csr[*c].1 != *value))
+            .map(|(_, (name, value))| (name.to_string(), serde_json::json!(value)))
+            .collect();
+        let unchanged = before.is_some_and(|b| b.pc == state.pc && b.priviledge_mode == state.priviledge_mode
+            && b.waiting == state.waiting && b.last_pc == state.last_pc) && regs.is_empty() && csr.is_empty();
+        if !unchanged {
+            harts.push(serde_json::json!({
+                "hart":             i,
+                "pc":               state.pc,
+                "priviledge_mode":  state.priviledge_mode,
+                "waiting":          state.waiting,
+                "last_pc":          state.last_pc,
+                "last_instruction": state.last_instruction,
+                "last_raw":         state.last_raw,
+                "regs":             regs,
+                "csr":              csr,
+            }));
+        }
+    }
+    let log_changed = published.as_ref().is_none_or(|p| p.log != sim.log);
+    if !harts.is_empty() || log_changed {
+        send_event(session, key, "delta", serde_json::json!({ "harts": harts, "log": sim.log, "mtime": sim.devices.clint.mtime }));
+    }
+
+    // uart_out only grows, unless a reset or a load started it again
+    let mut offset = published.as_ref().map_or(sim.uart_out.len(), |p| p.uart_len);
+    if offset > sim.uart_out.len() {
+        offset = 0;
+    }
+    if offset < sim.uart_out.len() {
+        send_event(session, key, "uart", serde_json::json!({ "offset": offset, "data": &sim.uart_out[offset..] }));
+    }
+
+    *published = Some(Published {
+        harts:    sim.states.iter().zip(csrs).map(|(s, csr)| PublishedHart {
+            pc:              s.pc,
+            priviledge_mode: s.priviledge_mode,
+            waiting:         s.waiting,
+            last_pc:         s.last_pc,
+            regs:            s.regs.clone(),
+            csr:             csr,
+        }).collect(),
+        uart_len: sim.uart_out.len(),
+        log:      sim.log.clone(),
+    });
+}
+
+fn trap_json(trap: &TrapHit) -> serde_json::Value {
+    return serde_json::json!({
+        "hart":      trap.hart,
+        "cause":     trap.cause,
+        "name":      cause_name(trap.cause),
+        "interrupt": trap.cause >> 63 == 1,
+        "tval":      trap.tval,
+        "pc":        trap.pc,
+    });
+}
+
+// starts a continuous run, a session that already runs only takes the new "traps"
+fn start_run(session: &Arc<Session>, key: i32, stop_on_trap: bool) {
+    session.stop_on_trap.store(stop_on_trap, Ordering::SeqCst);
+    let id = NEXT_RUN.fetch_add(1, Ordering::SeqCst);
+    if session.run.compare_exchange(0, id, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        return;
+    }
+    send_event(session, key, "run", serde_json::json!({ "traps": stop_on_trap }));
+    let session = session.clone();
+    std::thread::spawn(move || {
+        let mut steps = 0;
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| run_session(&session, key, id, &mut steps)));
+        if let Err(panic) = result {
+            let message = panic_message(&*panic);
+            println!("ERROR stream: the simulator panicked: {}", message);
+            // a new "run" can only start once run is back at 0
+            if session.run.compare_exchange(id, 0, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                let pc = lock(&session.sim).states.first().map_or(0, |state| state.pc);
+                let stop = StopEvent { reason: String::from("panic"), steps: steps, hart: 0, pc: pc, address: None, detail: message, trap: None };
+                send_event(&session, key, "stop", serde_json::json!({ "stop": stop }));
+            }
+        }
+    });
+}
+
+fn run_session(session: &Session, key: i32, id: u64, steps: &mut u64) {
+    while session.run.load(Ordering::SeqCst) == id {
+        let mut sim = lock(&session.sim);
+        let started = Instant::now();
+        let mut traps = vec![];
+        let mut dropped = 0;
+        let mut stop = None;
+        while stop.is_none() && started.elapsed() < CHUNK_TIME {
+            let event = run_until(&mut sim, CHUNK_STEPS, true);
+            *steps += event.steps;
+            match event.trap {
+                Some(trap) => {
+                    if traps.len() < MAX_TRAPS {
+                        traps.push(trap_json(&trap));
+                    } else {
+                        dropped += 1;
+                    }
+                    if session.stop_on_trap.load(Ordering::SeqCst) {
+                        stop = Some(event);
+                    } else if let Some(hart) = breakpoint_hart(&sim) {
+                        // run_until moves on from a breakpoint before it looks, the trap vector could have one
+                        stop = Some(StopEvent { reason: String::from("breakpoint"), steps: *steps, hart: hart, pc: sim.states[hart].pc, address: None, detail: String::new(), trap: None });
+                    }
+                },
+                None if event.reason == "steps" => {},
+                None => stop = Some(event),
+            }
+        }
+        if !traps.is_empty() {
+            send_event(session, key, "traps", serde_json::json!({ "traps": traps, "dropped": dropped }));
+        }
+        publish_changes(session, key, &mut sim);
+        if let Some(mut stop) = stop {
+            stop.steps = *steps;
+            if session.run.compare_exchange(id, 0, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                send_event(session, key, "stop", serde_json::json!({ "stop": stop }));
+            }
+            return;
+        }
+        drop(sim);
+        // a chunk of its own for the HTTP actions waiting for the simulator
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    // a "stop" message ended the run, not a new "run"
+    if session.run.load(Ordering::SeqCst) == 0 {
+        let sim = lock(&session.sim);
+        let stop = StopEvent { reason: String::from("stop"), steps: *steps, hart: 0, pc: sim.states[0].pc, address: None, detail: String::new(), trap: None };
+        send_event(session, key, "stop", serde_json::json!({ "stop": stop }));
+    }
+}
+
+// the whole simulator, the changes up to it go out first so that the next "delta" starts from it
+fn state_event(session: &Session, key: i32) -> String {
+    let mut sim = lock(&session.sim);
+    publish_changes(session, key, &mut sim);
+    describe_last_step(&mut sim);
+    let running = session.run.load(Ordering::SeqCst) != 0;
+    let sim_contents = serde_json::to_string(&*sim).unwrap_or(String::from("null"));
+    return format!("{{\"type\": \"state\", \"device_index\": {key}, \"running\": {running},\r\n\"sim\": {sim_contents}\r\n}}");
+}
+
+fn error_event(message: &str) -> String {
+    return serde_json::json!({ "type": "error", "error": message }).to_string();
+}
+
+// what the connection task waits for: messages of the client and events of the session it subscribed to
+enum Input {
+    Client(Result<Message, WebSocketError>),
+    Event(i32, Arc<String>),
+    Lagged(i32),
+}
+
+struct Subscription {
+    key:       i32,
+    session:   Arc<Session>,
+    forwarder: tokio::task::JoinHandle<()>,
+}
+
+fn subscribe(sessions: &SharedSessions, key: i32, inputs: &mpsc::Sender<Input>) -> Option<Subscription> {
+    let session = lock(sessions).simulators.get(&key).cloned()?;
+    let mut events = session.events.subscribe();
+    let inputs = inputs.clone();
+    let forwarder = tokio::spawn(async move {
+        loop {
+            let input = match events.recv().await {
+                Ok(event) => Input::Event(key, event),
+                Err(broadcast::error::RecvError::Lagged(_)) => Input::Lagged(key),
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            if inputs.send(input).await.is_err() {
+                return;
+            }
+        }
+    });
+    return Some(Subscription { key: key, session: session, forwarder: forwarder });
+}
+
+// answers the opening handshake of the request and streams until the client goes away
+pub async fn serve_websocket(sessions: SharedSessions, key: i32, accept: String, reader: BufReader<OwnedReadHalf>, mut write: OwnedWriteHalf) {
+    let head = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept);
+    if write.write_all(head.as_bytes()).await.is_err() {
+        return;
+    }
+
+    let (inputs, mut received) = mpsc::channel::<Input>(64);
+    let client = inputs.clone();
+    let reader_task = tokio::spawn(async move {
+        let mut reader = reader;
+        let mut messages = new_message_reader();
+        loop {
+            let message = messages.read(&mut reader).await;
+            let last = matches!(message, Err(_) | Ok(Message::Close));
+            if client.send(Input::Client(message)).await.is_err() || last {
+                return;
+            }
+        }
+    });
+
+    let mut subscription: Option<Subscription> = None;
+    let mut outgoing = vec![];
+    match subscribe(&sessions, key, &inputs) {
+        Some(s) => {
+            let session = s.session.clone();
+            outgoing.push(tokio::task::spawn_blocking(move || state_event(&session, key)).await.unwrap_or_default());
+            subscription = Some(s);
+        },
+        None => outgoing.push(error_event(&format!("no device with device_index {}", key))),
+    }
+
+    let mut close: Option<(u16, String)> = None;
+    'connection: loop {
+        for text in outgoing.drain(..) {
+            if write_text(&mut write, &text).await.is_err() {
+                break 'connection;
+            }
+        }
+        if let Some((code, reason)) = close.take() {
+            _ = write_close(&mut write, code, &reason).await;
+            break;
+        }
+        let input = match received.recv().await {
+            Some(input) => input,
+            None => break,
+        };
+        match input {
+            Input::Event(from, event) => {
+                // events of a session the client moved away from can still be queued
+                if subscription.as_ref().is_some_and(|s| s.key == from) {
+                    outgoing.push(event.to_string());
+                }
+            },
+            Input::Lagged(from) => {
+                if let Some(s) = subscription.as_ref().filter(|s| s.key == from) {
+                    let session = s.session.clone();
+                    outgoing.push(tokio::task::spawn_blocking(move || state_event(&session, from)).await.unwrap_or_default());
+                }
+            },
+            Input::Client(Ok(Message::Text(text))) => {
+                let reply = client_message(&sessions, &mut subscription, &inputs, &text).await;
+                outgoing.extend(reply);
+            },
+            Input::Client(Ok(Message::Binary(bytes))) => {
+                outgoing.extend(uart_input(&subscription, bytes).await);
+            },
+            Input::Client(Ok(Message::Ping(payload))) => {
+                if write_pong(&mut write, &payload).await.is_err() {
+                    break;
+                }
+            },
+            Input::Client(Ok(Message::Pong)) => {},
+            Input::Client(Ok(Message::Close)) => close = Some((CLOSE_NORMAL, String::new())),
+            Input::Client(Err(error)) => {
+                if error.code == 0 {
+                    break;
+                }
+                println!("WARN server: WebSocket closed: {}", error.reason);
+                close = Some((error.code, error.reason));
+            },
+        }
+    }
+    if let Some(s) = subscription {
+        s.forwarder.abort();
+    }
+    reader_task.abort();
+}
+
+// the replies to a text message of the client
+async fn client_message(sessions: &SharedSessions, subscription: &mut Option<Subscription>, inputs: &mpsc::Sender<Input>, text: &str) -> Vec<String> {
+    let message: serde_json::Value = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(e) => return vec![error_event(&format!("invalid JSON: {}", e))],
+    };
+    let kind = message["type"].as_str().unwrap_or("");
+    if kind == "subscribe" {
+        let key = match message["device_index"].as_i64().and_then(|i| i32::try_from(i).ok()) {
+            Some(key) => key,
+            None => return vec![error_event(&format!("invalid device_index: {}", message["device_index"]))],
+        };
+        let new = match subscribe(sessions, key, inputs) {
+            Some(new) => new,
+            None => return vec![error_event(&format!("no device with device_index {}", key))],
+        };
+        if let Some(old) = subscription.replace(new) {
+            old.forwarder.abort();
+        }
+    }
+
+    let s = match subscription.as_ref() {
+        Some(s) => s,
+        None => return vec![error_event("subscribe to a device first")],
+    };
+    let (session, key) = (s.session.clone(), s.key);
+    match kind {
+        "subscribe" | "state" => {
+            return vec![tokio::task::spawn_blocking(move || state_event(&session, key)).await.unwrap_or_default()];
+        },
+        "run" => start_run(&session, key, message["traps"].as_bool().unwrap_or(false)),
+        "stop" => session.run.store(0, Ordering::SeqCst),
+        "uart" => {
+            let data = match &message["data"] {
+                serde_json::Value::String(text) => text.as_bytes().to_vec(),
+                serde_json::Value::Array(bytes) if bytes.iter().all(|b| b.as_u64().is_some_and(|b| b <= 0xff)) => {
+                    bytes.iter().map(|b| b.as_u64().unwrap() as u8).collect()
+                },
+                data => return vec![error_event(&format!("invalid uart data: {}", data))],
+            };
+            return uart_input(subscription, data).await;
+        },
+        _ => return vec![error_event(&format!("unknown message type: {}", kind))],
+    }
+    return vec![];
+}
+
+// keystrokes of the console, for the UART receiver of the session
+async fn uart_input(subscription: &Option<Subscription>, data: Vec<u8>) -> Vec<String> {
+    let session = match subscription.as_ref() {
+        Some(s) => s.session.clone(),
+        None => return vec![error_event("subscribe to a device first")],
+    };
+    let accepted = tokio::task::spawn_blocking(move || {
+        let rx = &mut lock(&session.sim).devices.uart.rx;
+        if rx.len() + data.len() > UART_RX_MAX {
+            return false;
+        }
+        rx.extend(data);
+        return true;
+    }).await.unwrap_or(false);
+    if !accepted {
+        return vec![error_event("the UART receiver is full, the input was dropped")];
+    }
+    return vec![];
+}
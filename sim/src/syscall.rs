@@ -0,0 +1,758 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+
+use crate::sim::*;
+use crate::elf::*;
+
+/*
+ * Linux user-mode emulation, like qemu-user: a static riscv64 Linux program runs in U-mode without a kernel
+ * and its ECALLs are carried out on the host. a7 holds the syscall number, a0-a5 the arguments,
+ * the result or -errno goes back in a0.
+ *
+ * The guest address space is the RAM window, virtual addresses are physical (satp stays 0):
+ *
+ *      ELF segments                    loaded at their addresses, 0x10000 for a default link
+ *      heap                            from the end of the ELF, grown with brk
+ *      mmap                            allocated top down below the stack
+ *      stack                           STACK_SIZE at the top of RAM, argc/argv/envp/auxv at sp
+ *
+ * Threads, signals and processes are not emulated: signal handlers are never called, futex never waits.
+ *
+ * Bare-metal programs built against newlib or picolibc (libgloss) use the same numbering from M-mode,
+ * "-T path --newlib" services their ECALLs the same way: the heap starts at the end of the image and
+ * may grow up to the stack pointer, exit(0) is a pass and any other code a failure, which matches the
+ * self test convention of sim/doc (a7 = 93, a0 = 0 or test number << 1 | 1).
+ *
+ * https://github.com/torvalds/linux/blob/master/include/uapi/asm-generic/unistd.h
+ * https://sourceware.org/git/?p=newlib-cygwin.git;a=tree;f=libgloss/riscv
+ */
+
+pub const STACK_SIZE: u64 = 8 << 20;
+const GETRANDOM_MAX: u64 = (32 << 20) - 1;
+const PAGE_SIZE: u64 = 4096;
+
+const SYS_GETCWD:          u64 = 17;
+const SYS_FCNTL:           u64 = 25;
+const SYS_IOCTL:           u64 = 29;
+const SYS_MKDIRAT:         u64 = 34;
+const SYS_UNLINKAT:        u64 = 35;
+const SYS_FACCESSAT:       u64 = 48;
+const SYS_OPENAT:          u64 = 56;
+const SYS_CLOSE:           u64 = 57;
+const SYS_LSEEK:           u64 = 62;
+const SYS_READ:            u64 = 63;
+const SYS_WRITE:           u64 = 64;
+const SYS_READV:           u64 = 65;
+const SYS_WRITEV:          u64 = 66;
+const SYS_PREAD64:         u64 = 67;
+const SYS_PWRITE64:        u64 = 68;
+const SYS_READLINKAT:      u64 = 78;
+const SYS_NEWFSTATAT:      u64 = 79;
+const SYS_FSTAT:           u64 = 80;
+const SYS_EXIT:            u64 = 93;
+const SYS_EXIT_GROUP:      u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_FUTEX:           u64 = 98;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_NANOSLEEP:       u64 = 101;
+const SYS_CLOCK_GETTIME:   u64 = 113;
+const SYS_CLOCK_NANOSLEEP: u64 = 115;
+const SYS_SCHED_YIELD:     u64 = 124;
+const SYS_KILL:            u64 = 129;
+const SYS_TKILL:           u64 = 130;
+const SYS_TGKILL:          u64 = 131;
+const SYS_SIGALTSTACK:     u64 = 132;
+const SYS_RT_SIGACTION:    u64 = 134;
+const SYS_RT_SIGPROCMASK:  u64 = 135;
+const SYS_TIMES:           u64 = 153;
+const SYS_UNAME:           u64 = 160;
+const SYS_GETRLIMIT:       u64 = 163;
+const SYS_GETTIMEOFDAY:    u64 = 169;
+const SYS_GETPID:          u64 = 172;
+const SYS_GETPPID:         u64 = 173;
+const SYS_GETUID:          u64 = 174;
+const SYS_GETEUID:         u64 = 175;
+const SYS_GETGID:          u64 = 176;
+const SYS_GETEGID:         u64 = 177;
+const SYS_GETTID:          u64 = 178;
+const SYS_BRK:             u64 = 214;
+const SYS_MUNMAP:          u64 = 215;
+const SYS_MMAP:            u64 = 222;
+const SYS_MPROTECT:        u64 = 226;
+const SYS_MADVISE:         u64 = 233;
+const SYS_RISCV_FLUSH_ICACHE: u64 = 259;
+const SYS_PRLIMIT64:       u64 = 261;
+const SYS_GETRANDOM:       u64 = 278;
+// older libgloss, before it moved to the *at variants
+const SYS_OPEN:            u64 = 1024;
+
+const EPERM:   i64 = 1;
+const EIO:     i64 = 5;
+const EBADF:   i64 = 9;
+const ENOMEM:  i64 = 12;
+const EFAULT:  i64 = 14;
+const EINVAL:  i64 = 22;
+const ENOTTY:  i64 = 25;
+const ESPIPE:  i64 = 29;
+const ERANGE:  i64 = 34;
+const ENOSYS:  i64 = 38;
+
+const AT_FDCWD:            i64 = -100;
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_REMOVEDIR:        u64 = 0x200;
+const AT_EMPTY_PATH:       u64 = 0x1000;
+
+const O_ACCMODE: u64 = 0o3;
+const O_WRONLY:  u64 = 0o1;
+const O_RDWR:    u64 = 0o2;
+const O_CREAT:   u64 = 0o100;
+const O_EXCL:    u64 = 0o200;
+const O_TRUNC:   u64 = 0o1000;
+const O_APPEND:  u64 = 0o2000;
+
+const MAP_FIXED:     u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const F_DUPFD:         u64 = 0;
+const F_GETFL:         u64 = 3;
+const F_DUPFD_CLOEXEC: u64 = 1030;
+
+const S_IFCHR: u32 = 0o020000;
+
+const CLOCK_REALTIME:        u64 = 0;
+const CLOCK_REALTIME_COARSE: u64 = 5;
+
+const RLIMIT_STACK:  u64 = 3;
+const RLIM_INFINITY: u64 = u64::MAX;
+
+// auxiliary vector entries
+const AT_NULL:   u64 = 0;
+const AT_PHDR:   u64 = 3;
+const AT_PHENT:  u64 = 4;
+const AT_PHNUM:  u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE:   u64 = 7;
+const AT_FLAGS:  u64 = 8;
+const AT_ENTRY:  u64 = 9;
+const AT_UID:    u64 = 11;
+const AT_EUID:   u64 = 12;
+const AT_GID:    u64 = 13;
+const AT_EGID:   u64 = 14;
+const AT_HWCAP:  u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+// one bit per single letter extension, bit 0 is 'A': IMAC
+const HWCAP: u64 = 1 << (b'I' - b'A') | 1 << (b'M' - b'A') | 1 | 1 << (b'C' - b'A');
+
+const SIGILL:  u64 = 4;
+const SIGTRAP: u64 = 5;
+const SIGBUS:  u64 = 7;
+const SIGSEGV: u64 = 11;
+
+// what a guest file descriptor refers to on the host
+#[derive(Debug)]
+pub enum HostFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(fs::File),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Syscalls {
+    pub brk_start: u64,
+    pub brk:       u64,
+    pub mmap_top:  u64,
+    pub mappings:  Vec<(u64, u64)>, // [start, end) of the mmap regions, sorted
+    pub exit_code: Option<u64>,
+    pub random:    u64,             // xorshift state for getrandom and AT_RANDOM
+    pub bare_metal: bool,           // newlib/picolibc program in M-mode instead of a Linux process
+    #[serde(skip)]
+    pub files:     HashMap<i64, HostFile>,
+    #[serde(skip)]
+    pub start:     Option<Instant>,
+}
+
+pub fn new_syscalls(brk_start: u64, mmap_top: u64) -> Syscalls {
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    return Syscalls {
+        brk_start: brk_start,
+        brk:       brk_start,
+        mmap_top:  mmap_top,
+        mappings:  vec![],
+        exit_code: None,
+        random:    seed | 1,
+        bare_metal: false,
+        files:     HashMap::from([(0, HostFile::Stdin), (1, HostFile::Stdout), (2, HostFile::Stderr)]),
+        start:     Some(Instant::now()),
+    };
+}
+
+fn page_align(value: u64) -> u64 {
+    return (value + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+}
+
+impl Syscalls {
+    fn next_random(&mut self) -> u64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        return self.random;
+    }
+
+    fn lowest_free_fd(&self) -> i64 {
+        return (0..).find(|fd| !self.files.contains_key(fd)).unwrap();
+    }
+
+    // removes [start, end) from the mappings, splitting the ones it cuts through
+    fn unmap(&mut self, start: u64, end: u64) {
+        let mut kept = Vec::new();
+        for &(s, e) in self.mappings.iter() {
+            if e <= start || s >= end {
+                kept.push((s, e));
+                continue;
+            }
+            if s < start {
+                kept.push((s, start));
+            }
+            if e > end {
+                kept.push((end, e));
+            }
+        }
+        self.mappings = kept;
+    }
+
+    fn map(&mut self, start: u64, end: u64) {
+        self.unmap(start, end);
+        self.mappings.push((start, end));
+        self.mappings.sort();
+    }
+
+    // highest free range of length bytes between the heap and mmap_top
+    fn find_free(&self, length: u64) -> Option<u64> {
+        let mut top = self.mmap_top;
+        for &(s, e) in self.mappings.iter().rev() {
+            if top >= e && top - e >= length {
+                break;
+            }
+            top = top.min(s);
+        }
+        let start = top.checked_sub(length)?;
+        return if start >= page_align(self.brk) { Some(start) } else { None };
+    }
+
+    // brk may grow up to the lowest mapping
+    fn brk_limit(&self) -> u64 {
+        return self.mappings.first().map_or(self.mmap_top, |m| m.0);
+    }
+}
+
+pub fn guest_bytes(sim: &Simulator, address: u64, length: u64) -> Result<&[u8], i64> {
+    return ram_range(sim, address, length).map(|r| &sim.mem[r]).ok_or(EFAULT);
+}
+
+// guest memory the host is about to write, the guest runs on HART 0 without address translation
+pub fn guest_bytes_mut(sim: &mut Simulator, address: u64, length: u64) -> Result<&mut [u8], i64> {
+    memory_written(sim, 0, address, address, length);
+    return match ram_range(sim, address, length) {
+        Some(range) => Ok(&mut sim.mem[range]),
+        None => Err(EFAULT),
+    };
+}
+
+pub fn guest_write(sim: &mut Simulator, address: u64, data: &[u8]) -> Result<(), i64> {
+    guest_bytes_mut(sim, address, data.len() as u64)?.copy_from_slice(data);
+    return Ok(());
+}
+
+pub fn guest_u64(sim: &Simulator, address: u64) -> Result<u64, i64> {
+    return Ok(u64::from_le_bytes(guest_bytes(sim, address, 8)?.try_into().unwrap()));
+}
+
+// NUL terminated string
+fn guest_string(sim: &Simulator, address: u64) -> Result<String, i64> {
+    let start = ram_range(sim, address, 0).ok_or(EFAULT)?.start;
+    let length = sim.mem[start..].iter().position(|b| *b == 0).ok_or(EFAULT)?;
+    return Ok(String::from_utf8_lossy(&sim.mem[start..start + length]).to_string());
+}
+
+pub fn host_error(e: std::io::Error) -> i64 {
+    return e.raw_os_error().map_or(EIO, |e| e as i64);
+}
+
+// paths relative to a directory descriptor other than AT_FDCWD are not supported
+fn host_path(sim: &Simulator, dirfd: u64, path: u64) -> Result<String, i64> {
+    let path = guest_string(sim, path)?;
+    if dirfd as i64 != AT_FDCWD && !path.starts_with('/') {
+        return Err(ENOSYS);
+    }
+    return Ok(path);
+}
+
+/*
+ * struct stat of the generic Linux ABI, 128 bytes:
+ *      dev ino mode:u32 nlink:u32 uid:u32 gid:u32 rdev pad size blksize:i32 pad:i32 blocks
+ *      atime atime_nsec mtime mtime_nsec ctime ctime_nsec unused:2*u32
+ */
+fn stat_bytes(metadata: Option<&fs::Metadata>) -> [u8; 128] {
+    let mut stat = [0u8; 128];
+    let mut put = |offset: usize, value: u64, size: usize| {
+        stat[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    };
+    match metadata {
+        Some(m) => {
+            put(0,  m.dev(), 8);
+            put(8,  m.ino(), 8);
+            put(16, m.mode() as u64, 4);
+            put(20, m.nlink(), 4);
+            put(24, m.uid() as u64, 4);
+            put(28, m.gid() as u64, 4);
+            put(32, m.rdev(), 8);
+            put(48, m.size(), 8);
+            put(56, m.blksize(), 4);
+            put(64, m.blocks(), 8);
+            put(72, m.atime() as u64, 8);
+            put(80, m.atime_nsec() as u64, 8);
+            put(88, m.mtime() as u64, 8);
+            put(96, m.mtime_nsec() as u64, 8);
+            put(104, m.ctime() as u64, 8);
+            put(112, m.ctime_nsec() as u64, 8);
+        },
+        None => {
+            // the console: a character device
+            put(16, (S_IFCHR | 0o620) as u64, 4);
+            put(20, 1, 4);
+            put(56, 1024, 4);
+        },
+    }
+    return stat;
+}
+
+fn timespec_bytes(seconds: u64, nanoseconds: u64) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&seconds.to_le_bytes());
+    bytes[8..].copy_from_slice(&nanoseconds.to_le_bytes());
+    return bytes;
+}
+
+// the signal the process dies of when it takes a trap nothing handles
+pub fn trap_signal(cause: u64) -> u64 {
+    return match cause {
+        CAUSE_ILLEGAL_INSTRUCTION => SIGILL,
+        CAUSE_BREAKPOINT => SIGTRAP,
+        CAUSE_LOAD_MISALIGNED | CAUSE_STORE_MISALIGNED => SIGBUS,
+        _ => SIGSEGV,
+    };
+}
+
+/*
+ * Sets up the process like execve does: the HART in U-mode at the entry point,
+ * sp at argc, followed by argv, envp and the auxiliary vector. The ELF is already loaded.
+ */
+pub fn start_user_process(sim: &mut Simulator, elf: &Elf, args: &[String], env: &[String]) -> Result<(), String> {
+    let stack_top = sim.mem_base + sim.mem.len() as u64;
+    let brk_start = page_align(sim.image_end);
+    if brk_start + STACK_SIZE > stack_top {
+        return Err(String::from("not enough memory for the stack, use --mem"));
+    }
+    let mut syscalls = new_syscalls(brk_start, stack_top - STACK_SIZE);
+
+    // strings and AT_RANDOM bytes at the top of the stack
+    let mut sp = stack_top;
+    let mut push = |sim: &mut Simulator, data: &[u8]| -> u64 {
+        sp -= data.len() as u64;
+        let offset = (sp - sim.mem_base) as usize;
+        sim.mem[offset..offset + data.len()].copy_from_slice(data);
+        return sp;
+    };
+    let execfn = push(sim, format!("{}\0", args[0]).as_bytes());
+    let env_pointers: Vec<u64> = env.iter().map(|e| push(sim, format!("{}\0", e).as_bytes())).collect();
+    let arg_pointers: Vec<u64> = args.iter().map(|a| push(sim, format!("{}\0", a).as_bytes())).collect();
+    let random = [syscalls.next_random().to_le_bytes(), syscalls.next_random().to_le_bytes()].concat();
+    let random = push(sim, &random);
+
+    let auxv = [
+        (AT_PHDR,   elf.phdr_address().unwrap_or(0)),
+        (AT_PHENT,  56),
+        (AT_PHNUM,  elf.segments.len() as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE,   0),
+        (AT_FLAGS,  0),
+        (AT_ENTRY,  sim.reset_vector),
+        (AT_UID,    0),
+        (AT_EUID,   0),
+        (AT_GID,    0),
+        (AT_EGID,   0),
+        (AT_HWCAP,  HWCAP),
+        (AT_CLKTCK, 100),
+        (AT_SECURE, 0),
+        (AT_RANDOM, random),
+        (AT_EXECFN, execfn),
+        (AT_NULL,   0),
+    ];
+    let mut words = vec![args.len() as u64];
+    words.extend(arg_pointers.iter());
+    words.push(0);
+    words.extend(env_pointers.iter());
+    words.push(0);
+    for (key, value) in auxv.iter() {
+        words.push(*key);
+        words.push(*value);
+    }
+    let sp = (sp - 8 * words.len() as u64) & !0xf;
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    guest_write(sim, sp, &bytes).map_err(|_| String::from("the stack does not fit in RAM"))?;
+
+    let state = &mut sim.states[0];
+    state.regs[2] = sp;
+    state.regs[10] = 0; // no function for atexit
+    state.pc = sim.reset_vector;
+    state.priviledge_mode = PRIV_U;
+    sim.devices.syscalls = Some(syscalls);
+    return Ok(());
+}
+
+/*
+ * Services the ECALLs of a bare-metal newlib or picolibc program loaded with -T.
+ * The program sets up its own stack, the heap follows the image.
+ */
+pub fn start_bare_metal(sim: &mut Simulator) {
+    let ram_end = sim.mem_base + sim.mem.len() as u64;
+    let mut syscalls = new_syscalls(sim.image_end, ram_end);
+    syscalls.bare_metal = true;
+    sim.devices.syscalls = Some(syscalls);
+}
+
+// ECALL from U-mode, or from any mode for a bare-metal program
+pub fn syscall(sim: &mut Simulator, state: &mut CpuState) {
+    state.pc = state.pc.wrapping_add(4);
+    let syscalls = sim.devices.syscalls.as_mut().unwrap();
+    if syscalls.bare_metal {
+        // the heap may grow up to the stack
+        let ram_end = sim.mem_base + sim.mem.len() as u64;
+        syscalls.mmap_top = if state.regs[2] > syscalls.brk_start { state.regs[2].min(ram_end) } else { ram_end };
+    }
+    let number = state.regs[17];
+    let a: [u64; 6] = state.regs[10..16].try_into().unwrap();
+    let result = match linux_syscall(sim, number, a) {
+        Ok(value) => value,
+        Err(errno) => -errno as u64,
+    };
+    state.regs[10] = result;
+}
+
+fn linux_syscall(sim: &mut Simulator, number: u64, a: [u64; 6]) -> Result<u64, i64> {
+    return match number {
+        SYS_READ | SYS_PREAD64 => {
+            let offset = if number == SYS_PREAD64 { Some(a[3]) } else { None };
+            read_file(sim, a[0] as i64, a[1], a[2], offset)
+        },
+        SYS_WRITE | SYS_PWRITE64 => {
+            let offset = if number == SYS_PWRITE64 { Some(a[3]) } else { None };
+            write_file(sim, a[0] as i64, a[1], a[2], offset)
+        },
+        SYS_READV | SYS_WRITEV => {
+            let mut total = 0;
+            for i in 0..a[2] {
+                let base   = guest_u64(sim, a[1] + 16 * i)?;
+                let length = guest_u64(sim, a[1] + 16 * i + 8)?;
+                let count = if number == SYS_READV {
+                    read_file(sim, a[0] as i64, base, length, None)?
+                } else {
+                    write_file(sim, a[0] as i64, base, length, None)?
+                };
+                total += count;
+                if count < length {
+                    break;
+                }
+            }
+            Ok(total)
+        },
+        SYS_OPEN => linux_syscall(sim, SYS_OPENAT, [AT_FDCWD as u64, a[0], a[1], a[2], 0, 0]),
+        SYS_OPENAT => {
+            let path = host_path(sim, a[0], a[1])?;
+            let flags = a[2];
+            let mut options = fs::OpenOptions::new();
+            match flags & O_ACCMODE {
+                O_WRONLY => options.write(true),
+                O_RDWR   => options.read(true).write(true),
+                _        => options.read(true),
+            };
+            options.append(flags & O_APPEND != 0)
+                .truncate(flags & O_TRUNC != 0)
+                .create(flags & O_CREAT != 0 && flags & O_EXCL == 0)
+                .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0)
+                .mode(a[3] as u32);
+            let file = options.open(&path).map_err(host_error)?;
+            let syscalls = sim.devices.syscalls.as_mut().unwrap();
+            let fd = syscalls.lowest_free_fd();
+            syscalls.files.insert(fd, HostFile::File(file));
+            Ok(fd as u64)
+        },
+        SYS_CLOSE => match sim.devices.syscalls.as_mut().unwrap().files.remove(&(a[0] as i64)) {
+            Some(_) => Ok(0),
+            None => Err(EBADF),
+        },
+        SYS_LSEEK => {
+            let whence = match a[2] {
+                0 => SeekFrom::Start(a[1]),
+                1 => SeekFrom::Current(a[1] as i64),
+                2 => SeekFrom::End(a[1] as i64),
+                _ => return Err(EINVAL),
+            };
+            match sim.devices.syscalls.as_mut().unwrap().files.get_mut(&(a[0] as i64)) {
+                Some(HostFile::File(file)) => file.seek(whence).map_err(host_error),
+                Some(_) => Err(ESPIPE),
+                None => Err(EBADF),
+            }
+        },
+        SYS_FSTAT => {
+            let stat = match sim.devices.syscalls.as_ref().unwrap().files.get(&(a[0] as i64)) {
+                Some(HostFile::File(file)) => stat_bytes(Some(&file.metadata().map_err(host_error)?)),
+                Some(_) => stat_bytes(None),
+                None => return Err(EBADF),
+            };
+            guest_write(sim, a[1], &stat)?;
+            Ok(0)
+        },
+        SYS_NEWFSTATAT => {
+            if a[3] & AT_EMPTY_PATH != 0 && guest_string(sim, a[1])?.is_empty() {
+                return linux_syscall(sim, SYS_FSTAT, [a[0], a[2], 0, 0, 0, 0]);
+            }
+            let path = host_path(sim, a[0], a[1])?;
+            let metadata = if a[3] & AT_SYMLINK_NOFOLLOW != 0 { fs::symlink_metadata(&path) } else { fs::metadata(&path) };
+            let stat = stat_bytes(Some(&metadata.map_err(host_error)?));
+            guest_write(sim, a[2], &stat)?;
+            Ok(0)
+        },
+        SYS_FACCESSAT => {
+            let path = host_path(sim, a[0], a[1])?;
+            fs::metadata(&path).map_err(host_error)?;
+            Ok(0)
+        },
+        SYS_MKDIRAT => {
+            let path = host_path(sim, a[0], a[1])?;
+            fs::create_dir(&path).map_err(host_error)?;
+            Ok(0)
+        },
+        SYS_UNLINKAT => {
+            let path = host_path(sim, a[0], a[1])?;
+            let removed = if a[2] & AT_REMOVEDIR != 0 { fs::remove_dir(&path) } else { fs::remove_file(&path) };
+            removed.map_err(host_error)?;
+            Ok(0)
+        },
+        SYS_READLINKAT => Err(EINVAL),
+        SYS_GETCWD => {
+            let cwd = std::env::current_dir().map_err(host_error)?;
+            let cwd = format!("{}\0", cwd.display());
+            if cwd.len() as u64 > a[1] {
+                return Err(ERANGE);
+            }
+            guest_write(sim, a[0], cwd.as_bytes())?;
+            Ok(cwd.len() as u64)
+        },
+        SYS_IOCTL => Err(ENOTTY),
+        SYS_FCNTL => {
+            let syscalls = sim.devices.syscalls.as_mut().unwrap();
+            match (syscalls.files.get(&(a[0] as i64)), a[1]) {
+                (None, _) => Err(EBADF),
+                (Some(HostFile::File(file)), F_DUPFD) | (Some(HostFile::File(file)), F_DUPFD_CLOEXEC) => {
+                    let copy = file.try_clone().map_err(host_error)?;
+                    let fd = (a[2] as i64..).find(|fd| !syscalls.files.contains_key(fd)).unwrap();
+                    syscalls.files.insert(fd, HostFile::File(copy));
+                    Ok(fd as u64)
+                },
+                (Some(HostFile::Stdin), F_GETFL) => Ok(0),
+                (Some(_), F_GETFL) => Ok(O_WRONLY),
+                (Some(_), F_DUPFD) | (Some(_), F_DUPFD_CLOEXEC) => Err(EINVAL),
+                _ => Ok(0),
+            }
+        },
+
+        SYS_EXIT | SYS_EXIT_GROUP => {
+            let syscalls = sim.devices.syscalls.as_mut().unwrap();
+            // a Linux exit status is 8 bits, a self test reports its test number in the rest
+            syscalls.exit_code = Some(if syscalls.bare_metal { a[0] } else { a[0] & 0xff });
+            Ok(0)
+        },
+        SYS_KILL | SYS_TKILL | SYS_TGKILL => {
+            let signal = if number == SYS_TGKILL { a[2] } else { a[1] };
+            if signal != 0 {
+                println!("INFO guest killed by signal {}", signal);
+                sim.devices.syscalls.as_mut().unwrap().exit_code = Some(128 + signal);
+            }
+            Ok(0)
+        },
+        SYS_SET_TID_ADDRESS | SYS_GETTID | SYS_GETPID => Ok(1),
+        SYS_GETPPID => Ok(0),
+        SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+        SYS_FUTEX | SYS_SET_ROBUST_LIST | SYS_SCHED_YIELD => Ok(0),
+        SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK | SYS_SIGALTSTACK => Ok(0),
+        SYS_MPROTECT | SYS_MADVISE | SYS_RISCV_FLUSH_ICACHE => Ok(0),
+        SYS_NANOSLEEP | SYS_CLOCK_NANOSLEEP => Ok(0),
+
+        SYS_CLOCK_GETTIME => {
+            let (seconds, nanoseconds) = match a[0] {
+                CLOCK_REALTIME | CLOCK_REALTIME_COARSE => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                    (now.as_secs(), now.subsec_nanos() as u64)
+                },
+                _ => {
+                    let now = sim.devices.syscalls.as_ref().unwrap().start.map(|s| s.elapsed()).unwrap_or_default();
+                    (now.as_secs(), now.subsec_nanos() as u64)
+                },
+            };
+            guest_write(sim, a[1], &timespec_bytes(seconds, nanoseconds))?;
+            Ok(0)
+        },
+        SYS_GETTIMEOFDAY => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            if a[0] != 0 {
+                guest_write(sim, a[0], &timespec_bytes(now.as_secs(), now.subsec_micros() as u64))?;
+            }
+            Ok(0)
+        },
+        SYS_TIMES => {
+            // struct tms: user, system and children times, all counted as user time in clock ticks
+            let ticks = sim.devices.syscalls.as_ref().unwrap().start.map_or(0, |s| s.elapsed().as_millis() as u64 / 10);
+            if a[0] != 0 {
+                let tms = [ticks.to_le_bytes(), [0; 8], [0; 8], [0; 8]].concat();
+                guest_write(sim, a[0], &tms)?;
+            }
+            Ok(ticks)
+        },
+        SYS_UNAME => {
+            // six fields of 65 bytes
+            let mut uts = [0u8; 6 * 65];
+            for (i, field) in ["Linux", "ar64", "6.1.0", "#1", "riscv64", "(none)"].iter().enumerate() {
+                uts[65 * i..65 * i + field.len()].copy_from_slice(field.as_bytes());
+            }
+            guest_write(sim, a[0], &uts)?;
+            Ok(0)
+        },
+        SYS_GETRLIMIT | SYS_PRLIMIT64 => {
+            let (resource, old) = if number == SYS_GETRLIMIT { (a[0], a[1]) } else { (a[1], a[3]) };
+            if old != 0 {
+                let current = if resource == RLIMIT_STACK { STACK_SIZE } else { RLIM_INFINITY };
+                let limit = [current.to_le_bytes(), RLIM_INFINITY.to_le_bytes()].concat();
+                guest_write(sim, old, &limit)?;
+            }
+            Ok(0)
+        },
+        SYS_GETRANDOM => {
+            // Linux returns at most 32 MiB - 1 bytes of urandom, the rest is a short read
+            let length = a[1].min(GETRANDOM_MAX);
+            let mut done = 0;
+            while done < length {
+                let chunk = (length - done).min(256);
+                let syscalls = sim.devices.syscalls.as_mut().unwrap();
+                let bytes: Vec<u8> = (0..chunk.div_ceil(8)).flat_map(|_| syscalls.next_random().to_le_bytes()).collect();
+                guest_write(sim, a[0].wrapping_add(done), &bytes[..chunk as usize])?;
+                done += chunk;
+            }
+            Ok(length)
+        },
+
+        SYS_BRK => {
+            let syscalls = sim.devices.syscalls.as_mut().unwrap();
+            let old = syscalls.brk;
+            if a[0] < syscalls.brk_start || a[0] > syscalls.brk_limit() {
+                return Ok(old);
+            }
+            syscalls.brk = a[0];
+            if a[0] > old {
+                // memory given back earlier and grown again reads as zero
+                guest_bytes_mut(sim, old, a[0] - old)?.fill(0);
+            }
+            Ok(a[0])
+        },
+        SYS_MMAP => mmap(sim, a),
+        SYS_MUNMAP => {
+            if !a[0].is_multiple_of(PAGE_SIZE) {
+                return Err(EINVAL);
+            }
+            sim.devices.syscalls.as_mut().unwrap().unmap(a[0], a[0] + page_align(a[1]));
+            Ok(0)
+        },
+
+        _ => {
+            println!("WARN: unsupported syscall {} ({:X?})", number, a);
+            Err(ENOSYS)
+        },
+    };
+}
+
+// mmap(addr, length, prot, flags, fd, offset), private file mappings are copies
+fn mmap(sim: &mut Simulator, a: [u64; 6]) -> Result<u64, i64> {
+    let (address, flags, fd, offset) = (a[0], a[3], a[4] as i64, a[5]);
+    let length = page_align(a[1]);
+    if length == 0 || !address.is_multiple_of(PAGE_SIZE) {
+        return Err(EINVAL);
+    }
+    let syscalls = sim.devices.syscalls.as_mut().unwrap();
+    let start = if flags & MAP_FIXED != 0 {
+        address
+    } else {
+        syscalls.find_free(length).ok_or(ENOMEM)?
+    };
+    ram_range(sim, start, length).ok_or(ENOMEM)?;
+    sim.devices.syscalls.as_mut().unwrap().map(start, start + length);
+    guest_bytes_mut(sim, start, length)?.fill(0);
+
+    if flags & MAP_ANONYMOUS == 0 {
+        let file = match sim.devices.syscalls.as_mut().unwrap().files.get(&fd) {
+            Some(HostFile::File(file)) => file.try_clone().map_err(host_error)?,
+            Some(_) => return Err(EPERM),
+            None => return Err(EBADF),
+        };
+        let target = guest_bytes_mut(sim, start, length)?;
+        let mut done = 0;
+        while done < target.len() {
+            match file.read_at(&mut target[done..], offset + done as u64).map_err(host_error)? {
+                0 => break,
+                count => done += count,
+            }
+        }
+    }
+    return Ok(start);
+}
+
+fn read_file(sim: &mut Simulator, fd: i64, address: u64, length: u64, offset: Option<u64>) -> Result<u64, i64> {
+    let range = ram_range(sim, address, length).ok_or(EFAULT)?;
+    memory_written(sim, 0, address, address, length);
+    let syscalls = sim.devices.syscalls.as_mut().unwrap();
+    let buffer = &mut sim.mem[range];
+    let count = match (syscalls.files.get_mut(&fd), offset) {
+        (Some(HostFile::Stdin), None) => std::io::stdin().read(buffer),
+        (Some(HostFile::File(file)), None) => file.read(buffer),
+        (Some(HostFile::File(file)), Some(offset)) => file.read_at(buffer, offset),
+        (Some(_), Some(_)) => return Err(ESPIPE),
+        (Some(_), None) => return Err(EBADF),
+        (None, _) => return Err(EBADF),
+    };
+    return count.map(|c| c as u64).map_err(host_error);
+}
+
+fn write_file(sim: &mut Simulator, fd: i64, address: u64, length: u64, offset: Option<u64>) -> Result<u64, i64> {
+    let range = ram_range(sim, address, length).ok_or(EFAULT)?;
+    let syscalls = sim.devices.syscalls.as_mut().unwrap();
+    let buffer = &sim.mem[range];
+    let count = match (syscalls.files.get_mut(&fd), offset) {
+        (Some(HostFile::Stdout), None) => std::io::stdout().write_all(buffer).and_then(|_| std::io::stdout().flush()).map(|_| buffer.len()),
+        (Some(HostFile::Stderr), None) => std::io::stderr().write_all(buffer).map(|_| buffer.len()),
+        (Some(HostFile::File(file)), None) => file.write(buffer),
+        (Some(HostFile::File(file)), Some(offset)) => file.write_at(buffer, offset),
+        (Some(_), Some(_)) => return Err(ESPIPE),
+        (Some(HostFile::Stdin), None) => return Err(EBADF),
+        (None, _) => return Err(EBADF),
+    };
+    return count.map(|c| c as u64).map_err(host_error);
+}
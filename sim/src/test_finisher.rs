@@ -0,0 +1,62 @@
+use serde::{Serialize, Deserialize};
+
+/*
+ * SiFive test finisher ("sifive,test0"), as found in the QEMU virt machine.
+ *
+ * A 32 bit write to the device stops the machine:
+ *      0x5555                  pass, exit code 0
+ *      (code << 16) | 0x3333   fail with exit code `code`
+ *      0x7777                  reset
+ * Linux drives it through the syscon-poweroff and syscon-reboot drivers.
+ */
+
+pub const TEST_FINISHER_BASE: u64 = 0x100000;
+pub const TEST_FINISHER_SIZE: u64 = 0x1000;
+
+pub const FINISHER_FAIL:  u64 = 0x3333;
+pub const FINISHER_PASS:  u64 = 0x5555;
+pub const FINISHER_RESET: u64 = 0x7777;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FinisherStatus {
+    Pass,
+    Fail(u16),
+    Reset,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TestFinisher {
+    pub base:   u64,
+    // set by the guest, cleared by the simulator once handled
+    pub status: Option<FinisherStatus>,
+}
+
+pub fn default_test_finisher() -> TestFinisher {
+    return TestFinisher {
+        base:   TEST_FINISHER_BASE,
+        status: None,
+    };
+}
+
+impl TestFinisher {
+    pub fn contains(&self, address: u64) -> bool {
+        return address >= self.base && address < self.base + TEST_FINISHER_SIZE;
+    }
+
+    pub fn read(&self, _address: u64, _size: u64) -> u64 {
+        return 0;
+    }
+
+    pub fn write(&mut self, address: u64, size: u64, value: u64) {
+        // only the first word is a register, unknown commands are ignored like on real hardware
+        if address != self.base || size < 4 {
+            return;
+        }
+        self.status = match value & 0xffff {
+            FINISHER_PASS  => Some(FinisherStatus::Pass),
+            FINISHER_FAIL  => Some(FinisherStatus::Fail(((value >> 16) & 0xffff) as u16)),
+            FINISHER_RESET => Some(FinisherStatus::Reset),
+            _ => self.status,
+        };
+    }
+}
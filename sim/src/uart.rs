@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+
+use serde::{Serialize, Deserialize};
+
+/*
+ * ns16550a compatible UART, one byte per register (reg-shift 0).
+ *
+ *      offset  read            write           DLAB=1
+ *      0       RBR receive     THR transmit    DLL divisor low
+ *      1       IER             IER             DLM divisor high
+ *      2       IIR             FCR
+ *      3       LCR             LCR
+ *      4       MCR             MCR
+ *      5       LSR
+ *      6       MSR
+ *      7       SCR             SCR
+ *
+ * Transmission is instant, so the transmitter is always empty. Received bytes are queued in `rx`
+ * by the host. The interrupt line goes to PLIC source 10.
+ *
+ * https://www.ti.com/lit/ds/symlink/pc16550d.pdf
+ */
+
+pub const UART_BASE: u64 = 0x10000000;
+pub const UART_SIZE: u64 = 0x100;
+
+const IER_RX_AVAILABLE: u8 = 0x01;
+const IER_TX_EMPTY:     u8 = 0x02;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_TX_EMPTY:     u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const LCR_DLAB: u8 = 0x80;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY:  u8 = 0x20;
+const LSR_TX_EMPTY:   u8 = 0x40;
+
+const MCR_LOOPBACK: u8 = 0x10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Uart {
+    pub base:       u64,
+    pub ier:        u8,
+    pub fcr:        u8,
+    pub lcr:        u8,
+    pub mcr:        u8,
+    pub scr:        u8,
+    pub dll:        u8,
+    pub dlm:        u8,
+    pub tx_pending: bool, // transmitter empty interrupt, cleared by reading IIR or writing THR
+    pub rx:         VecDeque<u8>,
+}
+
+pub fn default_uart() -> Uart {
+    return Uart {
+        base:       UART_BASE,
+        ier:        0,
+        fcr:        0,
+        lcr:        0,
+        mcr:        0,
+        scr:        0,
+        dll:        0,
+        dlm:        0,
+        tx_pending: false,
+        rx:         VecDeque::new(),
+    };
+}
+
+impl Uart {
+    pub fn contains(&self, address: u64) -> bool {
+        return address >= self.base && address < self.base + UART_SIZE;
+    }
+
+    fn iir(&self) -> u8 {
+        let fifo = if self.fcr & 1 != 0 { IIR_FIFO_ENABLED } else { 0 };
+        if self.ier & IER_RX_AVAILABLE != 0 && !self.rx.is_empty() {
+            return fifo | IIR_RX_AVAILABLE;
+        }
+        if self.ier & IER_TX_EMPTY != 0 && self.tx_pending {
+            return fifo | IIR_TX_EMPTY;
+        }
+        return fifo | IIR_NO_INTERRUPT;
+    }
+
+    // level of the interrupt line
+    pub fn interrupt_pending(&self) -> bool {
+        return self.iir() & IIR_NO_INTERRUPT == 0;
+    }
+
+    pub fn read(&mut self, address: u64, _size: u64) -> u64 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match address - self.base {
+            0 if dlab => self.dll,
+            0 => self.rx.pop_front().unwrap_or(0),
+            1 if dlab => self.dlm,
+            1 => self.ier,
+            2 => {
+                let iir = self.iir();
+                if iir & 0x0f == IIR_TX_EMPTY {
+                    self.tx_pending = false;
+                }
+                iir
+            },
+            3 => self.lcr,
+            4 => self.mcr,
+            5 => {
+                let ready = if self.rx.is_empty() { 0 } else { LSR_DATA_READY };
+                ready | LSR_THR_EMPTY | LSR_TX_EMPTY
+            },
+            6 if self.mcr & MCR_LOOPBACK != 0 => {
+                // loopback: DTR->DSR, RTS->CTS, OUT1->RI, OUT2->DCD
+                (self.mcr & 0x0f) << 4
+            },
+            6 => 0xb0, // DCD, DSR and CTS asserted
+            7 => self.scr,
+            _ => 0,
+        };
+        return value as u64;
+    }
+
+    pub fn write(&mut self, address: u64, _size: u64, value: u64, uart_out: &mut Vec<u8>) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = value as u8;
+        match address - self.base {
+            0 if dlab => self.dll = value,
+            0 => {
+                if self.mcr & MCR_LOOPBACK != 0 {
+                    self.rx.push_back(value);
+                } else {
+                    uart_out.push(value);
+                }
+                self.tx_pending = true;
+            },
+            1 if dlab => self.dlm = value,
+            1 => {
+                // enabling the transmitter interrupt with an empty transmitter raises it right away
+                if value & IER_TX_EMPTY != 0 && self.ier & IER_TX_EMPTY == 0 {
+                    self.tx_pending = true;
+                }
+                self.ier = value & 0x0f;
+            },
+            2 => {
+                if value & 0x02 != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = value & 0xc9;
+            },
+            3 => self.lcr = value,
+            4 => self.mcr = value & 0x1f,
+            7 => self.scr = value,
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transmits_receives_and_switches_to_the_divisor_with_dlab() {
+        let mut uart = default_uart();
+        let mut out = vec![];
+        uart.write(UART_BASE, 1, b'h' as u64, &mut out);
+        assert_eq!(out, b"h");
+        assert_eq!(uart.read(UART_BASE + 5, 1) as u8, LSR_THR_EMPTY | LSR_TX_EMPTY);
+        uart.rx.extend(b"ab");
+        assert_eq!(uart.read(UART_BASE + 5, 1) as u8 & LSR_DATA_READY, LSR_DATA_READY);
+        assert_eq!(uart.read(UART_BASE, 1), b'a' as u64);
+        // DLAB covers RBR/THR and IER with the divisor
+        uart.write(UART_BASE + 3, 1, LCR_DLAB as u64 | 3, &mut out);
+        uart.write(UART_BASE, 1, 0x12, &mut out);
+        uart.write(UART_BASE + 1, 1, 0x34, &mut out);
+        assert_eq!((uart.dll, uart.dlm, uart.ier), (0x12, 0x34, 0));
+        assert_eq!(uart.read(UART_BASE, 1), 0x12);
+        assert_eq!(out, b"h");
+        uart.write(UART_BASE + 3, 1, 3, &mut out);
+        assert_eq!(uart.read(UART_BASE, 1), b'b' as u64);
+        assert_eq!(uart.read(UART_BASE, 1), 0);
+    }
+
+    #[test]
+    fn interrupts_for_received_bytes_before_an_empty_transmitter() {
+        let mut uart = default_uart();
+        let mut out = vec![];
+        uart.write(UART_BASE + 2, 1, 1, &mut out);
+        assert_eq!(uart.read(UART_BASE + 2, 1) as u8, IIR_FIFO_ENABLED | IIR_NO_INTERRUPT);
+        // enabling the transmitter interrupt raises it, reading IIR clears it
+        uart.write(UART_BASE + 1, 1, (IER_RX_AVAILABLE | IER_TX_EMPTY) as u64, &mut out);
+        assert!(uart.interrupt_pending());
+        uart.rx.push_back(b'x');
+        assert_eq!(uart.read(UART_BASE + 2, 1) as u8, IIR_FIFO_ENABLED | IIR_RX_AVAILABLE);
+        assert_eq!(uart.read(UART_BASE, 1), b'x' as u64);
+        assert_eq!(uart.read(UART_BASE + 2, 1) as u8, IIR_FIFO_ENABLED | IIR_TX_EMPTY);
+        assert!(!uart.interrupt_pending());
+        // a FIFO reset drops what was received
+        uart.rx.push_back(b'y');
+        uart.write(UART_BASE + 2, 1, 3, &mut out);
+        assert!(uart.rx.is_empty() && !uart.interrupt_pending());
+    }
+
+    #[test]
+    fn loopback_receives_what_is_sent() {
+        let mut uart = default_uart();
+        let mut out = vec![];
+        uart.write(UART_BASE + 4, 1, (MCR_LOOPBACK | 0x03) as u64, &mut out);
+        uart.write(UART_BASE, 1, b'z' as u64, &mut out);
+        assert!(out.is_empty());
+        assert_eq!(uart.read(UART_BASE, 1), b'z' as u64);
+        // DTR and RTS come back as DSR and CTS
+        assert_eq!(uart.read(UART_BASE + 6, 1), 0x30);
+        uart.write(UART_BASE + 4, 1, 0, &mut out);
+        assert_eq!(uart.read(UART_BASE + 6, 1), 0xb0);
+    }
+}
@@ -0,0 +1,390 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/*
+ * The WebSocket protocol of the streaming endpoint of the server: the accept key of the opening handshake
+ * and the framing of messages.
+ *
+ *      0               1               2               3
+ *      FIN RSV1-3 opcode | MASK payload len | extended len (16 or 64 bit) | masking key (client frames) | payload
+ *
+ * Frames from the client are masked, frames of the server are not. Messages can be fragmented into a text or binary
+ * frame followed by continuation frames, control frames (close, ping, pong) can come in between.
+ * No extensions or subprotocols are negotiated.
+ *
+ * RFC 6455 The WebSocket Protocol https://www.rfc-editor.org/rfc/rfc6455
+ * FIPS 180-4 Secure Hash Standard, SHA-1 https://csrc.nist.gov/pubs/fips/180-4/upd1/final
+ */
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const MAX_MESSAGE: usize = 1 << 20;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT:         u8 = 0x1;
+const OPCODE_BINARY:       u8 = 0x2;
+const OPCODE_CLOSE:        u8 = 0x8;
+const OPCODE_PING:         u8 = 0x9;
+const OPCODE_PONG:         u8 = 0xa;
+
+// close status codes, RFC 6455 7.4.1
+pub const CLOSE_NORMAL:         u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA:   u16 = 1007;
+pub const CLOSE_TOO_BIG:        u16 = 1009;
+
+#[derive(Debug)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong,
+    Close,
+}
+
+// why reading stopped, code 0 when the connection broke and no close frame can be sent
+#[derive(Debug)]
+pub struct WebSocketError {
+    pub code:   u16,
+    pub reason: String,
+}
+
+fn ws_error(code: u16, reason: impl Into<String>) -> WebSocketError {
+    return WebSocketError { code: code, reason: reason.into() };
+}
+
+// Sec-WebSocket-Accept for the Sec-WebSocket-Key of the client
+pub fn accept_key(key: &str) -> String {
+    return base64(&sha1(format!("{}{}", key.trim(), ACCEPT_GUID).as_bytes()));
+}
+
+// the key is 16 random bytes in base64
+pub fn valid_key(key: &str) -> bool {
+    let key = key.trim();
+    return key.len() == 24 && key.ends_with("==") && key[..22].bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/');
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19  => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _       => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut digest = [0u8; 20];
+    for (i, v) in h.iter().enumerate() {
+        digest[4 * i..4 * i + 4].copy_from_slice(&v.to_be_bytes());
+    }
+    return digest;
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    return text;
+}
+
+// reassembles the fragments of a message, the partial message stays while control frames are returned
+pub struct MessageReader {
+    partial: Option<(u8, Vec<u8>)>, // opcode of the first frame and the payload so far
+}
+
+pub fn new_message_reader() -> MessageReader {
+    return MessageReader { partial: None };
+}
+
+impl MessageReader {
+    pub async fn read<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> Result<Message, WebSocketError> {
+        loop {
+            let (fin, opcode, payload) = read_frame(reader).await?;
+            match opcode {
+                // the status code of the client is not looked at, a payload has to hold one though
+                OPCODE_CLOSE if payload.len() == 1 => return Err(ws_error(CLOSE_PROTOCOL_ERROR, "close frame with a 1 byte payload")),
+                OPCODE_CLOSE => return Ok(Message::Close),
+                OPCODE_PING => return Ok(Message::Ping(payload)),
+                OPCODE_PONG => return Ok(Message::Pong),
+                OPCODE_TEXT | OPCODE_BINARY if self.partial.is_some() => {
+                    return Err(ws_error(CLOSE_PROTOCOL_ERROR, "new message before the last one ended"));
+                },
+                OPCODE_TEXT | OPCODE_BINARY => self.partial = Some((opcode, payload)),
+                OPCODE_CONTINUATION => match self.partial.as_mut() {
+                    Some((_, message)) if message.len() + payload.len() > MAX_MESSAGE => {
+                        return Err(ws_error(CLOSE_TOO_BIG, format!("message larger than {} bytes", MAX_MESSAGE)));
+                    },
+                    Some((_, message)) => message.extend_from_slice(&payload),
+                    None => return Err(ws_error(CLOSE_PROTOCOL_ERROR, "continuation without a message")),
+                },
+                _ => return Err(ws_error(CLOSE_PROTOCOL_ERROR, format!("unknown opcode 0x{:X}", opcode))),
+            }
+            if !fin {
+                continue;
+            }
+            let (opcode, message) = self.partial.take().unwrap();
+            if opcode == OPCODE_BINARY {
+                return Ok(Message::Binary(message));
+            }
+            return String::from_utf8(message).map(Message::Text).map_err(|_| ws_error(CLOSE_INVALID_DATA, "text message is not UTF-8"));
+        }
+    }
+}
+
+// fin, opcode and the unmasked payload of the next frame
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(bool, u8, Vec<u8>), WebSocketError> {
+    let broken = |e: std::io::Error| ws_error(0, e.to_string());
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await.map_err(broken)?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    if head[0] & 0x70 != 0 {
+        return Err(ws_error(CLOSE_PROTOCOL_ERROR, "reserved bits set without an extension"));
+    }
+    if head[1] & 0x80 == 0 {
+        return Err(ws_error(CLOSE_PROTOCOL_ERROR, "frames of the client have to be masked"));
+    }
+    let length = match head[1] & 0x7f {
+        126 => {
+            let mut bytes = [0u8; 2];
+            reader.read_exact(&mut bytes).await.map_err(broken)?;
+            u16::from_be_bytes(bytes) as u64
+        },
+        127 => {
+            let mut bytes = [0u8; 8];
+            reader.read_exact(&mut bytes).await.map_err(broken)?;
+            u64::from_be_bytes(bytes)
+        },
+        length => length as u64,
+    };
+    if opcode >= OPCODE_CLOSE && (!fin || length > 125) {
+        return Err(ws_error(CLOSE_PROTOCOL_ERROR, "fragmented or long control frame"));
+    }
+    if length > MAX_MESSAGE as u64 {
+        return Err(ws_error(CLOSE_TOO_BIG, format!("message larger than {} bytes", MAX_MESSAGE)));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await.map_err(broken)?;
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload).await.map_err(broken)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    return Ok((fin, opcode, payload));
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        0..=125 => frame.push(payload.len() as u8),
+        126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        },
+        _ => {
+            frame.push(127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    return writer.flush().await;
+}
+
+pub async fn write_text<W: AsyncWrite + Unpin>(writer: &mut W, text: &str) -> std::io::Result<()> {
+    return write_frame(writer, OPCODE_TEXT, text.as_bytes()).await;
+}
+
+pub async fn write_pong<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> std::io::Result<()> {
+    return write_frame(writer, OPCODE_PONG, payload).await;
+}
+
+// the reason is cut to what fits a control frame
+pub async fn write_close<W: AsyncWrite + Unpin>(writer: &mut W, code: u16, reason: &str) -> std::io::Result<()> {
+    let mut payload = code.to_be_bytes().to_vec();
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    return write_frame(writer, OPCODE_CLOSE, &payload).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a frame like a client sends it, masked unless told otherwise
+    fn frame(fin: bool, opcode: u8, payload: &[u8], masked: bool) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        let mask_bit = if masked { 0x80 } else { 0 };
+        match payload.len() {
+            0..=125 => frame.push(mask_bit | payload.len() as u8),
+            126..=0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            },
+            _ => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+            },
+        }
+        if !masked {
+            frame.extend_from_slice(payload);
+            return frame;
+        }
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        return frame;
+    }
+
+    async fn read_all(bytes: &[u8]) -> Vec<Result<Message, WebSocketError>> {
+        let mut reader = bytes;
+        let mut messages = new_message_reader();
+        let mut results = vec![];
+        while !reader.is_empty() {
+            let result = messages.read(&mut reader).await;
+            let error = result.is_err();
+            results.push(result);
+            if error {
+                break;
+            }
+        }
+        return results;
+    }
+
+    async fn error_code(bytes: &[u8]) -> u16 {
+        return match read_all(bytes).await.pop() {
+            Some(Err(error)) => error.code,
+            other => panic!("no error but {:?}", other),
+        };
+    }
+
+    #[test]
+    fn accept_key_is_the_one_of_rfc_6455() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert!(valid_key("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert!(!valid_key("dGhlIHNhbXBsZSBub25jZQ="));
+        assert!(!valid_key("dGhlIHNhbXBsZSBub25jZ!=="));
+    }
+
+    #[test]
+    fn sha1_and_base64_match_their_test_vectors() {
+        let hex = |digest: [u8; 20]| digest.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // 56 bytes, the length goes into a block of its own
+        assert_eq!(hex(sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        for (data, text) in [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="), ("foobar", "Zm9vYmFy")] {
+            assert_eq!(base64(data.as_bytes()), text);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_masked_messages_of_every_length() {
+        let long = vec![0xa5u8; 70000];
+        let mut bytes = frame(true, OPCODE_TEXT, b"hello", true);
+        bytes.extend(frame(true, OPCODE_BINARY, &[7; 300], true));
+        bytes.extend(frame(true, OPCODE_BINARY, &long, true));
+        bytes.extend(frame(true, OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes(), true));
+        let messages = read_all(&bytes).await;
+        assert!(matches!(&messages[0], Ok(Message::Text(text)) if text == "hello"));
+        assert!(matches!(&messages[1], Ok(Message::Binary(data)) if *data == [7; 300]));
+        assert!(matches!(&messages[2], Ok(Message::Binary(data)) if *data == long));
+        assert!(matches!(&messages[3], Ok(Message::Close)));
+    }
+
+    #[tokio::test]
+    async fn reassembles_fragments_around_control_frames() {
+        let mut bytes = frame(false, OPCODE_TEXT, b"{\"type\":", true);
+        bytes.extend(frame(true, OPCODE_PING, b"are you there", true));
+        bytes.extend(frame(false, OPCODE_CONTINUATION, b" \"st", true));
+        bytes.extend(frame(true, OPCODE_CONTINUATION, b"op\"}", true));
+        let messages = read_all(&bytes).await;
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(&messages[0], Ok(Message::Ping(payload)) if payload == b"are you there"));
+        assert!(matches!(&messages[1], Ok(Message::Text(text)) if text == "{\"type\": \"stop\"}"));
+    }
+
+    #[tokio::test]
+    async fn protocol_errors_close_with_their_code() {
+        let text = frame(false, OPCODE_TEXT, b"a", true);
+        let cases: Vec<(Vec<u8>, u16)> = vec![
+            (frame(true, OPCODE_TEXT, b"unmasked", false), CLOSE_PROTOCOL_ERROR),
+            ([vec![0xc1], frame(true, OPCODE_TEXT, b"x", true)[1..].to_vec()].concat(), CLOSE_PROTOCOL_ERROR), // RSV1
+            (frame(true, OPCODE_CONTINUATION, b"x", true), CLOSE_PROTOCOL_ERROR),
+            ([text.clone(), frame(true, OPCODE_TEXT, b"b", true)].concat(), CLOSE_PROTOCOL_ERROR),
+            (frame(false, OPCODE_PING, b"", true), CLOSE_PROTOCOL_ERROR),
+            (frame(true, OPCODE_PING, &[0; 126], true), CLOSE_PROTOCOL_ERROR),
+            (frame(true, OPCODE_CLOSE, &[3], true), CLOSE_PROTOCOL_ERROR),
+            (frame(true, 0x3, b"", true), CLOSE_PROTOCOL_ERROR),
+            (frame(true, OPCODE_TEXT, &[0xff, 0xfe], true), CLOSE_INVALID_DATA),
+            (frame(true, OPCODE_BINARY, &vec![0; MAX_MESSAGE + 1], true), CLOSE_TOO_BIG),
+            ([frame(false, OPCODE_BINARY, &vec![0; MAX_MESSAGE], true), frame(true, OPCODE_CONTINUATION, b"x", true)].concat(), CLOSE_TOO_BIG),
+            // the connection broke in the middle of a frame, there is nobody to send a close frame to
+            (frame(true, OPCODE_TEXT, b"cut short", true)[..8].to_vec(), 0),
+        ];
+        for (i, (bytes, code)) in cases.iter().enumerate() {
+            assert_eq!(error_code(bytes).await, *code, "case {}", i);
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_unmasked_frames_with_the_shortest_length() {
+        for (length, head) in [(5, vec![0x81, 5]), (126, vec![0x81, 126, 0, 126]), (70000, vec![0x81, 127, 0, 0, 0, 0, 0, 1, 0x11, 0x70])] {
+            let mut written: Vec<u8> = vec![];
+            write_text(&mut written, &"a".repeat(length)).await.unwrap();
+            assert_eq!(written[..head.len()], head[..], "length {}", length);
+            assert_eq!(written.len(), head.len() + length);
+        }
+        let mut written: Vec<u8> = vec![];
+        write_pong(&mut written, b"ping").await.unwrap();
+        assert_eq!(written, [0x8a, 4, b'p', b'i', b'n', b'g']);
+    }
+
+    #[tokio::test]
+    async fn close_reasons_are_cut_at_a_character() {
+        let mut written: Vec<u8> = vec![];
+        write_close(&mut written, CLOSE_TOO_BIG, &"é".repeat(100)).await.unwrap();
+        // 2 bytes of code and 122 of the reason, 123 would split an é
+        assert_eq!(written[..4], [0x88, 124, 0x03, 0xf1]);
+        assert!(std::str::from_utf8(&written[4..]).is_ok());
+        assert_eq!(written.len(), 2 + 124);
+    }
+}
@@ -0,0 +1,66 @@
+#!/bin/bash
+# Boots OpenSBI fw_jump + Linux + a buildroot initramfs and waits for the shell prompt on the UART.
+#
+#   ./boot.sh [images directory]
+#
+# The directory (default ./images) needs fw_jump.elf (or fw_jump.bin), Image and rootfs.cpio, see README.md.
+
+IMAGES=${1:-./images}
+TIMEOUT=${TIMEOUT:-3600}
+LOG=${LOG:-./boot.log}
+
+echo "==== LINUX BOOT TEST ===="
+
+# FIRMWARE=builtin boots on the SBI built into the simulator
+if [ -z "$FIRMWARE" ]; then
+    FIRMWARE="$IMAGES/fw_jump.elf"
+    [ -f "$FIRMWARE" ] || FIRMWARE="$IMAGES/fw_jump.bin"
+fi
+for f in "$IMAGES/Image" "$IMAGES/rootfs.cpio"; do
+    if [ ! -f "$f" ]; then
+        echo "missing $f, see README.md"
+        exit 1
+    fi
+done
+if [ "$FIRMWARE" != "builtin" ] && [ ! -f "$FIRMWARE" ]; then
+    echo "missing $FIRMWARE, see README.md"
+    exit 1
+fi
+
+pushd ./../../sim > /dev/null
+cargo build --release || exit 1
+popd > /dev/null
+
+# the console input is a fifo, so the test can log in once buildroot asks for it
+CONSOLE=$(mktemp -u)
+mkfifo "$CONSOLE"
+exec 3<> "$CONSOLE"
+
+timeout "$TIMEOUT" ./../../sim/target/release/ar64 -B "$FIRMWARE" \
+    --kernel "$IMAGES/Image" --initrd "$IMAGES/rootfs.cpio" \
+    --mem 256M --bootargs "console=ttyS0" < "$CONSOLE" > "$LOG" &
+SIM=$!
+
+result=1
+logged_in=0
+while kill -0 $SIM 2> /dev/null; do
+    if [ $logged_in -eq 0 ] && grep -q "login:" "$LOG"; then
+        echo "root" >&3
+        logged_in=1
+    fi
+    if grep -q "^# " "$LOG"; then
+        result=0
+        break
+    fi
+    sleep 5
+done
+kill $SIM 2> /dev/null
+exec 3>&-
+rm -f "$CONSOLE"
+
+if [ $result -eq 0 ]; then
+    echo "==== SHELL PROMPT REACHED ===="
+else
+    echo "No shell prompt, see $LOG"
+fi
+exit $result
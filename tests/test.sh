@@ -12,16 +12,21 @@ function run_single_test() {
     # go there and store current location
     pushd ./riscv-tests/isa
     make "$1"
-    llvm-objcopy "$1" -O binary "$1.bin"
 
     # go back
     popd
 
     pushd ./../sim
-    RUST_BACKTRACE=1 RUST_BACKTRACE=full cargo run -- -T "./../tests/riscv-tests/isa/$1.bin"
-    if [ $? -gt 0 ]; then
-        echo "Test failed!"
-        exit $?
+    RUST_BACKTRACE=1 RUST_BACKTRACE=full cargo run -- -T "./../tests/riscv-tests/isa/$1"
+    result=$?
+    # 125: the simulator stopped on its own, 101: the simulator panicked, other: code reported by the guest
+    # (ar64 turns guest codes that collide with 101, 124-127 into 1)
+    if [ $result -eq 125 ] || [ $result -eq 101 ]; then
+        echo "Simulator error ($result)!"
+        exit $result
+    elif [ $result -gt 0 ]; then
+        echo "Test failed with code $result!"
+        exit $result
     fi
 
     popd